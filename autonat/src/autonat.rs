@@ -0,0 +1,425 @@
+use futures::channel::{mpsc, oneshot};
+use futures::{select, SinkExt, StreamExt};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use libp2p_rs::core::muxing::IStreamMuxer;
+use libp2p_rs::core::transport::{ITransport, TransportError};
+use libp2p_rs::core::{Multiaddr, PeerId};
+use libp2p_rs::runtime::task;
+use libp2p_rs::swarm::protocol_handler::{IProtocolHandler, ProtocolImpl};
+use libp2p_rs::swarm::substream::Substream;
+use libp2p_rs::swarm::Control as SwarmControl;
+
+use crate::autonat_pb::message::ResponseStatus;
+use crate::config::AutonatConfig;
+use crate::error::AutonatError;
+use crate::protocol::{
+    dial_response, is_global, is_relayed, is_same_host, parse_dial, read_message, send_dial,
+    write_message, Handler, MAX_DIAL_ADDRS,
+};
+
+/// The reachability of the local node.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NatStatus {
+    /// Not enough peers answered yet.
+    Unknown,
+    /// Peers can dial the node.
+    Public,
+    /// The node is behind a NAT or a firewall.
+    Private,
+}
+
+type StatusListener = Arc<dyn Fn(NatStatus) + Send + Sync>;
+
+/// The maximum number of observed address candidates, older candidates are dropped first.
+const MAX_CANDIDATES: usize = 8;
+
+struct State {
+    status: NatStatus,
+    confirmed: Vec<Multiaddr>,
+    candidates: Vec<Multiaddr>,
+}
+
+pub(crate) enum ControlCommand {
+    Probe(oneshot::Sender<NatStatus>),
+}
+
+/// Limits the dial back requests answered globally and per peer, so that peers can't use the
+/// node to flood a host with dials.
+#[derive(Default)]
+struct Throttle {
+    answered: VecDeque<(Instant, PeerId)>,
+    per_peer: HashMap<PeerId, usize>,
+}
+
+impl Throttle {
+    /// Returns `true` and counts the request if `peer` may be answered at `now`.
+    fn allow(&mut self, config: &AutonatConfig, peer: PeerId, now: Instant) -> bool {
+        while let Some((at, old)) = self.answered.front() {
+            if now.saturating_duration_since(*at) < config.throttle_period {
+                break;
+            }
+            if let Some(count) = self.per_peer.get_mut(old) {
+                *count -= 1;
+                if *count == 0 {
+                    self.per_peer.remove(old);
+                }
+            }
+            self.answered.pop_front();
+        }
+        let count = self.per_peer.get(&peer).copied().unwrap_or_default();
+        if self.answered.len() >= config.throttle_global_max || count >= config.throttle_peer_max {
+            return false;
+        }
+        self.answered.push_back((now, peer));
+        *self.per_peer.entry(peer).or_default() += 1;
+        true
+    }
+}
+
+/// Dials back peers and probes the reachability of the local node.
+pub struct Autonat {
+    config: AutonatConfig,
+    local_peer_id: PeerId,
+
+    // Dial back peers on fresh connections, independent of the swarm connections.
+    transports: Vec<ITransport<IStreamMuxer>>,
+
+    state: Arc<Mutex<State>>,
+    listener: Option<StatusListener>,
+    throttle: Throttle,
+
+    // Fires on the first connection, the first probe follows after the boot delay.
+    first_connection: Arc<Mutex<Option<oneshot::Sender<()>>>>,
+    first_connection_rx: Option<oneshot::Receiver<()>>,
+
+    incoming_tx: mpsc::UnboundedSender<Substream>,
+    incoming_rx: mpsc::UnboundedReceiver<Substream>,
+
+    control_tx: mpsc::UnboundedSender<ControlCommand>,
+    control_rx: mpsc::UnboundedReceiver<ControlCommand>,
+}
+
+impl Autonat {
+    /// Creates an autonat service dialing back peers on `transports`, each address is dialed
+    /// on the transports in order until one supports it.
+    pub fn new(
+        config: AutonatConfig,
+        local_peer_id: PeerId,
+        transports: Vec<ITransport<IStreamMuxer>>,
+    ) -> Self {
+        let (incoming_tx, incoming_rx) = mpsc::unbounded();
+        let (control_tx, control_rx) = mpsc::unbounded();
+        let (first_connection, first_connection_rx) = oneshot::channel();
+        Self {
+            config,
+            local_peer_id,
+            transports,
+            state: Arc::new(Mutex::new(State {
+                status: NatStatus::Unknown,
+                confirmed: vec![],
+                candidates: vec![],
+            })),
+            listener: None,
+            throttle: Default::default(),
+            first_connection: Arc::new(Mutex::new(Some(first_connection))),
+            first_connection_rx: Some(first_connection_rx),
+            incoming_tx,
+            incoming_rx,
+            control_tx,
+            control_rx,
+        }
+    }
+
+    /// Calls `listener` whenever the reachability changes.
+    pub fn with_status_listener<F: Fn(NatStatus) + Send + Sync + 'static>(
+        mut self,
+        listener: F,
+    ) -> Self {
+        self.listener = Some(Arc::new(listener));
+        self
+    }
+
+    pub fn control(&self) -> AutonatControl {
+        AutonatControl {
+            state: self.state.clone(),
+            control_tx: self.control_tx.clone(),
+        }
+    }
+
+    async fn process_loop(&mut self, swarm: SwarmControl) {
+        loop {
+            select! {
+                stream = self.incoming_rx.next() => {
+                    if let Some(stream) = stream {
+                        self.dial_back(stream);
+                    }
+                }
+                cmd = self.control_rx.next() => {
+                    match cmd {
+                        Some(ControlCommand::Probe(tx)) => {
+                            let status = self.probe(swarm.clone()).await;
+                            let _ = tx.send(status);
+                        }
+                        None => break,
+                    }
+                }
+            }
+        }
+    }
+
+    /// Answers a dial request in the background. Only the addresses on the host the request
+    /// came from are dialed, and requests beyond the throttle limits are refused.
+    fn dial_back(&mut self, mut stream: Substream) {
+        let config = self.config.clone();
+        let transports: Vec<_> = self.transports.iter().map(|t| t.box_clone()).collect();
+        let peer = stream.remote_peer();
+        let observed = stream.remote_multiaddr();
+        let allowed = config.enable_server && self.throttle.allow(&config, peer, Instant::now());
+        task::spawn(async move {
+            let res = async {
+                let msg = read_message(&mut stream).await?;
+                let response = match parse_dial(&msg) {
+                    _ if !allowed => dial_response(ResponseStatus::EDialRefused, None),
+                    Some((peer_id, addrs)) if peer_id == peer => {
+                        let addrs: Vec<_> = addrs
+                            .into_iter()
+                            .filter(|addr| !is_relayed(addr))
+                            .filter(|addr| is_same_host(&observed, addr))
+                            .filter(|addr| !config.only_global_ips || is_global(addr))
+                            .take(MAX_DIAL_ADDRS)
+                            .collect();
+                        if addrs.is_empty() {
+                            dial_response(ResponseStatus::EDialRefused, None)
+                        } else {
+                            match dial_any(transports, &config, peer, addrs).await {
+                                Some(addr) => dial_response(ResponseStatus::Ok, Some(&addr)),
+                                None => dial_response(ResponseStatus::EDialError, None),
+                            }
+                        }
+                    }
+                    _ => dial_response(ResponseStatus::EBadRequest, None),
+                };
+                write_message(&mut stream, &response).await
+            };
+            if let Err(err) = res.await {
+                log::debug!("autonat request from {} failed: {}", peer, err);
+            }
+        });
+    }
+
+    /// Asks connected peers to dial back the local addresses and the observed address
+    /// candidates and updates the reachability.
+    async fn probe(&mut self, mut swarm: SwarmControl) -> NatStatus {
+        let mut addrs = swarm.self_addrs().await.unwrap_or_default();
+        for addr in self.state.lock().unwrap().candidates.iter() {
+            if !addrs.contains(addr) {
+                addrs.push(addr.clone());
+            }
+        }
+        addrs.retain(|addr| !is_relayed(addr));
+        let mut peers: Vec<PeerId> = swarm
+            .dump_connections(None)
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|c| !is_relayed(&c.info.ra))
+            .map(|c| c.info.remote_peer_id)
+            .collect();
+        peers.dedup();
+        peers.truncate(self.config.probe_peers);
+
+        let mut confirmed = vec![];
+        let mut failures = 0;
+        if !addrs.is_empty() {
+            for peer in peers {
+                match send_dial(swarm.clone(), self.local_peer_id, peer, &addrs).await {
+                    Ok(addr) => confirmed.push(addr),
+                    Err(AutonatError::Status(status))
+                        if status == ResponseStatus::EDialError as i32 =>
+                    {
+                        failures += 1
+                    }
+                    Err(err) => log::debug!("autonat probe with {} failed: {}", peer, err),
+                }
+            }
+        }
+
+        let (status, changed) = {
+            let mut state = self.state.lock().unwrap();
+            let old = state.status;
+            if confirmed.len() >= self.config.confidence {
+                state.status = NatStatus::Public;
+            } else if failures >= self.config.confidence || addrs.is_empty() {
+                state.status = NatStatus::Private;
+            }
+            if state.status == NatStatus::Private {
+                state.confirmed.clear();
+            }
+            for addr in confirmed {
+                if !state.confirmed.contains(&addr) {
+                    state.confirmed.push(addr);
+                }
+            }
+            (state.status, old != state.status)
+        };
+        if changed {
+            log::info!("nat status changed to {:?}", status);
+            if let Some(listener) = self.listener.as_ref() {
+                listener(status);
+            }
+        }
+        status
+    }
+}
+
+/// Dials the addresses until one reaches `peer`.
+async fn dial_any(
+    mut transports: Vec<ITransport<IStreamMuxer>>,
+    config: &AutonatConfig,
+    peer: PeerId,
+    addrs: Vec<Multiaddr>,
+) -> Option<Multiaddr> {
+    for addr in addrs {
+        for transport in transports.iter_mut() {
+            match task::timeout(config.dial_timeout, transport.dial(addr.clone())).await {
+                Ok(Ok(mut conn)) => {
+                    let remote = conn.remote_peer();
+                    let _ = conn.close().await;
+                    if remote == peer {
+                        return Some(addr);
+                    }
+                    break;
+                }
+                Ok(Err(TransportError::MultiaddrNotSupported(_))) => continue,
+                Ok(Err(err)) => log::debug!("dial back to {} failed: {:?}", addr, err),
+                Err(_) => log::debug!("dial back to {} timed out", addr),
+            }
+            break;
+        }
+    }
+    None
+}
+
+impl ProtocolImpl for Autonat {
+    fn handler(&self) -> IProtocolHandler {
+        Box::new(Handler::new(
+            self.incoming_tx.clone(),
+            self.first_connection.clone(),
+        ))
+    }
+
+    fn start(mut self, swarm: SwarmControl) -> Option<task::TaskHandle<()>>
+    where
+        Self: Sized,
+    {
+        // the probe ticker stops once the control channel is closed, the first probe runs
+        // shortly after the first connection
+        let mut ticker = self.control_tx.clone();
+        let first_connection = self.first_connection_rx.take();
+        let boot_delay = self.config.boot_delay;
+        let interval = self.config.probe_interval;
+        task::spawn(async move {
+            if let Some(first_connection) = first_connection {
+                if first_connection.await.is_err() {
+                    return;
+                }
+            }
+            let mut delay = boot_delay;
+            loop {
+                task::sleep(delay).await;
+                delay = interval;
+                let (tx, _rx) = oneshot::channel();
+                if ticker.send(ControlCommand::Probe(tx)).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut autonat = self;
+        Some(task::spawn(async move {
+            log::info!("starting autonat main loop...");
+            autonat.process_loop(swarm).await;
+            log::info!("exiting autonat main loop...");
+        }))
+    }
+}
+
+/// Returns the reachability and the confirmed external addresses.
+#[derive(Clone)]
+pub struct AutonatControl {
+    state: Arc<Mutex<State>>,
+    control_tx: mpsc::UnboundedSender<ControlCommand>,
+}
+
+impl AutonatControl {
+    /// Returns the current reachability.
+    pub fn status(&self) -> NatStatus {
+        self.state.lock().unwrap().status
+    }
+
+    /// Returns the addresses peers dialed back successfully.
+    pub fn confirmed_addrs(&self) -> Vec<Multiaddr> {
+        self.state.lock().unwrap().confirmed.clone()
+    }
+
+    /// Adds an address a peer observed the local node at. Candidates are dialed back on the
+    /// next probe and become external addresses once confirmed.
+    pub fn add_candidate(&self, addr: Multiaddr) {
+        if is_relayed(&addr) {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.candidates.retain(|a| a != &addr);
+        state.candidates.push(addr);
+        if state.candidates.len() > MAX_CANDIDATES {
+            state.candidates.remove(0);
+        }
+    }
+
+    /// Probes the reachability now instead of waiting for the next probe.
+    pub async fn probe(&mut self) -> Result<NatStatus, AutonatError> {
+        let (tx, rx) = oneshot::channel();
+        self.control_tx.send(ControlCommand::Probe(tx)).await?;
+        Ok(rx.await?)
+    }
+
+    /// Closes the autonat main loop.
+    pub fn close(&mut self) {
+        self.control_tx.close_channel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_throttle() {
+        let config = AutonatConfig {
+            throttle_global_max: 3,
+            throttle_peer_max: 2,
+            throttle_period: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let mut throttle = Throttle::default();
+        let now = Instant::now();
+        let a = PeerId::random();
+        let b = PeerId::random();
+        assert!(throttle.allow(&config, a, now));
+        assert!(throttle.allow(&config, a, now));
+        assert!(!throttle.allow(&config, a, now));
+        assert!(throttle.allow(&config, b, now));
+        // the global limit is reached
+        assert!(!throttle.allow(&config, PeerId::random(), now));
+
+        // requests are counted again once the period passed
+        let later = now + Duration::from_secs(60);
+        assert!(throttle.allow(&config, a, later));
+        assert!(throttle.allow(&config, a, later));
+        assert!(!throttle.allow(&config, a, later));
+    }
+}
@@ -0,0 +1,44 @@
+use std::time::Duration;
+
+/// AutoNAT configuration.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AutonatConfig {
+    /// Dial back peers asking for it.
+    pub enable_server: bool,
+    /// Only dial back addresses with a global ip address.
+    pub only_global_ips: bool,
+    /// The maximum number of dial back requests answered in a `throttle_period`.
+    pub throttle_global_max: usize,
+    /// The maximum number of dial back requests of a single peer answered in a
+    /// `throttle_period`.
+    pub throttle_peer_max: usize,
+    /// The period the dial back requests are counted in.
+    pub throttle_period: Duration,
+    /// The delay between the first connection and the first reachability probe.
+    pub boot_delay: Duration,
+    /// The interval between two reachability probes.
+    pub probe_interval: Duration,
+    /// The number of peers asked to dial back in a probe.
+    pub probe_peers: usize,
+    /// The number of agreeing dial backs needed to change the reachability.
+    pub confidence: usize,
+    /// The timeout of a dial back.
+    pub dial_timeout: Duration,
+}
+
+impl Default for AutonatConfig {
+    fn default() -> Self {
+        Self {
+            enable_server: true,
+            only_global_ips: true,
+            throttle_global_max: 30,
+            throttle_peer_max: 3,
+            throttle_period: Duration::from_secs(60),
+            boot_delay: Duration::from_secs(15),
+            probe_interval: Duration::from_secs(15 * 60),
+            probe_peers: 3,
+            confidence: 1,
+            dial_timeout: Duration::from_secs(15),
+        }
+    }
+}
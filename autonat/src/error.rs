@@ -0,0 +1,20 @@
+use futures::channel::{mpsc, oneshot};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum AutonatError {
+    #[error("Error while decoding autonat message: {0}")]
+    ProtobufError(#[from] prost::DecodeError),
+    #[error("Unexpected autonat message")]
+    UnexpectedMessage,
+    #[error("Dial back failed with status {0}")]
+    Status(i32),
+    #[error("Io error {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Swarm error {0}")]
+    Swarm(String),
+    #[error("Error sending {0}")]
+    Send(#[from] mpsc::SendError),
+    #[error("Cancelled oneshot {0}")]
+    Cancel(#[from] oneshot::Canceled),
+}
@@ -0,0 +1,20 @@
+//! AutoNAT.
+//!
+//! A node asks connected peers to dial it back on its addresses. Addresses the peers could
+//! dial are confirmed external addresses, and the outcome of the dial backs tells whether
+//! the node is publicly reachable or behind a NAT.
+
+mod autonat;
+mod config;
+mod error;
+mod protocol;
+
+pub use crate::autonat::{Autonat, AutonatControl, NatStatus};
+pub use crate::config::AutonatConfig;
+pub use crate::error::AutonatError;
+
+const AUTONAT_PROTO_ID: &[u8] = b"/libp2p/autonat/1.0.0";
+
+mod autonat_pb {
+    include!(concat!(env!("OUT_DIR"), "/autonat_pb.rs"));
+}
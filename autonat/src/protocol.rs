@@ -0,0 +1,245 @@
+use async_trait::async_trait;
+use futures::channel::{mpsc, oneshot};
+use futures::SinkExt;
+use prost::Message as _;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+
+use libp2p_rs::core::multiaddr::protocol::Protocol;
+use libp2p_rs::core::upgrade::UpgradeInfo;
+use libp2p_rs::core::{Multiaddr, PeerId, ProtocolId};
+use libp2p_rs::swarm::connection::Connection;
+use libp2p_rs::swarm::protocol_handler::{IProtocolHandler, Notifiee, ProtocolHandler};
+use libp2p_rs::swarm::substream::Substream;
+use libp2p_rs::swarm::Control as SwarmControl;
+use libp2p_rs::traits::{ReadEx, WriteEx};
+
+use crate::autonat_pb::message::{Dial, DialResponse, MessageType, PeerInfo, ResponseStatus};
+use crate::autonat_pb::Message;
+use crate::error::AutonatError;
+use crate::AUTONAT_PROTO_ID;
+
+const MAX_MESSAGE_SIZE: usize = 4096;
+
+/// The maximum number of addresses dialed back for a request.
+pub(crate) const MAX_DIAL_ADDRS: usize = 16;
+
+#[derive(Clone)]
+pub(crate) struct Handler {
+    incoming_tx: mpsc::UnboundedSender<Substream>,
+    first_connection: Arc<Mutex<Option<oneshot::Sender<()>>>>,
+}
+
+impl Handler {
+    pub(crate) fn new(
+        incoming_tx: mpsc::UnboundedSender<Substream>,
+        first_connection: Arc<Mutex<Option<oneshot::Sender<()>>>>,
+    ) -> Self {
+        Self {
+            incoming_tx,
+            first_connection,
+        }
+    }
+}
+
+impl UpgradeInfo for Handler {
+    type Info = ProtocolId;
+
+    fn protocol_info(&self) -> Vec<Self::Info> {
+        vec![AUTONAT_PROTO_ID.into()]
+    }
+}
+
+impl Notifiee for Handler {
+    fn connected(&mut self, _conn: &mut Connection) {
+        if let Some(tx) = self.first_connection.lock().unwrap().take() {
+            let _ = tx.send(());
+        }
+    }
+}
+
+#[async_trait]
+impl ProtocolHandler for Handler {
+    async fn handle(
+        &mut self,
+        stream: Substream,
+        _info: <Self as UpgradeInfo>::Info,
+    ) -> Result<(), Box<dyn Error>> {
+        log::trace!("Handle autonat stream from {}", stream.remote_peer());
+        self.incoming_tx.send(stream).await?;
+        Ok(())
+    }
+
+    fn box_clone(&self) -> IProtocolHandler {
+        Box::new(self.clone())
+    }
+}
+
+pub(crate) async fn write_message(
+    stream: &mut Substream,
+    msg: &Message,
+) -> Result<(), AutonatError> {
+    let mut buf = Vec::with_capacity(msg.encoded_len());
+    msg.encode(&mut buf)
+        .expect("Vec<u8> provides capacity as needed");
+    stream.write_one(&buf).await?;
+    Ok(())
+}
+
+pub(crate) async fn read_message(stream: &mut Substream) -> Result<Message, AutonatError> {
+    let buf = stream.read_one(MAX_MESSAGE_SIZE).await?;
+    Ok(Message::decode(buf.as_slice())?)
+}
+
+/// Parses the peer and the addresses of a dial request.
+pub(crate) fn parse_dial(msg: &Message) -> Option<(PeerId, Vec<Multiaddr>)> {
+    if msg.r#type != Some(MessageType::Dial as i32) {
+        return None;
+    }
+    let peer = msg.dial.as_ref()?.peer.as_ref()?;
+    let peer_id = PeerId::from_bytes(peer.id.as_ref()?).ok()?;
+    let addrs = peer
+        .addrs
+        .iter()
+        .filter_map(|addr| Multiaddr::try_from(addr.clone()).ok())
+        .collect();
+    Some((peer_id, addrs))
+}
+
+pub(crate) fn dial_response(status: ResponseStatus, addr: Option<&Multiaddr>) -> Message {
+    Message {
+        r#type: Some(MessageType::DialResponse as i32),
+        dial: None,
+        dial_response: Some(DialResponse {
+            status: Some(status as i32),
+            status_text: None,
+            addr: addr.map(|addr| addr.to_vec()),
+        }),
+    }
+}
+
+/// Asks `peer` to dial back the local peer on `addrs`, returning the address it reached.
+pub(crate) async fn send_dial(
+    mut swarm: SwarmControl,
+    local: PeerId,
+    peer: PeerId,
+    addrs: &[Multiaddr],
+) -> Result<Multiaddr, AutonatError> {
+    let mut stream = swarm
+        .new_stream(peer, vec![AUTONAT_PROTO_ID.into()])
+        .await
+        .map_err(|err| AutonatError::Swarm(err.to_string()))?;
+    let msg = Message {
+        r#type: Some(MessageType::Dial as i32),
+        dial: Some(Dial {
+            peer: Some(PeerInfo {
+                id: Some(local.to_bytes()),
+                addrs: addrs.iter().map(|addr| addr.to_vec()).collect(),
+            }),
+        }),
+        dial_response: None,
+    };
+    write_message(&mut stream, &msg).await?;
+    let msg = read_message(&mut stream).await?;
+    let response = msg.dial_response.ok_or(AutonatError::UnexpectedMessage)?;
+    match response.status {
+        Some(status) if status == ResponseStatus::Ok as i32 => {
+            let addr = response.addr.ok_or(AutonatError::UnexpectedMessage)?;
+            Multiaddr::try_from(addr).map_err(|_| AutonatError::UnexpectedMessage)
+        }
+        Some(status) => Err(AutonatError::Status(status)),
+        None => Err(AutonatError::UnexpectedMessage),
+    }
+}
+
+/// Returns `true` if the address is relayed.
+pub(crate) fn is_relayed(addr: &Multiaddr) -> bool {
+    addr.iter().any(|p| p == Protocol::P2pCircuit)
+}
+
+/// Returns `true` if `addr` is on the host the request came from, so that dial backs can't
+/// be directed at third parties. Addresses without an ip address are only dialed back on
+/// in-memory connections.
+pub(crate) fn is_same_host(observed: &Multiaddr, addr: &Multiaddr) -> bool {
+    if is_relayed(observed) {
+        return false;
+    }
+    match (observed.iter().next(), addr.iter().next()) {
+        (Some(Protocol::Ip4(a)), Some(Protocol::Ip4(b))) => a == b,
+        (Some(Protocol::Ip6(a)), Some(Protocol::Ip6(b))) => a == b,
+        (Some(Protocol::Memory(_)), Some(Protocol::Memory(_))) => true,
+        _ => false,
+    }
+}
+
+/// Returns `true` if the ip address of `addr` is globally routable.
+pub(crate) fn is_global(addr: &Multiaddr) -> bool {
+    match addr.iter().next() {
+        Some(Protocol::Ip4(ip)) => {
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation())
+        }
+        Some(Protocol::Ip6(ip)) => {
+            // unique local fc00::/7 and link local fe80::/10 addresses
+            let segment = ip.segments()[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || (segment & 0xfe00) == 0xfc00
+                || (segment & 0xffc0) == 0xfe80)
+        }
+        Some(Protocol::Dns4(_)) | Some(Protocol::Dns6(_)) | Some(Protocol::Dns(_)) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_global() {
+        let global: Multiaddr = "/ip4/1.1.1.1/tcp/4001".parse().unwrap();
+        let private: Multiaddr = "/ip4/192.168.1.1/tcp/4001".parse().unwrap();
+        let loopback: Multiaddr = "/ip6/::1/tcp/4001".parse().unwrap();
+        assert!(is_global(&global));
+        assert!(!is_global(&private));
+        assert!(!is_global(&loopback));
+    }
+
+    #[test]
+    fn test_is_same_host() {
+        let observed: Multiaddr = "/ip4/1.1.1.1/tcp/53000".parse().unwrap();
+        let same: Multiaddr = "/ip4/1.1.1.1/tcp/4001".parse().unwrap();
+        let other: Multiaddr = "/ip4/8.8.8.8/tcp/4001".parse().unwrap();
+        let dns: Multiaddr = "/dns4/example.com/tcp/4001".parse().unwrap();
+        assert!(is_same_host(&observed, &same));
+        assert!(!is_same_host(&observed, &other));
+        assert!(!is_same_host(&observed, &dns));
+
+        let relayed: Multiaddr = "/ip4/1.1.1.1/tcp/4001/p2p-circuit".parse().unwrap();
+        assert!(!is_same_host(&relayed, &same));
+    }
+
+    #[test]
+    fn test_dial_message() {
+        let peer = PeerId::random();
+        let addr: Multiaddr = "/ip4/1.1.1.1/tcp/4001".parse().unwrap();
+        let msg = Message {
+            r#type: Some(MessageType::Dial as i32),
+            dial: Some(Dial {
+                peer: Some(PeerInfo {
+                    id: Some(peer.to_bytes()),
+                    addrs: vec![addr.to_vec()],
+                }),
+            }),
+            dial_response: None,
+        };
+        assert_eq!(parse_dial(&msg), Some((peer, vec![addr])));
+        assert_eq!(parse_dial(&dial_response(ResponseStatus::Ok, None)), None);
+    }
+}
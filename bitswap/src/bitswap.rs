@@ -30,6 +30,7 @@ pub(crate) enum ControlCommand {
     WantList(Option<PeerId>, oneshot::Sender<Result<Vec<(Cid, Priority)>>>),
     Peers(oneshot::Sender<Result<Vec<PeerId>>>),
     Stats(oneshot::Sender<Result<Stats>>),
+    Close(oneshot::Sender<()>),
 }
 
 pub struct Bitswap<TBlockstore: BitswapStore, TRouting> {
@@ -66,6 +67,9 @@ pub struct Bitswap<TBlockstore: BitswapStore, TRouting> {
 
     /// Statistics related to peers.
     stats: HashMap<PeerId, Arc<Stats>>,
+
+    /// Notified when the main loop exited after a close request.
+    closed: Option<oneshot::Sender<()>>,
 }
 
 impl<TBlockstore, TRouting> Bitswap<TBlockstore, TRouting>
@@ -92,6 +96,7 @@ impl<TBlockstore, TRouting> Bitswap<TBlockstore, TRouting>
             wanted_blocks: Default::default(),
             connected_peers: Default::default(),
             stats: Default::default(),
+            closed: None,
         }
     }
 
@@ -218,7 +223,7 @@ impl<TBlockstore, TRouting> Bitswap<TBlockstore, TRouting>
             task::spawn(async move {
                 let mut blocks = vec![];
                 for cid in to_check {
-                    if let Ok(Some(data)) = blockstore.get(&cid) {
+                    if let Ok(Some(data)) = blockstore.get(&cid).await {
                         log::debug!("block {} found in blockstore", cid);
                         blocks.push(Block::new_unchecked(cid, data));
                     }
@@ -264,7 +269,7 @@ impl<TBlockstore, TRouting> Bitswap<TBlockstore, TRouting>
         task::spawn(async move {
             for block in blocks {
                 let bytes = block.data().len() as u64;
-                let res = blockstore.insert(&block);
+                let res = blockstore.insert(block).await;
                 match res {
                     Ok(_) => {
                         peer_stats.update_incoming_unique(bytes);
@@ -310,6 +315,11 @@ impl<TBlockstore, TRouting> Bitswap<TBlockstore, TRouting>
             Some(ControlCommand::Stats(reply)) => {
                 let _ = reply.send(Ok(self.stats()));
             },
+            Some(ControlCommand::Close(reply)) => {
+                self.control_rx.close();
+                self.closed = Some(reply);
+                return Err(BitswapError::Closing.into());
+            }
             None => {
                 // control channel closed, exit the main loop
                 return Err(BitswapError::Closing.into());
@@ -466,6 +476,9 @@ impl<TBlockstore, TRouting> ProtocolImpl for Bitswap<TBlockstore, TRouting>
             log::info!("starting bitswap main loop...");
             let _ = bitswap.process_loop().await;
             log::info!("exiting bitswap main loop...");
+            if let Some(closed) = bitswap.closed.take() {
+                let _ = closed.send(());
+            }
         }))
     }
 }
\ No newline at end of file
@@ -1,4 +1,5 @@
 
+use async_trait::async_trait;
 use libipld::{Cid, Block, Result};
 use libipld::store::StoreParams;
 
@@ -58,15 +59,19 @@ use libipld::store::StoreParams;
 // }
 
 /// Trait implemented by a block store.
+///
+/// The queries are async, so a store can run them without blocking the tasks of the main
+/// loop.
+#[async_trait]
 pub trait BitswapStore: Clone + Send + Sync + 'static {
     /// The store params.
     type Params: StoreParams;
     /// A have query needs to know if the block store contains the block.
-    fn contains(&mut self, cid: &Cid) -> Result<bool>;
+    async fn contains(&mut self, cid: &Cid) -> Result<bool>;
     /// A block query needs to retrieve the block from the store.
-    fn get(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>>;
+    async fn get(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>>;
     /// A block response needs to insert the block into the store.
-    fn insert(&mut self, block: &Block<Self::Params>) -> Result<()>;
+    async fn insert(&mut self, block: Block<Self::Params>) -> Result<()>;
     /// A sync query needs a list of missing blocks to make progress.
     fn missing_blocks(&mut self, cid: &Cid) -> Result<Vec<Cid>>;
 }
\ No newline at end of file
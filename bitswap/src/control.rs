@@ -16,10 +16,12 @@ impl Control {
         Control(tx)
     }
 
-    /// Closes the bitswap main loop.
-    pub fn close(&mut self) {
-        // simply close the tx, then exit the main loop
-        // TODO: wait for the main loop to exit before returning
+    /// Closes the bitswap main loop and waits for it to exit.
+    pub async fn close(&mut self) {
+        let (tx, rx) = oneshot::channel();
+        if self.0.send(ControlCommand::Close(tx)).await.is_ok() {
+            let _ = rx.await;
+        }
         self.0.close_channel();
     }
 
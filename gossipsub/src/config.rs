@@ -0,0 +1,50 @@
+use std::time::Duration;
+
+/// Gossipsub configuration.
+#[derive(Clone, Debug)]
+pub struct GossipsubConfig {
+    /// Target number of peers in the mesh of a topic (`D`).
+    pub mesh_n: usize,
+    /// Minimum number of peers in the mesh of a topic before more are grafted (`D_low`).
+    pub mesh_n_low: usize,
+    /// Maximum number of peers in the mesh of a topic before some are pruned (`D_high`).
+    pub mesh_n_high: usize,
+    /// Number of peers outside of the mesh gossip is emitted to (`D_lazy`).
+    pub gossip_lazy: usize,
+    /// Interval between heartbeats, which maintain the mesh and emit gossip.
+    pub heartbeat_interval: Duration,
+    /// Time to live of the fanout of a topic we publish to without being subscribed.
+    pub fanout_ttl: Duration,
+    /// Number of heartbeats messages are kept in the message cache.
+    pub history_length: usize,
+    /// Number of heartbeats messages are advertised in gossip, at most `history_length`.
+    pub history_gossip: usize,
+    /// Time a message id is remembered to drop duplicates.
+    pub seen_ttl: Duration,
+    /// Maximum size of an rpc message.
+    pub max_transmit_size: usize,
+    /// Deliver messages published by the local node to local subscriptions.
+    pub subscribe_local_messages: bool,
+    /// Drop messages that aren't signed by their source. Messages published by the local
+    /// node are always signed.
+    pub strict_signing: bool,
+}
+
+impl Default for GossipsubConfig {
+    fn default() -> Self {
+        Self {
+            mesh_n: 6,
+            mesh_n_low: 4,
+            mesh_n_high: 12,
+            gossip_lazy: 6,
+            heartbeat_interval: Duration::from_secs(1),
+            fanout_ttl: Duration::from_secs(60),
+            history_length: 5,
+            history_gossip: 3,
+            seen_ttl: Duration::from_secs(120),
+            max_transmit_size: 2048 * 1024,
+            subscribe_local_messages: false,
+            strict_signing: true,
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use futures::channel::{mpsc, oneshot};
+use futures::SinkExt;
+
+use libp2p_rs::core::PeerId;
+
+use crate::error::GossipsubError;
+use crate::gossipsub::ControlCommand;
+use crate::subscription::Subscription;
+use crate::validation::TopicValidator;
+
+#[derive(Clone)]
+pub struct Control(mpsc::UnboundedSender<ControlCommand>);
+
+impl Control {
+    pub(crate) fn new(tx: mpsc::UnboundedSender<ControlCommand>) -> Self {
+        Control(tx)
+    }
+
+    /// Closes the gossipsub main loop.
+    pub fn close(&mut self) {
+        self.0.close_channel();
+    }
+
+    /// Subscribes to a topic, joining the mesh of the topic.
+    pub async fn subscribe(&mut self, topic: String) -> Result<Subscription, GossipsubError> {
+        let (tx, rx) = oneshot::channel();
+        self.0.send(ControlCommand::Subscribe(topic, tx)).await?;
+        Ok(rx.await?)
+    }
+
+    /// Publishes a message on a topic.
+    pub async fn publish(&mut self, topic: String, data: Vec<u8>) -> Result<(), GossipsubError> {
+        let (tx, rx) = oneshot::channel();
+        self.0
+            .send(ControlCommand::Publish(topic, data, tx))
+            .await?;
+        Ok(rx.await?)
+    }
+
+    /// Registers a validator for the messages of a topic, replacing any previous validator.
+    /// Messages that aren't accepted are neither delivered to subscriptions nor forwarded.
+    pub async fn register_topic_validator(
+        &mut self,
+        topic: String,
+        validator: TopicValidator,
+    ) -> Result<(), GossipsubError> {
+        self.0
+            .send(ControlCommand::RegisterValidator(topic, Some(validator)))
+            .await?;
+        Ok(())
+    }
+
+    /// Removes the validator of a topic.
+    pub async fn unregister_topic_validator(
+        &mut self,
+        topic: String,
+    ) -> Result<(), GossipsubError> {
+        self.0
+            .send(ControlCommand::RegisterValidator(topic, None))
+            .await?;
+        Ok(())
+    }
+
+    /// Returns the topics the local node is subscribed to.
+    pub async fn ls(&mut self) -> Result<Vec<String>, GossipsubError> {
+        let (tx, rx) = oneshot::channel();
+        self.0.send(ControlCommand::Ls(tx)).await?;
+        Ok(rx.await?)
+    }
+
+    /// Returns the peers subscribed to a topic, or all known gossipsub peers if `topic` is
+    /// `None`.
+    pub async fn get_peers(
+        &mut self,
+        topic: Option<String>,
+    ) -> Result<Vec<PeerId>, GossipsubError> {
+        let (tx, rx) = oneshot::channel();
+        self.0.send(ControlCommand::GetPeers(topic, tx)).await?;
+        Ok(rx.await?)
+    }
+}
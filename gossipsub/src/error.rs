@@ -0,0 +1,16 @@
+use futures::channel::{mpsc, oneshot};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum GossipsubError {
+    #[error("Error while decoding gossipsub message: {0}")]
+    ProtobufError(#[from] prost::DecodeError),
+    #[error("Invalid peer id")]
+    InvalidPeerId,
+    #[error("Closing")]
+    Closing,
+    #[error("Error sending {0}")]
+    Send(#[from] mpsc::SendError),
+    #[error("Cancelled oneshot {0}")]
+    Cancel(#[from] oneshot::Canceled),
+}
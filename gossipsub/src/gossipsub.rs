@@ -0,0 +1,678 @@
+use futures::channel::{mpsc, oneshot};
+use futures::{select, SinkExt, StreamExt};
+use rand::seq::SliceRandom;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Instant;
+
+use libp2p_rs::core::identity::Keypair;
+use libp2p_rs::core::PeerId;
+use libp2p_rs::runtime::task;
+use libp2p_rs::swarm::protocol_handler::{IProtocolHandler, ProtocolImpl};
+use libp2p_rs::swarm::Control as SwarmControl;
+
+use crate::config::GossipsubConfig;
+use crate::control::Control;
+use crate::error::GossipsubError;
+use crate::mcache::MessageCache;
+use crate::message::{GossipsubMessage, MessageId, Rpc};
+use crate::protocol::{send_rpcs, Handler, PeerEvent};
+use crate::subscription::Subscription;
+use crate::validation::{TopicValidator, ValidationResult};
+use crate::GOSSIPSUB_PROTO_ID;
+
+pub(crate) enum ControlCommand {
+    Subscribe(String, oneshot::Sender<Subscription>),
+    Unsubscribe(String, usize),
+    Publish(String, Vec<u8>, oneshot::Sender<()>),
+    Ls(oneshot::Sender<Vec<String>>),
+    GetPeers(Option<String>, oneshot::Sender<Vec<PeerId>>),
+    RegisterValidator(String, Option<TopicValidator>),
+    Heartbeat,
+}
+
+type Subscriber = (usize, mpsc::UnboundedSender<Arc<GossipsubMessage>>);
+
+pub struct Gossipsub {
+    config: GossipsubConfig,
+
+    // Signs the messages published by the local node.
+    keypair: Keypair,
+    local_peer_id: PeerId,
+
+    // Swarm controller.
+    swarm: Option<SwarmControl>,
+
+    // New peer is connected or peer is dead.
+    peer_tx: mpsc::UnboundedSender<PeerEvent>,
+    peer_rx: mpsc::UnboundedReceiver<PeerEvent>,
+
+    // Used to recv incoming rpc message.
+    incoming_tx: mpsc::UnboundedSender<(PeerId, Rpc)>,
+    incoming_rx: mpsc::UnboundedReceiver<(PeerId, Rpc)>,
+
+    // Used to pub/sub/ls/peers and the heartbeat.
+    control_tx: mpsc::UnboundedSender<ControlCommand>,
+    control_rx: mpsc::UnboundedReceiver<ControlCommand>,
+
+    /// Topics each connected gossipsub peer is subscribed to.
+    peers: HashMap<PeerId, HashSet<String>>,
+
+    /// Queues of the rpcs written to the outbound stream of each peer.
+    outbound: HashMap<PeerId, mpsc::UnboundedSender<Rpc>>,
+
+    /// Local subscriptions per topic.
+    subscriptions: HashMap<String, Vec<Subscriber>>,
+    next_subscription_id: usize,
+
+    /// Mesh peers of the topics we are subscribed to.
+    mesh: HashMap<String, HashSet<PeerId>>,
+
+    /// Peers we publish to for topics we are not subscribed to.
+    fanout: HashMap<String, HashSet<PeerId>>,
+    fanout_last_pub: HashMap<String, Instant>,
+
+    mcache: MessageCache,
+
+    /// Recently seen message ids, used to drop duplicates.
+    seen: HashMap<MessageId, Instant>,
+
+    /// Application validators per topic.
+    validators: HashMap<String, TopicValidator>,
+
+    seqno: u64,
+}
+
+impl Gossipsub {
+    pub fn new(keypair: Keypair, config: GossipsubConfig) -> Self {
+        let local_peer_id = keypair.public().into_peer_id();
+        let (peer_tx, peer_rx) = mpsc::unbounded();
+        let (incoming_tx, incoming_rx) = mpsc::unbounded();
+        let (control_tx, control_rx) = mpsc::unbounded();
+        let mcache = MessageCache::new(config.history_gossip, config.history_length);
+        Gossipsub {
+            config,
+            keypair,
+            local_peer_id,
+            swarm: None,
+            peer_tx,
+            peer_rx,
+            incoming_tx,
+            incoming_rx,
+            control_tx,
+            control_rx,
+            peers: Default::default(),
+            outbound: Default::default(),
+            subscriptions: Default::default(),
+            next_subscription_id: 0,
+            mesh: Default::default(),
+            fanout: Default::default(),
+            fanout_last_pub: Default::default(),
+            mcache,
+            seen: Default::default(),
+            validators: Default::default(),
+            seqno: rand::random(),
+        }
+    }
+
+    /// Get control of gossipsub, which can be used to publish or subscribe.
+    pub fn control(&self) -> Control {
+        Control::new(self.control_tx.clone())
+    }
+
+    /// Message Process Loop.
+    async fn process_loop(&mut self) -> Result<(), GossipsubError> {
+        loop {
+            select! {
+                evt = self.peer_rx.next() => {
+                    self.handle_peer_event(evt);
+                }
+                rpc = self.incoming_rx.next() => {
+                    if let Some((source, rpc)) = rpc {
+                        self.handle_rpc(source, rpc);
+                    }
+                }
+                cmd = self.control_rx.next() => {
+                    self.handle_control_command(cmd)?;
+                }
+            }
+        }
+    }
+
+    fn send_rpc(&mut self, peer_id: PeerId, rpc: Rpc) {
+        if rpc.is_empty() {
+            return;
+        }
+        // queue the rpc on the stream of the peer, or open a new one if there is none or
+        // writing to the previous one failed
+        let rpc = match self.outbound.get(&peer_id) {
+            Some(tx) => match tx.unbounded_send(rpc) {
+                Ok(()) => return,
+                Err(err) => err.into_inner(),
+            },
+            None => rpc,
+        };
+        let (tx, rx) = mpsc::unbounded();
+        let _ = tx.unbounded_send(rpc);
+        self.outbound.insert(peer_id, tx);
+        let swarm = self.swarm.clone().expect("swarm??");
+        task::spawn(async move {
+            if let Err(err) = send_rpcs(swarm, peer_id, rx).await {
+                log::debug!("rpc stream to {:?} failed: {}", peer_id, err);
+            }
+        });
+    }
+
+    fn handle_peer_event(&mut self, evt: Option<PeerEvent>) {
+        match evt {
+            Some(PeerEvent::NewPeer(peer_id)) => self.add_peer(peer_id),
+            Some(PeerEvent::Identified(peer_id)) => {
+                let supported = self
+                    .swarm
+                    .as_ref()
+                    .and_then(|swarm| swarm.get_identify_info(&peer_id))
+                    .map(|info| {
+                        info.protocols
+                            .iter()
+                            .any(|proto| proto.as_bytes() == GOSSIPSUB_PROTO_ID)
+                    })
+                    .unwrap_or(false);
+                if supported {
+                    self.add_peer(peer_id);
+                }
+            }
+            Some(PeerEvent::DeadPeer(peer_id)) => {
+                log::debug!("{:?} disconnected", peer_id);
+                self.peers.remove(&peer_id);
+                self.outbound.remove(&peer_id);
+                for peers in self.mesh.values_mut() {
+                    peers.remove(&peer_id);
+                }
+                for peers in self.fanout.values_mut() {
+                    peers.remove(&peer_id);
+                }
+            }
+            None => {}
+        }
+    }
+
+    /// Adds a peer speaking gossipsub and tells it about our subscriptions.
+    fn add_peer(&mut self, peer_id: PeerId) {
+        if self.peers.contains_key(&peer_id) {
+            return;
+        }
+        log::debug!("{:?} connected", peer_id);
+        self.peers.insert(peer_id, Default::default());
+        let rpc = Rpc {
+            subscriptions: self
+                .subscriptions
+                .keys()
+                .map(|topic| (true, topic.clone()))
+                .collect(),
+            ..Default::default()
+        };
+        self.send_rpc(peer_id, rpc);
+    }
+
+    fn handle_rpc(&mut self, source: PeerId, rpc: Rpc) {
+        log::trace!(
+            "incoming rpc: from {:?}, s={} m={}",
+            source,
+            rpc.subscriptions.len(),
+            rpc.messages.len()
+        );
+
+        let mut response = Rpc::default();
+        for (subscribe, topic) in rpc.subscriptions {
+            if subscribe {
+                self.peers.entry(source).or_default().insert(topic.clone());
+                // graft the peer right away if the mesh of the topic is short of peers,
+                // instead of waiting for the next heartbeat
+                if let Some(mesh) = self.mesh.get_mut(&topic) {
+                    if mesh.len() < self.config.mesh_n_low && mesh.insert(source) {
+                        response.control.graft.push(topic);
+                    }
+                }
+            } else {
+                if let Some(topics) = self.peers.get_mut(&source) {
+                    topics.remove(&topic);
+                }
+                if let Some(mesh) = self.mesh.get_mut(&topic) {
+                    mesh.remove(&source);
+                }
+                if let Some(fanout) = self.fanout.get_mut(&topic) {
+                    fanout.remove(&source);
+                }
+            }
+        }
+
+        for msg in rpc.messages {
+            self.handle_message(source, msg);
+        }
+
+        let control = rpc.control;
+        let mut iwant = HashSet::new();
+        for (topic, ids) in control.ihave {
+            if !self.mesh.contains_key(&topic) {
+                continue;
+            }
+            for id in ids {
+                if !self.seen.contains_key(&id) {
+                    iwant.insert(id);
+                }
+            }
+        }
+        response.control.iwant = iwant.into_iter().collect();
+
+        for id in control.iwant {
+            if let Some(msg) = self.mcache.get(&id) {
+                response.messages.push(msg.clone());
+            }
+        }
+
+        for topic in control.graft {
+            if let Some(mesh) = self.mesh.get_mut(&topic) {
+                mesh.insert(source);
+            } else {
+                // we are not subscribed to the topic, refuse the graft
+                response.control.prune.push(topic);
+            }
+        }
+
+        for topic in control.prune {
+            if let Some(mesh) = self.mesh.get_mut(&topic) {
+                mesh.remove(&source);
+            }
+        }
+
+        self.send_rpc(source, response);
+    }
+
+    fn handle_message(&mut self, propagation_source: PeerId, msg: GossipsubMessage) {
+        let id = msg.id();
+        if self.seen.contains_key(&id) {
+            return;
+        }
+        self.seen.insert(id, Instant::now());
+
+        // invalid messages are neither delivered nor forwarded, and as they never make it
+        // into the message cache they aren't gossiped about either
+        if self.config.strict_signing && !msg.verify_signature() {
+            log::debug!(
+                "dropping unsigned message from {:?} via {:?}",
+                msg.source,
+                propagation_source
+            );
+            return;
+        }
+        match self.validate(&msg) {
+            ValidationResult::Accept => {}
+            ValidationResult::Reject => {
+                log::debug!(
+                    "rejected message from {:?} via {:?}",
+                    msg.source,
+                    propagation_source
+                );
+                return;
+            }
+            ValidationResult::Ignore => return,
+        }
+        self.mcache.put(msg.clone());
+
+        let msg = Arc::new(msg);
+        self.notify_subscribers(&msg);
+        self.forward_message(&msg, Some(propagation_source));
+    }
+
+    /// Runs the validators of the topics of a message. A message is only accepted if all
+    /// validators accept it.
+    fn validate(&self, msg: &GossipsubMessage) -> ValidationResult {
+        let mut result = ValidationResult::Accept;
+        for topic in &msg.topics {
+            if let Some(validator) = self.validators.get(topic) {
+                match validator(&msg.source, &msg.data) {
+                    ValidationResult::Accept => {}
+                    ValidationResult::Reject => return ValidationResult::Reject,
+                    ValidationResult::Ignore => result = ValidationResult::Ignore,
+                }
+            }
+        }
+        result
+    }
+
+    fn notify_subscribers(&self, msg: &Arc<GossipsubMessage>) {
+        for topic in &msg.topics {
+            if let Some(subscribers) = self.subscriptions.get(topic) {
+                for (_, tx) in subscribers {
+                    let _ = tx.unbounded_send(msg.clone());
+                }
+            }
+        }
+    }
+
+    /// Forwards a message to the mesh peers of its topics.
+    fn forward_message(&mut self, msg: &GossipsubMessage, propagation_source: Option<PeerId>) {
+        let mut recipients = HashSet::new();
+        for topic in &msg.topics {
+            if let Some(mesh) = self.mesh.get(topic) {
+                recipients.extend(mesh.iter().copied());
+            }
+        }
+        if let Some(peer_id) = propagation_source {
+            recipients.remove(&peer_id);
+        }
+        recipients.remove(&msg.source);
+        for peer_id in recipients {
+            let rpc = Rpc {
+                messages: vec![msg.clone()],
+                ..Default::default()
+            };
+            self.send_rpc(peer_id, rpc);
+        }
+    }
+
+    fn handle_control_command(
+        &mut self,
+        cmd: Option<ControlCommand>,
+    ) -> Result<(), GossipsubError> {
+        match cmd {
+            Some(ControlCommand::Subscribe(topic, reply)) => {
+                let _ = reply.send(self.subscribe(topic));
+            }
+            Some(ControlCommand::Unsubscribe(topic, id)) => {
+                self.unsubscribe(topic, id);
+            }
+            Some(ControlCommand::Publish(topic, data, reply)) => {
+                self.publish(topic, data);
+                let _ = reply.send(());
+            }
+            Some(ControlCommand::Ls(reply)) => {
+                let _ = reply.send(self.subscriptions.keys().cloned().collect());
+            }
+            Some(ControlCommand::GetPeers(topic, reply)) => {
+                let peers = self
+                    .peers
+                    .iter()
+                    .filter(|(_, topics)| {
+                        topic.as_ref().map(|t| topics.contains(t)).unwrap_or(true)
+                    })
+                    .map(|(peer_id, _)| *peer_id)
+                    .collect();
+                let _ = reply.send(peers);
+            }
+            Some(ControlCommand::RegisterValidator(topic, validator)) => {
+                if let Some(validator) = validator {
+                    self.validators.insert(topic, validator);
+                } else {
+                    self.validators.remove(&topic);
+                }
+            }
+            Some(ControlCommand::Heartbeat) => {
+                self.heartbeat();
+            }
+            None => {
+                // control channel closed, exit the main loop
+                return Err(GossipsubError::Closing);
+            }
+        }
+        Ok(())
+    }
+
+    fn subscribe(&mut self, topic: String) -> Subscription {
+        log::debug!("gossipsub subscribe {}", topic);
+        let (tx, rx) = mpsc::unbounded();
+        let id = self.next_subscription_id;
+        self.next_subscription_id += 1;
+
+        let subscribers = self.subscriptions.entry(topic.clone()).or_default();
+        let first = subscribers.is_empty();
+        subscribers.push((id, tx));
+        if first {
+            self.announce(true, &topic);
+            self.join(&topic);
+        }
+        Subscription::new(id, topic, rx, self.control_tx.clone())
+    }
+
+    fn unsubscribe(&mut self, topic: String, id: usize) {
+        if let Some(subscribers) = self.subscriptions.get_mut(&topic) {
+            subscribers.retain(|(i, _)| *i != id);
+            if subscribers.is_empty() {
+                log::debug!("gossipsub unsubscribe {}", topic);
+                self.subscriptions.remove(&topic);
+                self.announce(false, &topic);
+                self.leave(&topic);
+            }
+        }
+    }
+
+    /// Announces a subscription change to all peers.
+    fn announce(&mut self, subscribe: bool, topic: &str) {
+        let peers: Vec<_> = self.peers.keys().copied().collect();
+        for peer_id in peers {
+            let rpc = Rpc {
+                subscriptions: vec![(subscribe, topic.to_string())],
+                ..Default::default()
+            };
+            self.send_rpc(peer_id, rpc);
+        }
+    }
+
+    /// Joins the mesh of a topic, reusing the fanout peers if there are any.
+    fn join(&mut self, topic: &str) {
+        if self.mesh.contains_key(topic) {
+            return;
+        }
+        let mut peers = self.fanout.remove(topic).unwrap_or_default();
+        self.fanout_last_pub.remove(topic);
+        if peers.len() < self.config.mesh_n {
+            let needed = self.config.mesh_n - peers.len();
+            let extra = random_peers(&self.peers, topic, needed, |p| !peers.contains(p));
+            peers.extend(extra);
+        }
+        for peer_id in &peers {
+            let mut rpc = Rpc::default();
+            rpc.control.graft.push(topic.to_string());
+            self.send_rpc(*peer_id, rpc);
+        }
+        self.mesh.insert(topic.to_string(), peers);
+    }
+
+    /// Leaves the mesh of a topic.
+    fn leave(&mut self, topic: &str) {
+        if let Some(peers) = self.mesh.remove(topic) {
+            for peer_id in peers {
+                let mut rpc = Rpc::default();
+                rpc.control.prune.push(topic.to_string());
+                self.send_rpc(peer_id, rpc);
+            }
+        }
+    }
+
+    fn publish(&mut self, topic: String, data: Vec<u8>) {
+        self.seqno = self.seqno.wrapping_add(1);
+        let mut msg = GossipsubMessage {
+            source: self.local_peer_id,
+            data,
+            sequence_number: self.seqno.to_be_bytes().to_vec(),
+            topics: vec![topic.clone()],
+            signature: None,
+            key: None,
+        };
+        msg.sign(&self.keypair);
+        self.seen.insert(msg.id(), Instant::now());
+        self.mcache.put(msg.clone());
+
+        let recipients = if let Some(mesh) = self.mesh.get(&topic) {
+            mesh.clone()
+        } else {
+            // not subscribed, publish to the fanout peers of the topic
+            let mesh_n = self.config.mesh_n;
+            let peers = &self.peers;
+            let fanout = self.fanout.entry(topic.clone()).or_insert_with(|| {
+                random_peers(peers, &topic, mesh_n, |_| true)
+                    .into_iter()
+                    .collect()
+            });
+            self.fanout_last_pub.insert(topic, Instant::now());
+            fanout.clone()
+        };
+
+        let msg = Arc::new(msg);
+        if self.config.subscribe_local_messages {
+            self.notify_subscribers(&msg);
+        }
+        for peer_id in recipients {
+            let rpc = Rpc {
+                messages: vec![(*msg).clone()],
+                ..Default::default()
+            };
+            self.send_rpc(peer_id, rpc);
+        }
+    }
+
+    /// Maintains the mesh and fanout peers and emits gossip.
+    fn heartbeat(&mut self) {
+        let mut control: HashMap<PeerId, Rpc> = HashMap::new();
+
+        // maintain the mesh of the topics we are subscribed to
+        for (topic, mesh) in self.mesh.iter_mut() {
+            // drop peers that left the topic
+            let peers = &self.peers;
+            mesh.retain(|p| peers.get(p).map(|t| t.contains(topic)).unwrap_or(false));
+
+            if mesh.len() < self.config.mesh_n_low {
+                let needed = self.config.mesh_n - mesh.len();
+                for peer_id in random_peers(&self.peers, topic, needed, |p| !mesh.contains(p)) {
+                    mesh.insert(peer_id);
+                    control
+                        .entry(peer_id)
+                        .or_default()
+                        .control
+                        .graft
+                        .push(topic.clone());
+                }
+            }
+
+            if mesh.len() > self.config.mesh_n_high {
+                let excess = mesh.len() - self.config.mesh_n;
+                let mut shuffled: Vec<_> = mesh.iter().copied().collect();
+                shuffled.shuffle(&mut rand::thread_rng());
+                for peer_id in shuffled.into_iter().take(excess) {
+                    mesh.remove(&peer_id);
+                    control
+                        .entry(peer_id)
+                        .or_default()
+                        .control
+                        .prune
+                        .push(topic.clone());
+                }
+            }
+        }
+
+        // expire and maintain the fanout
+        let fanout_ttl = self.config.fanout_ttl;
+        let expired: Vec<_> = self
+            .fanout_last_pub
+            .iter()
+            .filter(|(_, last_pub)| last_pub.elapsed() > fanout_ttl)
+            .map(|(topic, _)| topic.clone())
+            .collect();
+        for topic in expired {
+            self.fanout.remove(&topic);
+            self.fanout_last_pub.remove(&topic);
+        }
+        for (topic, fanout) in self.fanout.iter_mut() {
+            let peers = &self.peers;
+            fanout.retain(|p| peers.get(p).map(|t| t.contains(topic)).unwrap_or(false));
+            if fanout.len() < self.config.mesh_n {
+                let needed = self.config.mesh_n - fanout.len();
+                let extra = random_peers(&self.peers, topic, needed, |p| !fanout.contains(p));
+                fanout.extend(extra);
+            }
+        }
+
+        // emit gossip about the messages of the last few heartbeats
+        for (topic, peers) in self.mesh.iter().chain(self.fanout.iter()) {
+            let ids = self.mcache.get_gossip_ids(topic);
+            if ids.is_empty() {
+                continue;
+            }
+            let lazy = random_peers(&self.peers, topic, self.config.gossip_lazy, |p| {
+                !peers.contains(p)
+            });
+            for peer_id in lazy {
+                control
+                    .entry(peer_id)
+                    .or_default()
+                    .control
+                    .ihave
+                    .push((topic.clone(), ids.clone()));
+            }
+        }
+
+        for (peer_id, rpc) in control {
+            self.send_rpc(peer_id, rpc);
+        }
+
+        self.mcache.shift();
+        let seen_ttl = self.config.seen_ttl;
+        self.seen.retain(|_, seen| seen.elapsed() < seen_ttl);
+    }
+}
+
+/// Returns up to `n` random peers subscribed to `topic` that pass the `filter`.
+fn random_peers(
+    peers: &HashMap<PeerId, HashSet<String>>,
+    topic: &str,
+    n: usize,
+    filter: impl Fn(&PeerId) -> bool,
+) -> Vec<PeerId> {
+    let mut candidates: Vec<_> = peers
+        .iter()
+        .filter(|(peer_id, topics)| topics.contains(topic) && filter(peer_id))
+        .map(|(peer_id, _)| *peer_id)
+        .collect();
+    candidates.shuffle(&mut rand::thread_rng());
+    candidates.truncate(n);
+    candidates
+}
+
+impl ProtocolImpl for Gossipsub {
+    /// Get handler of gossipsub, swarm will call "handle" func after muxer negotiate success.
+    fn handler(&self) -> IProtocolHandler {
+        Box::new(Handler::new(
+            self.incoming_tx.clone(),
+            self.peer_tx.clone(),
+            self.config.max_transmit_size,
+        ))
+    }
+
+    /// Start message process loop.
+    fn start(mut self, swarm: SwarmControl) -> Option<task::TaskHandle<()>>
+    where
+        Self: Sized,
+    {
+        self.swarm = Some(swarm);
+
+        // the heartbeat ticker stops once the control channel is closed
+        let mut heartbeat = self.control_tx.clone();
+        let interval = self.config.heartbeat_interval;
+        task::spawn(async move {
+            loop {
+                task::sleep(interval).await;
+                if heartbeat.send(ControlCommand::Heartbeat).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut gossipsub = self;
+
+        Some(task::spawn(async move {
+            log::info!("starting gossipsub main loop...");
+            let _ = gossipsub.process_loop().await;
+            log::info!("exiting gossipsub main loop...");
+        }))
+    }
+}
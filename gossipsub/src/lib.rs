@@ -0,0 +1,23 @@
+mod config;
+mod control;
+mod error;
+mod gossipsub;
+mod mcache;
+mod message;
+mod protocol;
+mod subscription;
+mod validation;
+
+pub use config::GossipsubConfig;
+pub use control::Control;
+pub use error::GossipsubError;
+pub use gossipsub::Gossipsub;
+pub use message::{GossipsubMessage, MessageId};
+pub use subscription::Subscription;
+pub use validation::{TopicValidator, ValidationResult};
+
+const GOSSIPSUB_PROTO_ID: &[u8] = b"/meshsub/1.0.0";
+
+mod rpc_pb {
+    include!(concat!(env!("OUT_DIR"), "/gossipsub_pb.rs"));
+}
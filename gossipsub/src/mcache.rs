@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+
+use crate::message::{GossipsubMessage, MessageId};
+
+/// Sliding window of recently seen messages, used to answer IWANT requests and to emit
+/// IHAVE gossip.
+#[derive(Debug)]
+pub(crate) struct MessageCache {
+    msgs: HashMap<MessageId, GossipsubMessage>,
+    /// One window per heartbeat, the most recent window first.
+    history: Vec<Vec<(MessageId, Vec<String>)>>,
+    /// Number of windows advertised in gossip.
+    gossip: usize,
+}
+
+impl MessageCache {
+    pub fn new(gossip: usize, history_length: usize) -> Self {
+        Self {
+            msgs: Default::default(),
+            history: vec![vec![]; history_length.max(1)],
+            gossip: gossip.min(history_length),
+        }
+    }
+
+    /// Puts a message in to the current window.
+    pub fn put(&mut self, msg: GossipsubMessage) {
+        let id = msg.id();
+        self.history[0].push((id.clone(), msg.topics.clone()));
+        self.msgs.insert(id, msg);
+    }
+
+    /// Returns a message if it is still cached.
+    pub fn get(&self, id: &[u8]) -> Option<&GossipsubMessage> {
+        self.msgs.get(id)
+    }
+
+    /// Returns the ids of the messages of a topic in the gossip windows.
+    pub fn get_gossip_ids(&self, topic: &str) -> Vec<MessageId> {
+        self.history[..self.gossip]
+            .iter()
+            .flatten()
+            .filter(|(_, topics)| topics.iter().any(|t| t == topic))
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+
+    /// Shifts the windows, dropping the messages of the oldest window.
+    pub fn shift(&mut self) {
+        if let Some(last) = self.history.pop() {
+            for (id, _) in last {
+                self.msgs.remove(&id);
+            }
+        }
+        self.history.insert(0, vec![]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p_rs::core::PeerId;
+
+    fn message(seqno: u64, topic: &str) -> GossipsubMessage {
+        GossipsubMessage {
+            source: PeerId::random(),
+            data: vec![],
+            sequence_number: seqno.to_be_bytes().to_vec(),
+            topics: vec![topic.into()],
+            signature: None,
+            key: None,
+        }
+    }
+
+    #[test]
+    fn test_mcache_shift() {
+        let mut mcache = MessageCache::new(1, 2);
+        let a = message(0, "a");
+        let b = message(1, "b");
+        mcache.put(a.clone());
+        assert_eq!(mcache.get_gossip_ids("a"), vec![a.id()]);
+        mcache.shift();
+        mcache.put(b.clone());
+        assert!(mcache.get_gossip_ids("a").is_empty());
+        assert_eq!(mcache.get(&a.id()), Some(&a));
+        mcache.shift();
+        assert_eq!(mcache.get(&a.id()), None);
+        assert_eq!(mcache.get(&b.id()), Some(&b));
+    }
+}
@@ -0,0 +1,270 @@
+use prost::Message as ProstMessage;
+
+use libp2p_rs::core::identity::Keypair;
+use libp2p_rs::core::{PeerId, PublicKey};
+
+use crate::error::GossipsubError;
+use crate::rpc_pb;
+
+/// Identifies a message, the concatenation of the source and the sequence number.
+pub type MessageId = Vec<u8>;
+
+const SIGNING_PREFIX: &[u8] = b"libp2p-pubsub:";
+
+/// A message published on one or more topics.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GossipsubMessage {
+    /// The peer that published the message.
+    pub source: PeerId,
+    /// The message payload.
+    pub data: Vec<u8>,
+    /// Sequence number, unique per source.
+    pub sequence_number: Vec<u8>,
+    /// The topics the message is published on.
+    pub topics: Vec<String>,
+    /// Signature of the source.
+    pub signature: Option<Vec<u8>>,
+    /// Protobuf encoded public key of the source.
+    pub key: Option<Vec<u8>>,
+}
+
+impl GossipsubMessage {
+    /// Returns the id of the message.
+    pub fn id(&self) -> MessageId {
+        let mut id = self.source.to_bytes();
+        id.extend_from_slice(&self.sequence_number);
+        id
+    }
+
+    fn to_pb(&self) -> rpc_pb::Message {
+        rpc_pb::Message {
+            from: Some(self.source.to_bytes()),
+            data: Some(self.data.clone()),
+            seqno: Some(self.sequence_number.clone()),
+            topic_ids: self.topics.clone(),
+            signature: self.signature.clone(),
+            key: self.key.clone(),
+        }
+    }
+
+    /// The bytes covered by the signature, the message without signature and key.
+    fn signing_bytes(&self) -> Vec<u8> {
+        let mut msg = self.to_pb();
+        msg.signature = None;
+        msg.key = None;
+        let mut buf = Vec::with_capacity(SIGNING_PREFIX.len() + msg.encoded_len());
+        buf.extend_from_slice(SIGNING_PREFIX);
+        msg.encode(&mut buf)
+            .expect("Vec<u8> provides capacity as needed");
+        buf
+    }
+
+    /// Signs the message with the keypair of the source.
+    pub(crate) fn sign(&mut self, keypair: &Keypair) {
+        match keypair.sign(&self.signing_bytes()) {
+            Ok(signature) => {
+                self.signature = Some(signature);
+                self.key = Some(keypair.public().into_protobuf_encoding());
+            }
+            Err(err) => log::error!("failed to sign message: {}", err),
+        }
+    }
+
+    /// Checks that the message is signed by its source.
+    pub fn verify_signature(&self) -> bool {
+        let (key, signature) = match (&self.key, &self.signature) {
+            (Some(key), Some(signature)) => (key, signature),
+            _ => return false,
+        };
+        let key = match PublicKey::from_protobuf_encoding(key) {
+            Ok(key) => key,
+            Err(_) => return false,
+        };
+        key.clone().into_peer_id() == self.source && key.verify(&self.signing_bytes(), signature)
+    }
+}
+
+/// Control messages maintaining the mesh and exchanging gossip.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct RpcControl {
+    pub ihave: Vec<(String, Vec<MessageId>)>,
+    pub iwant: Vec<MessageId>,
+    pub graft: Vec<String>,
+    pub prune: Vec<String>,
+}
+
+impl RpcControl {
+    pub fn is_empty(&self) -> bool {
+        self.ihave.is_empty()
+            && self.iwant.is_empty()
+            && self.graft.is_empty()
+            && self.prune.is_empty()
+    }
+}
+
+/// A decoded gossipsub rpc.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Rpc {
+    /// Subscription changes of the sender, `true` for subscribe.
+    pub subscriptions: Vec<(bool, String)>,
+    /// Published messages.
+    pub messages: Vec<GossipsubMessage>,
+    /// Control messages.
+    pub control: RpcControl,
+}
+
+impl Rpc {
+    pub fn is_empty(&self) -> bool {
+        self.subscriptions.is_empty() && self.messages.is_empty() && self.control.is_empty()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let control = if self.control.is_empty() {
+            None
+        } else {
+            Some(rpc_pb::ControlMessage {
+                ihave: self
+                    .control
+                    .ihave
+                    .iter()
+                    .map(|(topic, ids)| rpc_pb::ControlIHave {
+                        topic_id: Some(topic.clone()),
+                        message_ids: ids.clone(),
+                    })
+                    .collect(),
+                iwant: if self.control.iwant.is_empty() {
+                    vec![]
+                } else {
+                    vec![rpc_pb::ControlIWant {
+                        message_ids: self.control.iwant.clone(),
+                    }]
+                },
+                graft: self
+                    .control
+                    .graft
+                    .iter()
+                    .map(|topic| rpc_pb::ControlGraft {
+                        topic_id: Some(topic.clone()),
+                    })
+                    .collect(),
+                prune: self
+                    .control
+                    .prune
+                    .iter()
+                    .map(|topic| rpc_pb::ControlPrune {
+                        topic_id: Some(topic.clone()),
+                    })
+                    .collect(),
+            })
+        };
+        let rpc = rpc_pb::Rpc {
+            subscriptions: self
+                .subscriptions
+                .iter()
+                .map(|(subscribe, topic)| rpc_pb::rpc::SubOpts {
+                    subscribe: Some(*subscribe),
+                    topic_id: Some(topic.clone()),
+                })
+                .collect(),
+            publish: self.messages.iter().map(GossipsubMessage::to_pb).collect(),
+            control,
+        };
+        let mut buf = Vec::with_capacity(rpc.encoded_len());
+        rpc.encode(&mut buf)
+            .expect("Vec<u8> provides capacity as needed");
+        buf
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, GossipsubError> {
+        let rpc = rpc_pb::Rpc::decode(bytes)?;
+        let mut messages = Vec::with_capacity(rpc.publish.len());
+        for msg in rpc.publish {
+            let source = PeerId::from_bytes(&msg.from.unwrap_or_default())
+                .map_err(|_| GossipsubError::InvalidPeerId)?;
+            messages.push(GossipsubMessage {
+                source,
+                data: msg.data.unwrap_or_default(),
+                sequence_number: msg.seqno.unwrap_or_default(),
+                topics: msg.topic_ids,
+                signature: msg.signature,
+                key: msg.key,
+            });
+        }
+        let control = rpc.control.unwrap_or_default();
+        Ok(Self {
+            subscriptions: rpc
+                .subscriptions
+                .into_iter()
+                .filter_map(|sub| Some((sub.subscribe.unwrap_or_default(), sub.topic_id?)))
+                .collect(),
+            messages,
+            control: RpcControl {
+                ihave: control
+                    .ihave
+                    .into_iter()
+                    .filter_map(|ihave| Some((ihave.topic_id?, ihave.message_ids)))
+                    .collect(),
+                iwant: control
+                    .iwant
+                    .into_iter()
+                    .flat_map(|iwant| iwant.message_ids)
+                    .collect(),
+                graft: control
+                    .graft
+                    .into_iter()
+                    .filter_map(|graft| graft.topic_id)
+                    .collect(),
+                prune: control
+                    .prune
+                    .into_iter()
+                    .filter_map(|prune| prune.topic_id)
+                    .collect(),
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rpc_roundtrip() {
+        let rpc = Rpc {
+            subscriptions: vec![(true, "a".into()), (false, "b".into())],
+            messages: vec![GossipsubMessage {
+                source: PeerId::random(),
+                data: b"hello".to_vec(),
+                sequence_number: 1u64.to_be_bytes().to_vec(),
+                topics: vec!["a".into()],
+                signature: None,
+                key: None,
+            }],
+            control: RpcControl {
+                ihave: vec![("a".into(), vec![b"id".to_vec()])],
+                iwant: vec![b"id".to_vec()],
+                graft: vec!["a".into()],
+                prune: vec!["b".into()],
+            },
+        };
+        assert_eq!(Rpc::from_bytes(&rpc.to_bytes()).unwrap(), rpc);
+    }
+
+    #[test]
+    fn test_message_signature() {
+        let keypair = Keypair::generate_ed25519();
+        let mut msg = GossipsubMessage {
+            source: keypair.public().into_peer_id(),
+            data: b"hello".to_vec(),
+            sequence_number: 1u64.to_be_bytes().to_vec(),
+            topics: vec!["a".into()],
+            signature: None,
+            key: None,
+        };
+        assert!(!msg.verify_signature());
+        msg.sign(&keypair);
+        assert!(msg.verify_signature());
+        msg.data = b"bye".to_vec();
+        assert!(!msg.verify_signature());
+    }
+}
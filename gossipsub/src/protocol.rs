@@ -0,0 +1,106 @@
+use async_trait::async_trait;
+use futures::channel::mpsc;
+use futures::{SinkExt, StreamExt};
+use std::error::Error;
+
+use libp2p_rs::core::upgrade::UpgradeInfo;
+use libp2p_rs::core::{PeerId, ProtocolId};
+use libp2p_rs::swarm::connection::Connection;
+use libp2p_rs::swarm::protocol_handler::{IProtocolHandler, Notifiee, ProtocolHandler};
+use libp2p_rs::swarm::substream::Substream;
+use libp2p_rs::swarm::Control as SwarmControl;
+use libp2p_rs::traits::{ReadEx, WriteEx};
+
+use crate::message::Rpc;
+use crate::GOSSIPSUB_PROTO_ID;
+
+pub(crate) enum PeerEvent {
+    /// The peer opened a gossipsub stream.
+    NewPeer(PeerId),
+    /// The peer sent its identify info, it is a gossipsub peer if it lists the protocol.
+    Identified(PeerId),
+    DeadPeer(PeerId),
+}
+
+#[derive(Clone)]
+pub(crate) struct Handler {
+    incoming_tx: mpsc::UnboundedSender<(PeerId, Rpc)>,
+    peer_tx: mpsc::UnboundedSender<PeerEvent>,
+    max_transmit_size: usize,
+}
+
+impl Handler {
+    pub(crate) fn new(
+        incoming_tx: mpsc::UnboundedSender<(PeerId, Rpc)>,
+        peer_tx: mpsc::UnboundedSender<PeerEvent>,
+        max_transmit_size: usize,
+    ) -> Self {
+        Handler {
+            incoming_tx,
+            peer_tx,
+            max_transmit_size,
+        }
+    }
+}
+
+impl UpgradeInfo for Handler {
+    type Info = ProtocolId;
+
+    fn protocol_info(&self) -> Vec<Self::Info> {
+        vec![GOSSIPSUB_PROTO_ID.into()]
+    }
+}
+
+impl Notifiee for Handler {
+    fn identified(&mut self, peer_id: PeerId) {
+        let _ = self.peer_tx.unbounded_send(PeerEvent::Identified(peer_id));
+    }
+
+    fn disconnected(&mut self, conn: &mut Connection) {
+        let _ = self
+            .peer_tx
+            .unbounded_send(PeerEvent::DeadPeer(conn.remote_peer()));
+    }
+}
+
+#[async_trait]
+impl ProtocolHandler for Handler {
+    async fn handle(
+        &mut self,
+        mut stream: Substream,
+        _info: <Self as UpgradeInfo>::Info,
+    ) -> Result<(), Box<dyn Error>> {
+        log::trace!("Handle stream from {}", stream.remote_peer());
+        let _ = self
+            .peer_tx
+            .unbounded_send(PeerEvent::NewPeer(stream.remote_peer()));
+        loop {
+            let packet = stream.read_one(self.max_transmit_size).await?;
+            let rpc = Rpc::from_bytes(&packet)?;
+            let peer = stream.remote_peer();
+            self.incoming_tx.send((peer, rpc)).await?;
+        }
+    }
+
+    fn box_clone(&self) -> IProtocolHandler {
+        Box::new(self.clone())
+    }
+}
+
+// Sends the rpcs of a remote peer over a single stream, until the sender is dropped or
+// writing fails.
+pub(crate) async fn send_rpcs(
+    mut swarm: SwarmControl,
+    peer_id: PeerId,
+    mut rpcs: mpsc::UnboundedReceiver<Rpc>,
+) -> Result<(), Box<dyn Error>> {
+    log::trace!("opening rpc stream to {:?}...", peer_id);
+    let mut stream = swarm
+        .new_stream(peer_id, vec![GOSSIPSUB_PROTO_ID.into()])
+        .await?;
+    while let Some(rpc) = rpcs.next().await {
+        stream.write_one(&rpc.to_bytes()).await?;
+    }
+    stream.close2().await?;
+    Ok(())
+}
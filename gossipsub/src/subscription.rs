@@ -0,0 +1,53 @@
+use futures::channel::mpsc;
+use futures::stream::Stream;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use crate::gossipsub::ControlCommand;
+use crate::message::GossipsubMessage;
+
+/// A subscription to a topic. The topic is left once all subscriptions to it are dropped.
+pub struct Subscription {
+    id: usize,
+    topic: String,
+    rx: mpsc::UnboundedReceiver<Arc<GossipsubMessage>>,
+    cancel: mpsc::UnboundedSender<ControlCommand>,
+}
+
+impl Subscription {
+    pub(crate) fn new(
+        id: usize,
+        topic: String,
+        rx: mpsc::UnboundedReceiver<Arc<GossipsubMessage>>,
+        cancel: mpsc::UnboundedSender<ControlCommand>,
+    ) -> Self {
+        Self {
+            id,
+            topic,
+            rx,
+            cancel,
+        }
+    }
+
+    /// Returns the topic of the subscription.
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+}
+
+impl Stream for Subscription {
+    type Item = Arc<GossipsubMessage>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.rx).poll_next(cx)
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let _ = self
+            .cancel
+            .unbounded_send(ControlCommand::Unsubscribe(self.topic.clone(), self.id));
+    }
+}
@@ -0,0 +1,17 @@
+use std::sync::Arc;
+
+use libp2p_rs::core::PeerId;
+
+/// The outcome of validating a message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValidationResult {
+    /// The message is delivered to subscriptions and forwarded.
+    Accept,
+    /// The message is invalid and dropped.
+    Reject,
+    /// The message is dropped without being considered invalid, e.g. because it is stale.
+    Ignore,
+}
+
+/// Validates the messages of a topic, given the source and the payload of a message.
+pub type TopicValidator = Arc<dyn Fn(&PeerId, &[u8]) -> ValidationResult + Send + Sync>;
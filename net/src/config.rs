@@ -1,7 +1,15 @@
 use std::num::NonZeroU16;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
+use libipld::Result;
 use libp2p_rs::core::identity::Keypair;
 use libp2p_rs::core::{PublicKey, PeerId, Multiaddr};
+use crate::connmgr::{ConnectionLimits, ConnectionManagerConfig};
+use crate::kad_store::KadStoreConfig;
+use crate::pubsub::PubsubConfig;
+use crate::transport::{MuxerConfig, SecurityConfig, TransportConfig};
+use autonat::AutonatConfig;
+use relay::RelayServerConfig;
 
 /// Network configuration.
 #[derive(Clone)]
@@ -10,16 +18,47 @@ pub struct NetworkConfig {
     pub node_key: Keypair,
     /// Name of the node. Sent over the wire for debugging purposes.
     pub node_name: String,
+    /// The agent version sent in identify, followed by the node name.
+    pub agent_version: String,
     /// Bound listening addresses; by default the node will not listen on any address.
+    /// `/memory/<n>` addresses enable the process-local memory transport.
     pub listening_addrs: Vec<Multiaddr>,
+    /// The transports used to listen and dial.
+    pub transport: TransportConfig,
+    /// The security protocols offered on new connections, noise only by default.
+    pub security: SecurityConfig,
+    /// The stream multiplexers offered on new connections.
+    pub muxer: MuxerConfig,
     /// The peers to connect to on startup.
     pub bootstrap: Vec<(PeerId, Multiaddr)>,
+    /// Relays to reserve a slot on, so that peers can reach the node through the relays'
+    /// `/p2p-circuit` addresses when it is behind a NAT.
+    pub relays: Vec<(PeerId, Multiaddr)>,
+    /// Act as a relay server for other peers with the given limits.
+    pub relay_server: Option<RelayServerConfig>,
+    /// Reachability detection. While the node isn't publicly reachable the dht runs in
+    /// client mode.
+    pub autonat: AutonatConfig,
     /// Enable mdns.
     pub enable_mdns: bool,
     /// Enable kad.
     pub enable_kad: bool,
+    /// Path of the sqlite database the dht records and provider records are persisted in. If
+    /// it is `None` the records are kept in memory.
+    pub kad_store_path: Option<PathBuf>,
+    /// Dht record store limits.
+    pub kad_store: KadStoreConfig,
+    /// The pubsub protocol used by `subscribe` and `publish`.
+    pub pubsub: PubsubConfig,
+    /// Also push published name records to the pubsub topic of the name, so that
+    /// subscribers learn about updates without querying the dht.
+    pub name_pubsub: bool,
     /// Should we insert non-global addresses into the DHT?
     pub allow_non_globals_in_dht: bool,
+    /// Hard connection limits.
+    pub connection_limits: ConnectionLimits,
+    /// Connection manager watermarks.
+    pub connection_manager: ConnectionManagerConfig,
     /// Bitswap request timeout.
     pub bitswap_request_timeout: Duration,
     /// Bitswap connection keep alive.
@@ -36,20 +75,43 @@ impl NetworkConfig {
         Self {
             enable_mdns: true,
             enable_kad: true,
+            kad_store_path: None,
+            kad_store: KadStoreConfig::default(),
+            pubsub: PubsubConfig::default(),
+            name_pubsub: false,
             allow_non_globals_in_dht: false,
+            connection_limits: ConnectionLimits::default(),
+            connection_manager: ConnectionManagerConfig::default(),
             node_key: Keypair::generate_ed25519(),
             node_name: names::Generator::with_naming(names::Name::Numbered)
                 .next()
                 .unwrap(),
+            agent_version: concat!("ipfs-embed/", env!("CARGO_PKG_VERSION")).into(),
             listening_addrs,
+            transport: TransportConfig::default(),
+            security: SecurityConfig::default(),
+            muxer: MuxerConfig::default(),
             bitswap_request_timeout: Duration::from_secs(10),
             bitswap_connection_keepalive: Duration::from_secs(10),
             bitswap_receive_limit: NonZeroU16::new(20).expect("20 > 0"),
             //psk: None,
-            bootstrap: vec![]
+            bootstrap: vec![],
+            relays: vec![],
+            relay_server: None,
+            autonat: AutonatConfig::default(),
         }
     }
 
+    /// Creates a new network configuration using the node keypair stored at `path`.
+    ///
+    /// If no keyfile exists at `path` a new ed25519 keypair is generated and saved, so that
+    /// the node keeps its `PeerId` across restarts.
+    pub fn from_keyfile<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut config = Self::new(vec![]);
+        config.node_key = crate::keyfile::load_or_generate_keypair(path.as_ref())?;
+        Ok(config)
+    }
+
     /// The public node key.
     pub fn public(&self) -> PublicKey {
         self.node_key.public()
@@ -72,9 +134,22 @@ impl std::fmt::Debug for NetworkConfig {
         f.debug_struct("NetworkConfig")
             .field("node_key", &self.peer_id().to_string())
             .field("node_name", &self.node_name)
+            .field("agent_version", &self.agent_version)
+            .field("transport", &self.transport)
+            .field("security", &self.security)
+            .field("muxer", &self.muxer)
             .field("enable_mdns", &self.enable_mdns)
             .field("enable_kad", &self.enable_kad)
+            .field("relays", &self.relays)
+            .field("relay_server", &self.relay_server)
+            .field("autonat", &self.autonat)
+            .field("kad_store_path", &self.kad_store_path)
+            .field("kad_store", &self.kad_store)
+            .field("pubsub", &self.pubsub)
+            .field("name_pubsub", &self.name_pubsub)
             .field("allow_non_globals_in_dht", &self.allow_non_globals_in_dht)
+            .field("connection_limits", &self.connection_limits)
+            .field("connection_manager", &self.connection_manager)
             .field("bitswap_request_timeout", &self.bitswap_request_timeout)
             .field(
                 "bitswap_connection_keepalive",
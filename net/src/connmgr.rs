@@ -0,0 +1,627 @@
+use async_trait::async_trait;
+use fnv::{FnvHashMap, FnvHashSet};
+use futures::channel::mpsc;
+use futures::{SinkExt, StreamExt};
+use std::error::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+use libp2p_rs::core::upgrade::UpgradeInfo;
+use libp2p_rs::core::{PeerId, ProtocolId};
+use libp2p_rs::runtime::task;
+use libp2p_rs::swarm::connection::Connection;
+use libp2p_rs::swarm::protocol_handler::{IProtocolHandler, Notifiee, ProtocolHandler};
+use libp2p_rs::swarm::substream::Substream;
+use libp2p_rs::swarm::Control as SwarmControl;
+
+/// Hard connection limits. Connections exceeding a limit are closed right away.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ConnectionLimits {
+    /// The maximum number of established connections.
+    pub max_connections: Option<usize>,
+    /// The maximum number of established connections to a single peer.
+    pub max_connections_per_peer: Option<usize>,
+    /// The maximum number of concurrent outbound dials. Only the dials of
+    /// `NetworkService::dial` and `NetworkService::dial_address` are counted, the dials the
+    /// dht, bitswap and the relay client start on their own aren't limited.
+    pub max_pending: Option<usize>,
+}
+
+/// Connection manager configuration.
+///
+/// Once there are more than `high_water` connections, connections are closed until
+/// `low_water` connections remain. Peers with the lowest tag values are disconnected first,
+/// and among peers of the same value the ones idle for the longest time. Protected peers and
+/// peers connected for less than the `grace_period` are never trimmed.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ConnectionManagerConfig {
+    pub low_water: usize,
+    pub high_water: usize,
+    pub grace_period: Duration,
+    /// The interval at which the connection count is checked.
+    pub trim_interval: Duration,
+}
+
+impl Default for ConnectionManagerConfig {
+    fn default() -> Self {
+        Self {
+            low_water: 100,
+            high_water: 400,
+            grace_period: Duration::from_secs(60),
+            trim_interval: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ConnectionLimitError {
+    #[error("Too many pending dials")]
+    Pending,
+    #[error("Peer is banned")]
+    Banned,
+}
+
+enum Event {
+    Ban(PeerId),
+    Trim,
+}
+
+struct Peer {
+    connections: usize,
+    connected_at: Instant,
+    /// The last time a connection was established or seen with open streams.
+    last_active: Instant,
+}
+
+#[derive(Default)]
+struct State {
+    connected: FnvHashMap<PeerId, Peer>,
+    tags: FnvHashMap<PeerId, FnvHashMap<String, i32>>,
+    protected: FnvHashMap<PeerId, FnvHashSet<String>>,
+    banned: FnvHashSet<PeerId>,
+}
+
+impl State {
+    fn connection_count(&self) -> usize {
+        self.connected.values().map(|peer| peer.connections).sum()
+    }
+
+    fn is_protected(&self, peer: &PeerId) -> bool {
+        self.protected.contains_key(peer)
+    }
+
+    fn value(&self, peer: &PeerId) -> i64 {
+        self.tags
+            .get(peer)
+            .map(|tags| tags.values().map(|v| *v as i64).sum())
+            .unwrap_or_default()
+    }
+
+    /// Counts a new connection to `peer`, returning why it has to be closed if it exceeds a
+    /// limit.
+    fn add_connection(
+        &mut self,
+        peer: PeerId,
+        limits: &ConnectionLimits,
+        now: Instant,
+    ) -> Result<(), &'static str> {
+        let p = self.connected.entry(peer).or_insert_with(|| Peer {
+            connections: 0,
+            connected_at: now,
+            last_active: now,
+        });
+        p.connections += 1;
+        p.last_active = now;
+        let peer_connections = p.connections;
+        if self.banned.contains(&peer) {
+            return Err("peer is banned");
+        }
+        if self.is_protected(&peer) {
+            return Ok(());
+        }
+        let per_peer = limits.max_connections_per_peer;
+        if per_peer
+            .map(|max| peer_connections > max)
+            .unwrap_or_default()
+        {
+            return Err("connection limit per peer exceeded");
+        }
+        let max = limits.max_connections;
+        if max
+            .map(|max| self.connection_count() > max)
+            .unwrap_or_default()
+        {
+            return Err("connection limit exceeded");
+        }
+        Ok(())
+    }
+
+    fn remove_connection(&mut self, peer: &PeerId) {
+        if let Some(p) = self.connected.get_mut(peer) {
+            p.connections = p.connections.saturating_sub(1);
+            if p.connections == 0 {
+                self.connected.remove(peer);
+            }
+        }
+    }
+
+    fn mark_active(&mut self, peer: &PeerId, now: Instant) {
+        if let Some(p) = self.connected.get_mut(peer) {
+            p.last_active = p.last_active.max(now);
+        }
+    }
+
+    /// Returns the least valuable peers to disconnect to get from above the high watermark
+    /// down to the low watermark.
+    fn trim_victims(&self, config: &ConnectionManagerConfig, now: Instant) -> Vec<PeerId> {
+        let mut total = self.connection_count();
+        if total <= config.high_water {
+            return vec![];
+        }
+        let mut candidates: Vec<_> = self
+            .connected
+            .iter()
+            .filter(|(peer, p)| {
+                !self.is_protected(peer) && now - p.connected_at >= config.grace_period
+            })
+            .map(|(peer, p)| {
+                let key = (self.value(peer), p.last_active, p.connected_at);
+                (key, *peer, p.connections)
+            })
+            .collect();
+        // lowest value first, then the longest idle, then newer connections before older ones.
+        candidates
+            .sort_by(|(a, _, _), (b, _, _)| a.0.cmp(&b.0).then(a.1.cmp(&b.1)).then(b.2.cmp(&a.2)));
+        let mut victims = vec![];
+        for (_, peer, connections) in candidates {
+            if total <= config.low_water {
+                break;
+            }
+            total = total.saturating_sub(connections);
+            victims.push(peer);
+        }
+        victims
+    }
+}
+
+/// Enforces the limits as connections are established, so that only the connection
+/// exceeding a limit is closed.
+#[derive(Clone)]
+struct Handler {
+    state: Arc<Mutex<State>>,
+    limits: ConnectionLimits,
+    high_water: usize,
+    event_tx: mpsc::UnboundedSender<Event>,
+}
+
+impl UpgradeInfo for Handler {
+    type Info = ProtocolId;
+
+    /// The handler is only notified of connections, it doesn't speak a protocol.
+    fn protocol_info(&self) -> Vec<Self::Info> {
+        vec![]
+    }
+}
+
+impl Notifiee for Handler {
+    fn connected(&mut self, conn: &mut Connection) {
+        let peer = conn.remote_peer();
+        let (res, total) = {
+            let mut state = self.state.lock().unwrap();
+            let res = state.add_connection(peer, &self.limits, Instant::now());
+            (res, state.connection_count())
+        };
+        match res {
+            Ok(()) if total > self.high_water => {
+                let _ = self.event_tx.unbounded_send(Event::Trim);
+            }
+            Ok(()) => {}
+            Err(reason) => {
+                tracing::debug!("closing connection to {}: {}", peer, reason);
+                conn.close();
+            }
+        }
+    }
+
+    fn disconnected(&mut self, conn: &mut Connection) {
+        self.state
+            .lock()
+            .unwrap()
+            .remove_connection(&conn.remote_peer());
+    }
+}
+
+#[async_trait]
+impl ProtocolHandler for Handler {
+    async fn handle(
+        &mut self,
+        _stream: Substream,
+        _info: <Self as UpgradeInfo>::Info,
+    ) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn box_clone(&self) -> IProtocolHandler {
+        Box::new(self.clone())
+    }
+}
+
+/// Enforces the connection limits and trims connections between the watermarks.
+pub struct ConnectionManager {
+    config: ConnectionManagerConfig,
+    limits: ConnectionLimits,
+    state: Arc<Mutex<State>>,
+    pending: Arc<AtomicUsize>,
+    swarm: Option<SwarmControl>,
+    event_tx: mpsc::UnboundedSender<Event>,
+    event_rx: mpsc::UnboundedReceiver<Event>,
+}
+
+impl ConnectionManager {
+    pub fn new(config: ConnectionManagerConfig, limits: ConnectionLimits) -> Self {
+        let (event_tx, event_rx) = mpsc::unbounded();
+        Self {
+            config,
+            limits,
+            state: Default::default(),
+            pending: Default::default(),
+            swarm: None,
+            event_tx,
+            event_rx,
+        }
+    }
+
+    pub fn control(&self) -> ConnectionManagerControl {
+        ConnectionManagerControl {
+            state: self.state.clone(),
+            event_tx: self.event_tx.clone(),
+            pending: self.pending.clone(),
+            max_pending: self.limits.max_pending,
+        }
+    }
+
+    /// The handler notified of the connections of the swarm, it is registered along with a
+    /// protocol as it doesn't speak one.
+    pub(crate) fn notifiee(&self) -> IProtocolHandler {
+        Box::new(Handler {
+            state: self.state.clone(),
+            limits: self.limits.clone(),
+            high_water: self.config.high_water,
+            event_tx: self.event_tx.clone(),
+        })
+    }
+
+    async fn process_loop(&mut self) {
+        while let Some(event) = self.event_rx.next().await {
+            match event {
+                Event::Ban(peer) => {
+                    if self.state.lock().unwrap().connected.contains_key(&peer) {
+                        self.disconnect(peer);
+                    }
+                }
+                Event::Trim => self.trim().await,
+            }
+        }
+    }
+
+    /// Disconnects the least valuable peers until the low watermark is reached. Peers with
+    /// open streams are marked as active first, so that idle peers are trimmed before them.
+    async fn trim(&mut self) {
+        if self.state.lock().unwrap().connection_count() <= self.config.high_water {
+            return;
+        }
+        let mut swarm = self.swarm.clone().expect("swarm??");
+        if let Ok(connections) = swarm.dump_connections(None).await {
+            let now = Instant::now();
+            let mut state = self.state.lock().unwrap();
+            for conn in connections {
+                if conn.info.num_inbound_streams + conn.info.num_outbound_streams > 0 {
+                    state.mark_active(&conn.info.remote_peer_id, now);
+                }
+            }
+        }
+        let victims = self
+            .state
+            .lock()
+            .unwrap()
+            .trim_victims(&self.config, Instant::now());
+        if victims.is_empty() {
+            return;
+        }
+        tracing::debug!("trimming {} peers", victims.len());
+        for peer in victims {
+            self.disconnect(peer);
+        }
+    }
+
+    fn disconnect(&self, peer: PeerId) {
+        let mut swarm = self.swarm.clone().expect("swarm??");
+        task::spawn(async move {
+            let _ = swarm.disconnect(peer).await;
+        });
+    }
+
+    /// Starts the loop trimming connections and closing the connections of banned peers.
+    pub(crate) fn start(mut self, swarm: SwarmControl) -> task::TaskHandle<()> {
+        self.swarm = Some(swarm);
+
+        // the trim ticker stops once the manager exits
+        let mut ticker = self.event_tx.clone();
+        let interval = self.config.trim_interval;
+        task::spawn(async move {
+            loop {
+                task::sleep(interval).await;
+                if ticker.send(Event::Trim).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut connmgr = self;
+        task::spawn(async move {
+            tracing::info!("starting connection manager loop...");
+            connmgr.process_loop().await;
+            tracing::info!("exiting connection manager loop...");
+        })
+    }
+}
+
+/// Tags and protects peers and limits the number of pending dials.
+#[derive(Clone)]
+pub struct ConnectionManagerControl {
+    state: Arc<Mutex<State>>,
+    event_tx: mpsc::UnboundedSender<Event>,
+    pending: Arc<AtomicUsize>,
+    max_pending: Option<usize>,
+}
+
+impl ConnectionManagerControl {
+    /// Sets the value of a tag of a peer. Peers with a higher total value are kept longer
+    /// when trimming connections.
+    pub fn tag_peer(&self, peer: &PeerId, tag: &str, value: i32) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .tags
+            .entry(*peer)
+            .or_default()
+            .insert(tag.to_string(), value);
+    }
+
+    /// Removes a tag of a peer.
+    pub fn untag_peer(&self, peer: &PeerId, tag: &str) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(tags) = state.tags.get_mut(peer) {
+            tags.remove(tag);
+            if tags.is_empty() {
+                state.tags.remove(peer);
+            }
+        }
+    }
+
+    /// Stops the connection manager loop and its trim ticker.
+    pub(crate) fn close(&self) {
+        self.event_tx.close_channel();
+    }
+
+    /// Protects the connections to a peer from being trimmed or closed by the connection
+    /// limits until all protection tags are removed.
+    pub fn protect(&self, peer: &PeerId, tag: &str) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .protected
+            .entry(*peer)
+            .or_default()
+            .insert(tag.to_string());
+    }
+
+    /// Removes a protection tag, returning `true` if the peer is still protected.
+    pub fn unprotect(&self, peer: &PeerId, tag: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if let Some(tags) = state.protected.get_mut(peer) {
+            tags.remove(tag);
+            if tags.is_empty() {
+                state.protected.remove(peer);
+            }
+        }
+        state.is_protected(peer)
+    }
+
+    /// Returns `true` if the peer is protected.
+    pub fn is_protected(&self, peer: &PeerId) -> bool {
+        self.state.lock().unwrap().is_protected(peer)
+    }
+
+    /// Bans a peer, closing its connections and refusing new ones until it is unbanned.
+    /// Bans take precedence over protection.
+    pub fn ban(&self, peer: &PeerId) {
+        self.state.lock().unwrap().banned.insert(*peer);
+        let _ = self.event_tx.unbounded_send(Event::Ban(*peer));
+    }
+
+    /// Unbans a peer, returning `true` if the peer was banned.
+    pub fn unban(&self, peer: &PeerId) -> bool {
+        self.state.lock().unwrap().banned.remove(peer)
+    }
+
+    /// Returns `true` if the peer is banned.
+    pub fn is_banned(&self, peer: &PeerId) -> bool {
+        self.state.lock().unwrap().banned.contains(peer)
+    }
+
+    /// Returns the number of established connections.
+    pub fn connection_count(&self) -> usize {
+        self.state.lock().unwrap().connection_count()
+    }
+
+    /// Trims connections if there are more than the high watermark.
+    pub fn trim(&self) {
+        let _ = self.event_tx.unbounded_send(Event::Trim);
+    }
+
+    /// Reserves a pending dial, the reservation is released when the guard is dropped. The
+    /// reservations are shared by all controls, only the explicit dials of the network
+    /// service take one.
+    pub fn pending_dial(&self) -> Result<PendingDial, ConnectionLimitError> {
+        let pending = self.pending.fetch_add(1, Ordering::SeqCst);
+        let dial = PendingDial(self.pending.clone());
+        if self
+            .max_pending
+            .map(|max| pending >= max)
+            .unwrap_or_default()
+        {
+            return Err(ConnectionLimitError::Pending);
+        }
+        Ok(dial)
+    }
+}
+
+/// A reserved pending dial.
+pub struct PendingDial(Arc<AtomicUsize>);
+
+impl Drop for PendingDial {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pending_dials() {
+        let limits = ConnectionLimits {
+            max_pending: Some(1),
+            ..Default::default()
+        };
+        let connmgr = ConnectionManager::new(Default::default(), limits);
+        let control = connmgr.control();
+        let dial = control.pending_dial().unwrap();
+        assert!(control.pending_dial().is_err());
+        // the reservations are shared by all controls
+        assert!(connmgr.control().pending_dial().is_err());
+        drop(dial);
+        assert!(connmgr.control().pending_dial().is_ok());
+    }
+
+    #[test]
+    fn test_connection_limits() {
+        let limits = ConnectionLimits {
+            max_connections: Some(3),
+            max_connections_per_peer: Some(2),
+            ..Default::default()
+        };
+        let mut state = State::default();
+        let now = Instant::now();
+        let a = PeerId::random();
+        let b = PeerId::random();
+        assert!(state.add_connection(a, &limits, now).is_ok());
+        assert!(state.add_connection(a, &limits, now).is_ok());
+        // only the connection exceeding the limit is refused, closing it removes it
+        assert!(state.add_connection(a, &limits, now).is_err());
+        state.remove_connection(&a);
+        assert_eq!(state.connected[&a].connections, 2);
+
+        assert!(state.add_connection(b, &limits, now).is_ok());
+        assert!(state.add_connection(b, &limits, now).is_err());
+        state.remove_connection(&b);
+        assert_eq!(state.connection_count(), 3);
+
+        // protected peers are exempt from the limits, but not from bans
+        state.protected.entry(b).or_default().insert("test".into());
+        assert!(state.add_connection(b, &limits, now).is_ok());
+        state.banned.insert(b);
+        assert!(state.add_connection(b, &limits, now).is_err());
+    }
+
+    #[test]
+    fn test_trim() {
+        let config = ConnectionManagerConfig {
+            low_water: 3,
+            high_water: 4,
+            grace_period: Duration::from_secs(60),
+            trim_interval: Duration::from_secs(10),
+        };
+        let limits = ConnectionLimits::default();
+        let mut state = State::default();
+        let start = Instant::now();
+        let now = start + Duration::from_secs(120);
+        let peers: Vec<_> = (0..6).map(|_| PeerId::random()).collect();
+        for peer in &peers[..5] {
+            state.add_connection(*peer, &limits, start).unwrap();
+        }
+        // below the high watermark nothing is trimmed
+        assert!(state.trim_victims(&config, now).is_empty());
+
+        // peers[5] is still in its grace period
+        state.add_connection(peers[5], &limits, now).unwrap();
+        state
+            .tags
+            .entry(peers[2])
+            .or_default()
+            .insert("a".into(), 10);
+        state
+            .tags
+            .entry(peers[3])
+            .or_default()
+            .insert("a".into(), 5);
+        state
+            .protected
+            .entry(peers[4])
+            .or_default()
+            .insert("a".into());
+
+        // the least valuable peers are trimmed until the low watermark is reached
+        let victims: FnvHashSet<_> = state.trim_victims(&config, now).into_iter().collect();
+        let expected: FnvHashSet<_> = vec![peers[0], peers[1], peers[3]].into_iter().collect();
+        assert_eq!(victims, expected);
+    }
+
+    #[test]
+    fn test_trim_idle_first() {
+        let config = ConnectionManagerConfig {
+            low_water: 2,
+            high_water: 3,
+            grace_period: Duration::from_secs(60),
+            trim_interval: Duration::from_secs(10),
+        };
+        let limits = ConnectionLimits::default();
+        let mut state = State::default();
+        let start = Instant::now();
+        let now = start + Duration::from_secs(120);
+        let peers: Vec<_> = (0..4).map(|_| PeerId::random()).collect();
+        for peer in &peers {
+            state.add_connection(*peer, &limits, start).unwrap();
+        }
+        state.mark_active(&peers[0], now);
+        state.mark_active(&peers[2], now);
+        // peers of the same value are trimmed longest idle first
+        let victims: FnvHashSet<_> = state.trim_victims(&config, now).into_iter().collect();
+        let expected: FnvHashSet<_> = vec![peers[1], peers[3]].into_iter().collect();
+        assert_eq!(victims, expected);
+    }
+
+    #[test]
+    fn test_protect() {
+        let control = ConnectionManager::new(Default::default(), Default::default()).control();
+        let peer = PeerId::random();
+        control.protect(&peer, "a");
+        control.protect(&peer, "b");
+        assert!(control.unprotect(&peer, "a"));
+        assert!(!control.unprotect(&peer, "b"));
+        assert!(!control.is_protected(&peer));
+    }
+
+    #[test]
+    fn test_ban() {
+        let control = ConnectionManager::new(Default::default(), Default::default()).control();
+        let peer = PeerId::random();
+        control.ban(&peer);
+        assert!(control.is_banned(&peer));
+        assert!(control.unban(&peer));
+        assert!(!control.unban(&peer));
+        assert!(!control.is_banned(&peer));
+    }
+}
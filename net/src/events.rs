@@ -0,0 +1,490 @@
+use async_trait::async_trait;
+use fnv::{FnvHashMap, FnvHashSet};
+use futures::channel::mpsc;
+use futures::stream::{Stream, StreamExt};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use libp2p_rs::core::upgrade::UpgradeInfo;
+use libp2p_rs::core::{Multiaddr, PeerId, ProtocolId};
+use libp2p_rs::kad::Control as KadControl;
+use libp2p_rs::runtime::task;
+use libp2p_rs::swarm::connection::Connection;
+use libp2p_rs::swarm::protocol_handler::{IProtocolHandler, Notifiee, ProtocolHandler};
+use libp2p_rs::swarm::substream::Substream;
+use libp2p_rs::swarm::Control as SwarmControl;
+
+use crate::peer_info::PeerInfo;
+
+/// The dht adds the identified peers supporting its protocol to the routing table.
+const KAD_PROTOCOL: &str = "/ipfs/kad/1.0.0";
+
+/// A change of the network state.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum NetworkEvent {
+    /// A connection to a peer was established.
+    ConnectionEstablished { peer: PeerId, addr: Multiaddr },
+    /// A connection to a peer was closed.
+    ConnectionClosed { peer: PeerId, addr: Multiaddr },
+    /// The node listens on a new address.
+    NewListenAddr(Multiaddr),
+    /// The node no longer listens on an address.
+    ExpiredListenAddr(Multiaddr),
+    /// A peer sent its identify info.
+    Identified {
+        peer: PeerId,
+        agent_version: String,
+        listen_addrs: Vec<Multiaddr>,
+        protocols: Vec<String>,
+    },
+    /// A peer was added to the dht routing table for the first time since the node
+    /// started.
+    Discovered(PeerId),
+    /// A peer was banned.
+    Banned(PeerId),
+    /// A peer was unbanned.
+    Unbanned(PeerId),
+}
+
+enum Event {
+    Connected(PeerId, Multiaddr),
+    Disconnected(PeerId, Multiaddr),
+    Identified(PeerId),
+    AddressChanged(Vec<Multiaddr>),
+    RoutingUpdated,
+}
+
+#[derive(Clone)]
+struct Handler {
+    event_tx: mpsc::UnboundedSender<Event>,
+}
+
+impl UpgradeInfo for Handler {
+    type Info = ProtocolId;
+
+    /// The handler is only notified by the swarm, it doesn't speak a protocol.
+    fn protocol_info(&self) -> Vec<Self::Info> {
+        vec![]
+    }
+}
+
+impl Notifiee for Handler {
+    fn connected(&mut self, conn: &mut Connection) {
+        let event = Event::Connected(conn.remote_peer(), conn.remote_addr());
+        let _ = self.event_tx.unbounded_send(event);
+    }
+
+    fn disconnected(&mut self, conn: &mut Connection) {
+        let event = Event::Disconnected(conn.remote_peer(), conn.remote_addr());
+        let _ = self.event_tx.unbounded_send(event);
+    }
+
+    fn identified(&mut self, peer: PeerId) {
+        let _ = self.event_tx.unbounded_send(Event::Identified(peer));
+    }
+
+    fn address_changed(&mut self, addrs: Vec<Multiaddr>) {
+        let _ = self.event_tx.unbounded_send(Event::AddressChanged(addrs));
+    }
+}
+
+#[async_trait]
+impl ProtocolHandler for Handler {
+    async fn handle(
+        &mut self,
+        _stream: Substream,
+        _info: <Self as UpgradeInfo>::Info,
+    ) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn box_clone(&self) -> IProtocolHandler {
+        Box::new(self.clone())
+    }
+}
+
+/// A subscriber fell behind and missed events.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Lagged(pub u64);
+
+impl std::fmt::Display for Lagged {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "network event subscriber missed {} events", self.0)
+    }
+}
+
+impl std::error::Error for Lagged {}
+
+#[derive(Debug)]
+struct Queue {
+    events: VecDeque<NetworkEvent>,
+    capacity: usize,
+    lagged: u64,
+    waker: Option<Waker>,
+    closed: bool,
+}
+
+type Subscribers = Arc<Mutex<Vec<Arc<Mutex<Queue>>>>>;
+type Peers = Arc<Mutex<FnvHashMap<PeerId, PeerInfo>>>;
+type ObservedAddrListener = Arc<dyn Fn(Multiaddr) + Send + Sync>;
+
+/// Sends an event to the bounded queues of the subscribers. When a queue is full the oldest
+/// event is dropped and the subscriber is told how many events it missed.
+fn emit(subscribers: &Subscribers, event: NetworkEvent) {
+    subscribers.lock().unwrap().retain(|queue| {
+        let mut queue = queue.lock().unwrap();
+        if queue.closed {
+            return false;
+        }
+        if queue.events.len() >= queue.capacity {
+            queue.events.pop_front();
+            queue.lagged += 1;
+        }
+        queue.events.push_back(event.clone());
+        if let Some(waker) = queue.waker.take() {
+            waker.wake();
+        }
+        true
+    });
+}
+
+/// Turns the swarm notifications into network events and keeps the identify info of
+/// connected peers.
+pub struct EventWatcher {
+    subscribers: Subscribers,
+    peers: Peers,
+    /// The number of connections of each connected peer.
+    connections: FnvHashMap<PeerId, usize>,
+    observed_addr_listener: Option<ObservedAddrListener>,
+    /// The peers that were in the dht routing table.
+    discovered: FnvHashSet<PeerId>,
+    kad: Option<KadControl>,
+    event_tx: mpsc::UnboundedSender<Event>,
+    event_rx: mpsc::UnboundedReceiver<Event>,
+}
+
+impl Default for EventWatcher {
+    fn default() -> Self {
+        let (event_tx, event_rx) = mpsc::unbounded();
+        Self {
+            subscribers: Default::default(),
+            peers: Default::default(),
+            connections: Default::default(),
+            observed_addr_listener: None,
+            discovered: Default::default(),
+            kad: None,
+            event_tx,
+            event_rx,
+        }
+    }
+}
+
+impl EventWatcher {
+    /// Calls `listener` with the address peers observed the node at.
+    pub fn with_observed_addr_listener<F>(mut self, listener: F) -> Self
+    where
+        F: Fn(Multiaddr) + Send + Sync + 'static,
+    {
+        self.observed_addr_listener = Some(Arc::new(listener));
+        self
+    }
+
+    /// Reports the peers added to the routing table of the dht as discovered. The routing
+    /// table is checked when a peer supporting the dht protocol is identified, as the dht
+    /// adds these peers to it, and when a bootstrap completes.
+    pub fn with_routing_table(mut self, kad: KadControl) -> Self {
+        self.kad = Some(kad);
+        self
+    }
+
+    pub fn control(&self) -> NetworkEvents {
+        NetworkEvents {
+            subscribers: self.subscribers.clone(),
+            peers: self.peers.clone(),
+            event_tx: self.event_tx.clone(),
+        }
+    }
+
+    async fn process_loop(&mut self, swarm: SwarmControl) {
+        let mut listen_addrs = vec![];
+        // the listeners are bound before the watcher starts
+        let addrs = swarm.clone().self_addrs().await.unwrap_or_default();
+        self.update_listen_addrs(&mut listen_addrs, addrs);
+        while let Some(event) = self.event_rx.next().await {
+            match event {
+                Event::Connected(peer, addr) => {
+                    *self.connections.entry(peer).or_default() += 1;
+                    self.emit(NetworkEvent::ConnectionEstablished { peer, addr })
+                }
+                Event::Disconnected(peer, addr) => {
+                    self.on_disconnected(peer);
+                    self.emit(NetworkEvent::ConnectionClosed { peer, addr })
+                }
+                Event::Identified(peer) => {
+                    if self.on_identified(&swarm, peer) {
+                        self.check_routing_table().await;
+                    }
+                }
+                Event::AddressChanged(addrs) => self.update_listen_addrs(&mut listen_addrs, addrs),
+                Event::RoutingUpdated => self.check_routing_table().await,
+            }
+        }
+    }
+
+    /// Forgets the identify info of a peer once its last connection is closed.
+    fn on_disconnected(&mut self, peer: PeerId) {
+        if let Some(connections) = self.connections.get_mut(&peer) {
+            *connections -= 1;
+            if *connections > 0 {
+                return;
+            }
+        }
+        self.connections.remove(&peer);
+        self.peers.lock().unwrap().remove(&peer);
+    }
+
+    /// Emits `Discovered` for the peers added to the dht routing table.
+    async fn check_routing_table(&mut self) {
+        let kad = match self.kad.as_mut() {
+            Some(kad) => kad,
+            None => return,
+        };
+        let buckets = match kad.dump_kbuckets().await {
+            Ok(buckets) => buckets,
+            Err(err) => {
+                tracing::debug!("failed to read the routing table: {:?}", err);
+                return;
+            }
+        };
+        for node in buckets.into_iter().flat_map(|bucket| bucket.bucket) {
+            if self.discovered.insert(node.id) {
+                self.emit(NetworkEvent::Discovered(node.id));
+            }
+        }
+    }
+
+    /// Stores the identify info of a peer, returning `true` if the peer supports the dht
+    /// protocol.
+    fn on_identified(&self, swarm: &SwarmControl, peer: PeerId) -> bool {
+        let info = match swarm.get_identify_info(&peer) {
+            Some(info) => info,
+            None => return false,
+        };
+        if let Some(listener) = self.observed_addr_listener.as_ref() {
+            listener(info.observed_addr.clone());
+        }
+        // identify may finish after the connection closed
+        if self.connections.contains_key(&peer) {
+            self.peers.lock().unwrap().insert(
+                peer,
+                PeerInfo {
+                    agent_version: info.agent_version.clone(),
+                    protocol_version: info.protocol_version.clone(),
+                    protocols: info.protocols.clone(),
+                    listen_addrs: info.listen_addrs.clone(),
+                    rtt: None,
+                },
+            );
+        }
+        let kad = info
+            .protocols
+            .iter()
+            .any(|protocol| protocol == KAD_PROTOCOL);
+        self.emit(NetworkEvent::Identified {
+            peer,
+            agent_version: info.agent_version,
+            listen_addrs: info.listen_addrs,
+            protocols: info.protocols,
+        });
+        kad
+    }
+
+    fn update_listen_addrs(&self, listen_addrs: &mut Vec<Multiaddr>, addrs: Vec<Multiaddr>) {
+        for addr in listen_addrs.iter().filter(|addr| !addrs.contains(addr)) {
+            self.emit(NetworkEvent::ExpiredListenAddr(addr.clone()));
+        }
+        for addr in addrs.iter().filter(|addr| !listen_addrs.contains(addr)) {
+            self.emit(NetworkEvent::NewListenAddr(addr.clone()));
+        }
+        *listen_addrs = addrs;
+    }
+
+    fn emit(&self, event: NetworkEvent) {
+        emit(&self.subscribers, event)
+    }
+}
+
+impl EventWatcher {
+    /// The handler notified by the swarm, it is registered along with a protocol as it
+    /// doesn't speak one.
+    pub(crate) fn notifiee(&self) -> IProtocolHandler {
+        Box::new(Handler {
+            event_tx: self.event_tx.clone(),
+        })
+    }
+
+    /// Starts the loop turning the notifications into events.
+    pub(crate) fn start(self, swarm: SwarmControl) -> task::TaskHandle<()> {
+        let mut watcher = self;
+        task::spawn(async move {
+            tracing::info!("starting event watcher loop...");
+            watcher.process_loop(swarm).await;
+            tracing::info!("exiting event watcher loop...");
+        })
+    }
+}
+
+/// Subscribes to network events and returns the identify info of peers.
+#[derive(Clone)]
+pub struct NetworkEvents {
+    subscribers: Subscribers,
+    peers: Peers,
+    event_tx: mpsc::UnboundedSender<Event>,
+}
+
+impl NetworkEvents {
+    /// Returns a stream of the network events from now on. At most `capacity` events are
+    /// queued, when the subscriber falls behind the oldest events are dropped. The
+    /// subscription ends when the stream is dropped.
+    pub fn subscribe(&self, capacity: usize) -> NetworkEventStream {
+        let queue = Arc::new(Mutex::new(Queue {
+            events: VecDeque::with_capacity(capacity.min(1024)),
+            capacity: capacity.max(1),
+            lagged: 0,
+            waker: None,
+            closed: false,
+        }));
+        self.subscribers.lock().unwrap().push(queue.clone());
+        NetworkEventStream { queue }
+    }
+
+    /// Returns the last identify info received from `peer` while it is connected.
+    pub fn peer_info(&self, peer: &PeerId) -> Option<PeerInfo> {
+        self.peers.lock().unwrap().get(peer).cloned()
+    }
+
+    /// Sends an event to all subscribers.
+    pub(crate) fn emit(&self, event: NetworkEvent) {
+        emit(&self.subscribers, event)
+    }
+
+    /// Checks the dht routing table for discovered peers, after the dht added peers found
+    /// by a query.
+    pub(crate) fn routing_updated(&self) {
+        let _ = self.event_tx.unbounded_send(Event::RoutingUpdated);
+    }
+
+    /// Stops the event watcher and ends the streams of the subscribers once they received
+    /// the queued events.
+    pub(crate) fn close(&self) {
+        self.event_tx.close_channel();
+        for queue in self.subscribers.lock().unwrap().drain(..) {
+            let mut queue = queue.lock().unwrap();
+            queue.closed = true;
+            if let Some(waker) = queue.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// A stream of network events. Returns `Err(Lagged(n))` before the next event if `n` events
+/// were dropped because the subscriber didn't keep up.
+pub struct NetworkEventStream {
+    queue: Arc<Mutex<Queue>>,
+}
+
+impl Stream for NetworkEventStream {
+    type Item = Result<NetworkEvent, Lagged>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.lagged > 0 {
+            let lagged = queue.lagged;
+            queue.lagged = 0;
+            return Poll::Ready(Some(Err(Lagged(lagged))));
+        }
+        if let Some(event) = queue.events.pop_front() {
+            return Poll::Ready(Some(Ok(event)));
+        }
+        if queue.closed {
+            return Poll::Ready(None);
+        }
+        queue.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for NetworkEventStream {
+    fn drop(&mut self) {
+        self.queue.lock().unwrap().closed = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    #[test]
+    fn test_subscribers() {
+        let events = EventWatcher::default().control();
+        let mut rx1 = events.subscribe(2);
+        let rx2 = events.subscribe(2);
+        drop(rx2);
+        let peer = PeerId::random();
+        events.emit(NetworkEvent::Banned(peer));
+        assert_eq!(events.subscribers.lock().unwrap().len(), 1);
+        assert_eq!(
+            rx1.next().now_or_never().unwrap(),
+            Some(Ok(NetworkEvent::Banned(peer)))
+        );
+    }
+
+    #[test]
+    fn test_lagged() {
+        let events = EventWatcher::default().control();
+        let mut rx = events.subscribe(2);
+        let peers: Vec<_> = (0..4).map(|_| PeerId::random()).collect();
+        for peer in &peers {
+            events.emit(NetworkEvent::Banned(*peer));
+        }
+        assert_eq!(rx.next().now_or_never().unwrap(), Some(Err(Lagged(2))));
+        assert_eq!(
+            rx.next().now_or_never().unwrap(),
+            Some(Ok(NetworkEvent::Banned(peers[2])))
+        );
+        assert_eq!(
+            rx.next().now_or_never().unwrap(),
+            Some(Ok(NetworkEvent::Banned(peers[3])))
+        );
+        events.close();
+        assert_eq!(rx.next().now_or_never().unwrap(), None);
+    }
+
+    #[test]
+    fn test_prune_disconnected_peers() {
+        let mut watcher = EventWatcher::default();
+        let events = watcher.control();
+        let peer = PeerId::random();
+        watcher.connections.insert(peer, 2);
+        watcher.peers.lock().unwrap().insert(
+            peer,
+            PeerInfo {
+                agent_version: "test".into(),
+                protocol_version: "test".into(),
+                protocols: vec![],
+                listen_addrs: vec![],
+                rtt: None,
+            },
+        );
+        watcher.on_disconnected(peer);
+        assert!(events.peer_info(&peer).is_some());
+        watcher.on_disconnected(peer);
+        assert!(events.peer_info(&peer).is_none());
+        assert!(watcher.connections.is_empty());
+    }
+}
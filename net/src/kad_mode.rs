@@ -0,0 +1,129 @@
+use async_trait::async_trait;
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use libp2p_rs::core::upgrade::UpgradeInfo;
+use libp2p_rs::core::{Multiaddr, PeerId, ProtocolId};
+use libp2p_rs::runtime::task;
+use libp2p_rs::swarm::connection::Connection;
+use libp2p_rs::swarm::protocol_handler::{
+    IProtocolHandler, Notifiee, ProtocolHandler, ProtocolImpl,
+};
+use libp2p_rs::swarm::substream::Substream;
+use libp2p_rs::swarm::Control as SwarmControl;
+
+/// Registers the dht protocol and switches it between server mode and client mode.
+///
+/// In client mode the handler doesn't list the dht protocol and refuses inbound dht
+/// streams, so that a node that isn't publicly reachable neither answers queries nor stores
+/// records on behalf of the network. Queries of the local node work in both modes.
+pub(crate) struct KadMode<P> {
+    inner: P,
+    server: Arc<AtomicBool>,
+}
+
+impl<P: ProtocolImpl> KadMode<P> {
+    /// Wraps the dht protocol, starting in server mode.
+    pub fn new(inner: P) -> Self {
+        Self {
+            inner,
+            server: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    pub fn control(&self) -> KadModeControl {
+        KadModeControl(self.server.clone())
+    }
+}
+
+/// Switches the dht between server mode and client mode.
+#[derive(Clone)]
+pub(crate) struct KadModeControl(Arc<AtomicBool>);
+
+impl KadModeControl {
+    pub fn set_server_mode(&self, server: bool) {
+        self.0.store(server, Ordering::SeqCst);
+    }
+
+    /// Returns `true` if the dht answers queries of remote peers.
+    pub fn is_server_mode(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+impl<P: ProtocolImpl> ProtocolImpl for KadMode<P> {
+    fn handler(&self) -> IProtocolHandler {
+        Box::new(Handler {
+            inner: self.inner.handler(),
+            server: self.server.clone(),
+        })
+    }
+
+    fn start(self, swarm: SwarmControl) -> Option<task::TaskHandle<()>>
+    where
+        Self: Sized,
+    {
+        self.inner.start(swarm)
+    }
+}
+
+struct Handler {
+    inner: IProtocolHandler,
+    server: Arc<AtomicBool>,
+}
+
+impl UpgradeInfo for Handler {
+    type Info = ProtocolId;
+
+    fn protocol_info(&self) -> Vec<Self::Info> {
+        if self.server.load(Ordering::SeqCst) {
+            self.inner.protocol_info()
+        } else {
+            vec![]
+        }
+    }
+}
+
+impl Notifiee for Handler {
+    fn connected(&mut self, conn: &mut Connection) {
+        self.inner.connected(conn);
+    }
+
+    fn disconnected(&mut self, conn: &mut Connection) {
+        self.inner.disconnected(conn);
+    }
+
+    fn identified(&mut self, peer: PeerId) {
+        self.inner.identified(peer);
+    }
+
+    fn address_changed(&mut self, addrs: Vec<Multiaddr>) {
+        self.inner.address_changed(addrs);
+    }
+}
+
+#[async_trait]
+impl ProtocolHandler for Handler {
+    async fn handle(
+        &mut self,
+        stream: Substream,
+        info: <Self as UpgradeInfo>::Info,
+    ) -> Result<(), Box<dyn Error>> {
+        if !self.server.load(Ordering::SeqCst) {
+            tracing::debug!(
+                "dht in client mode refuses stream of {}",
+                stream.remote_peer()
+            );
+            return Err("dht in client mode".into());
+        }
+        self.inner.handle(stream, info).await
+    }
+
+    fn box_clone(&self) -> IProtocolHandler {
+        Box::new(Handler {
+            inner: self.inner.box_clone(),
+            server: self.server.clone(),
+        })
+    }
+}
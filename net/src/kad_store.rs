@@ -0,0 +1,649 @@
+use libp2p_rs::core::PeerId;
+use libp2p_rs::kad::record::{Key, ProviderRecord, Record};
+use libp2p_rs::kad::store::{Error, RecordStore, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::validator::RecordValidators;
+use std::borrow::Cow;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Schema migrations of the dht tables. Migrations are only ever appended, the index of a
+/// migration is its schema version.
+const MIGRATIONS: &[&str] = &[r#"
+    CREATE TABLE IF NOT EXISTS kad_records (
+        key BLOB PRIMARY KEY,
+        value BLOB NOT NULL,
+        publisher TEXT,
+        expires INTEGER
+    );
+    CREATE TABLE IF NOT EXISTS kad_providers (
+        key BLOB NOT NULL,
+        provider TEXT NOT NULL,
+        expires INTEGER,
+        PRIMARY KEY (key, provider)
+    );
+    CREATE INDEX IF NOT EXISTS kad_providers_provider ON kad_providers (provider);
+    "#];
+
+/// Dht record store configuration.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct KadStoreConfig {
+    /// The maximum number of records.
+    pub max_records: usize,
+    /// The maximum size of record values, in bytes.
+    pub max_value_bytes: usize,
+    /// The maximum number of providers stored for a key.
+    pub max_providers_per_key: usize,
+    /// The maximum number of provider records for which the local node is the provider.
+    pub max_provided_keys: usize,
+}
+
+impl Default for KadStoreConfig {
+    fn default() -> Self {
+        Self {
+            max_records: 1024,
+            max_value_bytes: 65 * 1024,
+            max_providers_per_key: 20,
+            max_provided_keys: 1024,
+        }
+    }
+}
+
+type RecordsIter<'a> = std::iter::Map<std::vec::IntoIter<Record>, fn(Record) -> Cow<'a, Record>>;
+type ProvidedIter<'a> = std::iter::Map<
+    std::vec::IntoIter<ProviderRecord>,
+    fn(ProviderRecord) -> Cow<'a, ProviderRecord>,
+>;
+
+/// A dht `RecordStore` persisting records and provider records in sqlite.
+///
+/// The tables live in the same database as the block store, so dht state survives restarts.
+/// Expired records are never returned and are removed when the store is written to.
+///
+/// Records put by remote peers are checked by the record validators. Invalid records are
+/// dropped, and when a record for the key already exists the validator selects which of the
+/// two records is kept. Records of namespaces without a validator replace the existing
+/// record.
+///
+/// The store error of the dht can't carry sqlite errors. A failed write is reported as a
+/// full store and the sqlite error is kept, to be returned by `take_error`.
+#[derive(Clone)]
+pub struct SqliteRecordStore {
+    local_key: PeerId,
+    config: KadStoreConfig,
+    validators: RecordValidators,
+    conn: Arc<Mutex<Option<Connection>>>,
+    error: Arc<Mutex<Option<rusqlite::Error>>>,
+}
+
+impl SqliteRecordStore {
+    /// Opens the record store in the sqlite database at `path`.
+    pub fn open(
+        local_key: PeerId,
+        path: &Path,
+        config: KadStoreConfig,
+        validators: RecordValidators,
+    ) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.busy_timeout(Duration::from_secs(5))?;
+        Self::with_connection(local_key, conn, config, validators)
+    }
+
+    /// Creates an in-memory record store.
+    pub fn memory(
+        local_key: PeerId,
+        config: KadStoreConfig,
+        validators: RecordValidators,
+    ) -> rusqlite::Result<Self> {
+        Self::with_connection(local_key, Connection::open_in_memory()?, config, validators)
+    }
+
+    fn with_connection(
+        local_key: PeerId,
+        conn: Connection,
+        config: KadStoreConfig,
+        validators: RecordValidators,
+    ) -> rusqlite::Result<Self> {
+        migrate(&conn)?;
+        Ok(Self {
+            local_key,
+            config,
+            validators,
+            conn: Arc::new(Mutex::new(Some(conn))),
+            error: Default::default(),
+        })
+    }
+
+    /// Closes the database connection, later reads return nothing and writes fail.
+    pub fn close(&self) {
+        if let Some(conn) = self.conn.lock().unwrap().take() {
+            if let Err((_, err)) = conn.close() {
+                tracing::warn!("failed to close the dht store: {}", err);
+            }
+        }
+    }
+
+    /// Runs `f` on the database connection, fails once the store is closed.
+    fn with_conn<T>(
+        &self,
+        f: impl FnOnce(&Connection) -> rusqlite::Result<T>,
+    ) -> rusqlite::Result<T> {
+        match self.conn.lock().unwrap().as_ref() {
+            Some(conn) => f(conn),
+            None => Err(rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_MISUSE),
+                Some("dht store closed".into()),
+            )),
+        }
+    }
+
+    /// Returns the sqlite error of the last failed write, if it wasn't taken yet.
+    pub fn take_error(&self) -> Option<rusqlite::Error> {
+        self.error.lock().unwrap().take()
+    }
+
+    /// Logs a sqlite error, keeping it for `take_error`.
+    fn write_err<T>(&self, op: &str, res: rusqlite::Result<T>) -> Option<T> {
+        match res {
+            Ok(res) => Some(res),
+            Err(err) => {
+                tracing::error!("dht store {} failed: {}", op, err);
+                *self.error.lock().unwrap() = Some(err);
+                None
+            }
+        }
+    }
+
+    /// Returns the locally stored record for `key`.
+    pub fn local_record(&self, key: &Key) -> Option<Record> {
+        log_err("get", self.with_conn(|conn| get_record(conn, key))).flatten()
+    }
+
+    fn put_record(&self, record: &Record) -> rusqlite::Result<Result<()>> {
+        if record.value.len() > self.config.max_value_bytes {
+            return Ok(Err(Error::ValueTooLarge));
+        }
+        self.with_conn(|conn| self.put_record_with(conn, record))
+    }
+
+    fn put_record_with(&self, conn: &Connection, record: &Record) -> rusqlite::Result<Result<()>> {
+        remove_expired(conn)?;
+        let key = record.key.to_vec();
+        let existing = get_record(conn, &record.key)?;
+        if let Some(existing) = existing.as_ref() {
+            // without a validator the incoming record replaces the existing one.
+            if existing.value != record.value && self.validators.contains(&key) {
+                let values = [existing.value.as_slice(), record.value.as_slice()];
+                match self.validators.select(&key, &values) {
+                    Ok(best) if best == record.value.as_slice() => {}
+                    _ => {
+                        // the store error can't express invalid records, the record is
+                        // dropped instead.
+                        tracing::debug!("dht store keeps the existing record");
+                        return Ok(Ok(()));
+                    }
+                }
+            }
+        } else {
+            if let Err(err) = self.validators.validate(&key, &record.value) {
+                tracing::debug!("dht store drops invalid record: {}", err);
+                return Ok(Ok(()));
+            }
+            let count: i64 =
+                conn.query_row("SELECT COUNT(*) FROM kad_records", params![], |row| {
+                    row.get(0)
+                })?;
+            if count as usize >= self.config.max_records {
+                return Ok(Err(Error::MaxRecords));
+            }
+        }
+        conn.execute(
+            "INSERT OR REPLACE INTO kad_records (key, value, publisher, expires) VALUES (?, ?, ?, ?)",
+            params![
+                key,
+                record.value,
+                record.publisher.as_ref().map(|p| p.to_string()),
+                record.expires.map(to_unix),
+            ],
+        )?;
+        Ok(Ok(()))
+    }
+
+    fn all_records(&self) -> rusqlite::Result<Vec<Record>> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare(
+                "SELECT key, value, publisher, expires FROM kad_records \
+                 WHERE expires IS NULL OR expires > ?",
+            )?;
+            let rows = stmt.query_map(params![unix_now()], |row| {
+                let mut record = Record::new(Key::from(row.get::<_, Vec<u8>>(0)?), row.get(1)?);
+                record.publisher = row.get::<_, Option<String>>(2)?.and_then(parse_peer);
+                record.expires = row.get::<_, Option<i64>>(3)?.map(to_instant);
+                Ok(record)
+            })?;
+            rows.collect()
+        })
+    }
+
+    fn add_provider_record(&self, record: &ProviderRecord) -> rusqlite::Result<Result<()>> {
+        self.with_conn(|conn| self.add_provider_record_with(conn, record))
+    }
+
+    fn add_provider_record_with(
+        &self,
+        conn: &Connection,
+        record: &ProviderRecord,
+    ) -> rusqlite::Result<Result<()>> {
+        remove_expired(conn)?;
+        let key = record.key.to_vec();
+        let provider = record.provider.to_string();
+        let exists: bool = conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM kad_providers WHERE key = ? AND provider = ?)",
+            params![key, provider],
+            |row| row.get(0),
+        )?;
+        if !exists {
+            if record.provider == self.local_key {
+                let count: i64 = conn.query_row(
+                    "SELECT COUNT(*) FROM kad_providers WHERE provider = ?",
+                    params![provider],
+                    |row| row.get(0),
+                )?;
+                if count as usize >= self.config.max_provided_keys {
+                    return Ok(Err(Error::MaxProvidedKeys));
+                }
+            }
+            let count: i64 = conn.query_row(
+                "SELECT COUNT(*) FROM kad_providers WHERE key = ?",
+                params![key],
+                |row| row.get(0),
+            )?;
+            if count as usize >= self.config.max_providers_per_key {
+                // the provider set of this key is full, the remote provider expiring first
+                // makes room for a local provider or for one that expires later.
+                let oldest: Option<(String, Option<i64>)> = conn
+                    .query_row(
+                        "SELECT provider, expires FROM kad_providers \
+                         WHERE key = ? AND provider != ? \
+                         ORDER BY expires IS NULL, expires LIMIT 1",
+                        params![key, self.local_key.to_string()],
+                        |row| Ok((row.get(0)?, row.get(1)?)),
+                    )
+                    .optional()?;
+                let expires = record.expires.map(to_unix);
+                let replace = match oldest.as_ref() {
+                    Some(_) if record.provider == self.local_key => true,
+                    Some((_, Some(oldest))) => expires.map(|e| e > *oldest).unwrap_or(true),
+                    _ => false,
+                };
+                match oldest {
+                    Some((oldest, _)) if replace => {
+                        conn.execute(
+                            "DELETE FROM kad_providers WHERE key = ? AND provider = ?",
+                            params![key, oldest],
+                        )?;
+                    }
+                    _ => {
+                        tracing::debug!("dht store drops a provider of a full provider set");
+                        return Ok(Ok(()));
+                    }
+                }
+            }
+        }
+        conn.execute(
+            "INSERT OR REPLACE INTO kad_providers (key, provider, expires) VALUES (?, ?, ?)",
+            params![key, provider, record.expires.map(to_unix)],
+        )?;
+        Ok(Ok(()))
+    }
+
+    fn query_providers(
+        &self,
+        sql: &str,
+        arg: &dyn rusqlite::ToSql,
+    ) -> rusqlite::Result<Vec<ProviderRecord>> {
+        let rows = self.with_conn(|conn| {
+            let mut stmt = conn.prepare(sql)?;
+            let rows = stmt.query_map(params![arg, unix_now()], |row| {
+                Ok((
+                    row.get::<_, Vec<u8>>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Option<i64>>(2)?,
+                ))
+            })?;
+            rows.collect::<rusqlite::Result<Vec<_>>>()
+        })?;
+        let mut records = vec![];
+        for row in rows {
+            let (key, provider, expires) = row;
+            if let Some(provider) = parse_peer(provider) {
+                let mut record = ProviderRecord::new(Key::from(key), provider);
+                record.expires = expires.map(to_instant);
+                records.push(record);
+            }
+        }
+        Ok(records)
+    }
+}
+
+fn remove_expired(conn: &Connection) -> rusqlite::Result<()> {
+    let now = unix_now();
+    conn.execute("DELETE FROM kad_records WHERE expires <= ?", params![now])?;
+    conn.execute("DELETE FROM kad_providers WHERE expires <= ?", params![now])?;
+    Ok(())
+}
+
+fn get_record(conn: &Connection, key: &Key) -> rusqlite::Result<Option<Record>> {
+    conn.query_row(
+        "SELECT value, publisher, expires FROM kad_records \
+         WHERE key = ? AND (expires IS NULL OR expires > ?)",
+        params![key.to_vec(), unix_now()],
+        |row| {
+            let mut record = Record::new(key.clone(), row.get(0)?);
+            record.publisher = row.get::<_, Option<String>>(1)?.and_then(parse_peer);
+            record.expires = row.get::<_, Option<i64>>(2)?.map(to_instant);
+            Ok(record)
+        },
+    )
+    .optional()
+}
+
+impl<'a> RecordStore<'a> for SqliteRecordStore {
+    type RecordsIter = RecordsIter<'a>;
+    type ProvidedIter = ProvidedIter<'a>;
+
+    fn get(&'a self, k: &Key) -> Option<Cow<'_, Record>> {
+        self.local_record(k).map(Cow::Owned)
+    }
+
+    fn put(&'a mut self, r: Record) -> Result<()> {
+        self.write_err("put", self.put_record(&r))
+            .unwrap_or(Err(Error::MaxRecords))
+    }
+
+    fn remove(&'a mut self, k: &Key) {
+        self.write_err(
+            "remove",
+            self.with_conn(|conn| {
+                conn.execute("DELETE FROM kad_records WHERE key = ?", params![k.to_vec()])
+            }),
+        );
+    }
+
+    fn records(&'a self) -> Self::RecordsIter {
+        let records = log_err("records", self.all_records()).unwrap_or_default();
+        records
+            .into_iter()
+            .map(Cow::Owned as fn(Record) -> Cow<'a, Record>)
+    }
+
+    fn add_provider(&'a mut self, record: ProviderRecord) -> Result<()> {
+        self.write_err("add_provider", self.add_provider_record(&record))
+            .unwrap_or(Err(Error::MaxProvidedKeys))
+    }
+
+    fn providers(&'a self, key: &Key) -> Vec<ProviderRecord> {
+        let key = key.to_vec();
+        log_err(
+            "providers",
+            self.query_providers(
+                "SELECT key, provider, expires FROM kad_providers \
+                 WHERE key = ? AND (expires IS NULL OR expires > ?)",
+                &key,
+            ),
+        )
+        .unwrap_or_default()
+    }
+
+    fn provided(&'a self) -> Self::ProvidedIter {
+        let local_key = self.local_key.to_string();
+        let records = log_err(
+            "provided",
+            self.query_providers(
+                "SELECT key, provider, expires FROM kad_providers \
+                 WHERE provider = ? AND (expires IS NULL OR expires > ?)",
+                &local_key,
+            ),
+        )
+        .unwrap_or_default();
+        records
+            .into_iter()
+            .map(Cow::Owned as fn(ProviderRecord) -> Cow<'a, ProviderRecord>)
+    }
+
+    fn remove_provider(&'a mut self, k: &Key, p: &PeerId) {
+        self.write_err(
+            "remove_provider",
+            self.with_conn(|conn| {
+                conn.execute(
+                    "DELETE FROM kad_providers WHERE key = ? AND provider = ?",
+                    params![k.to_vec(), p.to_string()],
+                )
+            }),
+        );
+    }
+}
+
+fn migrate(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS ipfs_embed_migrations (
+            name TEXT PRIMARY KEY,
+            version INTEGER NOT NULL
+        );",
+    )?;
+    let version: i64 = conn
+        .query_row(
+            "SELECT version FROM ipfs_embed_migrations WHERE name = 'kad'",
+            params![],
+            |row| row.get(0),
+        )
+        .optional()?
+        .unwrap_or(0);
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        tracing::debug!("migrating dht store to version {}", i + 1);
+        // dropping the transaction on error rolls the migration back
+        let tx = conn.unchecked_transaction()?;
+        tx.execute_batch(migration)?;
+        tx.execute(
+            "INSERT OR REPLACE INTO ipfs_embed_migrations (name, version) VALUES ('kad', ?)",
+            params![(i + 1) as i64],
+        )?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+fn log_err<T>(op: &str, res: rusqlite::Result<T>) -> Option<T> {
+    match res {
+        Ok(res) => Some(res),
+        Err(err) => {
+            tracing::error!("dht store {} failed: {}", op, err);
+            None
+        }
+    }
+}
+
+fn parse_peer(peer: String) -> Option<PeerId> {
+    peer.parse().ok()
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+fn to_unix(instant: Instant) -> i64 {
+    let now = Instant::now();
+    if instant > now {
+        unix_now() + (instant - now).as_secs() as i64
+    } else {
+        unix_now() - (now - instant).as_secs() as i64
+    }
+}
+
+fn to_instant(unix: i64) -> Instant {
+    let now = unix_now();
+    if unix > now {
+        Instant::now() + Duration::from_secs((unix - now) as u64)
+    } else {
+        Instant::now()
+            .checked_sub(Duration::from_secs((now - unix) as u64))
+            .unwrap_or_else(Instant::now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_records_persist() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kad.sqlite");
+        let local = PeerId::random();
+        let key = Key::from(b"key".to_vec());
+        {
+            let mut store = SqliteRecordStore::open(
+                local.clone(),
+                &path,
+                Default::default(),
+                Default::default(),
+            )
+            .unwrap();
+            store
+                .put(Record::new(key.clone(), b"value".to_vec()))
+                .unwrap();
+            store
+                .add_provider(ProviderRecord::new(key.clone(), local.clone()))
+                .unwrap();
+        }
+        let store =
+            SqliteRecordStore::open(local, &path, Default::default(), Default::default()).unwrap();
+        assert_eq!(store.get(&key).unwrap().value, b"value".to_vec());
+        assert_eq!(store.providers(&key).len(), 1);
+        assert_eq!(store.provided().count(), 1);
+    }
+
+    #[test]
+    fn test_limits() {
+        let config = KadStoreConfig {
+            max_records: 1,
+            max_value_bytes: 4,
+            ..Default::default()
+        };
+        let mut store =
+            SqliteRecordStore::memory(PeerId::random(), config, Default::default()).unwrap();
+        let big = Record::new(Key::from(b"a".to_vec()), b"12345".to_vec());
+        assert!(matches!(store.put(big), Err(Error::ValueTooLarge)));
+        store
+            .put(Record::new(Key::from(b"a".to_vec()), b"1".to_vec()))
+            .unwrap();
+        let second = Record::new(Key::from(b"b".to_vec()), b"1".to_vec());
+        assert!(matches!(store.put(second), Err(Error::MaxRecords)));
+    }
+
+    #[test]
+    fn test_replace_record() {
+        let mut store =
+            SqliteRecordStore::memory(PeerId::random(), Default::default(), Default::default())
+                .unwrap();
+        let key = Key::from(b"key".to_vec());
+        store
+            .put(Record::new(key.clone(), b"first".to_vec()))
+            .unwrap();
+        store
+            .put(Record::new(key.clone(), b"second".to_vec()))
+            .unwrap();
+        assert_eq!(store.get(&key).unwrap().value, b"second".to_vec());
+    }
+
+    #[test]
+    fn test_full_provider_set() {
+        let local = PeerId::random();
+        let config = KadStoreConfig {
+            max_providers_per_key: 2,
+            ..Default::default()
+        };
+        let mut store =
+            SqliteRecordStore::memory(local.clone(), config, Default::default()).unwrap();
+        let key = Key::from(b"key".to_vec());
+        let provider = |secs: u64| {
+            let mut record = ProviderRecord::new(key.clone(), PeerId::random());
+            record.expires = Some(Instant::now() + Duration::from_secs(secs));
+            record
+        };
+        let (a, b) = (provider(100), provider(200));
+        store.add_provider(a.clone()).unwrap();
+        store.add_provider(b.clone()).unwrap();
+        // expires before every stored provider
+        store.add_provider(provider(50)).unwrap();
+        let providers = |store: &SqliteRecordStore| {
+            store
+                .providers(&key)
+                .into_iter()
+                .map(|record| record.provider)
+                .collect::<HashSet<_>>()
+        };
+        let expected = vec![a.provider.clone(), b.provider.clone()];
+        assert_eq!(providers(&store), expected.into_iter().collect());
+
+        // replaces `a`, which expires first
+        let c = provider(300);
+        store.add_provider(c.clone()).unwrap();
+        let expected = vec![b.provider.clone(), c.provider.clone()];
+        assert_eq!(providers(&store), expected.into_iter().collect());
+
+        // the local provider is always stored
+        store
+            .add_provider(ProviderRecord::new(key.clone(), local.clone()))
+            .unwrap();
+        let expected = vec![c.provider, local];
+        assert_eq!(providers(&store), expected.into_iter().collect());
+    }
+
+    #[test]
+    fn test_sqlite_error() {
+        let mut store =
+            SqliteRecordStore::memory(PeerId::random(), Default::default(), Default::default())
+                .unwrap();
+        store
+            .with_conn(|conn| conn.execute_batch("DROP TABLE kad_records"))
+            .unwrap();
+        assert!(store.take_error().is_none());
+        let record = Record::new(Key::from(b"a".to_vec()), b"1".to_vec());
+        assert!(store.put(record).is_err());
+        assert!(store.take_error().is_some());
+        assert!(store.take_error().is_none());
+    }
+
+    #[test]
+    fn test_close() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kad.sqlite");
+        let mut store = SqliteRecordStore::open(
+            PeerId::random(),
+            &path,
+            Default::default(),
+            Default::default(),
+        )
+        .unwrap();
+        let key = Key::from(b"a".to_vec());
+        store.put(Record::new(key.clone(), b"1".to_vec())).unwrap();
+        store.close();
+        assert!(store.get(&key).is_none());
+        assert!(store.put(Record::new(key.clone(), b"2".to_vec())).is_err());
+        let store = SqliteRecordStore::open(
+            PeerId::random(),
+            &path,
+            Default::default(),
+            Default::default(),
+        )
+        .unwrap();
+        assert_eq!(store.get(&key).unwrap().value, b"1".to_vec());
+    }
+}
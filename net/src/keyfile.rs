@@ -0,0 +1,226 @@
+use libp2p_rs::core::identity::{ed25519, secp256k1, Keypair};
+use prost::Message;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use thiserror::Error;
+
+/// Protobuf encoding of a private key, compatible with the `PrivateKey` message of the
+/// libp2p `keys.proto`.
+#[derive(Clone, PartialEq, Message)]
+struct PrivateKey {
+    #[prost(enumeration = "KeyType", required, tag = "1")]
+    r#type: i32,
+    #[prost(bytes, required, tag = "2")]
+    data: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+enum KeyType {
+    Rsa = 0,
+    Ed25519 = 1,
+    Secp256k1 = 2,
+    Ecdsa = 3,
+}
+
+#[derive(Debug, Error)]
+pub enum KeyfileError {
+    #[error("Error accessing keyfile {0}")]
+    Io(#[from] io::Error),
+    #[error("Error while decoding keyfile {0}")]
+    Protobuf(#[from] prost::DecodeError),
+    #[error("Unsupported key type {0}")]
+    UnsupportedKeyType(i32),
+    #[error("Invalid key {0}")]
+    InvalidKey(String),
+}
+
+/// The der encoded `AlgorithmIdentifier` of rsaEncryption with null parameters.
+const RSA_ALGORITHM: [u8; 15] = [
+    0x30, 0x0d, 0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01, 0x05, 0x00,
+];
+
+/// Appends a der length.
+fn der_len(buf: &mut Vec<u8>, len: usize) {
+    if len < 0x80 {
+        buf.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let skip = bytes.iter().take_while(|b| **b == 0).count();
+        buf.push(0x80 | (bytes.len() - skip) as u8);
+        buf.extend_from_slice(&bytes[skip..]);
+    }
+}
+
+/// Wraps a pkcs1 `RSAPrivateKey` in a pkcs8 `PrivateKeyInfo`.
+fn rsa_pkcs1_to_pkcs8(pkcs1: &[u8]) -> Vec<u8> {
+    let mut key = vec![0x04];
+    der_len(&mut key, pkcs1.len());
+    key.extend_from_slice(pkcs1);
+    let len = 3 + RSA_ALGORITHM.len() + key.len();
+    let mut buf = Vec::with_capacity(len + 4);
+    buf.push(0x30);
+    der_len(&mut buf, len);
+    buf.extend_from_slice(&[0x02, 0x01, 0x00]);
+    buf.extend_from_slice(&RSA_ALGORITHM);
+    buf.extend_from_slice(&key);
+    buf
+}
+
+fn encode_private_key(key_type: KeyType, data: Vec<u8>) -> Vec<u8> {
+    let key = PrivateKey {
+        r#type: key_type as i32,
+        data,
+    };
+    let mut buf = Vec::with_capacity(key.encoded_len());
+    key.encode(&mut buf)
+        .expect("Vec<u8> provides capacity as needed");
+    buf
+}
+
+/// Encodes a keypair using the libp2p protobuf encoding.
+///
+/// The secret of an rsa keypair can't be extracted, use `encode_rsa_keypair` with the
+/// pkcs1 der encoded private key instead.
+pub fn encode_keypair(keypair: &Keypair) -> Result<Vec<u8>, KeyfileError> {
+    let (key_type, data) = match keypair {
+        Keypair::Ed25519(keypair) => (KeyType::Ed25519, keypair.encode().to_vec()),
+        Keypair::Secp256k1(keypair) => (KeyType::Secp256k1, keypair.secret().to_bytes().to_vec()),
+        Keypair::Rsa(_) => return Err(KeyfileError::UnsupportedKeyType(KeyType::Rsa as i32)),
+    };
+    Ok(encode_private_key(key_type, data))
+}
+
+/// Encodes an rsa private key in pkcs1 der format, the encoding used by other libp2p
+/// implementations, using the libp2p protobuf encoding.
+pub fn encode_rsa_keypair(pkcs1: &[u8]) -> Result<Vec<u8>, KeyfileError> {
+    Keypair::rsa_from_pkcs8(&mut rsa_pkcs1_to_pkcs8(pkcs1))
+        .map_err(|e| KeyfileError::InvalidKey(e.to_string()))?;
+    Ok(encode_private_key(KeyType::Rsa, pkcs1.to_vec()))
+}
+
+/// Decodes a keypair from the libp2p protobuf encoding.
+///
+/// Ed25519 keys are expected as the 64 byte secret and public key, secp256k1 keys as the
+/// 32 byte secret and rsa keys in pkcs1 der format.
+pub fn decode_keypair(bytes: &[u8]) -> Result<Keypair, KeyfileError> {
+    let mut key = PrivateKey::decode(bytes)?;
+    let invalid = |e: libp2p_rs::core::identity::error::DecodingError| {
+        KeyfileError::InvalidKey(e.to_string())
+    };
+    let keypair = match KeyType::from_i32(key.r#type) {
+        Some(KeyType::Ed25519) => {
+            Keypair::Ed25519(ed25519::Keypair::decode(&mut key.data).map_err(invalid)?)
+        }
+        Some(KeyType::Secp256k1) => {
+            let secret = secp256k1::SecretKey::from_bytes(&mut key.data).map_err(invalid)?;
+            Keypair::Secp256k1(secp256k1::Keypair::from(secret))
+        }
+        Some(KeyType::Rsa) => {
+            let keypair = Keypair::rsa_from_pkcs8(&mut rsa_pkcs1_to_pkcs8(&key.data));
+            key.data.iter_mut().for_each(|b| *b = 0);
+            keypair.map_err(invalid)?
+        }
+        _ => return Err(KeyfileError::UnsupportedKeyType(key.r#type)),
+    };
+    Ok(keypair)
+}
+
+/// Reads the keypair stored at `path`.
+pub fn read_keypair(path: &Path) -> Result<Keypair, KeyfileError> {
+    let bytes = fs::read(path)?;
+    decode_keypair(&bytes)
+}
+
+/// Writes the keypair to a new file at `path`. The file is only readable and writable by
+/// the current user.
+pub fn write_keypair(path: &Path, keypair: &Keypair) -> Result<(), KeyfileError> {
+    write_keyfile(path, &encode_keypair(keypair)?)
+}
+
+/// Writes an rsa private key in pkcs1 der format to a new file at `path`. The file is only
+/// readable and writable by the current user.
+pub fn write_rsa_keypair(path: &Path, pkcs1: &[u8]) -> Result<(), KeyfileError> {
+    write_keyfile(path, &encode_rsa_keypair(pkcs1)?)
+}
+
+fn write_keyfile(path: &Path, bytes: &[u8]) -> Result<(), KeyfileError> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Reads the keypair stored at `path` or generates a new ed25519 keypair and writes it to
+/// `path` if the file doesn't exist.
+pub fn load_or_generate_keypair(path: &Path) -> Result<Keypair, KeyfileError> {
+    match read_keypair(path) {
+        Ok(keypair) => Ok(keypair),
+        Err(KeyfileError::Io(err)) if err.kind() == io::ErrorKind::NotFound => {
+            let keypair = Keypair::generate_ed25519();
+            write_keypair(path, &keypair)?;
+            tracing::info!("generated new keypair at {}", path.display());
+            Ok(keypair)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RSA_PKCS1: &[u8] = include_bytes!("../test-data/rsa-2048.pk1.der");
+    const RSA_PKCS8: &[u8] = include_bytes!("../test-data/rsa-2048.pk8.der");
+
+    #[test]
+    fn test_roundtrip() {
+        let secp256k1 = Keypair::Secp256k1(secp256k1::Keypair::generate());
+        for keypair in &[Keypair::generate_ed25519(), secp256k1] {
+            let decoded = decode_keypair(&encode_keypair(keypair).unwrap()).unwrap();
+            assert_eq!(decoded.public(), keypair.public());
+        }
+    }
+
+    #[test]
+    fn test_rsa() {
+        assert_eq!(rsa_pkcs1_to_pkcs8(RSA_PKCS1), RSA_PKCS8);
+        let keypair = Keypair::rsa_from_pkcs8(&mut RSA_PKCS8.to_vec()).unwrap();
+        let decoded = decode_keypair(&encode_rsa_keypair(RSA_PKCS1).unwrap()).unwrap();
+        assert_eq!(decoded.public(), keypair.public());
+        assert!(encode_keypair(&decoded).is_err());
+        assert!(encode_rsa_keypair(RSA_PKCS8).is_err());
+    }
+
+    #[test]
+    fn test_keyfile() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys").join("keypair");
+        let keypair = load_or_generate_keypair(&path).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let loaded = load_or_generate_keypair(&path).unwrap();
+        assert_eq!(loaded.public(), keypair.public());
+        assert!(write_keypair(&path, &keypair).is_err());
+
+        let path = dir.path().join("rsa");
+        write_rsa_keypair(&path, RSA_PKCS1).unwrap();
+        let keypair = Keypair::rsa_from_pkcs8(&mut RSA_PKCS8.to_vec()).unwrap();
+        assert_eq!(read_keypair(&path).unwrap().public(), keypair.public());
+    }
+}
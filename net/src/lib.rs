@@ -3,71 +3,126 @@ use libipld::Result;
 use std::time::Duration;
 
 mod config;
+mod connmgr;
+mod events;
+mod kad_mode;
+mod kad_store;
+mod keyfile;
+mod name;
+mod notify;
+mod peer_info;
+mod pubsub;
+mod transport;
+mod validator;
 
 pub use libp2p_rs::core::identity::Keypair;
 pub use libp2p_rs::core::{Multiaddr, PeerId, ProtocolId};
+pub use libp2p_rs::core::multiaddr::protocol::Protocol;
 pub use libp2p_rs::kad::record::{Key, Record};
-pub use libp2p_rs::floodsub::subscription::Subscription;
-pub use libp2p_rs::floodsub::Topic;
 pub use libp2p_rs::xcli;
 pub use libp2p_rs::swarm::cli::swarm_cli_commands;
 pub use libp2p_rs::kad::cli::dht_cli_commands;
 
 use libp2p_rs::swarm::{Control as SwarmControl, Swarm};
 use libp2p_rs::kad::Control as KadControl;
+use libp2p_rs::kad::store::RecordStore;
 //use libp2p_rs::mdns::control::Control as MdnsControl;
-use libp2p_rs::floodsub::control::Control as FloodsubControl;
 use bitswap::Control as BitswapControl;
 
-use libp2p_rs::kad::store::MemoryStore;
 use libp2p_rs::swarm::identify::IdentifyConfig;
 use libp2p_rs::swarm::ping::PingConfig;
 use libp2p_rs::kad::kad::{KademliaConfig, Kademlia};
 use libp2p_rs::floodsub::FloodsubConfig;
 use libp2p_rs::floodsub::floodsub::FloodSub;
-use libp2p_rs::{noise, yamux, mplex, secio};
-use libp2p_rs::core::upgrade::Selector;
-use libp2p_rs::core::transport::upgrade::TransportUpgrade;
-use libp2p_rs::tcp::TcpConfig;
 
 use bitswap::Bitswap;
+use gossipsub::Gossipsub;
+use relay::{RelayClient, RelayControl, RelayServer};
+use autonat::{Autonat, AutonatControl};
 
 pub use crate::config::NetworkConfig;
+pub use crate::events::{EventWatcher, Lagged, NetworkEvent, NetworkEventStream, NetworkEvents};
+pub use crate::peer_info::PeerInfo;
+pub use crate::transport::{MuxerConfig, SecurityConfig, TransportConfig};
+use crate::kad_mode::{KadMode, KadModeControl};
+use crate::notify::WithNotifiees;
+use crate::transport::build_transports;
+pub use crate::connmgr::{
+    ConnectionLimitError, ConnectionLimits, ConnectionManager, ConnectionManagerConfig,
+    ConnectionManagerControl, PendingDial,
+};
+pub use crate::pubsub::{
+    Floodsub, GossipsubConfig, PubsubConfig, PubsubControl, PubsubMessage, Subscription,
+    TopicValidator, ValidationResult,
+};
+pub use crate::kad_store::{KadStoreConfig, SqliteRecordStore};
+pub use crate::name::{NameRecord, NameValidator, DEFAULT_NAME, NAME_NAMESPACE};
+pub use crate::validator::{
+    namespace, PublicKeyValidator, RecordError, RecordValidator, RecordValidators, SignedRecord,
+    SignedRecordValidator, PUBLIC_KEY_NAMESPACE, SIGNED_NAMESPACE,
+};
+pub use crate::keyfile::{
+    decode_keypair, encode_keypair, encode_rsa_keypair, load_or_generate_keypair, read_keypair,
+    write_keypair, write_rsa_keypair, KeyfileError,
+};
 pub use bitswap::{BitswapStore};
-use libp2p_rs::dns::DnsConfig;
-use libp2p_rs::core::Transport;
+pub use relay::{circuit_addr, RelayServerConfig};
+pub use autonat::{AutonatConfig, NatStatus};
 
 
 #[derive(Clone)]
 pub struct NetworkService {
     swarm: SwarmControl,
     kad: KadControl,
-    pubsub: FloodsubControl,
+    pubsub: PubsubControl,
     // mdns: MdnsControl,
     bitswap: BitswapControl,
+    connmgr: ConnectionManagerControl,
+    relay: RelayControl,
+    autonat: AutonatControl,
+    kad_mode: KadModeControl,
+    events: NetworkEvents,
+    records: SqliteRecordStore,
+    validators: RecordValidators,
 }
 
 impl NetworkService {
     pub async fn new<S: BitswapStore>(config: NetworkConfig, repo: S) -> Result<Self> {
-        let sec_secio = secio::Config::new(config.node_key.clone());
-        // Set up an encrypted TCP transport over the Yamux or Mplex protocol.
-        let xx_keypair = noise::Keypair::<noise::X25519Spec>::new()
-            .into_authentic(&config.node_key)
-            .unwrap();
-        let sec_noise = noise::NoiseConfig::xx(xx_keypair, config.node_key.clone());
-        let sec = Selector::new(sec_noise, sec_secio);
-
-        let mux = Selector::new(yamux::Config::new(), mplex::Config::new());
-        let transport = TcpConfig::new().nodelay(true).outbound_timeout(Duration::from_secs(10));
-        let tu = TransportUpgrade::new(DnsConfig::new(transport), mux, sec);
+        // `/memory/<n>` addresses need the memory transport
+        let mut transport = config.transport.clone();
+        transport.enable_memory_for(
+            config
+                .listening_addrs
+                .iter()
+                .chain(config.bootstrap.iter().map(|(_, addr)| addr))
+                .chain(config.relays.iter().map(|(_, addr)| addr)),
+        );
+        let relay_client = RelayClient::new(config.relays.clone());
+        let transports = build_transports(
+            &transport,
+            &config.security,
+            &config.muxer,
+            &config.node_key,
+            relay_client.transport(),
+        )?;
 
         // Make swarm
-        let mut swarm = Swarm::new(config.node_key.public())
-            .with_transport(Box::new(tu))
+        let mut swarm = Swarm::new(config.node_key.public());
+        for transport in transports.swarm {
+            swarm = swarm.with_transport(transport);
+        }
+        swarm = swarm
             .with_ping(PingConfig::new())
-            .with_identify(IdentifyConfig::new(false));
+            .with_identify(
+                IdentifyConfig::new(false)
+                    .with_agent_version(format!("{} ({})", config.agent_version, config.node_name)),
+            );
 
-        swarm.listen_on(config.listening_addrs)?;
+        let mut listening_addrs = config.listening_addrs;
+        if !config.relays.is_empty() {
+            listening_addrs.push("/p2p-circuit".parse()?);
+        }
+        swarm.listen_on(listening_addrs)?;
 
         let swarm_control = swarm.control();
 
@@ -76,29 +131,93 @@ impl NetworkService {
         // build Kad
         let kad_config = KademliaConfig::default().with_query_timeout(Duration::from_secs(90));
 
-        let store = MemoryStore::new(swarm.local_peer_id().clone());
+        let local_peer_id = swarm.local_peer_id().clone();
+        let validators = RecordValidators::default();
+        let store = if let Some(path) = config.kad_store_path.as_ref() {
+            SqliteRecordStore::open(local_peer_id, path, config.kad_store.clone(), validators.clone())?
+        } else {
+            SqliteRecordStore::memory(local_peer_id, config.kad_store.clone(), validators.clone())?
+        };
+        let records = store.clone();
         let kad = Kademlia::with_config(swarm.local_peer_id().clone(), store, kad_config);
 
         let mut kad_control = kad.control();
 
+        // autonat switches the dht between server mode and client mode
+        let kad = KadMode::new(kad);
+        let kad_mode = kad.control();
+
         // update Swarm to support Kad and Routing
         swarm = swarm.with_protocol(kad).with_routing(Box::new(kad_control.clone()));
 
-        let mut floodsub_config = FloodsubConfig::new(swarm.local_peer_id().clone());
-        floodsub_config.subscribe_local_messages = true;
+        let pubsub_control = match config.pubsub {
+            PubsubConfig::Floodsub => {
+                let mut floodsub_config = FloodsubConfig::new(swarm.local_peer_id().clone());
+                floodsub_config.subscribe_local_messages = true;
+
+                let floodsub = FloodSub::new(floodsub_config);
+                let floodsub_control = floodsub.control();
 
-        let floodsub = FloodSub::new(floodsub_config);
-        let floodsub_control = floodsub.control();
+                // register floodsub into Swarm
+                swarm = swarm.with_protocol(floodsub);
+                PubsubControl::Floodsub(Floodsub::new(floodsub_control, config.node_key.clone()))
+            }
+            PubsubConfig::Gossipsub(gossipsub_config) => {
+                let gossipsub = Gossipsub::new(config.node_key.clone(), gossipsub_config);
+                let gossipsub_control = gossipsub.control();
 
-        // register floodsub into Swarm
-        swarm = swarm.with_protocol(floodsub);
+                // register gossipsub into Swarm
+                swarm = swarm.with_protocol(gossipsub);
+                PubsubControl::Gossipsub(gossipsub_control)
+            }
+        };
+
+        // connection manager
+        let connmgr = ConnectionManager::new(
+            config.connection_manager.clone(),
+            config.connection_limits.clone(),
+        );
+        let connmgr_control = connmgr.control();
+        for (peer, _) in &config.bootstrap {
+            connmgr_control.protect(peer, "bootstrap");
+        }
+        for (peer, _) in &config.relays {
+            connmgr_control.protect(peer, "relay");
+        }
+
+        // autonat switches the dht to client mode while the node isn't reachable
+        let dht_mode = kad_mode.clone();
+        let autonat = Autonat::new(config.autonat.clone(), local_peer_id, transports.dial_back)
+            .with_status_listener(move |status| {
+                dht_mode.set_server_mode(status != NatStatus::Private)
+            });
+        let autonat_control = autonat.control();
+        swarm = swarm.with_protocol(autonat);
+
+        // network events, observed addresses become autonat candidates and the peers added
+        // to the dht routing table are reported as discovered
+        let candidates = autonat_control.clone();
+        let watcher = EventWatcher::default()
+            .with_observed_addr_listener(move |addr| candidates.add_candidate(addr))
+            .with_routing_table(kad_control.clone());
+        let events = watcher.control();
 
         // bitswap
         let bitswap = Bitswap::new(repo, kad_control.clone());
         let bitswap_control = bitswap.control();
 
-        // register bitswap into Swarm
-        swarm = swarm.with_protocol(bitswap);
+        // register bitswap into Swarm, the connection manager and the event watcher are
+        // notified along with it
+        swarm = swarm.with_protocol(WithNotifiees::new(bitswap, vec![connmgr.notifiee(), watcher.notifiee()]));
+        let _ = connmgr.start(swarm_control.clone());
+        let _ = watcher.start(swarm_control.clone());
+
+        // relay
+        let relay_control = relay_client.control();
+        swarm = swarm.with_protocol(relay_client);
+        if let Some(relay_server_config) = config.relay_server.clone() {
+            swarm = swarm.with_protocol(RelayServer::new(relay_server_config));
+        }
 
         // To start Swarm/Kad/... main loops
         swarm.start();
@@ -106,24 +225,185 @@ impl NetworkService {
         // handle bootstrap nodes
         if !config.bootstrap.is_empty() {
             kad_control.bootstrap(config.bootstrap.clone()).await;
+            events.routing_updated();
         }
 
         Ok(NetworkService {
             swarm: swarm_control,
             kad: kad_control,
-            pubsub: floodsub_control,
+            pubsub: pubsub_control,
             bitswap: bitswap_control,
+            connmgr: connmgr_control,
+            relay: relay_control,
+            autonat: autonat_control,
+            kad_mode,
+            events,
             //mdns: ()
+            records,
+            validators,
         })
     }
 
     pub fn swarm(&self) -> SwarmControl { self.swarm.clone() }
     pub fn kad_mut(&mut self) -> &mut KadControl { &mut self.kad }
     pub fn kad(&self) -> KadControl { self.kad.clone() }
-    pub fn pubsub(&self) -> FloodsubControl { self.pubsub.clone() }
+
+    /// Bootstraps the dht, the peers it adds to the routing table are reported as
+    /// discovered.
+    pub async fn bootstrap(&self, nodes: Vec<(PeerId, Multiaddr)>) {
+        self.kad().bootstrap(nodes).await;
+        self.events.routing_updated();
+    }
+    pub fn pubsub(&self) -> PubsubControl { self.pubsub.clone() }
     pub fn bitswap(&self) -> BitswapControl { self.bitswap.clone() }
 
     pub fn bitswap_rd(&self) -> &BitswapControl { &self.bitswap }
+    pub fn connmgr(&self) -> ConnectionManagerControl { self.connmgr.clone() }
+
+    /// Returns the `/p2p-circuit` addresses of the relays holding a reservation for the
+    /// local peer.
+    pub fn relay_addrs(&self) -> Vec<Multiaddr> { self.relay.addrs() }
+    pub fn autonat(&self) -> AutonatControl { self.autonat.clone() }
+
+    /// Returns `true` if the dht answers the queries of remote peers, it runs in client mode
+    /// while autonat finds the node isn't reachable.
+    pub fn is_dht_server(&self) -> bool { self.kad_mode.is_server_mode() }
+
+    /// Switches the dht between server mode and client mode, until autonat switches it again
+    /// when the reachability of the node changes.
+    pub fn set_dht_server(&self, server: bool) { self.kad_mode.set_server_mode(server) }
+    pub fn events(&self) -> NetworkEvents { self.events.clone() }
+
+    /// Returns the identify info of a connected peer and the round trip time of a ping.
+    pub async fn peer_info(&self, peer: &PeerId) -> Option<PeerInfo> {
+        let mut info = self.events.peer_info(peer)?;
+        // the peer may have disconnected in the meantime, the ping must not dial it again
+        let connected = self.swarm().dump_connections(Some(*peer)).await
+            .map(|connections| !connections.is_empty())
+            .unwrap_or_default();
+        if !connected {
+            return None;
+        }
+        info.rtt = peer_info::ping(self.swarm(), *peer).await.ok();
+        Some(info)
+    }
+
+    /// Closes bitswap, waiting for its main loop to exit, then the other protocols, the
+    /// connection manager, the event watcher, the dht and its record store and the swarm.
+    pub async fn shutdown(&self) {
+        self.bitswap().close().await;
+        self.pubsub.close();
+        self.autonat().close();
+        self.relay.close();
+        self.connmgr.close();
+        self.events.close();
+        self.kad().close();
+        self.records.close();
+        self.swarm().close();
+    }
+
+    /// Bans a peer, closing its connections and refusing new ones until it is unbanned.
+    pub fn ban(&self, peer: &PeerId) {
+        self.connmgr.ban(peer);
+        self.events.emit(NetworkEvent::Banned(*peer));
+    }
+
+    /// Unbans a previously banned peer.
+    pub fn unban(&self, peer: &PeerId) {
+        if self.connmgr.unban(peer) {
+            self.events.emit(NetworkEvent::Unbanned(*peer));
+        }
+    }
+
+    /// Dials a `PeerId` using a known address, respecting bans and the pending dial limit.
+    pub async fn dial(&self, peer: &PeerId) -> Result<()> {
+        if self.connmgr.is_banned(peer) {
+            return Err(ConnectionLimitError::Banned.into());
+        }
+        let _pending = self.connmgr.pending_dial()?;
+        let _ = self.swarm().new_connection(*peer).await?;
+        Ok(())
+    }
+
+    /// Dials a `PeerId` using `Multiaddr`, respecting bans and the pending dial limit.
+    pub async fn dial_address(&self, peer: &PeerId, addr: Multiaddr) -> Result<()> {
+        if self.connmgr.is_banned(peer) {
+            return Err(ConnectionLimitError::Banned.into());
+        }
+        let _pending = self.connmgr.pending_dial()?;
+        let _ = self.swarm().connect_with_addrs(*peer, vec![addr]).await?;
+        Ok(())
+    }
+
+    /// Registers a record validator for a key namespace.
+    pub fn register_record_validator<V: RecordValidator>(&self, namespace: &str, validator: V) {
+        self.validators.register(namespace, validator)
+    }
+
+    /// Gets the best valid record for `key`, considering the locally stored record and all
+    /// the records the quorum of the dht query returned. The local record wins ties.
+    pub async fn get_record(&self, key: &[u8]) -> Result<Vec<u8>> {
+        let local = self.records.local_record(&Key::from(key.to_vec()));
+        let remote = match self.kad().get_record(Key::from(key.to_vec())).await {
+            Ok(records) => records,
+            Err(err) => {
+                tracing::debug!("get_record: {:?}", err);
+                vec![]
+            }
+        };
+        let mut values = vec![];
+        if let Some(record) = local.as_ref() {
+            values.push(record.value.as_slice());
+        }
+        values.extend(remote.iter().map(|peer_record| peer_record.record.value.as_slice()));
+        if values.is_empty() {
+            return Err(RecordError::NotFound.into());
+        }
+        Ok(self.validators.select(key, &values)?.to_vec())
+    }
+
+    /// Returns the locally stored record for `key`.
+    pub fn local_record(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.records
+            .local_record(&Key::from(key.to_vec()))
+            .map(|record| record.value)
+    }
+
+    /// Returns the keys the local node provides in the dht.
+    pub fn provided(&self) -> Vec<Vec<u8>> {
+        self.records
+            .provided()
+            .map(|record| record.key.to_vec())
+            .collect()
+    }
+
+    /// Validates a record and stores it in the local record store only. If a record for
+    /// `key` is already stored, the validator of its namespace selects which one is kept,
+    /// records of namespaces without a validator replace the stored record.
+    pub fn store_record(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.validators.validate(&key, &value)?;
+        let mut records = self.records.clone();
+        records
+            .put(Record::new(Key::from(key), value))
+            .map_err(|err| self.store_error(anyhow::anyhow!("dht store: {:?}", err)))
+    }
+
+    /// Validates a record and puts it in the dht.
+    pub async fn put_record(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.validators.validate(&key, &value)?;
+        match self.kad().put_value(key, value).await {
+            Ok(_) => Ok(()),
+            Err(err) => Err(self.store_error(err.into())),
+        }
+    }
+
+    /// Replaces the error of a failed dht store write with the sqlite error that caused it.
+    fn store_error(&self, err: anyhow::Error) -> anyhow::Error {
+        match self.records.take_error() {
+            Some(sqlite) => sqlite.into(),
+            None => err,
+        }
+    }
 
     //
     // pub fn register_metrics(&self, registry: &Registry) -> Result<()> {
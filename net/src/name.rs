@@ -0,0 +1,175 @@
+use crate::validator::{
+    check_publisher, decode_record, encode_record, select_max_by_key, sign_record, verify_record,
+    RecordError, RecordValidator,
+};
+use libipld::Result;
+use libp2p_rs::core::identity::Keypair;
+use libp2p_rs::core::PeerId;
+use prost::Message;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const SIGNING_PREFIX: &[u8] = b"ipfs-embed-name:";
+
+/// Namespace of mutable name records, `/ipns/<peer id>/<name>`.
+pub const NAME_NAMESPACE: &str = "ipns";
+/// The name resolved when only a peer id is given.
+pub const DEFAULT_NAME: &str = "self";
+
+/// A mutable name record pointing to a value, usually a `Cid`, signed by its publisher.
+#[derive(Clone, PartialEq, Message)]
+pub struct NameRecord {
+    /// Protobuf encoded public key of the publisher.
+    #[prost(bytes, tag = "1")]
+    pub key: Vec<u8>,
+    #[prost(bytes, tag = "2")]
+    pub signature: Vec<u8>,
+    #[prost(bytes, tag = "3")]
+    pub value: Vec<u8>,
+    /// Sequence number, records with a higher sequence number replace older records.
+    #[prost(uint64, tag = "4")]
+    pub sequence: u64,
+    /// Unix time in seconds after which the record is invalid.
+    #[prost(uint64, tag = "5")]
+    pub validity: u64,
+}
+
+impl NameRecord {
+    /// Returns the dht key of the name `name` published by `peer`.
+    pub fn key(peer: &PeerId, name: &str) -> Vec<u8> {
+        format!("/{}/{}/{}", NAME_NAMESPACE, peer, name).into_bytes()
+    }
+
+    /// Parses a name of the form `<peer id>`, `<peer id>/<name>` or `/ipns/<peer id>/<name>`
+    /// into the dht key of the name.
+    pub fn parse_key(name: &str) -> Result<Vec<u8>> {
+        let prefix = format!("/{}/", NAME_NAMESPACE);
+        let path = name.strip_prefix(prefix.as_str()).unwrap_or(name);
+        let mut parts = path.splitn(2, '/');
+        let peer: PeerId = parts
+            .next()
+            .unwrap_or_default()
+            .parse()
+            .map_err(|_| RecordError::MalformedKey)?;
+        let name = parts
+            .next()
+            .filter(|name| !name.is_empty())
+            .unwrap_or(DEFAULT_NAME);
+        Ok(Self::key(&peer, name))
+    }
+
+    /// Creates a new record for the dht `key` signed with `keypair`, valid for `ttl`.
+    pub fn new(
+        keypair: &Keypair,
+        key: &[u8],
+        value: Vec<u8>,
+        sequence: u64,
+        ttl: Duration,
+    ) -> Result<Self> {
+        let validity = unix_now() + ttl.as_secs();
+        let fields: [&[u8]; 3] = [&sequence.to_be_bytes(), &validity.to_be_bytes(), &value];
+        let (public, signature) = sign_record(keypair, SIGNING_PREFIX, key, &fields)?;
+        Ok(Self {
+            key: public,
+            signature,
+            value,
+            sequence,
+            validity,
+        })
+    }
+
+    /// Decodes a record.
+    pub fn decode_record(bytes: &[u8]) -> Result<Self> {
+        decode_record(bytes)
+    }
+
+    /// Encodes the record.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        encode_record(self)
+    }
+
+    /// Returns `true` if the validity of the record has passed.
+    pub fn is_expired(&self) -> bool {
+        self.validity <= unix_now()
+    }
+
+    /// Returns the publisher of the record after checking the signature.
+    pub fn verify(&self, key: &[u8]) -> Result<PeerId> {
+        let fields: [&[u8]; 3] = [
+            &self.sequence.to_be_bytes(),
+            &self.validity.to_be_bytes(),
+            &self.value,
+        ];
+        verify_record(&self.key, &self.signature, SIGNING_PREFIX, key, &fields)
+    }
+}
+
+/// Validates `/ipns/<peer id>/<name>` records, the value is a `NameRecord` signed by the peer
+/// that hasn't expired. The record with the highest sequence number is selected, ties are
+/// broken by the longest validity.
+pub struct NameValidator;
+
+impl RecordValidator for NameValidator {
+    fn validate(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let record = NameRecord::decode_record(value)?;
+        check_publisher(key, &record.verify(key)?)?;
+        if record.is_expired() {
+            return Err(RecordError::Expired.into());
+        }
+        Ok(())
+    }
+
+    fn select(&self, _key: &[u8], values: &[&[u8]]) -> usize {
+        select_max_by_key(values, |value| {
+            NameRecord::decode_record(value)
+                .map(|record| (record.sequence, record.validity))
+                .unwrap_or_default()
+        })
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_name_record() {
+        let keypair = Keypair::generate_ed25519();
+        let peer = keypair.public().into_peer_id();
+        let key = NameRecord::key(&peer, DEFAULT_NAME);
+        assert_eq!(NameRecord::parse_key(&peer.to_string()).unwrap(), key);
+        assert_eq!(
+            NameRecord::parse_key(std::str::from_utf8(&key).unwrap()).unwrap(),
+            key
+        );
+
+        let ttl = Duration::from_secs(60);
+        let old = NameRecord::new(&keypair, &key, b"old".to_vec(), 1, ttl)
+            .unwrap()
+            .to_bytes();
+        let new = NameRecord::new(&keypair, &key, b"new".to_vec(), 2, ttl)
+            .unwrap()
+            .to_bytes();
+        NameValidator.validate(&key, &old).unwrap();
+        NameValidator.validate(&key, &new).unwrap();
+        assert_eq!(NameValidator.select(&key, &[&old, &new]), 1);
+        assert_eq!(NameValidator.select(&key, &[&new, &old]), 0);
+
+        let expired = NameRecord::new(&keypair, &key, b"new".to_vec(), 3, Duration::from_secs(0))
+            .unwrap()
+            .to_bytes();
+        assert!(NameValidator.validate(&key, &expired).is_err());
+
+        let other = Keypair::generate_ed25519();
+        let forged = NameRecord::new(&other, &key, b"forged".to_vec(), 4, ttl)
+            .unwrap()
+            .to_bytes();
+        assert!(NameValidator.validate(&key, &forged).is_err());
+    }
+}
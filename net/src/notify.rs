@@ -0,0 +1,104 @@
+use async_trait::async_trait;
+use std::error::Error;
+
+use libp2p_rs::core::upgrade::UpgradeInfo;
+use libp2p_rs::core::{Multiaddr, PeerId, ProtocolId};
+use libp2p_rs::runtime::task;
+use libp2p_rs::swarm::connection::Connection;
+use libp2p_rs::swarm::protocol_handler::{
+    IProtocolHandler, Notifiee, ProtocolHandler, ProtocolImpl,
+};
+use libp2p_rs::swarm::substream::Substream;
+use libp2p_rs::swarm::Control as SwarmControl;
+
+/// Registers a protocol and passes its swarm notifications on to components that only
+/// watch the swarm, like the connection manager. The swarm only notifies the handlers of
+/// registered protocols, and every registered protocol id is advertised in identify, so
+/// watchers piggyback on a real protocol instead of registering an id of their own.
+pub(crate) struct WithNotifiees<P> {
+    inner: P,
+    notifiees: Vec<IProtocolHandler>,
+}
+
+impl<P: ProtocolImpl> WithNotifiees<P> {
+    pub fn new(inner: P, notifiees: Vec<IProtocolHandler>) -> Self {
+        Self { inner, notifiees }
+    }
+}
+
+impl<P: ProtocolImpl> ProtocolImpl for WithNotifiees<P> {
+    fn handler(&self) -> IProtocolHandler {
+        Box::new(Handler {
+            inner: self.inner.handler(),
+            notifiees: self.notifiees.iter().map(|n| n.box_clone()).collect(),
+        })
+    }
+
+    fn start(self, swarm: SwarmControl) -> Option<task::TaskHandle<()>>
+    where
+        Self: Sized,
+    {
+        self.inner.start(swarm)
+    }
+}
+
+struct Handler {
+    inner: IProtocolHandler,
+    notifiees: Vec<IProtocolHandler>,
+}
+
+impl UpgradeInfo for Handler {
+    type Info = ProtocolId;
+
+    fn protocol_info(&self) -> Vec<Self::Info> {
+        self.inner.protocol_info()
+    }
+}
+
+impl Notifiee for Handler {
+    fn connected(&mut self, conn: &mut Connection) {
+        self.inner.connected(conn);
+        for notifiee in &mut self.notifiees {
+            notifiee.connected(conn);
+        }
+    }
+
+    fn disconnected(&mut self, conn: &mut Connection) {
+        self.inner.disconnected(conn);
+        for notifiee in &mut self.notifiees {
+            notifiee.disconnected(conn);
+        }
+    }
+
+    fn identified(&mut self, peer: PeerId) {
+        self.inner.identified(peer);
+        for notifiee in &mut self.notifiees {
+            notifiee.identified(peer);
+        }
+    }
+
+    fn address_changed(&mut self, addrs: Vec<Multiaddr>) {
+        for notifiee in &mut self.notifiees {
+            notifiee.address_changed(addrs.clone());
+        }
+        self.inner.address_changed(addrs);
+    }
+}
+
+#[async_trait]
+impl ProtocolHandler for Handler {
+    async fn handle(
+        &mut self,
+        stream: Substream,
+        info: <Self as UpgradeInfo>::Info,
+    ) -> Result<(), Box<dyn Error>> {
+        self.inner.handle(stream, info).await
+    }
+
+    fn box_clone(&self) -> IProtocolHandler {
+        Box::new(Handler {
+            inner: self.inner.box_clone(),
+            notifiees: self.notifiees.iter().map(|n| n.box_clone()).collect(),
+        })
+    }
+}
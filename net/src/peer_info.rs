@@ -0,0 +1,39 @@
+use libipld::Result;
+use std::time::{Duration, Instant};
+
+use libp2p_rs::core::{Multiaddr, PeerId};
+use libp2p_rs::swarm::Control as SwarmControl;
+use libp2p_rs::traits::{ReadEx, WriteEx};
+
+const PING_PROTO_ID: &[u8] = b"/ipfs/ping/1.0.0";
+const PING_SIZE: usize = 32;
+
+/// What a peer told about itself in identify.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PeerInfo {
+    /// The agent version, e.g. `ipfs-embed/0.11.0 (node-name)`.
+    pub agent_version: String,
+    pub protocol_version: String,
+    /// The protocols the peer supports.
+    pub protocols: Vec<String>,
+    /// The addresses the peer listens on.
+    pub listen_addrs: Vec<Multiaddr>,
+    /// The round trip time of a ping, if the peer answered.
+    pub rtt: Option<Duration>,
+}
+
+/// Measures the round trip time to a connected peer.
+pub(crate) async fn ping(mut swarm: SwarmControl, peer: PeerId) -> Result<Duration> {
+    let mut stream = swarm.new_stream(peer, vec![PING_PROTO_ID.into()]).await?;
+    let payload: [u8; PING_SIZE] = rand::random();
+    let start = Instant::now();
+    stream.write_all2(&payload).await?;
+    let mut pong = [0u8; PING_SIZE];
+    stream.read_exact2(&mut pong).await?;
+    let rtt = start.elapsed();
+    let _ = stream.close2().await;
+    if pong != payload {
+        return Err(anyhow::anyhow!("ping payload mismatch"));
+    }
+    Ok(rtt)
+}
@@ -0,0 +1,354 @@
+use fnv::FnvHashSet;
+use futures::stream::{BoxStream, Stream, StreamExt};
+use gossipsub::Control as GossipsubControl;
+use libipld::Result;
+use libp2p_rs::core::identity::Keypair;
+use libp2p_rs::core::{PeerId, PublicKey};
+use libp2p_rs::floodsub::control::Control as FloodsubControl;
+use libp2p_rs::floodsub::Topic;
+use prost::Message;
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub use gossipsub::{GossipsubConfig, TopicValidator, ValidationResult};
+
+const SIGNING_PREFIX: &[u8] = b"libp2p-pubsub:";
+/// The maximum age of a floodsub message, older messages are dropped as replays.
+const MAX_MESSAGE_AGE: Duration = Duration::from_secs(120);
+
+/// The pubsub protocol backing `subscribe` and `publish`.
+#[derive(Clone, Debug)]
+pub enum PubsubConfig {
+    /// Floods every message to every peer subscribed to the topic. Messages published by
+    /// the local node are delivered to its own subscriptions.
+    ///
+    /// Messages are wrapped in a signed envelope only understood by other ipfs-embed nodes.
+    /// Floodsub forwards messages before they are validated, so topic validators are not
+    /// supported.
+    Floodsub,
+    /// Builds a mesh of peers per topic and gossips about messages to the remaining peers.
+    Gossipsub(GossipsubConfig),
+}
+
+impl Default for PubsubConfig {
+    fn default() -> Self {
+        Self::Floodsub
+    }
+}
+
+/// A message received on a topic.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PubsubMessage {
+    /// The peer that published the message.
+    pub source: PeerId,
+    /// The message payload.
+    pub data: Vec<u8>,
+    /// The topics the message was published on.
+    pub topics: Vec<String>,
+}
+
+/// A `Stream` of messages received on a topic. The topic is unsubscribed once all
+/// subscriptions to it are dropped.
+pub struct Subscription(BoxStream<'static, PubsubMessage>);
+
+impl Stream for Subscription {
+    type Item = PubsubMessage;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.0.poll_next_unpin(cx)
+    }
+}
+
+/// Floodsub has no notion of signed messages, so the payload is wrapped in an envelope
+/// signed by the publisher.
+#[derive(Clone, PartialEq, Message)]
+struct Envelope {
+    #[prost(bytes, tag = "1")]
+    key: Vec<u8>,
+    #[prost(bytes, tag = "2")]
+    signature: Vec<u8>,
+    #[prost(bytes, tag = "3")]
+    data: Vec<u8>,
+    /// The publish time in nanoseconds since the unix epoch, unique per publisher.
+    #[prost(uint64, tag = "4")]
+    seqno: u64,
+}
+
+fn signing_bytes(topic: &str, seqno: u64, data: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(SIGNING_PREFIX.len() + topic.len() + 9 + data.len());
+    buf.extend_from_slice(SIGNING_PREFIX);
+    buf.extend_from_slice(topic.as_bytes());
+    buf.push(0);
+    buf.extend_from_slice(&seqno.to_be_bytes());
+    buf.extend_from_slice(data);
+    buf
+}
+
+fn unix_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+fn seal(keypair: &Keypair, topic: &str, seqno: u64, data: Vec<u8>) -> Result<Vec<u8>> {
+    let signature = keypair.sign(&signing_bytes(topic, seqno, &data))?;
+    let envelope = Envelope {
+        key: keypair.public().into_protobuf_encoding(),
+        signature,
+        data,
+        seqno,
+    };
+    let mut buf = Vec::with_capacity(envelope.encoded_len());
+    envelope
+        .encode(&mut buf)
+        .expect("Vec<u8> provides capacity as needed");
+    Ok(buf)
+}
+
+/// The messages seen by a subscription within the maximum message age.
+#[derive(Default)]
+struct SeenMessages {
+    expiry: VecDeque<(Instant, (PeerId, u64))>,
+    seen: FnvHashSet<(PeerId, u64)>,
+}
+
+impl SeenMessages {
+    /// Returns `false` if the message was already seen.
+    fn insert(&mut self, source: &PeerId, seqno: u64) -> bool {
+        let now = Instant::now();
+        while let Some((expires, _)) = self.expiry.front() {
+            if *expires > now {
+                break;
+            }
+            let (_, id) = self.expiry.pop_front().unwrap();
+            self.seen.remove(&id);
+        }
+        let id = (*source, seqno);
+        if !self.seen.insert(id) {
+            return false;
+        }
+        self.expiry.push_back((now + MAX_MESSAGE_AGE, id));
+        true
+    }
+}
+
+/// Verifies the envelope and drops replayed and stale messages, returning the payload if
+/// the message is accepted.
+fn open(seen: &mut SeenMessages, topic: &str, source: &PeerId, bytes: &[u8]) -> Option<Vec<u8>> {
+    let envelope = Envelope::decode(bytes).ok()?;
+    let key = PublicKey::from_protobuf_encoding(&envelope.key).ok()?;
+    let signed = signing_bytes(topic, envelope.seqno, &envelope.data);
+    if &key.clone().into_peer_id() != source || !key.verify(&signed, &envelope.signature) {
+        tracing::debug!("dropping unsigned message from {}", source);
+        return None;
+    }
+    let now = unix_nanos();
+    let max_age = MAX_MESSAGE_AGE.as_nanos() as u64;
+    if envelope.seqno.saturating_add(max_age) < now || envelope.seqno > now + max_age {
+        tracing::debug!("dropping stale message from {}", source);
+        return None;
+    }
+    if !seen.insert(source, envelope.seqno) {
+        tracing::debug!("dropping replayed message from {}", source);
+        return None;
+    }
+    Some(envelope.data)
+}
+
+/// Floodsub with signed messages.
+///
+/// Floodsub forwards messages before they reach the application, so a validator couldn't
+/// stop invalid messages from being forwarded. Registering a topic validator fails, use
+/// gossipsub instead.
+///
+/// The signed bytes include a sequence number, the publish time. Subscriptions drop
+/// messages older than two minutes and messages they have already seen, so a signed
+/// message can't be replayed.
+#[derive(Clone)]
+pub struct Floodsub {
+    control: FloodsubControl,
+    keypair: Keypair,
+    seqno: Arc<AtomicU64>,
+}
+
+impl Floodsub {
+    pub fn new(control: FloodsubControl, keypair: Keypair) -> Self {
+        Self {
+            control,
+            keypair,
+            seqno: Default::default(),
+        }
+    }
+
+    /// Returns the current time, or the last sequence number plus one if the clock didn't
+    /// advance.
+    fn next_seqno(&self) -> u64 {
+        let now = unix_nanos();
+        let mut last = self.seqno.load(Ordering::SeqCst);
+        loop {
+            let next = now.max(last + 1);
+            match self
+                .seqno
+                .compare_exchange(last, next, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => return next,
+                Err(current) => last = current,
+            }
+        }
+    }
+}
+
+/// Control of the configured pubsub protocol.
+#[derive(Clone)]
+pub enum PubsubControl {
+    Floodsub(Floodsub),
+    Gossipsub(GossipsubControl),
+}
+
+impl PubsubControl {
+    /// Subscribes to a `topic`.
+    pub async fn subscribe(&self, topic: &str) -> Result<Subscription> {
+        let stream = match self.clone() {
+            Self::Floodsub(floodsub) => {
+                let sub = floodsub
+                    .control
+                    .clone()
+                    .subscribe(Topic::new(topic))
+                    .await?;
+                let topic = topic.to_string();
+                let seen = SeenMessages::default();
+                futures::stream::unfold((sub, seen), move |(mut sub, mut seen)| {
+                    let topic = topic.clone();
+                    async move {
+                        loop {
+                            let msg = sub.next().await?;
+                            let data = open(&mut seen, &topic, &msg.source, &msg.data);
+                            if let Some(data) = data {
+                                let msg = PubsubMessage {
+                                    source: msg.source,
+                                    data,
+                                    topics: msg.topics.iter().map(|t| t.id().to_string()).collect(),
+                                };
+                                return Some((msg, (sub, seen)));
+                            }
+                        }
+                    }
+                })
+                .boxed()
+            }
+            Self::Gossipsub(mut gossipsub) => {
+                let sub = gossipsub.subscribe(topic.to_string()).await?;
+                sub.map(|msg| PubsubMessage {
+                    source: msg.source,
+                    data: msg.data.clone(),
+                    topics: msg.topics.clone(),
+                })
+                .boxed()
+            }
+        };
+        Ok(Subscription(stream))
+    }
+
+    /// Publishes a message in a `topic`, signed with the node keypair.
+    pub async fn publish(&self, topic: &str, msg: Vec<u8>) -> Result<()> {
+        match self.clone() {
+            Self::Floodsub(mut floodsub) => {
+                let seqno = floodsub.next_seqno();
+                let msg = seal(&floodsub.keypair, topic, seqno, msg)?;
+                let _ = floodsub.control.publish(Topic::new(topic), msg).await;
+            }
+            Self::Gossipsub(mut gossipsub) => {
+                gossipsub.publish(topic.to_string(), msg).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the peers subscribed to a `topic`.
+    pub async fn peers(&self, topic: &str) -> Result<Vec<PeerId>> {
+        Ok(match self.clone() {
+            Self::Floodsub(mut floodsub) => floodsub.control.get_peers(Topic::new(topic)).await,
+            Self::Gossipsub(mut gossipsub) => gossipsub.get_peers(Some(topic.to_string())).await?,
+        })
+    }
+
+    /// Returns `true` if the protocol supports topic validators.
+    pub fn supports_validators(&self) -> bool {
+        matches!(self, Self::Gossipsub(_))
+    }
+
+    /// Registers a validator for the messages of a `topic`, replacing any previous validator.
+    ///
+    /// Fails when using floodsub, which would still forward the messages the validator
+    /// rejects.
+    pub async fn register_topic_validator(
+        &self,
+        topic: &str,
+        validator: TopicValidator,
+    ) -> Result<()> {
+        match self.clone() {
+            Self::Floodsub(_) => {
+                return Err(anyhow::anyhow!(
+                    "floodsub doesn't support topic validators, use gossipsub"
+                ));
+            }
+            Self::Gossipsub(mut gossipsub) => {
+                gossipsub
+                    .register_topic_validator(topic.to_string(), validator)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Closes the pubsub main loop.
+    pub fn close(&self) {
+        match self.clone() {
+            Self::Floodsub(mut floodsub) => floodsub.control.close(),
+            Self::Gossipsub(mut gossipsub) => gossipsub.close(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_floodsub_envelope() {
+        let keypair = Keypair::generate_ed25519();
+        let source = keypair.public().into_peer_id();
+        let mut seen = SeenMessages::default();
+        let now = unix_nanos();
+
+        let msg = seal(&keypair, "topic", now, b"hello".to_vec()).unwrap();
+        let check = |seen: &mut SeenMessages, topic: &str, source: &PeerId, msg: &[u8]| {
+            open(seen, topic, source, msg)
+        };
+        assert_eq!(
+            check(&mut seen, "topic", &source, &msg),
+            Some(b"hello".to_vec())
+        );
+        // replayed
+        assert_eq!(check(&mut seen, "topic", &source, &msg), None);
+        // signed for another topic or by another peer
+        let mut other = SeenMessages::default();
+        assert_eq!(check(&mut other, "other", &source, &msg), None);
+        assert_eq!(check(&mut other, "topic", &PeerId::random(), &msg), None);
+        // tampered
+        let mut envelope = Envelope::decode(msg.as_slice()).unwrap();
+        envelope.seqno += 1;
+        let mut tampered = vec![];
+        envelope.encode(&mut tampered).unwrap();
+        assert_eq!(check(&mut other, "topic", &source, &tampered), None);
+        // stale
+        let old = now - 2 * MAX_MESSAGE_AGE.as_nanos() as u64;
+        let msg = seal(&keypair, "topic", old, b"hello".to_vec()).unwrap();
+        assert_eq!(check(&mut other, "topic", &source, &msg), None);
+    }
+}
@@ -0,0 +1,279 @@
+use libipld::Result;
+use libp2p_rs::core::identity::Keypair;
+use libp2p_rs::core::multiaddr::protocol::Protocol;
+use libp2p_rs::core::muxing::IStreamMuxer;
+use libp2p_rs::core::transport::memory::MemoryTransport;
+use libp2p_rs::core::transport::upgrade::TransportUpgrade;
+use libp2p_rs::core::transport::ITransport;
+use libp2p_rs::core::upgrade::Selector;
+use libp2p_rs::core::Multiaddr;
+use libp2p_rs::dns::DnsConfig;
+use libp2p_rs::tcp::TcpConfig;
+use libp2p_rs::websocket::WsConfig;
+use libp2p_rs::{mplex, noise, secio, yamux};
+use relay::RelayTransport;
+use std::time::Duration;
+
+/// The transports the node listens and dials on.
+///
+/// Every transport is upgraded with the same security and muxer protocols, see
+/// [`SecurityConfig`] and [`MuxerConfig`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TransportConfig {
+    /// Enable the TCP transport, `/ip4/<ip>/tcp/<port>`.
+    pub tcp: bool,
+    /// Enable the WebSocket transport, `/ip4/<ip>/tcp/<port>/ws`.
+    pub websocket: bool,
+    /// Enable the process-local memory transport, `/memory/<n>`.
+    pub memory: bool,
+    /// Resolve `/dns4`, `/dns6` and `/dns` addresses of the TCP and WebSocket transports.
+    pub dns: bool,
+    /// Disable Nagle's algorithm on TCP sockets.
+    pub tcp_nodelay: bool,
+    /// The timeout of outbound TCP and WebSocket dials.
+    pub outbound_timeout: Duration,
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        Self {
+            tcp: true,
+            websocket: false,
+            memory: false,
+            dns: true,
+            tcp_nodelay: true,
+            outbound_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl TransportConfig {
+    /// Enables or disables the TCP transport.
+    pub fn with_tcp(mut self, tcp: bool) -> Self {
+        self.tcp = tcp;
+        self
+    }
+
+    /// Enables or disables the WebSocket transport.
+    pub fn with_websocket(mut self, websocket: bool) -> Self {
+        self.websocket = websocket;
+        self
+    }
+
+    /// Enables or disables the memory transport.
+    pub fn with_memory(mut self, memory: bool) -> Self {
+        self.memory = memory;
+        self
+    }
+
+    /// Enables or disables dns resolution.
+    pub fn with_dns(mut self, dns: bool) -> Self {
+        self.dns = dns;
+        self
+    }
+
+    /// Enables the memory transport if any of `addrs` is a `/memory/<n>` address.
+    pub(crate) fn enable_memory_for<'a>(&mut self, mut addrs: impl Iterator<Item = &'a Multiaddr>) {
+        if addrs.any(is_memory) {
+            self.memory = true;
+        }
+    }
+
+    fn tcp_config(&self) -> TcpConfig {
+        TcpConfig::new()
+            .nodelay(self.tcp_nodelay)
+            .outbound_timeout(self.outbound_timeout)
+    }
+
+    fn ws_config(&self) -> WsConfig {
+        WsConfig::new_with_tcp(self.tcp_config())
+    }
+}
+
+/// Returns `true` if `addr` is a `/memory/<n>` address.
+pub(crate) fn is_memory(addr: &Multiaddr) -> bool {
+    matches!(addr.iter().next(), Some(Protocol::Memory(_)))
+}
+
+/// The upgraded transports of the node.
+pub(crate) struct Transports {
+    /// The transports of the swarm, including the relay transport.
+    pub swarm: Vec<ITransport<IStreamMuxer>>,
+    /// The transports autonat dials back peers on, independent of the swarm connections.
+    pub dial_back: Vec<ITransport<IStreamMuxer>>,
+}
+
+/// The security protocols offered when upgrading connections, in order of preference.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SecurityConfig {
+    /// Offer noise XX.
+    pub noise: bool,
+    /// Offer secio. Secio is deprecated, it is only useful to talk to legacy peers.
+    pub secio: bool,
+}
+
+impl Default for SecurityConfig {
+    fn default() -> Self {
+        Self {
+            noise: true,
+            secio: false,
+        }
+    }
+}
+
+/// The stream multiplexers offered when upgrading connections, in order of preference.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MuxerConfig {
+    /// Offer yamux.
+    pub yamux: bool,
+    /// The initial yamux receive window of a stream. Larger windows speed up large block
+    /// transfers at the cost of buffer memory per stream.
+    pub yamux_window_size: u32,
+    /// The maximum number of concurrent yamux streams of a connection.
+    pub yamux_max_streams: usize,
+    /// Offer mplex.
+    pub mplex: bool,
+}
+
+impl Default for MuxerConfig {
+    fn default() -> Self {
+        Self {
+            yamux: true,
+            yamux_window_size: 256 * 1024,
+            yamux_max_streams: 8192,
+            mplex: true,
+        }
+    }
+}
+
+impl MuxerConfig {
+    fn yamux_config(&self) -> yamux::Config {
+        let mut config = yamux::Config::new();
+        config.set_receive_window(self.yamux_window_size);
+        config.set_max_num_streams(self.yamux_max_streams);
+        config
+    }
+}
+
+/// Upgrades the transports enabled in `config` with the security upgrade `$sec` and the
+/// muxer upgrade `$mux`. The upgrades are different types depending on the selected
+/// protocols, so this is a macro instead of a generic function.
+macro_rules! upgrade_transports {
+    ($config:expr, $relay:expr, $sec:expr, $mux:expr) => {{
+        let config: &TransportConfig = $config;
+        let sec = $sec;
+        let mux = $mux;
+        let mut swarm: Vec<ITransport<IStreamMuxer>> = vec![];
+        if config.tcp {
+            if config.dns {
+                let tcp = DnsConfig::new(config.tcp_config());
+                swarm.push(Box::new(TransportUpgrade::new(
+                    tcp,
+                    mux.clone(),
+                    sec.clone(),
+                )));
+            } else {
+                let tcp = config.tcp_config();
+                swarm.push(Box::new(TransportUpgrade::new(
+                    tcp,
+                    mux.clone(),
+                    sec.clone(),
+                )));
+            }
+        }
+        if config.websocket {
+            if config.dns {
+                let ws = DnsConfig::new(config.ws_config());
+                swarm.push(Box::new(TransportUpgrade::new(
+                    ws,
+                    mux.clone(),
+                    sec.clone(),
+                )));
+            } else {
+                let ws = config.ws_config();
+                swarm.push(Box::new(TransportUpgrade::new(
+                    ws,
+                    mux.clone(),
+                    sec.clone(),
+                )));
+            }
+        }
+        if config.memory {
+            let memory = MemoryTransport::default();
+            swarm.push(Box::new(TransportUpgrade::new(
+                memory,
+                mux.clone(),
+                sec.clone(),
+            )));
+        }
+
+        if swarm.is_empty() {
+            return Err(anyhow::anyhow!("no transport enabled"));
+        }
+        // the dial back transports are fresh instances, so their connections aren't reused
+        // by the swarm.
+        let dial_back = swarm
+            .iter()
+            .map(|transport| transport.box_clone())
+            .collect();
+
+        // relayed connections are upgraded like direct connections
+        swarm.push(Box::new(TransportUpgrade::new($relay, mux, sec)));
+
+        Ok(Transports { swarm, dial_back })
+    }};
+}
+
+/// Selects the muxer upgrade from `$muxer` and upgrades the transports.
+macro_rules! select_muxer {
+    ($config:expr, $muxer:expr, $relay:expr, $sec:expr) => {{
+        let muxer: &MuxerConfig = $muxer;
+        match (muxer.yamux, muxer.mplex) {
+            (true, true) => {
+                let mux = Selector::new(muxer.yamux_config(), mplex::Config::new());
+                upgrade_transports!($config, $relay, $sec, mux)
+            }
+            (true, false) => upgrade_transports!($config, $relay, $sec, muxer.yamux_config()),
+            (false, true) => upgrade_transports!($config, $relay, $sec, mplex::Config::new()),
+            (false, false) => Err(anyhow::anyhow!("no stream muxer enabled")),
+        }
+    }};
+}
+
+/// Builds the transports enabled in `config`, upgraded with the security protocols and
+/// stream muxers enabled in `security` and `muxer`.
+pub(crate) fn build_transports(
+    config: &TransportConfig,
+    security: &SecurityConfig,
+    muxer: &MuxerConfig,
+    keypair: &Keypair,
+    relay: RelayTransport,
+) -> Result<Transports> {
+    let noise = || -> Result<_> {
+        let xx_keypair = noise::Keypair::<noise::X25519Spec>::new().into_authentic(keypair)?;
+        Ok(noise::NoiseConfig::xx(xx_keypair, keypair.clone()))
+    };
+    let secio = || secio::Config::new(keypair.clone());
+    match (security.noise, security.secio) {
+        (true, true) => select_muxer!(config, muxer, relay, Selector::new(noise()?, secio())),
+        (true, false) => select_muxer!(config, muxer, relay, noise()?),
+        (false, true) => select_muxer!(config, muxer, relay, secio()),
+        (false, false) => Err(anyhow::anyhow!("no security protocol enabled")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_enable_memory() {
+        let tcp: Multiaddr = "/ip4/127.0.0.1/tcp/0".parse().unwrap();
+        let memory: Multiaddr = "/memory/0".parse().unwrap();
+        let mut config = TransportConfig::default();
+        config.enable_memory_for([tcp.clone()].iter());
+        assert!(!config.memory);
+        config.enable_memory_for([tcp, memory].iter());
+        assert!(config.memory);
+    }
+}
@@ -0,0 +1,313 @@
+use crate::name::{NameValidator, NAME_NAMESPACE};
+use libipld::Result;
+use libp2p_rs::core::identity::Keypair;
+use libp2p_rs::core::{PeerId, PublicKey};
+use prost::Message;
+use std::sync::{Arc, RwLock};
+use thiserror::Error;
+
+const SIGNING_PREFIX: &[u8] = b"ipfs-embed-record:";
+
+/// Namespace of the public keys of peers, `/pk/<peer id>`.
+pub const PUBLIC_KEY_NAMESPACE: &str = "pk";
+/// Namespace of records signed by their publisher, `/signed/<peer id>/<name>`.
+pub const SIGNED_NAMESPACE: &str = "signed";
+
+#[derive(Debug, Error)]
+pub enum RecordError {
+    #[error("Malformed record key")]
+    MalformedKey,
+    #[error("Error while decoding record {0}")]
+    Decode(String),
+    #[error("Record key doesn't match the publisher")]
+    PublisherMismatch,
+    #[error("Invalid record signature")]
+    InvalidSignature,
+    #[error("Record expired")]
+    Expired,
+    #[error("No valid record found")]
+    NotFound,
+}
+
+/// Validates the dht records of a key namespace.
+pub trait RecordValidator: Send + Sync + 'static {
+    /// Checks that `value` is a valid record for `key`.
+    fn validate(&self, key: &[u8], value: &[u8]) -> Result<()>;
+
+    /// Selects the best of several valid records for `key`, returning its index. It is
+    /// called with at least one value, the first one is the record already stored when
+    /// there is one and should be kept on ties.
+    fn select(&self, _key: &[u8], _values: &[&[u8]]) -> usize {
+        0
+    }
+}
+
+/// Returns the namespace of a key of the form `/<namespace>/...`.
+pub fn namespace(key: &[u8]) -> Option<&[u8]> {
+    let key = key.strip_prefix(b"/")?;
+    let end = key.iter().position(|b| *b == b'/')?;
+    Some(&key[..end])
+}
+
+/// Returns the index of the first value with the greatest key, so that ties keep the
+/// earliest value.
+pub(crate) fn select_max_by_key<K, F>(values: &[&[u8]], f: F) -> usize
+where
+    K: Ord,
+    F: Fn(&[u8]) -> K,
+{
+    let mut best: Option<(usize, K)> = None;
+    for (i, value) in values.iter().enumerate() {
+        let key = f(value);
+        if best.as_ref().map(|(_, best)| key > *best).unwrap_or(true) {
+            best = Some((i, key));
+        }
+    }
+    best.map(|(i, _)| i).unwrap_or_default()
+}
+
+/// Signs the dht `key` and the `fields` of a record, returning the protobuf encoded public
+/// key of the publisher and the signature.
+pub(crate) fn sign_record(
+    keypair: &Keypair,
+    prefix: &[u8],
+    key: &[u8],
+    fields: &[&[u8]],
+) -> Result<(Vec<u8>, Vec<u8>)> {
+    let signature = keypair.sign(&signing_bytes(prefix, key, fields))?;
+    Ok((keypair.public().into_protobuf_encoding(), signature))
+}
+
+/// Checks a signature made by `sign_record`, returning the publisher.
+pub(crate) fn verify_record(
+    public: &[u8],
+    signature: &[u8],
+    prefix: &[u8],
+    key: &[u8],
+    fields: &[&[u8]],
+) -> Result<PeerId> {
+    let public = PublicKey::from_protobuf_encoding(public)
+        .map_err(|e| RecordError::Decode(e.to_string()))?;
+    if !public.verify(&signing_bytes(prefix, key, fields), signature) {
+        return Err(RecordError::InvalidSignature.into());
+    }
+    Ok(public.into_peer_id())
+}
+
+fn signing_bytes(prefix: &[u8], key: &[u8], fields: &[&[u8]]) -> Vec<u8> {
+    let len = fields.iter().map(|field| field.len()).sum::<usize>();
+    let mut buf = Vec::with_capacity(prefix.len() + key.len() + len);
+    buf.extend_from_slice(prefix);
+    buf.extend_from_slice(key);
+    for field in fields {
+        buf.extend_from_slice(field);
+    }
+    buf
+}
+
+/// Checks that `publisher` is the peer of a key of the form `/<namespace>/<peer id>/<name>`.
+pub(crate) fn check_publisher(key: &[u8], publisher: &PeerId) -> Result<()> {
+    let path = std::str::from_utf8(key).map_err(|_| RecordError::MalformedKey)?;
+    let peer = path
+        .splitn(4, '/')
+        .nth(2)
+        .ok_or(RecordError::MalformedKey)?;
+    if publisher.to_string() != peer {
+        return Err(RecordError::PublisherMismatch.into());
+    }
+    Ok(())
+}
+
+pub(crate) fn decode_record<M: Message + Default>(bytes: &[u8]) -> Result<M> {
+    Ok(M::decode(bytes).map_err(|e| RecordError::Decode(e.to_string()))?)
+}
+
+pub(crate) fn encode_record<M: Message>(record: &M) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(record.encoded_len());
+    record
+        .encode(&mut buf)
+        .expect("Vec<u8> provides capacity as needed");
+    buf
+}
+
+/// The record validators by namespace.
+///
+/// Records of namespaces without a validator are accepted as they are.
+#[derive(Clone)]
+pub struct RecordValidators(Arc<RwLock<Vec<(Vec<u8>, Arc<dyn RecordValidator>)>>>);
+
+impl Default for RecordValidators {
+    fn default() -> Self {
+        let validators = Self(Default::default());
+        validators.register(PUBLIC_KEY_NAMESPACE, PublicKeyValidator);
+        validators.register(SIGNED_NAMESPACE, SignedRecordValidator);
+        validators.register(NAME_NAMESPACE, NameValidator);
+        validators
+    }
+}
+
+impl RecordValidators {
+    /// Registers a validator for a namespace, replacing any previous validator.
+    pub fn register<V: RecordValidator>(&self, namespace: &str, validator: V) {
+        let namespace = namespace.trim_matches('/').as_bytes().to_vec();
+        let mut validators = self.0.write().unwrap();
+        validators.retain(|(ns, _)| ns != &namespace);
+        validators.push((namespace, Arc::new(validator)));
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Arc<dyn RecordValidator>> {
+        let ns = namespace(key)?;
+        self.0
+            .read()
+            .unwrap()
+            .iter()
+            .find(|(namespace, _)| namespace.as_slice() == ns)
+            .map(|(_, validator)| validator.clone())
+    }
+
+    /// Returns `true` if a validator is registered for the namespace of `key`.
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Validates a record with the validator of its namespace.
+    pub fn validate(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        if let Some(validator) = self.get(key) {
+            validator.validate(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the best of several records for `key`, ignoring invalid records.
+    pub fn select<'a>(&self, key: &[u8], values: &[&'a [u8]]) -> Result<&'a [u8]> {
+        let valid: Vec<&[u8]> = values
+            .iter()
+            .copied()
+            .filter(|value| match self.validate(key, value) {
+                Ok(()) => true,
+                Err(err) => {
+                    tracing::debug!("dropping invalid record: {}", err);
+                    false
+                }
+            })
+            .collect();
+        if valid.is_empty() {
+            return Err(RecordError::NotFound.into());
+        }
+        let i = match self.get(key) {
+            Some(validator) => validator.select(key, &valid),
+            None => 0,
+        };
+        Ok(valid.get(i).copied().unwrap_or(valid[0]))
+    }
+}
+
+/// Validates `/pk/<peer id>` records, the value is the protobuf encoded public key of the
+/// peer.
+pub struct PublicKeyValidator;
+
+impl RecordValidator for PublicKeyValidator {
+    fn validate(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let peer = key.strip_prefix(b"/pk/").ok_or(RecordError::MalformedKey)?;
+        let public = PublicKey::from_protobuf_encoding(value)
+            .map_err(|e| RecordError::Decode(e.to_string()))?;
+        if public.into_peer_id().to_bytes() != peer {
+            return Err(RecordError::PublisherMismatch.into());
+        }
+        Ok(())
+    }
+}
+
+/// A record signed by its publisher.
+#[derive(Clone, PartialEq, Message)]
+pub struct SignedRecord {
+    /// Protobuf encoded public key of the publisher.
+    #[prost(bytes, tag = "1")]
+    pub key: Vec<u8>,
+    #[prost(bytes, tag = "2")]
+    pub signature: Vec<u8>,
+    /// Sequence number, records with a higher sequence number replace older records.
+    #[prost(uint64, tag = "3")]
+    pub sequence: u64,
+    #[prost(bytes, tag = "4")]
+    pub value: Vec<u8>,
+}
+
+impl SignedRecord {
+    /// Returns the dht key of the record `name` published by `peer`.
+    pub fn key(peer: &PeerId, name: &str) -> Vec<u8> {
+        format!("/{}/{}/{}", SIGNED_NAMESPACE, peer, name).into_bytes()
+    }
+
+    /// Creates a new record for the dht `key` signed with `keypair`.
+    pub fn new(keypair: &Keypair, key: &[u8], sequence: u64, value: Vec<u8>) -> Result<Self> {
+        let fields: [&[u8]; 2] = [&sequence.to_be_bytes(), &value];
+        let (public, signature) = sign_record(keypair, SIGNING_PREFIX, key, &fields)?;
+        Ok(Self {
+            key: public,
+            signature,
+            sequence,
+            value,
+        })
+    }
+
+    /// Decodes a record.
+    pub fn decode_record(bytes: &[u8]) -> Result<Self> {
+        decode_record(bytes)
+    }
+
+    /// Encodes the record.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        encode_record(self)
+    }
+
+    /// Returns the publisher of the record after checking the signature.
+    pub fn verify(&self, key: &[u8]) -> Result<PeerId> {
+        let fields: [&[u8]; 2] = [&self.sequence.to_be_bytes(), &self.value];
+        verify_record(&self.key, &self.signature, SIGNING_PREFIX, key, &fields)
+    }
+}
+
+/// Validates `/signed/<peer id>/<name>` records, the value is a `SignedRecord` signed by the
+/// peer. The record with the highest sequence number is selected.
+pub struct SignedRecordValidator;
+
+impl RecordValidator for SignedRecordValidator {
+    fn validate(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let publisher = SignedRecord::decode_record(value)?.verify(key)?;
+        check_publisher(key, &publisher)
+    }
+
+    fn select(&self, _key: &[u8], values: &[&[u8]]) -> usize {
+        select_max_by_key(values, |value| {
+            SignedRecord::decode_record(value)
+                .map(|record| record.sequence)
+                .unwrap_or_default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signed_record_select() {
+        let keypair = Keypair::generate_ed25519();
+        let key = SignedRecord::key(&keypair.public().into_peer_id(), "name");
+        let record = |sequence, value: &[u8]| {
+            SignedRecord::new(&keypair, &key, sequence, value.to_vec())
+                .unwrap()
+                .to_bytes()
+        };
+        let old = record(1, b"old");
+        let new = record(2, b"new");
+        let other = record(2, b"other");
+        assert_eq!(SignedRecordValidator.select(&key, &[&old, &new]), 1);
+        assert_eq!(SignedRecordValidator.select(&key, &[&new, &old]), 0);
+        // ties keep the existing record
+        assert_eq!(SignedRecordValidator.select(&key, &[&new, &other]), 0);
+        assert_eq!(SignedRecordValidator.select(&key, &[&other, &new]), 0);
+        assert_eq!(SignedRecordValidator.select(&key, &[&old, &other, &new]), 1);
+    }
+}
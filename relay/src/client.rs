@@ -0,0 +1,275 @@
+use async_trait::async_trait;
+use futures::channel::mpsc;
+use futures::future::{AbortHandle, Abortable};
+use futures::{SinkExt, StreamExt};
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use libp2p_rs::core::multiaddr::protocol::Protocol;
+use libp2p_rs::core::upgrade::UpgradeInfo;
+use libp2p_rs::core::{Multiaddr, PeerId, ProtocolId};
+use libp2p_rs::runtime::task;
+use libp2p_rs::swarm::protocol_handler::{
+    IProtocolHandler, Notifiee, ProtocolHandler, ProtocolImpl,
+};
+use libp2p_rs::swarm::substream::Substream;
+use libp2p_rs::swarm::Control as SwarmControl;
+
+use crate::circuit_pb::{hop_message, stop_message, HopMessage, Status};
+use crate::error::RelayError;
+use crate::message::{check_status, parse_peer, read_hop, read_stop, stop_status, write_message};
+use crate::transport::{circuit_addr, CircuitStream, RelayTransport};
+use crate::{HOP_PROTO_ID, STOP_PROTO_ID};
+
+/// Reservations are renewed this long before they expire.
+const RENEW_MARGIN: Duration = Duration::from_secs(60);
+/// Delay before retrying a failed reservation.
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+
+#[derive(Clone)]
+struct Handler {
+    incoming_tx: mpsc::UnboundedSender<Substream>,
+}
+
+impl UpgradeInfo for Handler {
+    type Info = ProtocolId;
+
+    fn protocol_info(&self) -> Vec<Self::Info> {
+        vec![STOP_PROTO_ID.into()]
+    }
+}
+
+impl Notifiee for Handler {}
+
+#[async_trait]
+impl ProtocolHandler for Handler {
+    async fn handle(
+        &mut self,
+        stream: Substream,
+        _info: <Self as UpgradeInfo>::Info,
+    ) -> Result<(), Box<dyn Error>> {
+        log::trace!("Handle stop stream from {}", stream.remote_peer());
+        self.incoming_tx.send(stream).await?;
+        Ok(())
+    }
+
+    fn box_clone(&self) -> IProtocolHandler {
+        Box::new(self.clone())
+    }
+}
+
+/// Reserves slots on relays and accepts the connections relayed to the local peer.
+pub struct RelayClient {
+    relays: Vec<(PeerId, Multiaddr)>,
+    swarm: Arc<Mutex<Option<SwarmControl>>>,
+    addrs: Arc<Mutex<Vec<Multiaddr>>>,
+    circuit_tx: mpsc::UnboundedSender<CircuitStream>,
+    circuit_rx: Arc<Mutex<Option<mpsc::UnboundedReceiver<CircuitStream>>>>,
+    stop_tx: mpsc::UnboundedSender<Substream>,
+    stop_rx: mpsc::UnboundedReceiver<Substream>,
+    /// Stops the client loop and the reservation loops.
+    tasks: Arc<Mutex<Vec<AbortHandle>>>,
+}
+
+impl RelayClient {
+    /// Creates a relay client reserving a slot on each of the `relays`.
+    pub fn new(relays: Vec<(PeerId, Multiaddr)>) -> Self {
+        let (circuit_tx, circuit_rx) = mpsc::unbounded();
+        let (stop_tx, stop_rx) = mpsc::unbounded();
+        Self {
+            relays,
+            swarm: Default::default(),
+            addrs: Default::default(),
+            circuit_tx,
+            circuit_rx: Arc::new(Mutex::new(Some(circuit_rx))),
+            stop_tx,
+            stop_rx,
+            tasks: Default::default(),
+        }
+    }
+
+    /// Returns the transport dialing and accepting relayed connections.
+    pub fn transport(&self) -> RelayTransport {
+        RelayTransport {
+            swarm: self.swarm.clone(),
+            incoming: self.circuit_rx.clone(),
+        }
+    }
+
+    pub fn control(&self) -> RelayControl {
+        RelayControl {
+            addrs: self.addrs.clone(),
+            tasks: self.tasks.clone(),
+        }
+    }
+}
+
+impl ProtocolImpl for RelayClient {
+    fn handler(&self) -> IProtocolHandler {
+        Box::new(Handler {
+            incoming_tx: self.stop_tx.clone(),
+        })
+    }
+
+    fn start(self, swarm: SwarmControl) -> Option<task::TaskHandle<()>>
+    where
+        Self: Sized,
+    {
+        *self.swarm.lock().unwrap() = Some(swarm.clone());
+        let mut tasks = self.tasks.lock().unwrap();
+        for (relay, relay_addr) in self.relays.iter().cloned() {
+            let swarm = swarm.clone();
+            let addrs = self.addrs.clone();
+            let (abort, registration) = AbortHandle::new_pair();
+            tasks.push(abort);
+            let reservations = reservation_loop(swarm, relay, relay_addr, addrs);
+            task::spawn(async move {
+                let _ = Abortable::new(reservations, registration).await;
+            });
+        }
+        let (abort, registration) = AbortHandle::new_pair();
+        tasks.push(abort);
+        drop(tasks);
+
+        let RelayClient {
+            circuit_tx,
+            circuit_rx,
+            mut stop_rx,
+            ..
+        } = self;
+        let client = async move {
+            while let Some(stream) = stop_rx.next().await {
+                // the listener takes the receiver, without it relayed connections are refused.
+                let listening = circuit_rx.lock().unwrap().is_none();
+                let circuit_tx = circuit_tx.clone();
+                task::spawn(async move {
+                    let relay = stream.remote_peer();
+                    if let Err(err) = accept(stream, listening, circuit_tx).await {
+                        log::debug!("relayed connection through {} failed: {}", relay, err);
+                    }
+                });
+            }
+        };
+        Some(task::spawn(async move {
+            log::info!("starting relay client loop...");
+            let _ = Abortable::new(client, registration).await;
+            log::info!("exiting relay client loop...");
+        }))
+    }
+}
+
+/// Answers a connect request of a relay, handing the stream to the listener.
+async fn accept(
+    mut stream: Substream,
+    listening: bool,
+    circuit_tx: mpsc::UnboundedSender<CircuitStream>,
+) -> Result<(), RelayError> {
+    let relay = stream.remote_peer();
+    let msg = read_stop(&mut stream).await?;
+    if stop_message::Type::from_i32(msg.r#type) != Some(stop_message::Type::Connect) {
+        write_message(&mut stream, &stop_status(Status::UnexpectedMessage)).await?;
+        return Err(RelayError::UnexpectedMessage);
+    }
+    let src = parse_peer(msg.peer.as_ref())?;
+    if !listening {
+        write_message(&mut stream, &stop_status(Status::PermissionDenied)).await?;
+        return Ok(());
+    }
+    write_message(&mut stream, &stop_status(Status::Ok)).await?;
+    log::debug!("accepted connection from {} through {}", src, relay);
+    let local_addr = Multiaddr::empty().with(Protocol::P2pCircuit);
+    let remote_addr = Multiaddr::empty()
+        .with(Protocol::P2p(relay.into()))
+        .with(Protocol::P2pCircuit)
+        .with(Protocol::P2p(src.into()));
+    circuit_tx
+        .unbounded_send(CircuitStream::new(stream, local_addr, remote_addr))
+        .map_err(|_| RelayError::Swarm("listener closed".into()))
+}
+
+/// Keeps a reservation on a relay, publishing the circuit address while it is valid.
+async fn reservation_loop(
+    mut swarm: SwarmControl,
+    relay: PeerId,
+    relay_addr: Multiaddr,
+    addrs: Arc<Mutex<Vec<Multiaddr>>>,
+) {
+    let addr = circuit_addr(&relay, &relay_addr);
+    loop {
+        let delay = match reserve(&mut swarm, relay, relay_addr.clone()).await {
+            Ok(expire) => {
+                log::debug!("reserved a slot on relay {}", relay);
+                let mut addrs = addrs.lock().unwrap();
+                if !addrs.contains(&addr) {
+                    addrs.push(addr.clone());
+                }
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                Duration::from_secs(expire.saturating_sub(now))
+                    .checked_sub(RENEW_MARGIN)
+                    .unwrap_or(RETRY_DELAY)
+                    .max(RETRY_DELAY)
+            }
+            Err(err) => {
+                log::debug!("reservation on relay {} failed: {}", relay, err);
+                addrs.lock().unwrap().retain(|a| a != &addr);
+                RETRY_DELAY
+            }
+        };
+        task::sleep(delay).await;
+    }
+}
+
+/// Reserves a slot on a relay, returning the unix time the reservation expires.
+async fn reserve(
+    swarm: &mut SwarmControl,
+    relay: PeerId,
+    relay_addr: Multiaddr,
+) -> Result<u64, RelayError> {
+    swarm
+        .connect_with_addrs(relay, vec![relay_addr])
+        .await
+        .map_err(|err| RelayError::Swarm(err.to_string()))?;
+    let mut stream = swarm
+        .new_stream(relay, vec![HOP_PROTO_ID.into()])
+        .await
+        .map_err(|err| RelayError::Swarm(err.to_string()))?;
+    let msg = HopMessage {
+        r#type: hop_message::Type::Reserve as i32,
+        peer: None,
+        reservation: None,
+        limit: None,
+        status: None,
+    };
+    write_message(&mut stream, &msg).await?;
+    let msg = read_hop(&mut stream).await?;
+    check_status(msg.status)?;
+    let reservation = msg.reservation.ok_or(RelayError::UnexpectedMessage)?;
+    Ok(reservation.expire)
+}
+
+/// Returns the relay addresses of the local peer.
+#[derive(Clone)]
+pub struct RelayControl {
+    addrs: Arc<Mutex<Vec<Multiaddr>>>,
+    tasks: Arc<Mutex<Vec<AbortHandle>>>,
+}
+
+impl RelayControl {
+    /// Returns the `/p2p-circuit` addresses of the relays holding a reservation for the
+    /// local peer.
+    pub fn addrs(&self) -> Vec<Multiaddr> {
+        self.addrs.lock().unwrap().clone()
+    }
+
+    /// Stops renewing the reservations and accepting relayed connections.
+    pub fn close(&self) {
+        for task in self.tasks.lock().unwrap().drain(..) {
+            task.abort();
+        }
+        self.addrs.lock().unwrap().clear();
+    }
+}
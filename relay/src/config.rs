@@ -0,0 +1,31 @@
+use std::time::Duration;
+
+/// Resource limits of a relay server.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RelayServerConfig {
+    /// The maximum number of active reservations.
+    pub max_reservations: usize,
+    /// How long a reservation is valid, peers renew their reservation before it expires.
+    pub reservation_duration: Duration,
+    /// The maximum number of relayed connections.
+    pub max_circuits: usize,
+    /// The maximum number of relayed connections initiated by a single peer.
+    pub max_circuits_per_peer: usize,
+    /// The maximum duration of a relayed connection.
+    pub max_circuit_duration: Duration,
+    /// The maximum number of bytes relayed in each direction of a relayed connection.
+    pub max_circuit_bytes: u64,
+}
+
+impl Default for RelayServerConfig {
+    fn default() -> Self {
+        Self {
+            max_reservations: 128,
+            reservation_duration: Duration::from_secs(60 * 60),
+            max_circuits: 16,
+            max_circuits_per_peer: 4,
+            max_circuit_duration: Duration::from_secs(2 * 60),
+            max_circuit_bytes: 1 << 17,
+        }
+    }
+}
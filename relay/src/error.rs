@@ -0,0 +1,21 @@
+use thiserror::Error;
+
+use crate::circuit_pb::Status;
+
+#[derive(Debug, Error)]
+pub enum RelayError {
+    #[error("Error while decoding relay message: {0}")]
+    ProtobufError(#[from] prost::DecodeError),
+    #[error("Invalid peer id")]
+    InvalidPeerId,
+    #[error("Unexpected relay message")]
+    UnexpectedMessage,
+    #[error("Relay responded with status {0:?}")]
+    Status(Status),
+    #[error("Invalid circuit address")]
+    InvalidAddress,
+    #[error("Io error {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Swarm error {0}")]
+    Swarm(String),
+}
@@ -0,0 +1,29 @@
+//! Circuit relay v2.
+//!
+//! A relay server lets peers behind a NAT reserve a slot, other peers then connect to the
+//! reserving peer through the relay using a `/p2p-circuit` address. The relay only forwards
+//! a limited amount of data for a limited duration, it is meant for establishing contact
+//! rather than for bulk transfers.
+
+mod client;
+mod config;
+mod error;
+mod message;
+mod server;
+mod transport;
+
+pub use client::{RelayClient, RelayControl};
+pub use config::RelayServerConfig;
+pub use error::RelayError;
+pub use server::RelayServer;
+pub use transport::{circuit_addr, CircuitListener, CircuitStream, RelayTransport};
+
+const HOP_PROTO_ID: &[u8] = b"/libp2p/circuit/relay/0.2.0/hop";
+const STOP_PROTO_ID: &[u8] = b"/libp2p/circuit/relay/0.2.0/stop";
+
+/// Multiaddr protocol code of `/p2p-circuit`.
+const P2P_CIRCUIT: u32 = 290;
+
+mod circuit_pb {
+    include!(concat!(env!("OUT_DIR"), "/circuit_pb.rs"));
+}
@@ -0,0 +1,79 @@
+use libp2p_rs::core::PeerId;
+use libp2p_rs::traits::{ReadEx, WriteEx};
+use prost::Message;
+
+use crate::circuit_pb::{hop_message, stop_message, HopMessage, Limit, Peer, Status, StopMessage};
+use crate::error::RelayError;
+
+/// The maximum size of a hop or stop message.
+pub(crate) const MAX_MESSAGE_SIZE: usize = 4096;
+
+pub(crate) async fn write_message<S: WriteEx + Unpin, M: Message>(
+    stream: &mut S,
+    msg: &M,
+) -> Result<(), RelayError> {
+    let mut buf = Vec::with_capacity(msg.encoded_len());
+    msg.encode(&mut buf)
+        .expect("Vec<u8> provides capacity as needed");
+    stream.write_one(&buf).await?;
+    Ok(())
+}
+
+pub(crate) async fn read_hop<S: ReadEx + Unpin>(stream: &mut S) -> Result<HopMessage, RelayError> {
+    let buf = stream.read_one(MAX_MESSAGE_SIZE).await?;
+    Ok(HopMessage::decode(buf.as_slice())?)
+}
+
+pub(crate) async fn read_stop<S: ReadEx + Unpin>(
+    stream: &mut S,
+) -> Result<StopMessage, RelayError> {
+    let buf = stream.read_one(MAX_MESSAGE_SIZE).await?;
+    Ok(StopMessage::decode(buf.as_slice())?)
+}
+
+pub(crate) fn hop_status(status: Status) -> HopMessage {
+    HopMessage {
+        r#type: hop_message::Type::Status as i32,
+        peer: None,
+        reservation: None,
+        limit: None,
+        status: Some(status as i32),
+    }
+}
+
+pub(crate) fn stop_status(status: Status) -> StopMessage {
+    StopMessage {
+        r#type: stop_message::Type::Status as i32,
+        peer: None,
+        limit: None,
+        status: Some(status as i32),
+    }
+}
+
+pub(crate) fn peer(peer_id: &PeerId) -> Peer {
+    Peer {
+        id: peer_id.to_bytes(),
+        addrs: vec![],
+    }
+}
+
+pub(crate) fn parse_peer(peer: Option<&Peer>) -> Result<PeerId, RelayError> {
+    let peer = peer.ok_or(RelayError::UnexpectedMessage)?;
+    PeerId::from_bytes(&peer.id).map_err(|_| RelayError::InvalidPeerId)
+}
+
+/// Returns an error unless the message status is `OK`.
+pub(crate) fn check_status(status: Option<i32>) -> Result<(), RelayError> {
+    match status.and_then(Status::from_i32) {
+        Some(Status::Ok) => Ok(()),
+        Some(status) => Err(RelayError::Status(status)),
+        None => Err(RelayError::UnexpectedMessage),
+    }
+}
+
+pub(crate) fn limit(duration: std::time::Duration, data: u64) -> Limit {
+    Limit {
+        duration: Some(duration.as_secs() as u32),
+        data: Some(data),
+    }
+}
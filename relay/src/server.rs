@@ -0,0 +1,344 @@
+use async_trait::async_trait;
+use futures::channel::mpsc;
+use futures::{SinkExt, StreamExt};
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use libp2p_rs::core::upgrade::UpgradeInfo;
+use libp2p_rs::core::{PeerId, ProtocolId};
+use libp2p_rs::runtime::task;
+use libp2p_rs::swarm::protocol_handler::{
+    IProtocolHandler, Notifiee, ProtocolHandler, ProtocolImpl,
+};
+use libp2p_rs::swarm::substream::Substream;
+use libp2p_rs::swarm::Control as SwarmControl;
+use libp2p_rs::traits::{ReadEx, WriteEx};
+
+use crate::circuit_pb::{hop_message, stop_message, HopMessage, Reservation, Status, StopMessage};
+use crate::config::RelayServerConfig;
+use crate::error::RelayError;
+use crate::message::{
+    check_status, hop_status, limit, parse_peer, peer, read_hop, read_stop, write_message,
+};
+use crate::{HOP_PROTO_ID, STOP_PROTO_ID};
+
+#[derive(Default)]
+struct State {
+    reservations: HashMap<PeerId, Instant>,
+    circuits: HashMap<PeerId, usize>,
+}
+
+impl State {
+    fn reserve(&mut self, peer: PeerId, config: &RelayServerConfig) -> Result<Instant, Status> {
+        let now = Instant::now();
+        self.reservations.retain(|_, expires| *expires > now);
+        if !self.reservations.contains_key(&peer)
+            && self.reservations.len() >= config.max_reservations
+        {
+            return Err(Status::ReservationRefused);
+        }
+        let expires = now + config.reservation_duration;
+        self.reservations.insert(peer, expires);
+        Ok(expires)
+    }
+
+    fn has_reservation(&self, peer: &PeerId) -> bool {
+        self.reservations
+            .get(peer)
+            .map(|expires| *expires > Instant::now())
+            .unwrap_or_default()
+    }
+
+    fn open_circuit(&mut self, src: PeerId, config: &RelayServerConfig) -> Result<(), Status> {
+        let total: usize = self.circuits.values().sum();
+        let count = self.circuits.get(&src).copied().unwrap_or_default();
+        if total >= config.max_circuits || count >= config.max_circuits_per_peer {
+            return Err(Status::ResourceLimitExceeded);
+        }
+        self.circuits.insert(src, count + 1);
+        Ok(())
+    }
+
+    fn close_circuit(&mut self, src: &PeerId) {
+        if let Some(count) = self.circuits.get_mut(src) {
+            *count -= 1;
+            if *count == 0 {
+                self.circuits.remove(src);
+            }
+        }
+    }
+}
+
+/// Releases a circuit slot when the circuit is closed.
+struct CircuitGuard(Arc<Mutex<State>>, PeerId);
+
+impl Drop for CircuitGuard {
+    fn drop(&mut self) {
+        self.0.lock().unwrap().close_circuit(&self.1);
+    }
+}
+
+#[derive(Clone)]
+struct Handler {
+    incoming_tx: mpsc::UnboundedSender<Substream>,
+}
+
+impl UpgradeInfo for Handler {
+    type Info = ProtocolId;
+
+    fn protocol_info(&self) -> Vec<Self::Info> {
+        vec![HOP_PROTO_ID.into()]
+    }
+}
+
+impl Notifiee for Handler {}
+
+#[async_trait]
+impl ProtocolHandler for Handler {
+    async fn handle(
+        &mut self,
+        stream: Substream,
+        _info: <Self as UpgradeInfo>::Info,
+    ) -> Result<(), Box<dyn Error>> {
+        log::trace!("Handle hop stream from {}", stream.remote_peer());
+        self.incoming_tx.send(stream).await?;
+        Ok(())
+    }
+
+    fn box_clone(&self) -> IProtocolHandler {
+        Box::new(self.clone())
+    }
+}
+
+/// Relays connections to peers that reserved a slot.
+pub struct RelayServer {
+    config: RelayServerConfig,
+    state: Arc<Mutex<State>>,
+    incoming_tx: mpsc::UnboundedSender<Substream>,
+    incoming_rx: mpsc::UnboundedReceiver<Substream>,
+}
+
+impl RelayServer {
+    pub fn new(config: RelayServerConfig) -> Self {
+        let (incoming_tx, incoming_rx) = mpsc::unbounded();
+        Self {
+            config,
+            state: Default::default(),
+            incoming_tx,
+            incoming_rx,
+        }
+    }
+}
+
+impl ProtocolImpl for RelayServer {
+    fn handler(&self) -> IProtocolHandler {
+        Box::new(Handler {
+            incoming_tx: self.incoming_tx.clone(),
+        })
+    }
+
+    fn start(self, swarm: SwarmControl) -> Option<task::TaskHandle<()>>
+    where
+        Self: Sized,
+    {
+        let RelayServer {
+            config,
+            state,
+            mut incoming_rx,
+            ..
+        } = self;
+        Some(task::spawn(async move {
+            log::info!("starting relay server loop...");
+            while let Some(stream) = incoming_rx.next().await {
+                let hop = Hop {
+                    config: config.clone(),
+                    state: state.clone(),
+                    swarm: swarm.clone(),
+                };
+                task::spawn(async move {
+                    let peer = stream.remote_peer();
+                    if let Err(err) = hop.handle(stream).await {
+                        log::debug!("hop request from {} failed: {}", peer, err);
+                    }
+                });
+            }
+            log::info!("exiting relay server loop...");
+        }))
+    }
+}
+
+struct Hop {
+    config: RelayServerConfig,
+    state: Arc<Mutex<State>>,
+    swarm: SwarmControl,
+}
+
+impl Hop {
+    async fn handle(mut self, mut stream: Substream) -> Result<(), RelayError> {
+        let src = stream.remote_peer();
+        let msg = read_hop(&mut stream).await?;
+        match hop_message::Type::from_i32(msg.r#type) {
+            Some(hop_message::Type::Reserve) => self.reserve(src, stream).await,
+            Some(hop_message::Type::Connect) => match parse_peer(msg.peer.as_ref()) {
+                Ok(dst) => self.connect(src, dst, stream).await,
+                Err(err) => {
+                    write_message(&mut stream, &hop_status(Status::MalformedMessage)).await?;
+                    Err(err)
+                }
+            },
+            _ => {
+                write_message(&mut stream, &hop_status(Status::UnexpectedMessage)).await?;
+                Err(RelayError::UnexpectedMessage)
+            }
+        }
+    }
+
+    async fn reserve(&mut self, src: PeerId, mut stream: Substream) -> Result<(), RelayError> {
+        let res = self.state.lock().unwrap().reserve(src, &self.config);
+        let expires = match res {
+            Ok(expires) => expires,
+            Err(status) => return write_message(&mut stream, &hop_status(status)).await,
+        };
+        log::debug!("reserved relay slot for {}", src);
+        let expire = SystemTime::now() + (expires - Instant::now());
+        let expire = expire
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let addrs = self.swarm.self_addrs().await.unwrap_or_default();
+        let msg = HopMessage {
+            reservation: Some(Reservation {
+                expire,
+                addrs: addrs.iter().map(|addr| addr.to_vec()).collect(),
+                voucher: None,
+            }),
+            limit: Some(limit(
+                self.config.max_circuit_duration,
+                self.config.max_circuit_bytes,
+            )),
+            ..hop_status(Status::Ok)
+        };
+        write_message(&mut stream, &msg).await
+    }
+
+    async fn connect(
+        &mut self,
+        src: PeerId,
+        dst: PeerId,
+        mut stream: Substream,
+    ) -> Result<(), RelayError> {
+        if !self.state.lock().unwrap().has_reservation(&dst) {
+            return write_message(&mut stream, &hop_status(Status::NoReservation)).await;
+        }
+        let res = self.state.lock().unwrap().open_circuit(src, &self.config);
+        if let Err(status) = res {
+            return write_message(&mut stream, &hop_status(status)).await;
+        }
+        let _guard = CircuitGuard(self.state.clone(), src);
+
+        let circuit_limit = limit(
+            self.config.max_circuit_duration,
+            self.config.max_circuit_bytes,
+        );
+        let dst_stream = async {
+            let mut dst_stream = self
+                .swarm
+                .new_stream(dst, vec![STOP_PROTO_ID.into()])
+                .await
+                .map_err(|err| RelayError::Swarm(err.to_string()))?;
+            let msg = StopMessage {
+                r#type: stop_message::Type::Connect as i32,
+                peer: Some(peer(&src)),
+                limit: Some(circuit_limit.clone()),
+                status: None,
+            };
+            write_message(&mut dst_stream, &msg).await?;
+            check_status(read_stop(&mut dst_stream).await?.status)?;
+            Ok::<_, RelayError>(dst_stream)
+        };
+        let dst_stream = match dst_stream.await {
+            Ok(dst_stream) => dst_stream,
+            Err(err) => {
+                write_message(&mut stream, &hop_status(Status::ConnectionFailed)).await?;
+                return Err(err);
+            }
+        };
+        let msg = HopMessage {
+            limit: Some(circuit_limit),
+            ..hop_status(Status::Ok)
+        };
+        write_message(&mut stream, &msg).await?;
+
+        log::debug!("relaying {} -> {}", src, dst);
+        relay(
+            stream,
+            dst_stream,
+            self.config.max_circuit_duration,
+            self.config.max_circuit_bytes,
+        )
+        .await;
+        log::debug!("closed relay {} -> {}", src, dst);
+        Ok(())
+    }
+}
+
+/// Copies data in both directions until either side closes or a limit is reached.
+async fn relay(a: Substream, b: Substream, duration: Duration, bytes: u64) {
+    let a_to_b = copy(a.clone(), b.clone(), bytes);
+    let b_to_a = copy(b.clone(), a.clone(), bytes);
+    let _ = task::timeout(duration, futures::future::join(a_to_b, b_to_a)).await;
+    let (mut a, mut b) = (a, b);
+    let _ = a.close2().await;
+    let _ = b.close2().await;
+}
+
+async fn copy(mut from: Substream, mut to: Substream, limit: u64) -> std::io::Result<()> {
+    let mut relayed = 0u64;
+    let mut buf = vec![0u8; 4096];
+    loop {
+        let n = from.read2(&mut buf).await?;
+        if n == 0 {
+            return to.close2().await;
+        }
+        relayed += n as u64;
+        if relayed > limit {
+            log::debug!("relay data limit reached");
+            return to.close2().await;
+        }
+        to.write_all2(&buf[..n]).await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits() {
+        let config = RelayServerConfig {
+            max_reservations: 1,
+            max_circuits: 2,
+            max_circuits_per_peer: 1,
+            ..Default::default()
+        };
+        let mut state = State::default();
+        let (a, b, c) = (PeerId::random(), PeerId::random(), PeerId::random());
+        assert!(state.reserve(a, &config).is_ok());
+        // renewing a reservation doesn't count against the limit
+        assert!(state.reserve(a, &config).is_ok());
+        assert_eq!(state.reserve(b, &config), Err(Status::ReservationRefused));
+        assert!(state.has_reservation(&a));
+        assert!(!state.has_reservation(&b));
+
+        assert!(state.open_circuit(b, &config).is_ok());
+        assert_eq!(
+            state.open_circuit(b, &config),
+            Err(Status::ResourceLimitExceeded)
+        );
+        assert!(state.open_circuit(c, &config).is_ok());
+        state.close_circuit(&b);
+        assert!(state.open_circuit(b, &config).is_ok());
+    }
+}
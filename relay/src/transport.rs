@@ -0,0 +1,233 @@
+use async_trait::async_trait;
+use futures::channel::mpsc;
+use futures::{AsyncRead, AsyncWrite, StreamExt};
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use libp2p_rs::core::multiaddr::protocol::Protocol;
+use libp2p_rs::core::transport::{
+    ConnectionInfo, IListener, ITransport, ListenerEvent, TransportError, TransportListener,
+};
+use libp2p_rs::core::{Multiaddr, PeerId, Transport};
+use libp2p_rs::swarm::substream::Substream;
+use libp2p_rs::swarm::Control as SwarmControl;
+
+use crate::circuit_pb::{hop_message, HopMessage};
+use crate::error::RelayError;
+use crate::message::{check_status, peer, read_hop, write_message};
+use crate::{HOP_PROTO_ID, P2P_CIRCUIT};
+
+/// Returns the `/p2p-circuit` address of the local peer through the relay at `relay_addr`.
+pub fn circuit_addr(relay: &PeerId, relay_addr: &Multiaddr) -> Multiaddr {
+    let mut addr = relay_addr.clone();
+    if !matches!(addr.iter().last(), Some(Protocol::P2p(_))) {
+        addr.push(Protocol::P2p((*relay).into()));
+    }
+    addr.push(Protocol::P2pCircuit);
+    addr
+}
+
+/// Splits `<relay addr>/p2p/<relay>/p2p-circuit/p2p/<dst>` into the relay, the relay
+/// address if any and the destination.
+fn parse_circuit(addr: &Multiaddr) -> Result<(PeerId, Option<Multiaddr>, PeerId), RelayError> {
+    let mut relay_addr = Multiaddr::empty();
+    let mut relay = None;
+    let mut dst = None;
+    let mut circuit = false;
+    for protocol in addr.iter() {
+        match protocol {
+            Protocol::P2pCircuit => circuit = true,
+            Protocol::P2p(hash) if circuit => {
+                dst = Some(PeerId::from_multihash(hash).map_err(|_| RelayError::InvalidPeerId)?);
+            }
+            Protocol::P2p(hash) => {
+                relay = Some(PeerId::from_multihash(hash).map_err(|_| RelayError::InvalidPeerId)?);
+            }
+            protocol if !circuit => relay_addr.push(protocol),
+            _ => return Err(RelayError::InvalidAddress),
+        }
+    }
+    let relay_addr = if relay_addr.is_empty() {
+        None
+    } else {
+        Some(relay_addr)
+    };
+    match (relay, dst) {
+        (Some(relay), Some(dst)) if circuit => Ok((relay, relay_addr, dst)),
+        _ => Err(RelayError::InvalidAddress),
+    }
+}
+
+/// A connection relayed through a relay server.
+pub struct CircuitStream {
+    stream: Substream,
+    local_addr: Multiaddr,
+    remote_addr: Multiaddr,
+}
+
+impl CircuitStream {
+    pub(crate) fn new(stream: Substream, local_addr: Multiaddr, remote_addr: Multiaddr) -> Self {
+        Self {
+            stream,
+            local_addr,
+            remote_addr,
+        }
+    }
+}
+
+impl ConnectionInfo for CircuitStream {
+    fn local_multiaddr(&self) -> Multiaddr {
+        self.local_addr.clone()
+    }
+
+    fn remote_multiaddr(&self) -> Multiaddr {
+        self.remote_addr.clone()
+    }
+}
+
+impl AsyncRead for CircuitStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for CircuitStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_close(cx)
+    }
+}
+
+/// Accepts the connections relayed to the local peer.
+pub struct CircuitListener {
+    addr: Multiaddr,
+    incoming: mpsc::UnboundedReceiver<CircuitStream>,
+}
+
+#[async_trait]
+impl TransportListener for CircuitListener {
+    type Output = CircuitStream;
+
+    async fn accept(&mut self) -> Result<ListenerEvent<Self::Output>, TransportError> {
+        match self.incoming.next().await {
+            Some(stream) => Ok(ListenerEvent::Accepted(stream)),
+            None => Err(TransportError::Internal),
+        }
+    }
+
+    fn multi_addr(&self) -> Vec<Multiaddr> {
+        vec![self.addr.clone()]
+    }
+}
+
+/// Dials peers through relays and listens on `/p2p-circuit` for relayed connections.
+///
+/// Relayed connections are plain streams, the transport is upgraded with the same security
+/// and muxer protocols as the other transports.
+#[derive(Clone)]
+pub struct RelayTransport {
+    pub(crate) swarm: Arc<Mutex<Option<SwarmControl>>>,
+    pub(crate) incoming: Arc<Mutex<Option<mpsc::UnboundedReceiver<CircuitStream>>>>,
+}
+
+impl RelayTransport {
+    async fn connect(&self, addr: &Multiaddr) -> Result<CircuitStream, RelayError> {
+        let (relay, relay_addr, dst) = parse_circuit(addr)?;
+        let mut swarm = self
+            .swarm
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or_else(|| RelayError::Swarm("not started".into()))?;
+        if let Some(relay_addr) = relay_addr {
+            swarm
+                .connect_with_addrs(relay, vec![relay_addr])
+                .await
+                .map_err(|err| RelayError::Swarm(err.to_string()))?;
+        }
+        let mut stream = swarm
+            .new_stream(relay, vec![HOP_PROTO_ID.into()])
+            .await
+            .map_err(|err| RelayError::Swarm(err.to_string()))?;
+        let msg = HopMessage {
+            r#type: hop_message::Type::Connect as i32,
+            peer: Some(peer(&dst)),
+            reservation: None,
+            limit: None,
+            status: None,
+        };
+        write_message(&mut stream, &msg).await?;
+        check_status(read_hop(&mut stream).await?.status)?;
+        log::debug!("connected to {} through {}", dst, relay);
+        let local_addr = Multiaddr::empty().with(Protocol::P2pCircuit);
+        Ok(CircuitStream::new(stream, local_addr, addr.clone()))
+    }
+}
+
+#[async_trait]
+impl Transport for RelayTransport {
+    type Output = CircuitStream;
+
+    fn listen_on(&mut self, addr: Multiaddr) -> Result<IListener<Self::Output>, TransportError> {
+        if !addr.iter().any(|p| p == Protocol::P2pCircuit) {
+            return Err(TransportError::MultiaddrNotSupported(addr));
+        }
+        let incoming = self
+            .incoming
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or(TransportError::Internal)?;
+        Ok(Box::new(CircuitListener { addr, incoming }))
+    }
+
+    async fn dial(&mut self, addr: Multiaddr) -> Result<Self::Output, TransportError> {
+        self.connect(&addr).await.map_err(|err| {
+            log::debug!("relayed dial to {} failed: {}", addr, err);
+            TransportError::Internal
+        })
+    }
+
+    fn box_clone(&self) -> ITransport<Self::Output> {
+        Box::new(self.clone())
+    }
+
+    fn protocols(&self) -> Vec<u32> {
+        vec![P2P_CIRCUIT]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_circuit() {
+        let relay = PeerId::random();
+        let dst = PeerId::random();
+        let relay_addr: Multiaddr = "/ip4/127.0.0.1/tcp/4001".parse().unwrap();
+        let addr = circuit_addr(&relay, &relay_addr).with(Protocol::P2p(dst.into()));
+        let (r, a, d) = parse_circuit(&addr).unwrap();
+        assert_eq!(r, relay);
+        assert_eq!(a, Some(relay_addr));
+        assert_eq!(d, dst);
+        assert!(parse_circuit(&"/ip4/127.0.0.1/tcp/4001".parse().unwrap()).is_err());
+    }
+}
@@ -0,0 +1,24 @@
+use rusqlite::backup::{Backup, StepResult};
+use rusqlite::Connection;
+use std::path::Path;
+use std::time::Duration;
+
+/// How long to wait before retrying a backup step when the source is locked.
+const BUSY_SLEEP: Duration = Duration::from_millis(10);
+
+/// Copies the database of `src` to `path`.
+///
+/// All pages are copied in a single step. A step runs in one read transaction, so the copy
+/// is a consistent snapshot and, in WAL mode, writers continue during the copy. A backup in
+/// several steps would restart whenever another connection writes to the database.
+pub(crate) fn backup(src: &Connection, path: &Path) -> rusqlite::Result<()> {
+    let mut dst = Connection::open(path)?;
+    let backup = Backup::new(src, &mut dst)?;
+    loop {
+        match backup.step(-1)? {
+            StepResult::Done => return Ok(()),
+            StepResult::More => {}
+            StepResult::Busy | StepResult::Locked => std::thread::sleep(BUSY_SLEEP),
+        }
+    }
+}
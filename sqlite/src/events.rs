@@ -0,0 +1,240 @@
+use futures::channel::mpsc;
+use futures::stream::Stream;
+use ipfs_sqlite_block_store::cache::BlockInfo;
+use libipld::Cid;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, SystemTime};
+
+use crate::stats::{GcHistory, GcRun};
+
+/// A change of the block store.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum StorageEvent {
+    /// A block was written.
+    Inserted(Cid),
+    /// A block was removed by the garbage collector.
+    Removed(Cid),
+    /// An alias was created, changed or removed.
+    AliasChanged {
+        alias: Vec<u8>,
+        old: Option<Cid>,
+        new: Option<Cid>,
+    },
+    /// A garbage collector run completed.
+    GcCompleted {
+        /// The number of removed blocks.
+        blocks: u64,
+        /// The number of freed bytes.
+        bytes: u64,
+        duration: Duration,
+    },
+}
+
+/// A subscriber fell behind and missed events.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Lagged(pub u64);
+
+impl std::fmt::Display for Lagged {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "storage event subscriber missed {} events", self.0)
+    }
+}
+
+impl std::error::Error for Lagged {}
+
+#[derive(Debug)]
+struct Queue {
+    events: VecDeque<StorageEvent>,
+    capacity: usize,
+    lagged: u64,
+    waker: Option<Waker>,
+    closed: bool,
+}
+
+/// Broadcasts storage events to subscribers with bounded queues. When a queue is full the
+/// oldest event is dropped and the subscriber is told how many events it missed.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Broadcast {
+    queues: Arc<Mutex<Vec<Arc<Mutex<Queue>>>>>,
+}
+
+impl Broadcast {
+    pub fn subscribe(&self, capacity: usize) -> StorageEvents {
+        let queue = Arc::new(Mutex::new(Queue {
+            events: VecDeque::with_capacity(capacity.min(1024)),
+            capacity: capacity.max(1),
+            lagged: 0,
+            waker: None,
+            closed: false,
+        }));
+        self.queues.lock().push(queue.clone());
+        StorageEvents { queue }
+    }
+
+    pub fn send(&self, event: StorageEvent) {
+        self.queues.lock().retain(|queue| {
+            let mut queue = queue.lock();
+            if queue.closed {
+                return false;
+            }
+            if queue.events.len() >= queue.capacity {
+                queue.events.pop_front();
+                queue.lagged += 1;
+            }
+            queue.events.push_back(event.clone());
+            if let Some(waker) = queue.waker.take() {
+                waker.wake();
+            }
+            true
+        });
+    }
+
+    /// Ends the streams of all subscribers once they received the queued events.
+    pub fn close(&self) {
+        for queue in self.queues.lock().drain(..) {
+            let mut queue = queue.lock();
+            queue.closed = true;
+            if let Some(waker) = queue.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// Sends storage events to the internal channel and to the subscribers.
+#[derive(Clone, Debug)]
+pub(crate) struct EventSink {
+    tx: mpsc::UnboundedSender<StorageEvent>,
+    pub broadcast: Broadcast,
+    pub gc_history: GcHistory,
+    /// The number of blocks and bytes removed since the last completed gc run.
+    removed: Arc<Mutex<(u64, u64)>>,
+}
+
+impl EventSink {
+    pub fn new(tx: mpsc::UnboundedSender<StorageEvent>) -> Self {
+        Self {
+            tx,
+            broadcast: Default::default(),
+            gc_history: Default::default(),
+            removed: Default::default(),
+        }
+    }
+
+    pub fn send(&self, event: StorageEvent) {
+        self.tx.unbounded_send(event.clone()).ok();
+        self.broadcast.send(event);
+    }
+
+    /// Sends a `Removed` event for every block deleted by the garbage collector and counts
+    /// them for the current run.
+    pub fn blocks_deleted(&self, blocks: &[BlockInfo]) {
+        {
+            let mut removed = self.removed.lock();
+            removed.0 += blocks.len() as u64;
+            removed.1 += blocks.iter().map(|b| b.block_len() as u64).sum::<u64>();
+        }
+        for block in blocks {
+            self.send(StorageEvent::Removed(*block.cid()));
+        }
+    }
+
+    /// Returns the number of blocks deleted since the last garbage collector run completed.
+    pub fn deleted_blocks(&self) -> u64 {
+        self.removed.lock().0
+    }
+
+    /// Records a garbage collector run and sends a `GcCompleted` event with the blocks
+    /// deleted since the last run completed. Inserts during the run don't offset them.
+    pub fn gc_completed(&self, duration: Duration) {
+        let (blocks, bytes) = std::mem::take(&mut *self.removed.lock());
+        self.gc_history.push(GcRun {
+            completed: SystemTime::now(),
+            blocks,
+            bytes,
+            duration,
+        });
+        self.send(StorageEvent::GcCompleted {
+            blocks,
+            bytes,
+            duration,
+        });
+    }
+}
+
+/// A stream of storage events. Returns `Err(Lagged(n))` before the next event if `n`
+/// events were dropped because the subscriber didn't keep up.
+pub struct StorageEvents {
+    queue: Arc<Mutex<Queue>>,
+}
+
+impl Stream for StorageEvents {
+    type Item = Result<StorageEvent, Lagged>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut queue = self.queue.lock();
+        if queue.lagged > 0 {
+            let lagged = queue.lagged;
+            queue.lagged = 0;
+            return Poll::Ready(Some(Err(Lagged(lagged))));
+        }
+        if let Some(event) = queue.events.pop_front() {
+            return Poll::Ready(Some(Ok(event)));
+        }
+        if queue.closed {
+            return Poll::Ready(None);
+        }
+        queue.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for StorageEvents {
+    fn drop(&mut self) {
+        self.queue.lock().closed = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream::StreamExt;
+    use libipld::multihash::{Code, MultihashDigest};
+
+    fn cid(n: u8) -> Cid {
+        Cid::new_v1(0x55, Code::Blake3_256.digest(&[n]))
+    }
+
+    #[async_std::test]
+    async fn test_lagged() {
+        let broadcast = Broadcast::default();
+        let mut events = broadcast.subscribe(2);
+        for n in 0..4 {
+            broadcast.send(StorageEvent::Inserted(cid(n)));
+        }
+        assert_eq!(events.next().await, Some(Err(Lagged(2))));
+        assert_eq!(
+            events.next().await,
+            Some(Ok(StorageEvent::Inserted(cid(2))))
+        );
+        assert_eq!(
+            events.next().await,
+            Some(Ok(StorageEvent::Inserted(cid(3))))
+        );
+        broadcast.close();
+        assert_eq!(events.next().await, None);
+    }
+
+    #[test]
+    fn test_dropped_subscriber() {
+        let broadcast = Broadcast::default();
+        let events = broadcast.subscribe(2);
+        drop(events);
+        broadcast.send(StorageEvent::Inserted(cid(0)));
+        assert!(broadcast.queues.lock().is_empty());
+    }
+}
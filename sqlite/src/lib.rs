@@ -1,22 +1,46 @@
+use fnv::{FnvHashMap, FnvHashSet};
 use futures::channel::mpsc;
 pub use ipfs_sqlite_block_store::TempPin;
 use ipfs_sqlite_block_store::{
     cache::{BlockInfo, CacheTracker, SqliteCacheTracker},
-    BlockStore, Config, SizeTargets, Synchronous,
+    BlockStore, BlockStoreError, Config, SizeTargets, Synchronous,
 };
 use lazy_static::lazy_static;
 use libipld::codec::References;
 use libipld::store::StoreParams;
 use libipld::{Block, Cid, Ipld, Result};
-use parking_lot::Mutex;
+use parking_lot::{Condvar, MappedMutexGuard, Mutex, MutexGuard};
 use prometheus::core::{Collector, Desc};
 use prometheus::proto::MetricFamily;
 use prometheus::{HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry};
 use std::future::Future;
 use std::marker::PhantomData;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+mod backup;
+mod events;
+mod meta;
+mod pool;
+mod quota;
+mod stats;
+mod verify;
+
+use crate::events::EventSink;
+pub use crate::events::{Lagged, StorageEvent, StorageEvents};
+pub use crate::meta::AliasMetadata;
+use crate::meta::MetadataStore;
+use crate::pool::ReaderPool;
+use crate::quota::Quotas;
+pub use crate::quota::{Quota, QuotaExceeded, QuotaPin};
+pub use crate::stats::{DagStat, GcRun, RepoStats, RepoUsage};
+pub use crate::verify::VerifyReport;
+
+/// The minimum number of blocks collected while the garbage collector holds the lock.
+const GC_STEP_BLOCKS: usize = 64;
+/// The target duration the garbage collector holds the lock.
+const GC_STEP_DURATION: Duration = Duration::from_millis(10);
 
 /// Storage configuration.
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -56,6 +80,16 @@ pub struct StorageConfig {
     /// This can not be guaranteed, since we guarantee to collect at least `gc_min_blocks`. But
     /// as soon as this duration is exceeded, the incremental gc will stop doing additional work.
     pub gc_target_duration: Duration,
+    /// The maximum number of idle read-only connections.
+    ///
+    /// Reads of a persistent store use their own connections, so they don't wait for writes
+    /// or the garbage collector.
+    pub readers: usize,
+    /// The byte quotas of alias namespaces.
+    ///
+    /// Aliases and inserts under a `QuotaPin` fail with `QuotaExceeded` if the dags of the
+    /// aliases starting with the prefix of a quota would grow beyond it.
+    pub quotas: Vec<Quota>,
 }
 
 impl StorageConfig {
@@ -68,74 +102,242 @@ impl StorageConfig {
             gc_interval,
             gc_min_blocks: usize::MAX,
             gc_target_duration: Duration::new(u64::MAX, 1_000_000_000 - 1),
+            readers: 4,
+            quotas: vec![],
         }
     }
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
-pub enum StorageEvent {
-    Remove(Cid),
+/// The storage was closed with `StorageService::close`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct StorageClosed;
+
+impl std::fmt::Display for StorageClosed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "storage closed")
+    }
+}
+
+impl std::error::Error for StorageClosed {}
+
+/// The block store, `None` once the storage is closed.
+type Store = Arc<Mutex<Option<BlockStore>>>;
+
+fn lock_store(store: &Mutex<Option<BlockStore>>) -> Result<MappedMutexGuard<'_, BlockStore>> {
+    MutexGuard::try_map(store.lock(), |store| store.as_mut()).map_err(|_| StorageClosed.into())
+}
+
+/// Interrupts the sleeps of the garbage collector loop when the storage is closed.
+#[derive(Default)]
+struct GcStop {
+    stopped: Mutex<bool>,
+    condvar: Condvar,
+}
+
+impl GcStop {
+    /// Sleeps for `duration`, returns `true` if the garbage collector was stopped.
+    fn sleep(&self, duration: Duration) -> bool {
+        let mut stopped = self.stopped.lock();
+        if !*stopped {
+            self.condvar.wait_for(&mut stopped, duration);
+        }
+        *stopped
+    }
+
+    fn stop(&self) {
+        *self.stopped.lock() = true;
+        self.condvar.notify_all();
+    }
 }
 
 #[derive(Clone)]
 pub struct StorageService<S: StoreParams> {
     _marker: PhantomData<S>,
-    store: Arc<Mutex<BlockStore>>,
+    store: Store,
+    meta: Arc<Mutex<Option<MetadataStore>>>,
+    readers: Option<Arc<ReaderPool>>,
+    tracker: IpfsCacheTracker,
+    events: EventSink,
+    quotas: Quotas,
+    gc_stop: Arc<GcStop>,
+    gc_task: Arc<Mutex<Option<async_global_executor::Task<()>>>>,
     gc_target_duration: Duration,
-    gc_min_blocks: usize,
 }
 
 impl<S: StoreParams> StorageService<S>
 where
     Ipld: References<S::Codecs>,
 {
+    /// Opens the block store and starts the garbage collector. Every storage event is sent
+    /// to `tx`, in addition to the subscribers.
     pub fn open(config: StorageConfig, tx: mpsc::UnboundedSender<StorageEvent>) -> Result<Self> {
+        let events = EventSink::new(tx);
         let size = SizeTargets::new(config.cache_size_blocks, config.cache_size_bytes);
         let store_config = Config::default()
             .with_size_targets(size)
             .with_pragma_synchronous(Synchronous::Normal);
-        let store = if let Some(path) = config.path {
+        let (store, meta, readers, tracker) = if let Some(path) = config.path {
             let tracker = SqliteCacheTracker::open(&path, |access, _| Some(access))?;
-            let tracker = IpfsCacheTracker { tracker, tx };
-            BlockStore::open(path, store_config.with_cache_tracker(tracker))?
+            let tracker = IpfsCacheTracker {
+                tracker: Arc::new(tracker),
+                events: events.clone(),
+            };
+            let meta = MetadataStore::open(&path)?;
+            let readers = Arc::new(ReaderPool::new(&path, config.readers));
+            let store_config = store_config.with_cache_tracker(tracker.clone());
+            (
+                BlockStore::open(path, store_config)?,
+                meta,
+                Some(readers),
+                tracker,
+            )
         } else {
+            // an in-memory database can't be shared between connections
             let tracker = SqliteCacheTracker::memory(|access, _| Some(access))?;
-            let tracker = IpfsCacheTracker { tracker, tx };
-            BlockStore::memory(store_config.with_cache_tracker(tracker))?
+            let tracker = IpfsCacheTracker {
+                tracker: Arc::new(tracker),
+                events: events.clone(),
+            };
+            let meta = MetadataStore::memory()?;
+            let store_config = store_config.with_cache_tracker(tracker.clone());
+            (BlockStore::memory(store_config)?, meta, None, tracker)
         };
-        let store = Arc::new(Mutex::new(store));
+        let persistent = readers.is_some();
+        let store = Arc::new(Mutex::new(Some(store)));
+        let meta = Arc::new(Mutex::new(Some(meta)));
         let gc = store.clone();
+        let gc_meta = meta.clone();
+        let gc_stop = Arc::new(GcStop::default());
+        let stop = gc_stop.clone();
         let gc_interval = config.gc_interval;
         let gc_min_blocks = config.gc_min_blocks;
         let gc_target_duration = config.gc_target_duration;
-        async_global_executor::spawn(async_global_executor::spawn_blocking(move || {
-            std::thread::sleep(gc_interval / 2);
+        let gc_events = events.clone();
+        let gc_task = async_global_executor::spawn_blocking(move || {
+            if stop.sleep(gc_interval / 2) {
+                return;
+            }
             loop {
                 tracing::debug!("gc_loop running incremental gc");
-                gc.lock()
-                    .incremental_gc(gc_min_blocks, gc_target_duration)
-                    .ok();
-                std::thread::sleep(gc_interval / 2);
+                let start = Instant::now();
+                run_gc(
+                    &gc,
+                    &gc_events,
+                    gc_min_blocks,
+                    gc_target_duration,
+                    |store, n, d| store.incremental_gc(n, d),
+                )
+                .ok();
+                let mut duration = start.elapsed();
+                if stop.sleep(gc_interval / 2) {
+                    break;
+                }
                 tracing::debug!("gc_loop running incremental delete orphaned");
-                gc.lock()
-                    .incremental_delete_orphaned(gc_min_blocks, gc_target_duration)
-                    .ok();
-                std::thread::sleep(gc_interval / 2);
+                let start = Instant::now();
+                run_gc(
+                    &gc,
+                    &gc_events,
+                    gc_min_blocks,
+                    gc_target_duration,
+                    |store, n, d| store.incremental_delete_orphaned(n, d),
+                )
+                .ok();
+                duration += start.elapsed();
+                gc_events.gc_completed(duration);
+                if persistent {
+                    prune_changes(&gc_meta);
+                }
+                if stop.sleep(gc_interval / 2) {
+                    break;
+                }
             }
-        }))
-        .detach();
+            tracing::debug!("gc_loop stopped");
+        });
         Ok(Self {
             _marker: PhantomData,
             gc_target_duration: config.gc_target_duration,
-            gc_min_blocks: config.gc_min_blocks,
             store,
+            meta,
+            readers,
+            tracker,
+            events,
+            quotas: Quotas::new(config.quotas),
+            gc_stop,
+            gc_task: Arc::new(Mutex::new(Some(gc_task))),
         })
     }
 
-    pub fn create_temp_pin(&self) -> Result<TempPin> {
-        observe_query::<_, std::io::Error, _>("create_temp_pin", || {
-            Ok(self.store.lock().temp_pin())
+    /// Runs a query on the block store.
+    fn query<T, E, F>(&self, name: &'static str, query: F) -> Result<T>
+    where
+        E: std::error::Error + Send + Sync + 'static,
+        F: FnOnce(&mut BlockStore) -> Result<T, E>,
+    {
+        let mut store = lock_store(&self.store)?;
+        observe_query(name, || query(&mut store))
+    }
+
+    /// Runs a query on the alias metadata.
+    fn query_meta<T, F>(&self, name: &'static str, query: F) -> Result<T>
+    where
+        F: FnOnce(&MetadataStore) -> rusqlite::Result<T>,
+    {
+        let meta = self.meta.lock();
+        let meta = meta.as_ref().ok_or(StorageClosed)?;
+        observe_query(name, || query(meta))
+    }
+
+    /// Logs the changed blocks, then runs `write` while the log stays locked. An export never
+    /// sees a change before its block is written, and a crash in between leaves a change
+    /// of a missing block, which exports skip, instead of a block missing from the log.
+    fn log_changes<'a, T, F>(&self, cids: impl IntoIterator<Item = &'a Cid>, write: F) -> Result<T>
+    where
+        F: FnOnce() -> Result<T>,
+    {
+        let meta = self.meta.lock();
+        let meta = meta.as_ref().ok_or(StorageClosed)?;
+        // the log of an in-memory store can't be pruned and there is no backup to export
+        // changes for
+        if self.readers.is_some() {
+            observe_query("blocks_changed", || meta.blocks_changed(cids))?;
+        }
+        write()
+    }
+
+    /// Stops the garbage collector, flushes the block store and closes the database. The
+    /// event subscriptions end and every following call fails with `StorageClosed`.
+    pub async fn close(&self) -> Result<()> {
+        self.gc_stop.stop();
+        let gc_task = self.gc_task.lock().take();
+        if let Some(gc_task) = gc_task {
+            gc_task.await;
+        }
+        if self.store.lock().is_none() {
+            return Ok(());
+        }
+        self.flush().await?;
+        if let Some(readers) = self.readers.as_ref() {
+            readers.close();
+        }
+        self.events.broadcast.close();
+        let store = self.store.lock().take();
+        let meta = self.meta.lock().take();
+        async_global_executor::spawn_blocking(move || {
+            drop(store);
+            drop(meta);
         })
+        .await;
+        Ok(())
+    }
+
+    /// Subscribes to storage events. At most `capacity` events are queued, when the
+    /// subscriber falls behind the oldest events are dropped.
+    pub fn subscribe(&self, capacity: usize) -> StorageEvents {
+        self.events.broadcast.subscribe(capacity)
+    }
+
+    pub fn create_temp_pin(&self) -> Result<TempPin> {
+        self.query::<_, std::io::Error, _>("create_temp_pin", |store| Ok(store.temp_pin()))
     }
 
     pub fn temp_pin(
@@ -143,67 +345,448 @@ where
         temp: &TempPin,
         iter: impl IntoIterator<Item = Cid> + Send + 'static,
     ) -> Result<()> {
-        observe_query("temp_pin", || {
-            self.store.lock().assign_temp_pin(&temp, iter)
-        })
+        self.query("temp_pin", |store| store.assign_temp_pin(&temp, iter))
     }
 
     pub fn iter(&self) -> Result<impl Iterator<Item = Cid>> {
-        let cids = observe_query("iter", || self.store.lock().get_block_cids::<Vec<Cid>>())?;
+        let cids = self.query("iter", |store| store.get_block_cids::<Vec<Cid>>())?;
         Ok(cids.into_iter())
     }
 
     pub fn contains(&self, cid: &Cid) -> Result<bool> {
-        observe_query("contains", || self.store.lock().has_block(cid))
+        if let Some(readers) = self.readers.as_ref() {
+            return observe_result("contains", || readers.contains(cid));
+        }
+        self.query("contains", |store| store.has_block(cid))
     }
 
     pub fn get(&self, cid: &Cid) -> Result<Option<Vec<u8>>> {
-        observe_query("get", || self.store.lock().get_block(cid))
+        if let Some(readers) = self.readers.as_ref() {
+            let block = observe_result("get", || readers.get(cid))?;
+            return Ok(block.map(|(id, data)| {
+                let info = BlockInfo::new(id, cid, data.len());
+                self.tracker.blocks_accessed(vec![info]);
+                data
+            }));
+        }
+        self.query("get", |store| store.get_block(cid))
     }
 
     pub fn insert(&self, block: &Block<S>) -> Result<()> {
-        observe_query("insert", || self.store.lock().put_block(block, None))
+        self.log_changes(std::iter::once(block.cid()), || {
+            self.query("insert", |store| store.put_block(block, None))
+        })?;
+        self.events.send(StorageEvent::Inserted(*block.cid()));
+        Ok(())
+    }
+
+    /// Like `contains`, but runs on the blocking thread pool.
+    pub async fn contains_async(&self, cid: &Cid) -> Result<bool> {
+        let storage = self.clone();
+        let cid = *cid;
+        async_global_executor::spawn_blocking(move || storage.contains(&cid)).await
+    }
+
+    /// Like `get`, but runs on the blocking thread pool.
+    pub async fn get_async(&self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+        let storage = self.clone();
+        let cid = *cid;
+        async_global_executor::spawn_blocking(move || storage.get(&cid)).await
+    }
+
+    /// Like `insert`, but runs on the blocking thread pool.
+    pub async fn insert_async(&self, block: Block<S>) -> Result<()> {
+        let storage = self.clone();
+        async_global_executor::spawn_blocking(move || storage.insert(&block)).await
+    }
+
+    /// Inserts the blocks in a single transaction. If an alias is given it is pointed at
+    /// `root` before the lock is released, so the garbage collector never sees the batch
+    /// unpinned. If a temporary pin is given the blocks are added to it, to keep them across
+    /// several batches.
+    pub fn insert_batch(
+        &self,
+        blocks: &[Block<S>],
+        pin: Option<&TempPin>,
+        alias: Option<(&[u8], &Cid)>,
+    ) -> Result<()> {
+        // a batch rejected by a quota leaves changes of missing blocks, which exports skip
+        let old = self.log_changes(blocks.iter().map(|block| block.cid()), || {
+            let mut store = lock_store(&self.store)?;
+            if let Some((alias, root)) = alias {
+                self.check_quotas(&mut store, alias, root, blocks)?;
+            }
+            observe_query("insert_batch", || {
+                let batch_pin = store.temp_pin();
+                store.put_blocks(blocks.iter(), Some(pin.unwrap_or(&batch_pin)))?;
+                let mut old = None;
+                if let Some((alias, root)) = alias {
+                    old = store.resolve(alias)?;
+                    store.alias(alias, Some(root))?;
+                }
+                drop(batch_pin);
+                Ok::<_, BlockStoreError>(old)
+            })
+        })?;
+        for block in blocks {
+            self.events.send(StorageEvent::Inserted(*block.cid()));
+        }
+        if let Some((alias, root)) = alias {
+            // the batch is stored, a failed metadata update doesn't fail the insert
+            if let Err(err) =
+                self.query_meta("alias_changed", |meta| meta.alias_changed(alias, true))
+            {
+                tracing::warn!("failed to update the metadata of an alias: {}", err);
+            }
+            self.events.send(StorageEvent::AliasChanged {
+                alias: alias.to_vec(),
+                old,
+                new: Some(*root),
+            });
+        }
+        Ok(())
     }
 
     pub async fn evict(&self) -> Result<()> {
         let store = self.store.clone();
-        let gc_min_blocks = self.gc_min_blocks;
         let gc_target_duration = self.gc_target_duration;
+        let events = self.events.clone();
+        let meta = self.readers.as_ref().map(|_| self.meta.clone());
         async_global_executor::spawn_blocking(move || {
-            while !store
-                .lock()
-                .incremental_gc(gc_min_blocks, gc_target_duration)?
-            {}
-            while !store
-                .lock()
-                .incremental_delete_orphaned(gc_min_blocks, gc_target_duration)?
-            {}
+            let start = Instant::now();
+            run_gc(
+                &store,
+                &events,
+                usize::MAX,
+                gc_target_duration,
+                |store, n, d| store.incremental_gc(n, d),
+            )?;
+            run_gc(
+                &store,
+                &events,
+                usize::MAX,
+                gc_target_duration,
+                |store, n, d| store.incremental_delete_orphaned(n, d),
+            )?;
+            events.gc_completed(start.elapsed());
+            if let Some(meta) = meta {
+                prune_changes(&meta);
+            }
             Ok(())
         })
         .await
     }
 
     pub fn alias(&self, alias: &[u8], cid: Option<&Cid>) -> Result<()> {
-        observe_query("alias", || self.store.lock().alias(alias, cid))
+        let old = {
+            let mut store = lock_store(&self.store)?;
+            if let Some(cid) = cid {
+                self.check_quotas(&mut store, alias, cid, &[])?;
+            }
+            observe_query("alias", || {
+                let old = store.resolve(alias)?;
+                store.alias(alias, cid)?;
+                Ok::<_, BlockStoreError>(old)
+            })?
+        };
+        // the alias is set, a failed metadata update doesn't fail it, like in `insert_batch`
+        if let Err(err) = self.query_meta("alias_changed", |meta| {
+            meta.alias_changed(alias, cid.is_some())
+        }) {
+            tracing::warn!("failed to update the metadata of an alias: {}", err);
+        }
+        if old.as_ref() != cid {
+            self.events.send(StorageEvent::AliasChanged {
+                alias: alias.to_vec(),
+                old,
+                new: cid.copied(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Returns the metadata of an alias.
+    pub fn alias_metadata(&self, alias: &[u8]) -> Result<Option<AliasMetadata>> {
+        self.query_meta("alias_metadata", |meta| meta.get(alias))
+    }
+
+    /// Sets the label of an alias, returning `false` if there is no such alias.
+    pub fn set_alias_label(&self, alias: &[u8], label: Option<&str>) -> Result<bool> {
+        self.query_meta("set_alias_label", |meta| meta.set_label(alias, label))
+    }
+
+    /// Returns the total size and the number of the blocks of the dag of `root` that are in
+    /// the store.
+    pub fn dag_stat(&self, root: &Cid) -> Result<DagStat> {
+        dags_stat::<S, _>(vec![*root], &[], |cid| self.get(cid))
+    }
+
+    /// Sets the quota of the aliases starting with `prefix`, or removes it with `None`.
+    pub fn set_quota(&self, prefix: &[u8], bytes: Option<u64>) {
+        self.quotas.set(prefix, bytes);
+    }
+
+    /// Returns the bytes used by the dags of the aliases starting with `prefix`.
+    pub fn quota_usage(&self, prefix: &[u8]) -> Result<u64> {
+        let mut store = lock_store(&self.store)?;
+        self.prefix_usage(&mut store, prefix, None, &[])
+    }
+
+    /// Returns the bytes used by the dags of the aliases starting with `prefix` if the alias
+    /// pointed at the given root. The blocks of `batch` count as if they were stored.
+    ///
+    /// A persistent store sums the blocks with a query over the references, an in-memory
+    /// store walks the dags through the locked `store`.
+    fn prefix_usage(
+        &self,
+        store: &mut BlockStore,
+        prefix: &[u8],
+        alias: Option<(&[u8], &Cid)>,
+        batch: &[Block<S>],
+    ) -> Result<u64> {
+        let readers = match self.readers.as_ref() {
+            Some(readers) => readers,
+            None => {
+                let roots = store
+                    .aliases::<Vec<(Vec<u8>, Cid)>>()?
+                    .into_iter()
+                    .filter(|(name, _)| {
+                        name.starts_with(prefix) && Some(name.as_slice()) != alias.map(|a| a.0)
+                    })
+                    .map(|(_, root)| root)
+                    .chain(alias.map(|a| *a.1))
+                    .collect();
+                let stat = dags_stat(roots, batch, |cid| Ok(store.get_block(cid)?))?;
+                return Ok(stat.size);
+            }
+        };
+        // the blocks of the batch that aren't stored yet are counted here, the query starts
+        // from the stored blocks they link to
+        let batch = batch
+            .iter()
+            .map(|block| (*block.cid(), block))
+            .collect::<FnvHashMap<_, _>>();
+        let mut visited = FnvHashSet::default();
+        let mut stack = alias.iter().map(|a| *a.1).collect::<Vec<_>>();
+        let mut roots = vec![];
+        let mut batch_bytes = 0;
+        while let Some(cid) = stack.pop() {
+            if !visited.insert(cid) {
+                continue;
+            }
+            match batch.get(&cid) {
+                Some(block) if !readers.contains(&cid)? => {
+                    batch_bytes += block.data().len() as u64;
+                    block.references(&mut stack)?;
+                }
+                _ => roots.push(cid),
+            }
+        }
+        let exclude = alias.map(|a| a.0);
+        let stat = observe_result("prefix_usage", || {
+            readers.read(|conn| stats::dags_usage(conn, prefix, exclude, &roots))
+        })?;
+        Ok(stat.size + batch_bytes)
+    }
+
+    /// Fails with `QuotaExceeded` if pointing `alias` at `root` exceeds a quota. The blocks
+    /// of `batch` count as if they were stored. The caller holds the lock of the `store` until
+    /// the write, so no other write can use the checked bytes in between.
+    fn check_quotas(
+        &self,
+        store: &mut BlockStore,
+        alias: &[u8],
+        root: &Cid,
+        batch: &[Block<S>],
+    ) -> Result<()> {
+        for quota in self.quotas.matching(alias) {
+            let used = self.prefix_usage(store, &quota.prefix, Some((alias, root)), batch)?;
+            if used > quota.bytes {
+                return Err(QuotaExceeded {
+                    prefix: quota.prefix,
+                    quota: quota.bytes,
+                    used,
+                }
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates a temp pin charged against the quotas of `alias`. The pins of a quota share
+    /// its charged bytes, so several pins can't each insert up to the whole quota.
+    pub fn create_quota_pin(&self, alias: &[u8]) -> Result<QuotaPin> {
+        let mut store = lock_store(&self.store)?;
+        let quotas = self
+            .quotas
+            .matching(alias)
+            .into_iter()
+            .map(|quota| {
+                let used = self.prefix_usage(&mut store, &quota.prefix, None, &[])?;
+                Ok((quota, used))
+            })
+            .collect::<Result<_>>()?;
+        Ok(QuotaPin {
+            pin: store.temp_pin(),
+            quotas,
+            charged: self.quotas.charged(),
+            inserted: Default::default(),
+        })
+    }
+
+    /// Temp pins and inserts a block, failing with `QuotaExceeded` if the block would exceed
+    /// a quota of the pin. Blocks already in the store aren't charged.
+    pub fn insert_pinned(&self, pin: &QuotaPin, block: &Block<S>) -> Result<()> {
+        let mut charged = 0;
+        if !self.contains(block.cid())? {
+            charged = block.data().len() as u64;
+            pin.charge(charged)?;
+        }
+        let res = self
+            .temp_pin(&pin.pin, std::iter::once(*block.cid()))
+            .and_then(|_| self.insert(block));
+        if res.is_err() {
+            pin.refund(charged);
+        }
+        res
+    }
+
+    /// Returns the number and size of the blocks, what they are used for and the last
+    /// garbage collector runs.
+    pub fn repo_stats(&self) -> Result<RepoStats> {
+        let (blocks, bytes) = self.query("store_stats", |store| {
+            let stats = store.get_store_stats()?;
+            Ok::<_, BlockStoreError>((stats.count() as u64, stats.size() as u64))
+        })?;
+        let usage = match self.readers.as_ref() {
+            Some(readers) => Some(observe_result("repo_usage", || {
+                readers.read(|conn| stats::usage(conn, bytes))
+            })?),
+            None => None,
+        };
+        Ok(RepoStats {
+            blocks,
+            bytes,
+            usage,
+            gc_history: self.events.gc_history.runs(),
+        })
+    }
+
+    /// Returns the aliases with blocks missing from their dag.
+    pub fn missing_alias_blocks(&self) -> Result<Vec<(Vec<u8>, Vec<Cid>)>> {
+        let mut missing = vec![];
+        for (alias, root) in self.aliases()? {
+            let blocks = self.missing_blocks(&root)?;
+            if !blocks.is_empty() {
+                missing.push((alias, blocks));
+            }
+        }
+        Ok(missing)
+    }
+
+    /// Checks the integrity of the block store. Every block is rehashed and, for a
+    /// persistent store, its links are compared with the stored references. With `repair`
+    /// corrupt blocks are deleted and blocks with wrong references are written again.
+    ///
+    /// This reads the whole store, so it should run on the blocking thread pool.
+    pub fn verify(&self, repair: bool) -> Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+        let readers = match self.readers.as_ref() {
+            Some(readers) => readers,
+            None => {
+                // the references of an in-memory store can't be read
+                for cid in self.iter()? {
+                    if let Some(data) = self.get(&cid)? {
+                        report.blocks += 1;
+                        if Block::<S>::new(cid, data).is_err() {
+                            report.corrupt.push(cid);
+                        }
+                    }
+                }
+                if repair && !report.corrupt.is_empty() {
+                    tracing::warn!("can't repair an in-memory store");
+                }
+                report.missing = self.missing_alias_blocks()?;
+                return Ok(report);
+            }
+        };
+        readers.read(|conn| verify::check_blocks::<S>(conn, &mut report))?;
+        if repair && !(report.corrupt.is_empty() && report.bad_refs.is_empty()) {
+            let mut conn = rusqlite::Connection::open(readers.path())?;
+            conn.busy_timeout(Duration::from_secs(5))?;
+            // holding the lock keeps the block store from writing while rows are deleted
+            let mut store = lock_store(&self.store)?;
+            for cid in &report.corrupt {
+                verify::delete_block(&mut conn, cid)?;
+                report.deleted.push(*cid);
+            }
+            for cid in &report.bad_refs {
+                if let Some(data) = store.get_block(cid)? {
+                    verify::delete_block(&mut conn, cid)?;
+                    store.put_block(&Block::<S>::new_unchecked(*cid, data), None)?;
+                }
+            }
+            drop(store);
+            for cid in &report.deleted {
+                self.events.send(StorageEvent::Removed(*cid));
+            }
+        }
+        report.missing = self.missing_alias_blocks()?;
+        Ok(report)
+    }
+
+    /// Returns the blocks inserted after the change `seq` with their change, in insertion
+    /// order. A block inserted again is returned with its last change, blocks removed by the
+    /// garbage collector are eventually dropped from the log. An in-memory store doesn't log
+    /// changes.
+    pub fn changes_since(&self, seq: u64) -> Result<Vec<(u64, Cid)>> {
+        self.query_meta("changes_since", |meta| meta.changes_since(seq))
+    }
+
+    /// Writes a consistent copy of the database to `path` with the sqlite online backup
+    /// api, while inserts and the garbage collector continue. Returns the last change
+    /// included in the copy, to pass to `changes_since` for an incremental backup.
+    ///
+    /// This copies the whole database, so it should run on the blocking thread pool.
+    pub fn backup(&self, path: &Path) -> Result<u64> {
+        let readers = self.readers.as_ref().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "can't back up an in-memory store",
+            )
+        })?;
+        observe_result("backup", || {
+            readers.read(|conn| {
+                // changes logged during the copy are exported again, which is harmless
+                let seq = meta::last_change(conn)?;
+                backup::backup(conn, path)?;
+                Ok(seq)
+            })
+        })
     }
 
     pub fn resolve(&self, alias: &[u8]) -> Result<Option<Cid>> {
-        observe_query("resolve", || self.store.lock().resolve(alias))
+        self.query("resolve", |store| store.resolve(alias))
+    }
+
+    pub fn aliases(&self) -> Result<Vec<(Vec<u8>, Cid)>> {
+        self.query("aliases", |store| store.aliases::<Vec<(Vec<u8>, Cid)>>())
     }
 
     pub fn reverse_alias(&self, cid: &Cid) -> Result<Option<Vec<Vec<u8>>>> {
-        observe_query("reverse_alias", || self.store.lock().reverse_alias(cid))
+        self.query("reverse_alias", |store| store.reverse_alias(cid))
     }
 
     pub fn missing_blocks(&self, cid: &Cid) -> Result<Vec<Cid>> {
-        observe_query("missing_blocks", || {
-            self.store.lock().get_missing_blocks(cid)
-        })
+        self.query("missing_blocks", |store| store.get_missing_blocks(cid))
     }
 
     pub async fn flush(&self) -> Result<()> {
         let store = self.store.clone();
-        let flush = async_global_executor::spawn_blocking(move || store.lock().flush());
+        let flush = async_global_executor::spawn_blocking(move || match store.lock().as_mut() {
+            Some(store) => store.flush(),
+            None => Ok(()),
+        });
         observe_future("flush", flush).await
     }
 
@@ -215,23 +798,93 @@ where
     }
 }
 
-#[derive(Debug)]
-struct IpfsCacheTracker<T> {
-    tracker: T,
-    tx: mpsc::UnboundedSender<StorageEvent>,
+/// Runs an incremental garbage collector `step` until it completes, or until at least
+/// `min_blocks` were collected and `target_duration` is exceeded. The lock is handed to
+/// waiting queries between steps.
+///
+/// Only the blocks the steps actually deleted count towards `min_blocks`. Deleting orphaned
+/// block data doesn't delete blocks, so that step runs until it completes.
+fn run_gc<F>(
+    store: &Mutex<Option<BlockStore>>,
+    events: &EventSink,
+    min_blocks: usize,
+    target_duration: Duration,
+    step: F,
+) -> Result<()>
+where
+    F: Fn(&mut BlockStore, usize, Duration) -> Result<bool, BlockStoreError>,
+{
+    let step_blocks = GC_STEP_BLOCKS.min(min_blocks);
+    let step_duration = GC_STEP_DURATION.min(target_duration);
+    let start = Instant::now();
+    let mut collected = 0usize;
+    loop {
+        let deleted = events.deleted_blocks();
+        let mut guard = lock_store(store)?;
+        let complete = step(&mut guard, step_blocks, step_duration)?;
+        MappedMutexGuard::unlock_fair(guard);
+        let step_deleted = events.deleted_blocks().saturating_sub(deleted);
+        collected = collected.saturating_add(step_deleted as usize);
+        if complete || (collected >= min_blocks && start.elapsed() >= target_duration) {
+            return Ok(());
+        }
+    }
+}
+
+/// Removes the blocks deleted by the garbage collector from the change log.
+fn prune_changes(meta: &Mutex<Option<MetadataStore>>) {
+    if let Some(meta) = meta.lock().as_ref() {
+        if let Err(err) = meta.prune_changes() {
+            tracing::warn!("failed to prune the change log: {}", err);
+        }
+    }
 }
 
-impl<T: CacheTracker> CacheTracker for IpfsCacheTracker<T> {
+/// Returns the stat of the union of the dags of `roots`, looking up blocks in `batch`
+/// before calling `get`. Blocks shared between the dags are counted once.
+fn dags_stat<S, F>(roots: Vec<Cid>, batch: &[Block<S>], mut get: F) -> Result<DagStat>
+where
+    S: StoreParams,
+    Ipld: References<S::Codecs>,
+    F: FnMut(&Cid) -> Result<Option<Vec<u8>>>,
+{
+    let batch = batch
+        .iter()
+        .map(|block| (*block.cid(), block))
+        .collect::<FnvHashMap<_, _>>();
+    let mut visited = FnvHashSet::default();
+    let mut stack = roots;
+    let mut stat = DagStat::default();
+    while let Some(cid) = stack.pop() {
+        if !visited.insert(cid) {
+            continue;
+        }
+        let block = match batch.get(&cid) {
+            Some(block) => Some((*block).clone()),
+            None => get(&cid)?.map(|data| Block::<S>::new_unchecked(cid, data)),
+        };
+        if let Some(block) = block {
+            stat.size += block.data().len() as u64;
+            stat.blocks += 1;
+            block.references(&mut stack)?;
+        }
+    }
+    Ok(stat)
+}
+
+#[derive(Clone, Debug)]
+struct IpfsCacheTracker {
+    tracker: Arc<dyn CacheTracker>,
+    events: EventSink,
+}
+
+impl CacheTracker for IpfsCacheTracker {
     fn blocks_accessed(&self, blocks: Vec<BlockInfo>) {
         self.tracker.blocks_accessed(blocks)
     }
 
     fn blocks_deleted(&self, blocks: Vec<BlockInfo>) {
-        for block in &blocks {
-            self.tx
-                .unbounded_send(StorageEvent::Remove(*block.cid()))
-                .ok();
-        }
+        self.events.blocks_deleted(&blocks);
         self.tracker.blocks_deleted(blocks)
     }
 
@@ -279,6 +932,22 @@ where
     Ok(res?)
 }
 
+/// Observes a query that returns an `anyhow::Error`.
+fn observe_result<T, F>(name: &'static str, query: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T>,
+{
+    QUERIES_TOTAL.with_label_values(&[name]).inc();
+    let timer = QUERY_DURATION.with_label_values(&[name]).start_timer();
+    let res = query();
+    if res.is_ok() {
+        timer.observe_duration();
+    } else {
+        timer.stop_and_discard();
+    }
+    res
+}
+
 async fn observe_future<T, E, F>(name: &'static str, query: F) -> Result<T>
 where
     E: std::error::Error + Send + Sync + 'static,
@@ -297,7 +966,7 @@ where
 
 struct SqliteStoreCollector {
     desc: Desc,
-    store: Arc<Mutex<BlockStore>>,
+    store: Store,
 }
 
 impl Collector for SqliteStoreCollector {
@@ -308,7 +977,8 @@ impl Collector for SqliteStoreCollector {
     fn collect(&self) -> Vec<MetricFamily> {
         let mut family = vec![];
 
-        if let Ok(stats) = self.store.lock().get_store_stats() {
+        let stats = lock_store(&self.store).and_then(|store| Ok(store.get_store_stats()?));
+        if let Ok(stats) = stats {
             let store_block_count =
                 IntGauge::new("block_store_block_count", "Number of stored blocks").unwrap();
             store_block_count.set(stats.count() as _);
@@ -325,7 +995,7 @@ impl Collector for SqliteStoreCollector {
 }
 
 impl SqliteStoreCollector {
-    pub fn new(store: Arc<Mutex<BlockStore>>) -> Self {
+    pub fn new(store: Store) -> Self {
         let desc = Desc::new(
             "block_store_stats".into(),
             ".".into(),
@@ -425,14 +1095,13 @@ mod tests {
         assert_unpinned!(&store, &blocks[1]);
         assert_evicted!(&store, &blocks[2]);
         assert_unpinned!(&store, &blocks[3]);
-        assert_eq!(
-            rx.next().await,
-            Some(StorageEvent::Remove(*blocks[0].cid()))
-        );
-        assert_eq!(
-            rx.next().await,
-            Some(StorageEvent::Remove(*blocks[2].cid()))
-        );
+        let removed: Vec<_> = std::iter::from_fn(|| rx.try_next().ok().flatten())
+            .filter_map(|event| match event {
+                StorageEvent::Removed(cid) => Some(cid),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(removed, vec![*blocks[0].cid(), *blocks[2].cid()]);
     }
 
     #[async_std::test]
@@ -491,4 +1160,351 @@ mod tests {
         assert_unpinned!(&store, &a);
         assert_unpinned!(&store, &b);
     }
+
+    #[async_std::test]
+    async fn test_alias_metadata() {
+        tracing_try_init();
+        let (store, _) = create_store();
+        let a = create_block(&ipld!({ "a": [] }));
+        let b = create_block(&ipld!({ "b": [a.cid()] }));
+        let x = alias!(x).as_bytes().to_vec();
+        store.insert(&a).unwrap();
+        store.insert(&b).unwrap();
+        assert!(!store.set_alias_label(&x, Some("x")).unwrap());
+        store.alias(&x, Some(b.cid())).unwrap();
+        assert!(store.set_alias_label(&x, Some("x")).unwrap());
+        let meta = store.alias_metadata(&x).unwrap().unwrap();
+        assert_eq!(meta.label.as_deref(), Some("x"));
+        let size = (a.data().len() + b.data().len()) as u64;
+        assert_eq!(
+            store.dag_stat(b.cid()).unwrap(),
+            DagStat { size, blocks: 2 }
+        );
+        store.alias(&x, None).unwrap();
+        assert_eq!(store.alias_metadata(&x).unwrap(), None);
+    }
+
+    #[async_std::test]
+    async fn test_insert_batch() {
+        tracing_try_init();
+        let (store, _) = create_store();
+        let a = create_block(&ipld!({ "a": [] }));
+        let b = create_block(&ipld!({ "b": [a.cid()] }));
+        let c = create_block(&ipld!({ "c": [b.cid()] }));
+        let x = alias!(x).as_bytes().to_vec();
+        store
+            .insert_batch(
+                &[a.clone(), b.clone(), c.clone()],
+                None,
+                Some((&x, c.cid())),
+            )
+            .unwrap();
+        store.flush().await.unwrap();
+        store.evict().await.unwrap();
+        assert_pinned!(&store, &a);
+        assert_pinned!(&store, &b);
+        assert_pinned!(&store, &c);
+        assert_eq!(store.resolve(&x).unwrap(), Some(*c.cid()));
+    }
+
+    #[async_std::test]
+    async fn test_reader_pool() {
+        tracing_try_init();
+        let dir = tempfile::tempdir().unwrap();
+        let (tx, _rx) = mpsc::unbounded();
+        let path = dir.path().join("blocks.sqlite");
+        let config = StorageConfig::new(Some(path), 2, Duration::from_secs(100));
+        let store = StorageService::<DefaultParams>::open(config, tx).unwrap();
+        let a = create_block(&ipld!(0));
+        let b = create_block(&ipld!(1));
+        assert!(!store.contains_async(a.cid()).await.unwrap());
+        store.insert_async(a.clone()).await.unwrap();
+        store.insert(&b).unwrap();
+        assert!(store.contains(a.cid()).unwrap());
+        assert_eq!(store.get(a.cid()).unwrap().as_deref(), Some(a.data()));
+        assert_eq!(
+            store.get_async(b.cid()).await.unwrap().as_deref(),
+            Some(b.data())
+        );
+        store.close().await.unwrap();
+        assert!(store.get(a.cid()).is_err());
+    }
+
+    #[test]
+    fn test_read_during_gc() {
+        tracing_try_init();
+        let dir = tempfile::tempdir().unwrap();
+        let (tx, _rx) = mpsc::unbounded();
+        let path = dir.path().join("blocks.sqlite");
+        let config = StorageConfig::new(Some(path.clone()), 2, Duration::from_secs(100));
+        let store = StorageService::<DefaultParams>::open(config, tx).unwrap();
+        let a = create_block(&ipld!(0));
+        store.insert(&a).unwrap();
+        // like a gc step, hold the block store and the write lock of the database
+        let guard = lock_store(&store.store).unwrap();
+        let mut writer = rusqlite::Connection::open(&path).unwrap();
+        let txn = writer
+            .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)
+            .unwrap();
+        let (read_tx, read_rx) = std::sync::mpsc::channel();
+        let reader = store.clone();
+        let cid = *a.cid();
+        std::thread::spawn(move || {
+            read_tx.send(reader.get(&cid).unwrap()).unwrap();
+        });
+        let data = read_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(data.as_deref(), Some(a.data()));
+        drop(txn);
+        drop(guard);
+    }
+
+    #[async_std::test]
+    async fn test_verify() {
+        tracing_try_init();
+        let dir = tempfile::tempdir().unwrap();
+        let (tx, _rx) = mpsc::unbounded();
+        let path = dir.path().join("blocks.sqlite");
+        let config = StorageConfig::new(Some(path.clone()), 2, Duration::from_secs(100));
+        let store = StorageService::<DefaultParams>::open(config, tx).unwrap();
+        let a = create_block(&ipld!({ "a": [] }));
+        let b = create_block(&ipld!({ "b": [a.cid()] }));
+        let x = alias!(x).as_bytes().to_vec();
+        store
+            .insert_batch(&[a.clone(), b.clone()], None, Some((&x, b.cid())))
+            .unwrap();
+        store.flush().await.unwrap();
+        let report = store.verify(false).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.blocks, 2);
+
+        // corrupt the data of `a`
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute(
+            "UPDATE blocks SET block = ?1 WHERE block_id = (SELECT id FROM cids WHERE cid = ?2)",
+            rusqlite::params![b"corrupt".to_vec(), a.cid().to_bytes()],
+        )
+        .unwrap();
+        let report = store.verify(false).unwrap();
+        assert_eq!(report.corrupt, vec![*a.cid()]);
+        assert!(report.deleted.is_empty());
+
+        let report = store.verify(true).unwrap();
+        assert_eq!(report.deleted, vec![*a.cid()]);
+        assert_eq!(report.missing, vec![(x, vec![*a.cid()])]);
+        assert!(!store.contains(a.cid()).unwrap());
+        store.insert(&a).unwrap();
+        assert!(store.verify(false).unwrap().is_ok());
+    }
+
+    #[async_std::test]
+    async fn test_repo_stats() {
+        tracing_try_init();
+        let dir = tempfile::tempdir().unwrap();
+        let (tx, _rx) = mpsc::unbounded();
+        let path = dir.path().join("blocks.sqlite");
+        let config = StorageConfig::new(Some(path), 0, Duration::from_secs(100));
+        let store = StorageService::<DefaultParams>::open(config, tx).unwrap();
+        let a = create_block(&ipld!({ "a": [] }));
+        let b = create_block(&ipld!({ "b": [a.cid()] }));
+        let c = create_block(&ipld!({ "c": [] }));
+        let x = alias!(x).as_bytes().to_vec();
+        store
+            .insert_batch(&[a.clone(), b.clone()], None, Some((&x, b.cid())))
+            .unwrap();
+        let tmp = store.create_temp_pin().unwrap();
+        store.temp_pin(&tmp, std::iter::once(*c.cid())).unwrap();
+        store.insert(&c).unwrap();
+        store.flush().await.unwrap();
+
+        let stats = store.repo_stats().unwrap();
+        let size = (a.data().len() + b.data().len()) as u64;
+        assert_eq!(stats.blocks, 3);
+        assert_eq!(stats.bytes, size + c.data().len() as u64);
+        let usage = stats.usage.unwrap();
+        assert_eq!(usage.pinned_blocks, 3);
+        assert_eq!(usage.cached_bytes, 0);
+        assert_eq!(usage.temp_pins, 1);
+        assert_eq!(usage.temp_pinned_blocks, 1);
+        assert!(stats.gc_history.is_empty());
+
+        drop(tmp);
+        store.evict().await.unwrap();
+        let stats = store.repo_stats().unwrap();
+        assert_eq!(stats.blocks, 2);
+        assert_eq!(stats.usage.unwrap().pinned_bytes, size);
+        assert_eq!(stats.gc_history.len(), 1);
+        assert_eq!(stats.gc_history[0].blocks, 1);
+    }
+
+    #[async_std::test]
+    async fn test_quotas() {
+        tracing_try_init();
+        let dir = tempfile::tempdir().unwrap();
+        let (tx, _rx) = mpsc::unbounded();
+        let path = dir.path().join("blocks.sqlite");
+        let config = StorageConfig::new(Some(path), 2, Duration::from_secs(100));
+        let persistent = StorageService::<DefaultParams>::open(config, tx).unwrap();
+        // the in-memory store walks the dags, the persistent store queries the references
+        for store in [create_store().0, persistent].iter() {
+            let a = create_block(&ipld!({ "a": [] }));
+            let b = create_block(&ipld!({ "b": [a.cid()] }));
+            let c = create_block(&ipld!({ "c": [1, 2, 3] }));
+            let size = (a.data().len() + b.data().len()) as u64;
+            store.set_quota(b"customer1/", Some(size));
+            store
+                .insert_batch(
+                    &[a.clone(), b.clone()],
+                    None,
+                    Some((b"customer1/x", b.cid())),
+                )
+                .unwrap();
+            // shared blocks are counted once
+            store.alias(b"customer1/y", Some(a.cid())).unwrap();
+            assert_eq!(store.quota_usage(b"customer1/").unwrap(), size);
+            store.insert(&c).unwrap();
+            let err = store.alias(b"customer1/z", Some(c.cid())).unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<QuotaExceeded>(),
+                Some(&QuotaExceeded {
+                    prefix: b"customer1/".to_vec(),
+                    quota: size,
+                    used: size + c.data().len() as u64,
+                })
+            );
+            assert_eq!(store.resolve(b"customer1/z").unwrap(), None);
+            store.alias(b"customer2/z", Some(c.cid())).unwrap();
+
+            let d = create_block(&ipld!({ "d": [] }));
+            let pin = store.create_quota_pin(b"customer1/z").unwrap();
+            let err = store.insert_pinned(&pin, &d).unwrap_err();
+            assert!(err.downcast_ref::<QuotaExceeded>().is_some());
+            assert!(!store.contains(d.cid()).unwrap());
+            // already stored blocks are free
+            store.insert_pinned(&pin, &a).unwrap();
+            assert_eq!(pin.inserted(), 0);
+
+            store.set_quota(b"customer1/", None);
+            store.alias(b"customer1/z", Some(c.cid())).unwrap();
+            let pin = store.create_quota_pin(b"customer1/z").unwrap();
+            store.insert_pinned(&pin, &d).unwrap();
+            assert_eq!(pin.inserted(), d.data().len() as u64);
+
+            // the pins of a quota share the charged bytes until they are dropped
+            let e = create_block(&ipld!({ "e": [] }));
+            let f = create_block(&ipld!({ "f": [] }));
+            let used = store.quota_usage(b"customer1/").unwrap();
+            store.set_quota(b"customer1/", Some(used + e.data().len() as u64));
+            let pin1 = store.create_quota_pin(b"customer1/v").unwrap();
+            let pin2 = store.create_quota_pin(b"customer1/w").unwrap();
+            store.insert_pinned(&pin1, &e).unwrap();
+            let err = store.insert_pinned(&pin2, &f).unwrap_err();
+            assert!(err.downcast_ref::<QuotaExceeded>().is_some());
+            drop(pin1);
+            store.insert_pinned(&pin2, &f).unwrap();
+        }
+    }
+
+    #[async_std::test]
+    async fn test_backup() {
+        tracing_try_init();
+        let dir = tempfile::tempdir().unwrap();
+        let (tx, _rx) = mpsc::unbounded();
+        let path = dir.path().join("blocks.sqlite");
+        let config = StorageConfig::new(Some(path), 0, Duration::from_secs(100));
+        let store = StorageService::<DefaultParams>::open(config, tx).unwrap();
+        let a = create_block(&ipld!({ "a": [] }));
+        let b = create_block(&ipld!({ "b": [a.cid()] }));
+        let c = create_block(&ipld!({ "c": [] }));
+        let x = alias!(x).as_bytes().to_vec();
+        store
+            .insert_batch(&[a.clone(), b.clone()], None, Some((&x, b.cid())))
+            .unwrap();
+        store.flush().await.unwrap();
+        let backup = dir.path().join("backup.sqlite");
+        let seq = store.backup(&backup).unwrap();
+        assert_eq!(store.changes_since(0).unwrap().len(), 2);
+        assert!(store.changes_since(seq).unwrap().is_empty());
+
+        store.insert(&c).unwrap();
+        let changes = store.changes_since(seq).unwrap();
+        assert_eq!(changes, vec![(seq + 1, *c.cid())]);
+        store.evict().await.unwrap();
+        assert!(store.changes_since(seq).unwrap().is_empty());
+
+        let (tx, _rx) = mpsc::unbounded();
+        let config = StorageConfig::new(Some(backup), 0, Duration::from_secs(100));
+        let copy = StorageService::<DefaultParams>::open(config, tx).unwrap();
+        assert_eq!(copy.resolve(&x).unwrap(), Some(*b.cid()));
+        assert_eq!(copy.get(a.cid()).unwrap().as_deref(), Some(a.data()));
+        assert!(!copy.contains(c.cid()).unwrap());
+
+        let (memory, _) = create_store();
+        assert!(memory.backup(&dir.path().join("memory.sqlite")).is_err());
+        memory.insert(&c).unwrap();
+        assert!(memory.changes_since(0).unwrap().is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_close() {
+        tracing_try_init();
+        let (store, _) = create_store();
+        let mut events = store.subscribe(16);
+        let a = create_block(&ipld!(0));
+        store.insert(&a).unwrap();
+        store.close().await.unwrap();
+        assert_eq!(
+            events.next().await,
+            Some(Ok(StorageEvent::Inserted(*a.cid())))
+        );
+        assert_eq!(events.next().await, None);
+        let err = store.get(a.cid()).unwrap_err();
+        assert_eq!(err.downcast_ref::<StorageClosed>(), Some(&StorageClosed));
+        store.close().await.unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_storage_events() {
+        tracing_try_init();
+        let (store, _) = create_store();
+        let mut events = store.subscribe(16);
+        let blocks = [
+            create_block(&ipld!(0)),
+            create_block(&ipld!(1)),
+            create_block(&ipld!(2)),
+        ];
+        let x = alias!(x).as_bytes().to_vec();
+        for block in &blocks {
+            store.insert(block).unwrap();
+        }
+        store.alias(&x, Some(blocks[2].cid())).unwrap();
+        store.flush().await.unwrap();
+        store.evict().await.unwrap();
+        for block in &blocks {
+            let event = events.next().await.unwrap().unwrap();
+            assert_eq!(event, StorageEvent::Inserted(*block.cid()));
+        }
+        assert_eq!(
+            events.next().await.unwrap().unwrap(),
+            StorageEvent::AliasChanged {
+                alias: x,
+                old: None,
+                new: Some(*blocks[2].cid()),
+            }
+        );
+        assert_eq!(
+            events.next().await.unwrap().unwrap(),
+            StorageEvent::Removed(*blocks[0].cid())
+        );
+        match events.next().await.unwrap().unwrap() {
+            StorageEvent::GcCompleted {
+                blocks: removed,
+                bytes,
+                ..
+            } => {
+                assert_eq!(removed, 1);
+                assert_eq!(bytes, blocks[0].data().len() as u64);
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
 }
@@ -0,0 +1,235 @@
+use libipld::Cid;
+use rusqlite::types::Type;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::convert::TryFrom;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Schema migrations of the metadata tables. Migrations are only ever appended, the
+/// index of a migration is its schema version.
+const MIGRATIONS: &[&str] = &[
+    r#"
+    CREATE TABLE IF NOT EXISTS alias_metadata (
+        alias BLOB PRIMARY KEY,
+        created INTEGER NOT NULL,
+        updated INTEGER NOT NULL,
+        label TEXT
+    );
+    "#,
+    r#"
+    CREATE TABLE IF NOT EXISTS block_changes (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        cid BLOB NOT NULL UNIQUE
+    );
+    "#,
+];
+
+/// The metadata of an alias.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AliasMetadata {
+    /// When the alias was created.
+    pub created: SystemTime,
+    /// When the alias was last pointed at a root.
+    pub updated: SystemTime,
+    /// A user defined label.
+    pub label: Option<String>,
+}
+
+/// Stores the alias metadata and the log of inserted blocks next to the block store.
+pub(crate) struct MetadataStore {
+    conn: Connection,
+}
+
+impl MetadataStore {
+    /// Opens the metadata store in the sqlite database at `path`.
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.busy_timeout(Duration::from_secs(5))?;
+        Self::with_connection(conn)
+    }
+
+    /// Creates an in-memory metadata store.
+    pub fn memory() -> rusqlite::Result<Self> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(conn: Connection) -> rusqlite::Result<Self> {
+        migrate(&conn)?;
+        Ok(Self { conn })
+    }
+
+    /// Records that `alias` was set or removed.
+    pub fn alias_changed(&self, alias: &[u8], set: bool) -> rusqlite::Result<()> {
+        if set {
+            let now = unix_time(SystemTime::now());
+            self.conn.execute(
+                "INSERT INTO alias_metadata (alias, created, updated) VALUES (?1, ?2, ?2)
+                 ON CONFLICT (alias) DO UPDATE SET updated = ?2",
+                params![alias, now],
+            )?;
+        } else {
+            self.conn.execute(
+                "DELETE FROM alias_metadata WHERE alias = ?1",
+                params![alias],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Sets the label of an alias, returning `false` if there is no such alias.
+    pub fn set_label(&self, alias: &[u8], label: Option<&str>) -> rusqlite::Result<bool> {
+        let n = self.conn.execute(
+            "UPDATE alias_metadata SET label = ?2 WHERE alias = ?1",
+            params![alias, label],
+        )?;
+        Ok(n > 0)
+    }
+
+    /// Logs inserted blocks. A block inserted again moves to the end of the log.
+    pub fn blocks_changed<'a>(
+        &self,
+        cids: impl IntoIterator<Item = &'a Cid>,
+    ) -> rusqlite::Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        {
+            let mut stmt =
+                tx.prepare_cached("INSERT OR REPLACE INTO block_changes (cid) VALUES (?1)")?;
+            for cid in cids {
+                stmt.execute(params![cid.to_bytes()])?;
+            }
+        }
+        tx.commit()
+    }
+
+    /// Returns the blocks inserted after the change `seq`, in insertion order.
+    pub fn changes_since(&self, seq: u64) -> rusqlite::Result<Vec<(u64, Cid)>> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT seq, cid FROM block_changes WHERE seq > ?1 ORDER BY seq")?;
+        let rows = stmt.query_map(params![seq as i64], |row| {
+            let seq: i64 = row.get(0)?;
+            let cid: Vec<u8> = row.get(1)?;
+            let cid = Cid::try_from(cid.as_slice()).map_err(|err| {
+                rusqlite::Error::FromSqlConversionFailure(1, Type::Blob, err.into())
+            })?;
+            Ok((seq as u64, cid))
+        })?;
+        rows.collect()
+    }
+
+    /// Removes the blocks no longer in the block store from the log. Only works when the
+    /// metadata shares the database with the block store.
+    pub fn prune_changes(&self) -> rusqlite::Result<usize> {
+        self.conn.execute(
+            "DELETE FROM block_changes WHERE cid NOT IN (
+                SELECT cids.cid FROM cids INNER JOIN blocks ON cids.id = blocks.block_id
+            )",
+            params![],
+        )
+    }
+
+    pub fn get(&self, alias: &[u8]) -> rusqlite::Result<Option<AliasMetadata>> {
+        self.conn
+            .query_row(
+                "SELECT created, updated, label FROM alias_metadata WHERE alias = ?1",
+                params![alias],
+                |row| {
+                    Ok(AliasMetadata {
+                        created: from_unix_time(row.get(0)?),
+                        updated: from_unix_time(row.get(1)?),
+                        label: row.get(2)?,
+                    })
+                },
+            )
+            .optional()
+    }
+}
+
+/// Returns the last change of the block log, `0` if no block was ever inserted.
+pub(crate) fn last_change(conn: &Connection) -> rusqlite::Result<u64> {
+    let seq: i64 = conn.query_row(
+        "SELECT COALESCE(MAX(seq), 0) FROM block_changes",
+        params![],
+        |row| row.get(0),
+    )?;
+    Ok(seq as u64)
+}
+
+fn unix_time(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+fn from_unix_time(secs: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs as u64)
+}
+
+fn migrate(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS ipfs_embed_migrations (
+            name TEXT PRIMARY KEY,
+            version INTEGER NOT NULL
+        );",
+    )?;
+    let version: i64 = conn
+        .query_row(
+            "SELECT version FROM ipfs_embed_migrations WHERE name = 'alias_metadata'",
+            params![],
+            |row| row.get(0),
+        )
+        .optional()?
+        .unwrap_or(0);
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        tracing::debug!("migrating alias metadata to version {}", i + 1);
+        // dropping the transaction on error rolls the migration back
+        let tx = conn.unchecked_transaction()?;
+        tx.execute_batch(migration)?;
+        tx.execute(
+            "INSERT OR REPLACE INTO ipfs_embed_migrations (name, version) VALUES ('alias_metadata', ?1)",
+            params![(i + 1) as i64],
+        )?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metadata() {
+        let store = MetadataStore::memory().unwrap();
+        assert_eq!(store.get(b"a").unwrap(), None);
+        assert!(!store.set_label(b"a", Some("label")).unwrap());
+        store.alias_changed(b"a", true).unwrap();
+        assert!(store.set_label(b"a", Some("label")).unwrap());
+        store.alias_changed(b"a", true).unwrap();
+        let meta = store.get(b"a").unwrap().unwrap();
+        assert_eq!(meta.label.as_deref(), Some("label"));
+        assert!(meta.created <= meta.updated);
+        store.alias_changed(b"a", false).unwrap();
+        assert_eq!(store.get(b"a").unwrap(), None);
+    }
+
+    #[test]
+    fn test_block_changes() {
+        use libipld::multihash::{Code, MultihashDigest};
+        let cid = |n: u8| Cid::new_v1(0x55, Code::Blake3_256.digest(&[n]));
+        let store = MetadataStore::memory().unwrap();
+        assert_eq!(last_change(&store.conn).unwrap(), 0);
+        store.blocks_changed(&[cid(0), cid(1)]).unwrap();
+        let seq = last_change(&store.conn).unwrap();
+        assert_eq!(
+            store.changes_since(0).unwrap(),
+            vec![(1, cid(0)), (2, cid(1))]
+        );
+        store.blocks_changed(&[cid(0)]).unwrap();
+        assert_eq!(store.changes_since(seq).unwrap(), vec![(3, cid(0))]);
+        assert_eq!(
+            store.changes_since(0).unwrap(),
+            vec![(2, cid(1)), (3, cid(0))]
+        );
+    }
+}
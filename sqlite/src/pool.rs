@@ -0,0 +1,102 @@
+use crate::StorageClosed;
+use libipld::{Cid, Result};
+use parking_lot::Mutex;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+struct Readers {
+    conns: Vec<Connection>,
+    closed: bool,
+}
+
+/// A pool of read-only connections to the block store. Sqlite in WAL mode lets readers
+/// run concurrently with the writer, so reads don't wait for inserts or the garbage
+/// collector. The queries depend on the schema of the pinned ipfs-sqlite-block-store
+/// version.
+pub(crate) struct ReaderPool {
+    path: PathBuf,
+    size: usize,
+    readers: Mutex<Readers>,
+}
+
+impl ReaderPool {
+    /// Creates a pool keeping at most `size` idle connections to the database at `path`.
+    pub fn new(path: &Path, size: usize) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            size,
+            readers: Mutex::new(Readers {
+                conns: Vec::with_capacity(size),
+                closed: false,
+            }),
+        }
+    }
+
+    /// The path of the database.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn open(&self) -> rusqlite::Result<Connection> {
+        let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+        let conn = Connection::open_with_flags(&self.path, flags)?;
+        conn.busy_timeout(Duration::from_secs(5))?;
+        Ok(conn)
+    }
+
+    /// Runs a query on an idle connection, opening a new one if all are busy.
+    pub fn read<T, F>(&self, query: F) -> Result<T>
+    where
+        F: FnOnce(&Connection) -> rusqlite::Result<T>,
+    {
+        let conn = {
+            let mut readers = self.readers.lock();
+            if readers.closed {
+                return Err(StorageClosed.into());
+            }
+            readers.conns.pop()
+        };
+        let conn = match conn {
+            Some(conn) => conn,
+            None => self.open()?,
+        };
+        let res = query(&conn);
+        let mut readers = self.readers.lock();
+        if !readers.closed && readers.conns.len() < self.size {
+            readers.conns.push(conn);
+        }
+        Ok(res?)
+    }
+
+    /// Returns the id and the data of a block.
+    pub fn get(&self, cid: &Cid) -> Result<Option<(i64, Vec<u8>)>> {
+        self.read(|conn| {
+            conn.query_row(
+                "SELECT cids.id, blocks.block FROM cids
+                 INNER JOIN blocks ON cids.id = blocks.block_id WHERE cids.cid = ?1",
+                params![cid.to_bytes()],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+        })
+    }
+
+    pub fn contains(&self, cid: &Cid) -> Result<bool> {
+        self.read(|conn| {
+            conn.query_row(
+                "SELECT EXISTS(SELECT 1 FROM cids
+                 INNER JOIN blocks ON cids.id = blocks.block_id WHERE cids.cid = ?1)",
+                params![cid.to_bytes()],
+                |row| row.get(0),
+            )
+        })
+    }
+
+    /// Closes the idle connections, following reads fail with `StorageClosed`.
+    pub fn close(&self) {
+        let mut readers = self.readers.lock();
+        readers.closed = true;
+        readers.conns.clear();
+    }
+}
@@ -0,0 +1,154 @@
+use crate::TempPin;
+use fnv::FnvHashMap;
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Limits the bytes of the dags of the aliases starting with `prefix`. A prefix equal to
+/// a whole alias limits a single alias.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Quota {
+    pub prefix: Vec<u8>,
+    pub bytes: u64,
+}
+
+impl Quota {
+    pub fn new(prefix: &[u8], bytes: u64) -> Self {
+        Self {
+            prefix: prefix.to_vec(),
+            bytes,
+        }
+    }
+}
+
+/// An alias or an insert would exceed a quota.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct QuotaExceeded {
+    /// The prefix of the exceeded quota.
+    pub prefix: Vec<u8>,
+    /// The quota in bytes.
+    pub quota: u64,
+    /// The bytes the aliases would use.
+    pub used: u64,
+}
+
+impl std::fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "quota of {} exceeded: {} of {} bytes",
+            String::from_utf8_lossy(&self.prefix),
+            self.used,
+            self.quota
+        )
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+/// The bytes charged to the live quota pins, by the prefix of the quota.
+type Charged = Arc<Mutex<FnvHashMap<Vec<u8>, u64>>>;
+
+/// The quotas of the store.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Quotas {
+    quotas: Arc<Mutex<Vec<Quota>>>,
+    charged: Charged,
+}
+
+impl Quotas {
+    pub fn new(quotas: Vec<Quota>) -> Self {
+        Self {
+            quotas: Arc::new(Mutex::new(quotas)),
+            charged: Default::default(),
+        }
+    }
+
+    /// The bytes charged to the quota pins, shared by all pins of the store.
+    pub fn charged(&self) -> Charged {
+        self.charged.clone()
+    }
+
+    /// Sets or removes the quota of a prefix.
+    pub fn set(&self, prefix: &[u8], bytes: Option<u64>) {
+        let mut quotas = self.quotas.lock();
+        quotas.retain(|quota| quota.prefix != prefix);
+        if let Some(bytes) = bytes {
+            quotas.push(Quota::new(prefix, bytes));
+        }
+    }
+
+    /// Returns the quotas limiting `alias`.
+    pub fn matching(&self, alias: &[u8]) -> Vec<Quota> {
+        self.quotas
+            .lock()
+            .iter()
+            .filter(|quota| alias.starts_with(&quota.prefix))
+            .cloned()
+            .collect()
+    }
+}
+
+/// A temp pin charged against the quotas of an alias. Inserts under the pin fail once the
+/// bytes used by the aliases of a quota and the bytes inserted under all live pins of the
+/// quota exceed it. The bytes of a pin are released when it is dropped.
+pub struct QuotaPin {
+    pub(crate) pin: TempPin,
+    /// The quotas with the bytes used when the pin was created.
+    pub(crate) quotas: Vec<(Quota, u64)>,
+    pub(crate) charged: Charged,
+    pub(crate) inserted: AtomicU64,
+}
+
+impl QuotaPin {
+    /// The temp pin keeping the inserted blocks.
+    pub fn temp_pin(&self) -> &TempPin {
+        &self.pin
+    }
+
+    /// The number of bytes inserted under the pin.
+    pub fn inserted(&self) -> u64 {
+        self.inserted.load(Ordering::SeqCst)
+    }
+
+    /// Charges `bytes` to the pin, failing if a quota would be exceeded.
+    pub(crate) fn charge(&self, bytes: u64) -> Result<(), QuotaExceeded> {
+        // the quotas are checked and charged under one lock, concurrent pins see the bytes
+        let mut charged = self.charged.lock();
+        for (quota, used) in &self.quotas {
+            let used = used + charged.get(&quota.prefix).copied().unwrap_or_default() + bytes;
+            if used > quota.bytes {
+                return Err(QuotaExceeded {
+                    prefix: quota.prefix.clone(),
+                    quota: quota.bytes,
+                    used,
+                });
+            }
+        }
+        for (quota, _) in &self.quotas {
+            *charged.entry(quota.prefix.clone()).or_default() += bytes;
+        }
+        self.inserted.fetch_add(bytes, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Returns the `bytes` charged for an insert that failed.
+    pub(crate) fn refund(&self, bytes: u64) {
+        let mut charged = self.charged.lock();
+        for (quota, _) in &self.quotas {
+            if let Some(total) = charged.get_mut(&quota.prefix) {
+                *total = total.saturating_sub(bytes);
+                if *total == 0 {
+                    charged.remove(&quota.prefix);
+                }
+            }
+        }
+        self.inserted.fetch_sub(bytes, Ordering::SeqCst);
+    }
+}
+
+impl Drop for QuotaPin {
+    fn drop(&mut self) {
+        self.refund(self.inserted());
+    }
+}
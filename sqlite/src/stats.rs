@@ -0,0 +1,146 @@
+use libipld::Cid;
+use parking_lot::Mutex;
+use rusqlite::types::Value;
+use rusqlite::{params, Connection};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// The number of garbage collector runs kept in the history.
+const GC_HISTORY: usize = 16;
+
+/// The statistics of the block store.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RepoStats {
+    /// The number of blocks.
+    pub blocks: u64,
+    /// The total size of the blocks in bytes.
+    pub bytes: u64,
+    /// What the blocks are used for, `None` for an in-memory store.
+    pub usage: Option<RepoUsage>,
+    /// The last garbage collector runs, oldest first.
+    pub gc_history: Vec<GcRun>,
+}
+
+/// The blocks of the store by what keeps them from being collected.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RepoUsage {
+    /// The number of blocks reachable from an alias or a temp pin.
+    pub pinned_blocks: u64,
+    /// The size of the pinned blocks in bytes.
+    pub pinned_bytes: u64,
+    /// The size of the blocks the garbage collector may remove in bytes.
+    pub cached_bytes: u64,
+    /// The number of live temp pins.
+    pub temp_pins: u64,
+    /// The number of blocks directly pinned by a temp pin.
+    pub temp_pinned_blocks: u64,
+    /// The number of blocks collected but not yet deleted.
+    pub orphaned_blocks: u64,
+}
+
+/// A completed garbage collector run.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct GcRun {
+    /// When the run completed.
+    pub completed: SystemTime,
+    /// The number of removed blocks.
+    pub blocks: u64,
+    /// The number of freed bytes.
+    pub bytes: u64,
+    pub duration: Duration,
+}
+
+/// The blocks of a dag in the store.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct DagStat {
+    /// The total size of the blocks in bytes.
+    pub size: u64,
+    /// The number of blocks.
+    pub blocks: u64,
+}
+
+/// The last garbage collector runs.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct GcHistory(Arc<Mutex<VecDeque<GcRun>>>);
+
+impl GcHistory {
+    pub fn push(&self, run: GcRun) {
+        let mut runs = self.0.lock();
+        if runs.len() >= GC_HISTORY {
+            runs.pop_front();
+        }
+        runs.push_back(run);
+    }
+
+    pub fn runs(&self) -> Vec<GcRun> {
+        self.0.lock().iter().cloned().collect()
+    }
+}
+
+/// Computes the blocks reachable from the aliases starting with `prefix`, except the alias
+/// `exclude`, and from `roots`. Blocks shared between the dags are counted once.
+pub(crate) fn dags_usage(
+    conn: &Connection,
+    prefix: &[u8],
+    exclude: Option<&[u8]>,
+    roots: &[Cid],
+) -> rusqlite::Result<DagStat> {
+    let sql = format!(
+        "WITH RECURSIVE dag(id) AS (
+            SELECT block_id FROM aliases
+            WHERE substr(name, 1, length(?1)) = ?1 AND (?2 IS NULL OR name != ?2)
+            UNION SELECT id FROM cids WHERE cid IN ({})
+            UNION SELECT refs.child_id FROM refs INNER JOIN dag ON refs.parent_id = dag.id
+        )
+        SELECT COUNT(*), COALESCE(SUM(LENGTH(blocks.block)), 0) FROM dag
+        INNER JOIN blocks ON blocks.block_id = dag.id",
+        vec!["?"; roots.len()].join(", ")
+    );
+    let mut args = vec![
+        Value::Blob(prefix.to_vec()),
+        exclude
+            .map(|alias| Value::Blob(alias.to_vec()))
+            .unwrap_or(Value::Null),
+    ];
+    args.extend(roots.iter().map(|root| Value::Blob(root.to_bytes())));
+    let (blocks, size): (i64, i64) =
+        conn.query_row(&sql, args, |row| Ok((row.get(0)?, row.get(1)?)))?;
+    Ok(DagStat {
+        size: size as u64,
+        blocks: blocks as u64,
+    })
+}
+
+/// Computes the usage of a block store of `bytes` total size.
+pub(crate) fn usage(conn: &Connection, bytes: u64) -> rusqlite::Result<RepoUsage> {
+    let (pinned_blocks, pinned_bytes): (i64, i64) = conn.query_row(
+        "WITH RECURSIVE pinned(id) AS (
+            SELECT block_id FROM aliases
+            UNION SELECT block_id FROM temp_pins
+            UNION SELECT refs.child_id FROM refs INNER JOIN pinned ON refs.parent_id = pinned.id
+        )
+        SELECT COUNT(*), COALESCE(SUM(LENGTH(blocks.block)), 0) FROM pinned
+        INNER JOIN blocks ON blocks.block_id = pinned.id",
+        params![],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    let (temp_pins, temp_pinned_blocks): (i64, i64) = conn.query_row(
+        "SELECT COUNT(DISTINCT id), COUNT(*) FROM temp_pins",
+        params![],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    let orphaned_blocks: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM blocks WHERE block_id NOT IN (SELECT id FROM cids)",
+        params![],
+        |row| row.get(0),
+    )?;
+    Ok(RepoUsage {
+        pinned_blocks: pinned_blocks as u64,
+        pinned_bytes: pinned_bytes as u64,
+        cached_bytes: bytes.saturating_sub(pinned_bytes as u64),
+        temp_pins: temp_pins as u64,
+        temp_pinned_blocks: temp_pinned_blocks as u64,
+        orphaned_blocks: orphaned_blocks as u64,
+    })
+}
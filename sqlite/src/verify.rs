@@ -0,0 +1,104 @@
+use fnv::FnvHashSet;
+use libipld::codec::References;
+use libipld::store::StoreParams;
+use libipld::{Block, Cid, Ipld};
+use rusqlite::{params, Connection};
+use std::convert::TryFrom;
+
+/// The result of an integrity check of the block store.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct VerifyReport {
+    /// The number of checked blocks.
+    pub blocks: u64,
+    /// Blocks whose data doesn't match the hash of their cid.
+    pub corrupt: Vec<Cid>,
+    /// Blocks whose stored references don't match the links of the block.
+    pub bad_refs: Vec<Cid>,
+    /// The aliases with blocks missing from their dag.
+    pub missing: Vec<(Vec<u8>, Vec<Cid>)>,
+    /// The corrupt blocks deleted by a repair.
+    pub deleted: Vec<Cid>,
+    /// The deleted blocks fetched again from the network.
+    pub refetched: Vec<Cid>,
+}
+
+impl VerifyReport {
+    /// Returns `true` if no problem was found.
+    pub fn is_ok(&self) -> bool {
+        self.corrupt.is_empty() && self.bad_refs.is_empty() && self.missing.is_empty()
+    }
+}
+
+fn cid_from_row(bytes: Vec<u8>) -> Option<Cid> {
+    match Cid::try_from(bytes.as_slice()) {
+        Ok(cid) => Some(cid),
+        Err(err) => {
+            tracing::warn!("invalid cid in the block store: {}", err);
+            None
+        }
+    }
+}
+
+/// Rehashes every block and compares its links with the references table.
+pub(crate) fn check_blocks<S: StoreParams>(
+    conn: &Connection,
+    report: &mut VerifyReport,
+) -> rusqlite::Result<()>
+where
+    Ipld: References<S::Codecs>,
+{
+    let mut blocks = conn.prepare(
+        "SELECT cids.id, cids.cid, blocks.block FROM cids
+         INNER JOIN blocks ON cids.id = blocks.block_id",
+    )?;
+    let mut refs = conn.prepare(
+        "SELECT cids.cid FROM refs
+         INNER JOIN cids ON refs.child_id = cids.id WHERE refs.parent_id = ?1",
+    )?;
+    let mut rows = blocks.query(params![])?;
+    while let Some(row) = rows.next()? {
+        let id: i64 = row.get(0)?;
+        report.blocks += 1;
+        let cid = match cid_from_row(row.get(1)?) {
+            Some(cid) => cid,
+            None => continue,
+        };
+        // `Block::new` verifies the hash
+        let block = match Block::<S>::new(cid, row.get(2)?) {
+            Ok(block) => block,
+            Err(_) => {
+                report.corrupt.push(cid);
+                continue;
+            }
+        };
+        let mut links = FnvHashSet::default();
+        if let Err(err) = block.references(&mut links) {
+            tracing::debug!("can't decode the links of {}: {}", cid, err);
+            continue;
+        }
+        let stored = refs
+            .query_map(params![id], |row| row.get(0))?
+            .filter_map(|cid| cid.ok().and_then(cid_from_row))
+            .collect::<FnvHashSet<_>>();
+        if links != stored {
+            report.bad_refs.push(cid);
+        }
+    }
+    Ok(())
+}
+
+/// Deletes the data and the references of a block. The cid stays, it may still be
+/// referenced by other blocks.
+pub(crate) fn delete_block(conn: &mut Connection, cid: &Cid) -> rusqlite::Result<()> {
+    let tx = conn.transaction()?;
+    let cid = cid.to_bytes();
+    tx.execute(
+        "DELETE FROM refs WHERE parent_id = (SELECT id FROM cids WHERE cid = ?1)",
+        params![cid],
+    )?;
+    tx.execute(
+        "DELETE FROM blocks WHERE block_id = (SELECT id FROM cids WHERE cid = ?1)",
+        params![cid],
+    )?;
+    tx.commit()
+}
@@ -0,0 +1,388 @@
+//! CAR (content addressable archive) import and export.
+//!
+//! CARv1 is a header followed by the blocks of a dag. CARv2 wraps a CARv1 payload with a
+//! fixed header and appends a sorted index of the block offsets.
+use fnv::FnvHashSet;
+use ipfs_embed_sqlite::StorageService;
+use libipld::cbor::DagCborCodec;
+use libipld::codec::{Codec, References};
+use libipld::error::BlockNotFound;
+use libipld::multihash::{Code, MultihashDigest};
+use libipld::store::StoreParams;
+use libipld::{Block, Cid, Ipld, Result};
+use std::collections::BTreeMap;
+use std::io::{Cursor, Read, Write};
+
+/// The CARv2 pragma, a CARv1 header with version 2.
+const V2_PRAGMA: [u8; 11] = [
+    0x0a, 0xa1, 0x67, 0x76, 0x65, 0x72, 0x73, 0x69, 0x6f, 0x6e, 0x02,
+];
+/// The size of the CARv2 header following the pragma.
+const V2_HEADER_SIZE: u64 = 40;
+/// The multicodec of the sorted CARv2 index.
+const INDEX_SORTED: u64 = 0x0400;
+/// The number of blocks inserted per transaction on import.
+pub(crate) const BATCH_SIZE: usize = 1024;
+/// The maximum size of a header or block section.
+const MAX_SECTION_SIZE: u64 = 4 * 1024 * 1024;
+/// The multicodec of the index blocks of an export of blocks.
+const DAG_CBOR: u64 = 0x71;
+/// The maximum number of links of an index block, keeping it well below the section size.
+const INDEX_LINKS: usize = 16 * 1024;
+
+/// The archive version written by an export.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CarVersion {
+    V1,
+    /// A CARv1 payload with an index of the block offsets.
+    V2,
+}
+
+fn write_varint<W: Write>(w: &mut W, mut n: u64) -> Result<usize> {
+    let mut buf = [0u8; 10];
+    let mut i = 0;
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            buf[i] = byte;
+            i += 1;
+            break;
+        }
+        buf[i] = byte | 0x80;
+        i += 1;
+    }
+    w.write_all(&buf[..i])?;
+    Ok(i)
+}
+
+/// Reads a varint, returning `None` at the end of the input.
+fn read_varint<R: Read>(r: &mut R) -> Result<Option<u64>> {
+    let mut n = 0u64;
+    for i in 0..10 {
+        let mut byte = [0u8];
+        if r.read(&mut byte)? == 0 {
+            if i == 0 {
+                return Ok(None);
+            }
+            return Err(anyhow::anyhow!("truncated varint"));
+        }
+        n |= ((byte[0] & 0x7f) as u64) << (i * 7);
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some(n));
+        }
+    }
+    Err(anyhow::anyhow!("varint overflow"))
+}
+
+fn read_section<R: Read>(r: &mut R) -> Result<Option<Vec<u8>>> {
+    let len = match read_varint(r)? {
+        Some(len) => len,
+        None => return Ok(None),
+    };
+    if len > MAX_SECTION_SIZE {
+        return Err(anyhow::anyhow!("car section too large: {}", len));
+    }
+    let mut buf = vec![0; len as usize];
+    r.read_exact(&mut buf)?;
+    Ok(Some(buf))
+}
+
+fn encode_header(roots: &[Cid]) -> Result<Vec<u8>> {
+    let mut header = BTreeMap::new();
+    header.insert(
+        "roots".to_string(),
+        Ipld::List(roots.iter().map(|root| Ipld::Link(*root)).collect()),
+    );
+    header.insert("version".to_string(), Ipld::Integer(1));
+    DagCborCodec.encode(&Ipld::Map(header))
+}
+
+/// Decodes a header, returning the version and the roots.
+fn decode_header(bytes: &[u8]) -> Result<(u64, Vec<Cid>)> {
+    let header = match DagCborCodec.decode::<Ipld>(bytes)? {
+        Ipld::Map(header) => header,
+        _ => return Err(anyhow::anyhow!("invalid car header")),
+    };
+    let version = match header.get("version") {
+        Some(Ipld::Integer(version)) if *version > 0 => *version as u64,
+        _ => return Err(anyhow::anyhow!("invalid car header")),
+    };
+    let mut roots = vec![];
+    if let Some(Ipld::List(list)) = header.get("roots") {
+        for root in list {
+            match root {
+                Ipld::Link(cid) => roots.push(*cid),
+                _ => return Err(anyhow::anyhow!("invalid car root")),
+            }
+        }
+    }
+    Ok((version, roots))
+}
+
+/// Visits the closure of `root` in depth first order, root first, reading every block
+/// once.
+fn visit_closure<P: StoreParams, F>(storage: &StorageService<P>, root: &Cid, mut f: F) -> Result<()>
+where
+    Ipld: References<P::Codecs>,
+    F: FnMut(&Cid, &[u8]) -> Result<()>,
+{
+    let mut visited = FnvHashSet::default();
+    let mut stack = vec![*root];
+    while let Some(cid) = stack.pop() {
+        if !visited.insert(cid) {
+            continue;
+        }
+        let data = storage.get(&cid)?.ok_or(BlockNotFound(cid))?;
+        f(&cid, &data)?;
+        let block = Block::<P>::new_unchecked(cid, data);
+        let mut refs = vec![];
+        block.references(&mut refs)?;
+        stack.extend(refs.into_iter().rev());
+    }
+    Ok(())
+}
+
+fn write_section<W: Write>(w: &mut W, cid: &Cid, data: &[u8]) -> Result<()> {
+    let cid_bytes = cid.to_bytes();
+    write_varint(w, (cid_bytes.len() + data.len()) as u64)?;
+    w.write_all(&cid_bytes)?;
+    w.write_all(data)?;
+    Ok(())
+}
+
+/// Writes the blocks as a CARv1 in the given order.
+#[cfg(test)]
+pub(crate) fn write_car<P: StoreParams>(roots: &[Cid], blocks: &[Block<P>]) -> Result<Vec<u8>> {
+    let mut w = vec![];
+    let header = encode_header(roots)?;
+    write_varint(&mut w, header.len() as u64)?;
+    w.write_all(&header)?;
+    for block in blocks {
+        write_section(&mut w, block.cid(), block.data())?;
+    }
+    Ok(w)
+}
+
+/// Encodes a DagCbor list of links to `cids`.
+fn index_block(cids: &[Cid]) -> Result<(Cid, Vec<u8>)> {
+    let links = cids.iter().map(|cid| Ipld::Link(*cid)).collect();
+    let data = DagCborCodec.encode(&Ipld::List(links))?;
+    let cid = Cid::new_v1(DAG_CBOR, Code::Sha2_256.digest(&data));
+    Ok((cid, data))
+}
+
+/// Writes the blocks as a CARv1, skipping blocks no longer in the store, and returns its
+/// root. The root is an index block listing the blocks, or listing index blocks of up to
+/// `INDEX_LINKS` blocks each for a large export. The index blocks are written first.
+pub(crate) fn export_blocks<P: StoreParams, W: Write>(
+    storage: &StorageService<P>,
+    cids: &[Cid],
+    mut w: W,
+) -> Result<Cid>
+where
+    Ipld: References<P::Codecs>,
+{
+    // keeps the blocks listed by the index from the garbage collector
+    let pin = storage.create_temp_pin()?;
+    storage.temp_pin(&pin, cids.to_vec())?;
+    let mut blocks = Vec::with_capacity(cids.len());
+    for cid in cids {
+        if storage.contains(cid)? {
+            blocks.push(*cid);
+        }
+    }
+    let mut index = vec![];
+    if blocks.len() > INDEX_LINKS {
+        let leaves = blocks
+            .chunks(INDEX_LINKS)
+            .map(index_block)
+            .collect::<Result<Vec<_>>>()?;
+        let links = leaves.iter().map(|(cid, _)| *cid).collect::<Vec<_>>();
+        index.push(index_block(&links)?);
+        index.extend(leaves);
+    } else {
+        index.push(index_block(&blocks)?);
+    }
+    let root = index[0].0;
+    let header = encode_header(&[root])?;
+    write_varint(&mut w, header.len() as u64)?;
+    w.write_all(&header)?;
+    for (cid, data) in &index {
+        write_section(&mut w, cid, data)?;
+    }
+    for cid in &blocks {
+        let data = storage.get(cid)?.ok_or(BlockNotFound(*cid))?;
+        write_section(&mut w, cid, &data)?;
+    }
+    w.flush()?;
+    Ok(root)
+}
+
+/// Writes the closure of `root` as a CAR.
+pub(crate) fn export<P: StoreParams, W: Write>(
+    storage: &StorageService<P>,
+    root: &Cid,
+    version: CarVersion,
+    mut w: W,
+) -> Result<()>
+where
+    Ipld: References<P::Codecs>,
+{
+    let header = encode_header(&[*root])?;
+    if version == CarVersion::V2 {
+        // the CARv2 header holds the size of the payload, so the payload is buffered
+        // while the index entries are gathered
+        let mut payload = vec![];
+        write_varint(&mut payload, header.len() as u64)?;
+        payload.write_all(&header)?;
+        let mut entries = vec![];
+        visit_closure(storage, root, |cid, data| {
+            entries.push((cid.hash().digest().to_vec(), payload.len() as u64));
+            write_section(&mut payload, cid, data)
+        })?;
+        let data_offset = V2_PRAGMA.len() as u64 + V2_HEADER_SIZE;
+        let data_size = payload.len() as u64;
+        w.write_all(&V2_PRAGMA)?;
+        w.write_all(&[0; 16])?;
+        w.write_all(&data_offset.to_le_bytes())?;
+        w.write_all(&data_size.to_le_bytes())?;
+        w.write_all(&(data_offset + data_size).to_le_bytes())?;
+        w.write_all(&payload)?;
+        write_index(&mut w, entries)?;
+    } else {
+        write_varint(&mut w, header.len() as u64)?;
+        w.write_all(&header)?;
+        visit_closure(storage, root, |cid, data| write_section(&mut w, cid, data))?;
+    }
+    w.flush()?;
+    Ok(())
+}
+
+/// Writes a sorted index, the entries are bucketed by digest width and sorted by digest.
+fn write_index<W: Write>(w: &mut W, mut entries: Vec<(Vec<u8>, u64)>) -> Result<()> {
+    entries.sort();
+    entries.dedup_by(|a, b| a.0 == b.0);
+    let mut buckets: BTreeMap<usize, Vec<(Vec<u8>, u64)>> = BTreeMap::new();
+    for entry in entries {
+        buckets.entry(entry.0.len()).or_default().push(entry);
+    }
+    write_varint(w, INDEX_SORTED)?;
+    w.write_all(&(buckets.len() as u32).to_le_bytes())?;
+    for (digest_len, entries) in buckets {
+        let width = digest_len as u64 + 8;
+        w.write_all(&(width as u32).to_le_bytes())?;
+        w.write_all(&(width * entries.len() as u64).to_le_bytes())?;
+        for (digest, offset) in entries {
+            w.write_all(&digest)?;
+            w.write_all(&offset.to_le_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads the blocks of a CARv1 or CARv2, verifying the hash of every block.
+pub(crate) struct CarReader<R> {
+    reader: std::io::Take<R>,
+    roots: Vec<Cid>,
+}
+
+impl<R: Read> CarReader<R> {
+    pub fn new(mut reader: R) -> Result<Self> {
+        let header = read_section(&mut reader)?.ok_or_else(|| anyhow::anyhow!("empty car"))?;
+        let (version, roots) = decode_header(&header)?;
+        match version {
+            1 => Ok(Self {
+                reader: reader.take(u64::MAX),
+                roots,
+            }),
+            2 => {
+                let mut header = [0u8; V2_HEADER_SIZE as usize];
+                reader.read_exact(&mut header)?;
+                let u64_at = |i: usize| {
+                    let mut bytes = [0u8; 8];
+                    bytes.copy_from_slice(&header[i..i + 8]);
+                    u64::from_le_bytes(bytes)
+                };
+                let data_offset = u64_at(16);
+                let data_size = u64_at(24);
+                let skip = data_offset
+                    .checked_sub(V2_PRAGMA.len() as u64 + V2_HEADER_SIZE)
+                    .ok_or_else(|| anyhow::anyhow!("invalid car data offset"))?;
+                std::io::copy(&mut (&mut reader).take(skip), &mut std::io::sink())?;
+                let mut reader = reader.take(data_size);
+                let header =
+                    read_section(&mut reader)?.ok_or_else(|| anyhow::anyhow!("empty car"))?;
+                let (version, roots) = decode_header(&header)?;
+                if version != 1 {
+                    return Err(anyhow::anyhow!("invalid car payload version {}", version));
+                }
+                Ok(Self { reader, roots })
+            }
+            version => Err(anyhow::anyhow!("unsupported car version {}", version)),
+        }
+    }
+
+    /// The roots of the archive.
+    pub fn roots(&self) -> &[Cid] {
+        &self.roots
+    }
+
+    /// Returns the next block, or `None` at the end of the archive.
+    pub fn next_block<P: StoreParams>(&mut self) -> Result<Option<Block<P>>> {
+        let section = match read_section(&mut self.reader)? {
+            Some(section) => section,
+            None => return Ok(None),
+        };
+        let mut cursor = Cursor::new(section);
+        let cid = Cid::read_bytes(&mut cursor)?;
+        let offset = cursor.position() as usize;
+        let mut data = cursor.into_inner();
+        data.drain(..offset);
+        // `Block::new` verifies the hash
+        Ok(Some(Block::new(cid, data)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_varint() {
+        for n in &[0, 1, 127, 128, 300, u64::MAX] {
+            let mut buf = vec![];
+            let len = write_varint(&mut buf, *n).unwrap();
+            assert_eq!(len, buf.len());
+            assert_eq!(read_varint(&mut buf.as_slice()).unwrap(), Some(*n));
+        }
+        assert_eq!(read_varint(&mut [].as_ref()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_v2_pragma() {
+        let mut header = BTreeMap::new();
+        header.insert("version".to_string(), Ipld::Integer(2));
+        let bytes = DagCborCodec.encode(&Ipld::Map(header)).unwrap();
+        let mut pragma = vec![];
+        write_varint(&mut pragma, bytes.len() as u64).unwrap();
+        pragma.extend(bytes);
+        assert_eq!(pragma, V2_PRAGMA);
+    }
+
+    #[test]
+    fn test_index_block() {
+        let (cid, data) = index_block(&[]).unwrap();
+        let links = (0..3u8)
+            .map(|i| Cid::new_v1(DAG_CBOR, Code::Sha2_256.digest(&[i])))
+            .collect::<Vec<_>>();
+        let (root, data2) = index_block(&links).unwrap();
+        assert_ne!(cid, root);
+        // the cid is verified like any imported block
+        let block = Block::<libipld::store::DefaultParams>::new(root, data2).unwrap();
+        let mut refs = vec![];
+        block.references(&mut refs).unwrap();
+        assert_eq!(refs, links);
+        assert!(Block::<libipld::store::DefaultParams>::new(root, data).is_err());
+    }
+}
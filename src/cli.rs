@@ -1,10 +1,12 @@
 
-use crate::Ipfs;
+use crate::{CarVersion, Ipfs, VerifyOptions};
 
 
 use async_global_executor::block_on;
 
 use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
 use libipld::DefaultParams;
 use libipld::multihash::Code;
 use libipld::cbor::DagCborCodec;
@@ -25,11 +27,51 @@ pub fn ipfs_cli_commands<'a>() -> Command<'a> {
         .usage("put <string>")
         .action(cli_put_block);
 
+    let car_export_cmd = Command::new("export")
+        .about("export a dag as a car file")
+        .usage("export <cid> <path> [v1|v2]")
+        .action(cli_car_export);
+    let car_import_cmd = Command::new("import")
+        .about("import a car file")
+        .usage("import <path> [alias]")
+        .action(cli_car_import);
+    let car_cmd = Command::new("car")
+        .about("import and export car files")
+        .usage("car")
+        .subcommand(car_export_cmd)
+        .subcommand(car_import_cmd);
+
+    let pin_ls_cmd = Command::new("ls")
+        .about("list aliases")
+        .usage("ls")
+        .action(cli_pin_ls);
+    let pin_cmd = Command::new("pin")
+        .about("aliases")
+        .usage("pin")
+        .subcommand(pin_ls_cmd);
+
+    let repo_verify_cmd = Command::new("verify")
+        .about("check the integrity of the block store")
+        .usage("verify [repair]")
+        .action(cli_repo_verify);
+    let repo_backup_cmd = Command::new("backup")
+        .about("copy the block store to a file")
+        .usage("backup <path>")
+        .action(cli_repo_backup);
+    let repo_cmd = Command::new("repo")
+        .about("block store")
+        .usage("repo")
+        .subcommand(repo_verify_cmd)
+        .subcommand(repo_backup_cmd);
+
     Command::new_with_alias(IPFS, "i")
         .about("IPFS")
         .usage("ipfs")
         .subcommand(get_block_cmd)
         .subcommand(put_block_cmd)
+        .subcommand(car_cmd)
+        .subcommand(pin_cmd)
+        .subcommand(repo_cmd)
 }
 
 
@@ -80,3 +122,133 @@ fn cli_put_block(app: &App, args: &[&str]) -> XcliResult {
 
     Ok(CmdExeCode::Ok)
 }
+
+fn cli_car_export(app: &App, args: &[&str]) -> XcliResult {
+    let ipfs = handler(app);
+
+    if args.len() != 2 && args.len() != 3 {
+        return Err(XcliError::MismatchArgument(2, args.len()));
+    }
+    let cid = Cid::try_from(args[0]).map_err(|e| XcliError::BadArgument(e.to_string()))?;
+    let version = match args.get(2) {
+        None | Some(&"v1") => CarVersion::V1,
+        Some(&"v2") => CarVersion::V2,
+        Some(v) => return Err(XcliError::BadArgument(format!("unknown car version {}", v))),
+    };
+
+    let res = File::create(args[1])
+        .map_err(Into::into)
+        .and_then(|file| ipfs.export_car(&cid, version, BufWriter::new(file)));
+    match res {
+        Ok(()) => println!("exported {} to {}", cid, args[1]),
+        Err(e) => println!("export failed: {:?}", e),
+    }
+
+    Ok(CmdExeCode::Ok)
+}
+
+fn cli_car_import(app: &App, args: &[&str]) -> XcliResult {
+    let ipfs = handler(app);
+
+    if args.len() != 1 && args.len() != 2 {
+        return Err(XcliError::MismatchArgument(1, args.len()));
+    }
+    let alias = args.get(1).map(|alias| alias.as_bytes());
+
+    let res = File::open(args[0])
+        .map_err(Into::into)
+        .and_then(|file| ipfs.import_car(BufReader::new(file), alias));
+    match res {
+        Ok(roots) => {
+            for root in roots {
+                println!("imported {}", root);
+            }
+        }
+        Err(e) => println!("import failed: {:?}", e),
+    }
+
+    Ok(CmdExeCode::Ok)
+}
+
+fn cli_pin_ls(app: &App, _args: &[&str]) -> XcliResult {
+    let ipfs = handler(app);
+
+    let aliases = match ipfs.aliases() {
+        Ok(aliases) => aliases,
+        Err(e) => {
+            println!("listing aliases failed: {:?}", e);
+            return Ok(CmdExeCode::Ok);
+        }
+    };
+    println!("{:32} {:64} {:>8} {:>12} label", "alias", "root", "blocks", "size");
+    for (alias, root) in aliases {
+        let name = String::from_utf8_lossy(&alias);
+        match ipfs.alias_info(&alias) {
+            Ok(Some(info)) => println!(
+                "{:32} {:64} {:>8} {:>12} {}",
+                name,
+                root.to_string(),
+                info.blocks,
+                info.size,
+                info.label.unwrap_or_default()
+            ),
+            Ok(None) => {}
+            Err(e) => println!("{:32} {:64} {:?}", name, root.to_string(), e),
+        }
+    }
+
+    Ok(CmdExeCode::Ok)
+}
+
+fn cli_repo_verify(app: &App, args: &[&str]) -> XcliResult {
+    let ipfs = handler(app);
+
+    let repair = match args {
+        [] => false,
+        ["repair"] => true,
+        _ => return Err(XcliError::BadArgument(args.join(" "))),
+    };
+    let options = VerifyOptions {
+        repair,
+        refetch: repair,
+    };
+
+    match block_on(ipfs.verify(options)) {
+        Ok(report) => {
+            println!("checked {} blocks", report.blocks);
+            for cid in &report.corrupt {
+                println!("corrupt {}", cid);
+            }
+            for cid in &report.bad_refs {
+                println!("bad references {}", cid);
+            }
+            for (alias, cids) in &report.missing {
+                println!("{} missing {} blocks", String::from_utf8_lossy(alias), cids.len());
+            }
+            for cid in &report.deleted {
+                println!("deleted {}", cid);
+            }
+            for cid in &report.refetched {
+                println!("refetched {}", cid);
+            }
+        }
+        Err(e) => println!("verify failed: {:?}", e),
+    }
+
+    Ok(CmdExeCode::Ok)
+}
+
+fn cli_repo_backup(app: &App, args: &[&str]) -> XcliResult {
+    let ipfs = handler(app);
+
+    if args.len() != 1 {
+        return Err(XcliError::MismatchArgument(1, args.len()));
+    }
+
+    match block_on(ipfs.backup(args[0])) {
+        Ok(seq) => println!("backed up to {} at change {}", args[0], seq),
+        Err(e) => println!("backup failed: {:?}", e),
+    }
+
+    Ok(CmdExeCode::Ok)
+}
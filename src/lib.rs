@@ -10,28 +10,75 @@
 //! ipfs.listen_on(?).await?;
 //! # Ok(()) }
 
+mod car;
 mod cli;
+mod reprovider;
 
+use async_global_executor::Task;
 use async_trait::async_trait;
 use futures::channel::mpsc;
-use futures::stream::StreamExt;
+use futures::stream::{BoxStream, StreamExt};
 pub use ipfs_embed_net::{
-    Key, Multiaddr, NetworkConfig, PeerId, Record,
+    AutonatConfig, ConnectionLimits, ConnectionManagerConfig, GossipsubConfig, Key, Multiaddr,
+    Lagged as NetworkLagged, MuxerConfig, NameRecord, NatStatus, NetworkConfig, NetworkEvent,
+    NetworkEventStream, PeerId, PeerInfo, PubsubConfig, PubsubMessage, Record, RecordValidator,
+    RelayServerConfig, SecurityConfig, SignedRecord, Subscription, TransportConfig,
+    ValidationResult,
 };
-use ipfs_embed_net::{BitswapStore, NetworkService, Keypair, Subscription, Topic, xcli::App, swarm_cli_commands, dht_cli_commands};
-pub use ipfs_embed_sqlite::{StorageConfig, TempPin};
-use ipfs_embed_sqlite::{StorageEvent, StorageService};
+use ipfs_embed_net::{BitswapStore, NetworkService, NameValidator, Keypair, xcli::App, swarm_cli_commands, dht_cli_commands};
+pub use ipfs_embed_sqlite::{
+    DagStat, GcRun, Lagged, Quota, QuotaExceeded, QuotaPin, RepoStats, RepoUsage, StorageClosed,
+    StorageConfig, StorageEvent, StorageEvents, TempPin, VerifyReport,
+};
+use ipfs_embed_sqlite::StorageService;
 use libipld::codec::References;
 use libipld::error::BlockNotFound;
 pub use libipld::store::DefaultParams;
 use libipld::store::{Store, StoreParams};
 use libipld::{Block, Cid, Ipld, Result};
 use prometheus::{Encoder, Registry};
+use std::convert::TryFrom;
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 use crate::cli::ipfs_cli_commands;
+use crate::reprovider::Reprovider;
+pub use crate::car::CarVersion;
+pub use crate::reprovider::{ReprovideStrategy, ReproviderConfig};
 use futures::Future;
 
+/// The number of storage events queued for a subscriber before the oldest are dropped.
+const STORAGE_EVENTS_CAPACITY: usize = 1024;
+/// The number of network events queued for a subscriber before the oldest are dropped.
+const NETWORK_EVENTS_CAPACITY: usize = 1024;
+
+/// An alias with its metadata.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AliasInfo {
+    pub alias: Vec<u8>,
+    /// The root of the alias.
+    pub root: Cid,
+    /// When the alias was created, `None` for aliases created before metadata was recorded.
+    pub created: Option<SystemTime>,
+    /// When the alias was last pointed at a root.
+    pub updated: Option<SystemTime>,
+    /// A user defined label.
+    pub label: Option<String>,
+    /// The total size in bytes of the blocks of the dag in the store.
+    pub size: u64,
+    /// The number of blocks of the dag in the store.
+    pub blocks: u64,
+}
+
+/// Options of `Ipfs::verify`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct VerifyOptions {
+    /// Deletes corrupt blocks and rewrites the references of blocks with wrong references.
+    pub repair: bool,
+    /// Fetches the deleted blocks from the network.
+    pub refetch: bool,
+}
+
 /// Ipfs configuration.
 #[derive(Clone, Debug)]
 pub struct Config {
@@ -39,6 +86,8 @@ pub struct Config {
     pub storage: StorageConfig,
     /// Network configuration.
     pub network: NetworkConfig,
+    /// Reprovider configuration.
+    pub reprovider: ReproviderConfig,
 }
 
 impl Config {
@@ -46,9 +95,36 @@ impl Config {
     /// ipfs will use an in-memory block store.
     pub fn new(path: Option<std::path::PathBuf>, cache_size: u64, listen_addr: Multiaddr) -> Self {
         let sweep_interval = std::time::Duration::from_millis(10000);
+        let mut network = NetworkConfig::new(vec![listen_addr]);
+        network.kad_store_path = path.clone();
         let storage = StorageConfig::new(path, cache_size, sweep_interval);
-        let network = NetworkConfig::new(vec![listen_addr]);
-        Self { storage, network }
+        Self {
+            storage,
+            network,
+            reprovider: Default::default(),
+        }
+    }
+
+    /// Creates a configuration for a persistent node stored in the directory `path`.
+    ///
+    /// The block store and the dht records are kept in `path/blocks.sqlite` and the node
+    /// keypair in `path/keypair`.
+    /// If there is no keypair yet, a new one is generated and saved, so the node keeps its
+    /// `PeerId` across restarts.
+    pub fn with_repo<T: AsRef<std::path::Path>>(path: T, cache_size: u64, listen_addr: Multiaddr) -> Result<Self> {
+        let path = path.as_ref();
+        std::fs::create_dir_all(path)?;
+        let sweep_interval = std::time::Duration::from_millis(10000);
+        let db = path.join("blocks.sqlite");
+        let mut network = NetworkConfig::from_keyfile(path.join("keypair"))?;
+        network.listening_addrs.push(listen_addr);
+        network.kad_store_path = Some(db.clone());
+        let storage = StorageConfig::new(Some(db), cache_size, sweep_interval);
+        Ok(Self {
+            storage,
+            network,
+            reprovider: Default::default(),
+        })
     }
 }
 
@@ -56,29 +132,34 @@ impl Config {
 #[derive(Clone)]
 pub struct Ipfs<P: StoreParams> {
     keypair: Keypair,
+    name_pubsub: bool,
     storage: StorageService<P>,
     network: NetworkService,
+    reprovider: Reprovider<P>,
+    /// The background tasks stopped on shutdown.
+    tasks: Arc<Mutex<Vec<Task<()>>>>,
 }
 
 #[derive(Clone)]
 struct BitswapStorage<P: StoreParams>(StorageService<P>);
 
+#[async_trait]
 impl<P: StoreParams> BitswapStore for BitswapStorage<P>
 where
     Ipld: References<P::Codecs>,
 {
     type Params = P;
 
-    fn contains(&mut self, cid: &Cid) -> Result<bool> {
-        self.0.contains(cid)
+    async fn contains(&mut self, cid: &Cid) -> Result<bool> {
+        self.0.contains_async(cid).await
     }
 
-    fn get(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>> {
-        self.0.get(cid)
+    async fn get(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+        self.0.get_async(cid).await
     }
 
-    fn insert(&mut self, block: &Block<P>) -> Result<()> {
-        self.0.insert(block)
+    async fn insert(&mut self, block: Block<P>) -> Result<()> {
+        self.0.insert_async(block).await
     }
 
     fn missing_blocks(&mut self, cid: &Cid) -> Result<Vec<Cid>> {
@@ -92,26 +173,33 @@ where
 {
     /// Creates a new `Ipfs` from a `Config`.
     ///
-    /// This starts three background tasks. The swarm, garbage collector and the dht cleanup
-    /// tasks run in the background.
+    /// This starts several background tasks. The swarm, garbage collector, reprovider and the
+    /// dht cleanup tasks run in the background.
     pub async fn new(config: Config) -> Result<Self> {
         let keypair = config.network.node_key.clone();
+        let name_pubsub = config.network.name_pubsub;
 
-        let (tx, mut storage_events) = mpsc::unbounded();
+        let (tx, storage_events) = mpsc::unbounded();
         let storage = StorageService::open(config.storage, tx)?;
         let bitswap = BitswapStorage(storage.clone());
         let network = NetworkService::new(config.network, bitswap).await?;
 
-        let mut _kad = network.kad();
-        async_global_executor::spawn(async move {
-            while let Some(StorageEvent::Remove(cid)) = storage_events.next().await {
-                //let _ = kad.unprovide(cid.to_bytes()).await;
-                tracing::debug!("stop providing {} ...", cid);
-            }
-        })
-        .detach();
+        let reprovider = Reprovider::new(config.reprovider, storage.clone(), network.clone());
+        let tasks = Arc::new(Mutex::new(reprovider.spawn(storage_events)));
+
+        Ok(Self { keypair, name_pubsub, storage, network, reprovider, tasks })
+    }
 
-        Ok(Self { keypair, storage, network })
+    /// Shuts the node down. Stops the background tasks, closes the network protocols, the dht
+    /// record store and the swarm, then stops the garbage collector, flushes the block store
+    /// and closes the database. The node and all its clones can't be used afterwards.
+    pub async fn shutdown(&self) -> Result<()> {
+        let tasks = std::mem::take(&mut *self.tasks.lock().unwrap());
+        for task in tasks {
+            task.cancel().await;
+        }
+        self.network.shutdown().await;
+        self.storage.close().await
     }
 
     /// Returns the local `PeerId`.
@@ -140,16 +228,31 @@ where
         self.network.swarm().self_addrs().await.unwrap_or_default()
     }
 
+    /// Returns the addresses other peers can reach the node at. These are the addresses
+    /// confirmed by peers dialing back the node and the `/p2p-circuit` addresses of the
+    /// relays the node reserved a slot on.
+    pub fn external_addresses(&self) -> Vec<Multiaddr> {
+        let mut addrs = self.network.autonat().confirmed_addrs();
+        addrs.extend(self.network.relay_addrs());
+        addrs
+    }
+
+    /// Returns the reachability of the node detected by autonat.
+    pub fn nat_status(&self) -> NatStatus {
+        self.network.autonat().status()
+    }
+
+    /// Asks connected peers to dial back the node and returns the updated reachability.
+    /// While the node isn't publicly reachable the dht runs in client mode.
+    pub async fn probe_nat(&self) -> Result<NatStatus> {
+        Ok(self.network.autonat().probe().await?)
+    }
+
     // /// Adds an external address.
     // pub fn add_external_address(&self, addr: Multiaddr) {
     //     self.network.add_external_address(addr)
     // }
     //
-    // /// Returns the currently used external addresses.
-    // pub fn external_addresses(&self) -> Vec<AddressRecord> {
-    //     self.network.external_addresses()
-    // }
-    //
     // /// Adds a known `Multiaddr` for a `PeerId`.
     // pub fn add_address(&self, peer: &PeerId, addr: Multiaddr) {
     //     self.network.add_address(peer, addr)
@@ -162,32 +265,73 @@ where
 
     /// Dials a `PeerId` using a known address.
     pub async fn dial(&self, peer: &PeerId) -> Result<()> {
-        let _= self.network.swarm().new_connection(*peer).await?;
-        Ok(())
+        self.network.dial(peer).await
     }
 
     /// Dials a `PeerId` using `Multiaddr`.
     pub async fn dial_address(&self, peer: &PeerId, addr: Multiaddr) -> Result<()> {
-        let _= self.network.swarm().connect_with_addrs(*peer, vec![addr]).await?;
-        Ok(())
+        self.network.dial_address(peer, addr).await
     }
 
-    // /// Bans a `PeerId` from the swarm, dropping all existing connections and
-    // /// preventing new connections from the peer.
-    // pub fn ban(&self, peer: PeerId) {
-    //     self.network.ban(peer)
-    // }
-    //
-    // /// Unbans a previously banned `PeerId`.
-    // pub fn unban(&self, peer: PeerId) {
-    //     self.network.unban(peer)
-    // }
+    /// Protects the connections to a peer from being closed by the connection manager until
+    /// all protection tags of the peer are removed.
+    pub fn protect_peer(&self, peer: &PeerId, tag: &str) {
+        self.network.connmgr().protect(peer, tag)
+    }
+
+    /// Removes a protection tag of a peer, returning `true` if the peer is still protected.
+    pub fn unprotect_peer(&self, peer: &PeerId, tag: &str) -> bool {
+        self.network.connmgr().unprotect(peer, tag)
+    }
+
+    /// Sets the value of a tag of a peer. When there are too many connections, peers with
+    /// the lowest total value are disconnected first.
+    pub fn tag_peer(&self, peer: &PeerId, tag: &str, value: i32) {
+        self.network.connmgr().tag_peer(peer, tag, value)
+    }
+
+    /// Removes a tag of a peer.
+    pub fn untag_peer(&self, peer: &PeerId, tag: &str) {
+        self.network.connmgr().untag_peer(peer, tag)
+    }
+
+    /// Bans a `PeerId` from the swarm, dropping all existing connections and
+    /// preventing new connections from the peer.
+    pub fn ban(&self, peer: PeerId) {
+        self.network.ban(&peer)
+    }
+
+    /// Unbans a previously banned `PeerId`.
+    pub fn unban(&self, peer: PeerId) {
+        self.network.unban(&peer)
+    }
+
+    /// Returns a stream of network events: connections, listen addresses, identified,
+    /// discovered and banned peers. Events are only delivered while the stream is alive, a
+    /// subscriber that falls behind receives `Err(NetworkLagged(n))` for the `n` events it
+    /// missed.
+    pub fn network_events(&self) -> NetworkEventStream {
+        self.network.events().subscribe(NETWORK_EVENTS_CAPACITY)
+    }
+
+    /// Returns a stream of storage events: inserted and removed blocks, alias changes and
+    /// completed garbage collector runs. A subscriber that falls behind receives
+    /// `Err(Lagged(n))` for the `n` events it missed.
+    pub fn storage_events(&self) -> StorageEvents {
+        self.storage.subscribe(STORAGE_EVENTS_CAPACITY)
+    }
 
     /// Returns the known peers.
     pub fn peers(&self) -> Vec<PeerId> {
         self.network.swarm().get_peers()
     }
 
+    /// Returns the agent version, protocols and listen addresses a connected peer sent in
+    /// identify and the round trip time of a ping.
+    pub async fn peer_info(&self, peer: &PeerId) -> Option<PeerInfo> {
+        self.network.peer_info(peer).await
+    }
+
     /// Returns a list of connected peers.
     pub async fn connections(&self) -> Vec<(PeerId, Multiaddr)> {
         let connections = self.network.swarm().dump_connections(None)
@@ -204,27 +348,154 @@ where
         cc
     }
 
-    /// Bootstraps the dht using a set of bootstrap nodes. After bootstrap completes it
-    /// provides all blocks in the block store.
+    /// Bootstraps the dht using a set of bootstrap nodes, which are protected from the
+    /// connection manager.
     pub async fn bootstrap(&self, nodes: &[(PeerId, Multiaddr)]) -> Result<()> {
-        self.network.kad().bootstrap(nodes.to_vec()).await;
-
-        for cid in self.storage.iter()? {
-            let _ = self.network.kad().provide(cid.to_bytes()).await;
+        for (peer, _) in nodes {
+            self.protect_peer(peer, "bootstrap");
         }
+        self.network.bootstrap(nodes.to_vec()).await;
         Ok(())
     }
 
-    /// Gets a record from the dht.
+    /// Announces the blocks selected by the reprovide strategy in the dht, returning the
+    /// number of announced blocks. This also runs periodically in the background.
+    pub async fn reprovide(&self) -> Result<usize> {
+        self.reprovider.reprovide().await
+    }
+
+    /// Registers a record validator for the keys of a `namespace`, e.g. `/ipns/`. Keys have
+    /// the form `/<namespace>/...`.
+    ///
+    /// The validator checks records on `put_record`, records received from the dht on
+    /// `get_record` and records put by remote peers in the local record store. Validators for
+    /// `/pk/` and for records signed by their publisher under `/signed/` are registered by
+    /// default.
+    pub fn register_record_validator<V: RecordValidator>(&self, namespace: &str, validator: V) {
+        self.network.register_record_validator(namespace, validator)
+    }
+
+    /// Gets a record from the dht. If there are several valid records, the validator of the
+    /// namespace of the key selects the best one.
     pub async fn get_record(&self, key: &[u8]) -> Result<Vec<u8>> {
-        let r = self.network.kad().get_value(key.to_vec()).await?;
-        Ok(r)
+        self.network.get_record(key).await
     }
 
-    /// Puts a new record in the dht.
+    /// Puts a new record in the dht after validating it.
     pub async fn put_record(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
-        let _ = self.network.kad().put_value(key, value).await?;
-        Ok(())
+        self.network.put_record(key, value).await
+    }
+
+    /// Puts a record signed with the node keypair under `/signed/<local peer id>/<name>`.
+    /// Returns the key of the record.
+    pub async fn put_signed_record(&self, name: &str, value: Vec<u8>) -> Result<Vec<u8>> {
+        let key = SignedRecord::key(&self.local_peer_id(), name);
+        let sequence = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_micros() as u64;
+        let record = SignedRecord::new(&self.keypair, &key, sequence, value)?;
+        self.put_record(key.clone(), record.to_bytes()).await?;
+        Ok(key)
+    }
+
+    /// Gets the value of a record signed by `peer` under `/signed/<peer>/<name>`.
+    pub async fn get_signed_record(&self, peer: &PeerId, name: &str) -> Result<Vec<u8>> {
+        let key = SignedRecord::key(peer, name);
+        let record = self.get_record(&key).await?;
+        Ok(SignedRecord::decode_record(&record)?.value)
+    }
+
+    /// Publishes the mutable name `name` of the local peer pointing to `cid`. The record is
+    /// signed with the node keypair and is valid for `ttl`. Returns the key of the name,
+    /// `/ipns/<local peer id>/<name>`.
+    ///
+    /// Every update increments the sequence number of the name, so that peers resolving the
+    /// name pick the latest record. The sequence number is at least the current unix time in
+    /// seconds, so that it also grows when the previous record isn't stored locally. When
+    /// `NetworkConfig::name_pubsub` is enabled the record is also pushed to the subscribers
+    /// of the name.
+    ///
+    /// The record is stored locally before it is pushed over pubsub and put in the dht,
+    /// which are best effort: failures are only logged, the record is still served to peers
+    /// asking for it.
+    pub async fn publish_name(&self, name: &str, cid: &Cid, ttl: Duration) -> Result<Vec<u8>> {
+        let key = NameRecord::key(&self.local_peer_id(), name);
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs();
+        let sequence = self
+            .network
+            .local_record(&key)
+            .and_then(|record| NameRecord::decode_record(&record).ok())
+            .map(|record| record.sequence + 1)
+            .unwrap_or_default()
+            .max(now);
+        let record = NameRecord::new(&self.keypair, &key, cid.to_bytes(), sequence, ttl)?;
+        let record = record.to_bytes();
+        self.network.store_record(key.clone(), record.clone())?;
+        if self.name_pubsub {
+            if let Err(err) = self.publish(&name_topic(&key), record.clone()).await {
+                tracing::warn!("publish_name: pubsub publish failed: {}", err);
+            }
+        }
+        if let Err(err) = self.put_record(key.clone(), record).await {
+            tracing::warn!("publish_name: dht put failed: {}", err);
+        }
+        Ok(key)
+    }
+
+    /// Resolves a mutable name to the `Cid` it points to. The name is either a peer id,
+    /// resolving the name `self` of the peer, `<peer id>/<name>` or `/ipns/<peer id>/<name>`.
+    ///
+    /// Records with invalid signatures or that expired are ignored and the record with the
+    /// highest sequence number wins.
+    pub async fn resolve_name(&self, name: &str) -> Result<Cid> {
+        let key = NameRecord::parse_key(name)?;
+        let record = self.get_record(&key).await?;
+        let record = NameRecord::decode_record(&record)?;
+        Ok(Cid::try_from(record.value)?)
+    }
+
+    /// Subscribes to the updates of a mutable name pushed over pubsub, see `resolve_name` for
+    /// the accepted names. Valid updates are stored in the local record store, so that
+    /// `resolve_name` returns them, and the new `Cid`s are returned by the `Stream`.
+    ///
+    /// When using gossipsub a topic validator rejects invalid records, so that they aren't
+    /// forwarded.
+    pub async fn subscribe_name(&self, name: &str) -> Result<BoxStream<'static, Cid>> {
+        let key = NameRecord::parse_key(name)?;
+        if self.network.pubsub().supports_validators() {
+            let validator_key = key.clone();
+            self.register_topic_validator(&name_topic(&key), move |_, data| {
+                match NameValidator.validate(&validator_key, data) {
+                    Ok(()) => ValidationResult::Accept,
+                    Err(err) => {
+                        tracing::debug!("rejecting name record: {}", err);
+                        ValidationResult::Reject
+                    }
+                }
+            })
+            .await?;
+        }
+        let subscription = self.subscribe(&name_topic(&key)).await?;
+        let network = self.network.clone();
+        Ok(subscription.filter_map(move |msg| {
+            let res = network.store_record(key.clone(), msg.data.clone());
+            let latest = network.local_record(&key);
+            async move {
+                if let Err(err) = res {
+                    tracing::debug!("dropping name record: {}", err);
+                    return None;
+                }
+                // the update is only returned if it replaced the stored record.
+                if latest.as_ref() != Some(&msg.data) {
+                    return None;
+                }
+                let record = NameRecord::decode_record(&msg.data).ok()?;
+                Cid::try_from(record.value).ok()
+            }
+        })
+        .boxed())
     }
 
     // /// Removes a record from the dht.
@@ -235,18 +506,40 @@ where
     /// Subscribes to a `topic` returning a `Stream` of messages. If all `Stream`s for
     /// a topic are dropped it unsubscribes from the `topic`.
     pub async fn subscribe(&self, topic: &str) -> Result<Subscription> {
-        let s = self.network.pubsub().subscribe(Topic::new(topic)).await?;
-        Ok(s)
+        self.network.pubsub().subscribe(topic).await
+    }
+
+    /// Returns the peers subscribed to a `topic`.
+    pub async fn pubsub_peers(&self, topic: &str) -> Result<Vec<PeerId>> {
+        self.network.pubsub().peers(topic).await
     }
 
     /// Publishes a new message in a `topic`, sending the message to all subscribed peers.
     pub async fn publish(&self, topic: &str, msg: Vec<u8>) -> Result<()> {
-        let _ = self.network.pubsub().publish(Topic::new(topic), msg).await;
-        Ok(())
+        self.network.pubsub().publish(topic, msg).await
+    }
+
+    /// Registers a validator for the messages of a `topic`, replacing any previous validator.
+    ///
+    /// Messages are signed with the keypair of the publisher and unsigned messages are always
+    /// dropped. The validator is called with the publisher and the payload of the remaining
+    /// messages, messages it doesn't accept are neither delivered to a `Subscription` nor
+    /// forwarded to other peers.
+    ///
+    /// Only supported by gossipsub, registering a validator when using floodsub fails.
+    pub async fn register_topic_validator<F>(&self, topic: &str, validator: F) -> Result<()>
+    where
+        F: Fn(&PeerId, &[u8]) -> ValidationResult + Send + Sync + 'static,
+    {
+        self.network
+            .pubsub()
+            .register_topic_validator(topic, Arc::new(validator))
+            .await
     }
 
     /// Creates a temporary pin in the block store. A temporary pin is not persisted to disk
-    /// and is released once it is dropped.
+    /// and is released once it is dropped. Blocks kept by a temporary pin aren't charged to
+    /// any quota, use `create_quota_pin` to limit untrusted inserts.
     pub fn create_temp_pin(&self) -> Result<TempPin> {
         self.storage.create_temp_pin()
     }
@@ -256,6 +549,12 @@ where
         self.storage.temp_pin(tmp, std::iter::once(*cid))
     }
 
+    /// Creates a temporary pin charged against the quotas of `alias`. Blocks inserted with
+    /// `insert_pinned` count towards the quotas of every pin until the pin is dropped.
+    pub fn create_quota_pin<T: AsRef<[u8]> + Send + Sync>(&self, alias: T) -> Result<QuotaPin> {
+        self.storage.create_quota_pin(alias.as_ref())
+    }
+
     /// Returns an `Iterator` of `Cid`s stored in the block store.
     pub fn iter(&self) -> Result<impl Iterator<Item = Cid>> {
         self.storage.iter()
@@ -279,12 +578,12 @@ where
     /// Either returns a block if it's in the block store or tries to retrieve it from
     /// a peer.
     pub async fn fetch(&self, cid: &Cid) -> Result<Block<P>> {
-        if let Some(data) = self.storage.get(cid)? {
+        if let Some(data) = self.storage.get_async(cid).await? {
             let block = Block::new_unchecked(*cid, data);
             return Ok(block);
         }
         self.network.bitswap().get(*cid).await?;
-        if let Some(data) = self.storage.get(cid)? {
+        if let Some(data) = self.storage.get_async(cid).await? {
             let block = Block::new_unchecked(*cid, data);
             return Ok(block);
         }
@@ -300,6 +599,128 @@ where
         Ok(self.network.bitswap_rd().has_block_rd(cid))
     }
 
+    /// Inserts a block under a quota pin and announces it to peers. Fails with
+    /// `QuotaExceeded` if the block would exceed a quota of the pin.
+    pub fn insert_pinned(
+        &self,
+        pin: &QuotaPin,
+        block: &Block<P>,
+    ) -> Result<impl Future<Output = Result<()>> + '_> {
+        let cid = *block.cid();
+        self.storage.insert_pinned(pin, block)?;
+
+        Ok(self.network.bitswap_rd().has_block_rd(cid))
+    }
+
+    /// Inserts a batch of blocks in a single transaction and announces only the `root`,
+    /// which has to be one of the blocks. If an alias is given it is set to the root
+    /// atomically with the insert. Returns a future announcing the root to peers.
+    pub fn insert_batch<T: AsRef<[u8]> + Send + Sync>(
+        &self,
+        blocks: &[Block<P>],
+        root: &Cid,
+        alias: Option<T>,
+    ) -> Result<impl Future<Output = Result<()>> + '_> {
+        if !blocks.iter().any(|block| block.cid() == root) {
+            return Err(anyhow::anyhow!("root {} is not in the batch", root));
+        }
+        let root = *root;
+        let alias = alias.as_ref().map(|alias| (alias.as_ref(), &root));
+        self.storage.insert_batch(blocks, None, alias)?;
+
+        Ok(self.network.bitswap_rd().has_block_rd(root))
+    }
+
+    /// Writes the dag of `root` as a CARv1 or a CARv2 with an index of the block offsets.
+    /// All blocks of the dag need to be in the block store.
+    ///
+    /// A CARv1 is streamed to the writer, the payload of a CARv2 is buffered in memory as
+    /// its size precedes it.
+    pub fn export_car<W>(&self, root: &Cid, version: CarVersion, writer: W) -> Result<()>
+    where
+        W: std::io::Write,
+    {
+        car::export(&self.storage, root, version, writer)
+    }
+
+    /// Writes the blocks inserted after the change `since` as a CARv1 and returns the last
+    /// exported change. Pass `0` to export every block, or the change returned by `backup`
+    /// or a previous export for an incremental backup. The root of the archive is an index
+    /// block linking the exported blocks, importing it with an alias keeps them. Aliases
+    /// aren't exported, import the archive into a restored backup and set them again. An
+    /// in-memory store doesn't log changes and exports no blocks.
+    pub fn export_changes<W>(&self, since: u64, writer: W) -> Result<u64>
+    where
+        W: std::io::Write,
+    {
+        let changes = self.storage.changes_since(since)?;
+        let last = changes.last().map(|(seq, _)| *seq).unwrap_or(since);
+        let cids = changes.into_iter().map(|(_, cid)| cid).collect::<Vec<_>>();
+        car::export_blocks(&self.storage, &cids, writer)?;
+        Ok(last)
+    }
+
+    /// Writes a consistent copy of the block store to `path` with the sqlite online backup
+    /// api. Bitswap and the garbage collector keep running. Returns the last change in the
+    /// copy, to pass to `export_changes` for an incremental backup. Fails for an in-memory
+    /// store.
+    pub async fn backup<T: AsRef<std::path::Path>>(&self, path: T) -> Result<u64> {
+        let storage = self.storage.clone();
+        let path = path.as_ref().to_path_buf();
+        async_global_executor::spawn_blocking(move || storage.backup(&path)).await
+    }
+
+    /// Imports the blocks of a CARv1 or CARv2, verifying the hash of every block, and returns
+    /// the roots of the archive. The blocks are inserted in batches. If an alias is given it
+    /// is set to the first root once all blocks are inserted.
+    pub fn import_car<R, T>(&self, reader: R, alias: Option<T>) -> Result<Vec<Cid>>
+    where
+        R: std::io::Read,
+        T: AsRef<[u8]> + Send + Sync,
+    {
+        self.import_car_with(reader, alias, car::BATCH_SIZE, || Ok(()))
+    }
+
+    /// Like `import_car`, calling `between_batches` after every inserted batch.
+    fn import_car_with<R, T, F>(
+        &self,
+        reader: R,
+        alias: Option<T>,
+        batch_size: usize,
+        mut between_batches: F,
+    ) -> Result<Vec<Cid>>
+    where
+        R: std::io::Read,
+        T: AsRef<[u8]> + Send + Sync,
+        F: FnMut() -> Result<()>,
+    {
+        let mut car = car::CarReader::new(reader)?;
+        let roots = car.roots().to_vec();
+        if alias.is_some() && roots.is_empty() {
+            return Err(anyhow::anyhow!("car has no root to alias"));
+        }
+        // every inserted block is added to the pin, the root may come last and the blocks
+        // inserted before it must survive the garbage collector until the alias is set
+        let tmp = self.create_temp_pin()?;
+        let mut batch = Vec::with_capacity(batch_size);
+        while let Some(block) = car.next_block()? {
+            batch.push(block);
+            if batch.len() >= batch_size {
+                self.storage.insert_batch(&batch, Some(&tmp), None)?;
+                batch.clear();
+                between_batches()?;
+            }
+        }
+        if !batch.is_empty() {
+            self.storage.insert_batch(&batch, Some(&tmp), None)?;
+        }
+        if let Some(alias) = alias {
+            self.alias(alias, Some(&roots[0]))?;
+        }
+        drop(tmp);
+        Ok(roots)
+    }
+
     /// Manually runs garbage collection to completion. This is mainly useful for testing and
     /// administrative interfaces. During normal operation, the garbage collector automatically
     /// runs in the background.
@@ -307,6 +728,40 @@ where
         self.storage.evict().await
     }
 
+    /// Checks the integrity of the block store. Every block is rehashed against its `Cid`,
+    /// the stored references are compared with the links of the blocks and the aliases with
+    /// missing blocks are reported. Optionally corrupt blocks are deleted and fetched again
+    /// from the network.
+    pub async fn verify(&self, options: VerifyOptions) -> Result<VerifyReport> {
+        let storage = self.storage.clone();
+        let mut report =
+            async_global_executor::spawn_blocking(move || storage.verify(options.repair)).await?;
+        if options.refetch && !report.deleted.is_empty() {
+            let tmp = self.create_temp_pin()?;
+            for cid in &report.deleted {
+                self.temp_pin(&tmp, cid)?;
+            }
+            let fetches = report.deleted.iter().map(|cid| async move {
+                match self.fetch(cid).await {
+                    Ok(_) => Some(*cid),
+                    Err(err) => {
+                        tracing::warn!("failed to refetch {}: {}", cid, err);
+                        None
+                    }
+                }
+            });
+            report.refetched = futures::future::join_all(fetches)
+                .await
+                .into_iter()
+                .flatten()
+                .collect();
+            if !report.refetched.is_empty() {
+                report.missing = self.storage.missing_alias_blocks()?;
+            }
+        }
+        Ok(report)
+    }
+
     pub async fn sync(&self, cid: &Cid) -> Result<()> {
         let _missing = self.storage.missing_blocks(cid).ok().unwrap_or_default();
 
@@ -316,7 +771,8 @@ where
         Ok(())
     }
 
-    /// Creates, updates or removes an alias with a new root `Cid`.
+    /// Creates, updates or removes an alias with a new root `Cid`. Fails with
+    /// `QuotaExceeded` if the dag of the new root exceeds a quota of the alias.
     pub fn alias<T: AsRef<[u8]> + Send + Sync>(&self, alias: T, cid: Option<&Cid>) -> Result<()> {
         self.storage.alias(alias.as_ref(), cid)
     }
@@ -326,6 +782,70 @@ where
         self.storage.resolve(alias.as_ref())
     }
 
+    /// Returns every alias with its root.
+    pub fn aliases(&self) -> Result<Vec<(Vec<u8>, Cid)>> {
+        self.storage.aliases()
+    }
+
+    /// Returns the root, the metadata and the dag size of an alias. Computing the dag size
+    /// walks the dag.
+    pub fn alias_info<T>(&self, alias: T) -> Result<Option<AliasInfo>>
+    where
+        T: AsRef<[u8]> + Send + Sync,
+    {
+        let alias = alias.as_ref();
+        let root = match self.storage.resolve(alias)? {
+            Some(root) => root,
+            None => return Ok(None),
+        };
+        let meta = self.storage.alias_metadata(alias)?;
+        let DagStat { size, blocks } = self.storage.dag_stat(&root)?;
+        Ok(Some(AliasInfo {
+            alias: alias.to_vec(),
+            root,
+            created: meta.as_ref().map(|meta| meta.created),
+            updated: meta.as_ref().map(|meta| meta.updated),
+            label: meta.and_then(|meta| meta.label),
+            size,
+            blocks,
+        }))
+    }
+
+    /// Returns the total size and the number of the blocks of the dag of `cid` that are in
+    /// the block store.
+    pub fn dag_stat(&self, cid: &Cid) -> Result<DagStat> {
+        self.storage.dag_stat(cid)
+    }
+
+    /// Returns the number and size of the stored blocks, the pinned and cached bytes, the
+    /// temp pins, the orphaned blocks and the last garbage collector runs.
+    pub fn repo_stats(&self) -> Result<RepoStats> {
+        self.storage.repo_stats()
+    }
+
+    /// Sets the byte quota of the aliases starting with `prefix`, or removes it with `None`.
+    /// A quota isn't persisted, quotas known at startup belong in `StorageConfig::quotas`.
+    /// Quotas are checked when an alias is set and by `insert_pinned`, blocks inserted under
+    /// a plain temporary pin aren't counted until they are aliased.
+    pub fn set_quota<T: AsRef<[u8]> + Send + Sync>(&self, prefix: T, bytes: Option<u64>) {
+        self.storage.set_quota(prefix.as_ref(), bytes)
+    }
+
+    /// Returns the bytes used by the dags of the aliases starting with `prefix`. Blocks
+    /// shared between the dags are counted once.
+    pub fn quota_usage<T: AsRef<[u8]> + Send + Sync>(&self, prefix: T) -> Result<u64> {
+        self.storage.quota_usage(prefix.as_ref())
+    }
+
+    /// Sets a label of an alias, returning `false` if there is no such alias.
+    pub fn set_alias_label<T: AsRef<[u8]> + Send + Sync>(
+        &self,
+        alias: T,
+        label: Option<&str>,
+    ) -> Result<bool> {
+        self.storage.set_alias_label(alias.as_ref(), label)
+    }
+
     /// Returns a list of aliases preventing a `Cid` from being garbage collected.
     pub fn reverse_alias(&self, cid: &Cid) -> Result<Option<Vec<Vec<u8>>>> {
         self.storage.reverse_alias(cid)
@@ -345,6 +865,11 @@ where
     }
 }
 
+/// The pubsub topic name record updates are pushed to.
+fn name_topic(key: &[u8]) -> String {
+    String::from_utf8_lossy(key).into_owned()
+}
+
 /// Telemetry server
 pub fn telemetry<P: StoreParams>(addr: SocketAddr, ipfs: &Ipfs<P>) -> Result<()>
 where
@@ -354,7 +879,13 @@ where
     ipfs.register_metrics(registry)?;
     let mut s = tide::new();
     s.at("/metrics").get(get_metric);
-    async_global_executor::spawn(async move { s.listen(addr).await }).detach();
+    let server = async_global_executor::spawn(async move {
+        if let Err(err) = s.listen(addr).await {
+            tracing::error!("telemetry server failed: {}", err);
+        }
+    });
+    // the server is stopped on shutdown
+    ipfs.tasks.lock().unwrap().push(server);
     Ok(())
 }
 
@@ -437,6 +968,7 @@ mod tests {
     use libipld::raw::RawCodec;
     use libipld::store::DefaultParams;
     use libipld::{alias, ipld};
+    use std::collections::HashSet;
     use std::time::Duration;
 
     fn tracing_try_init() {
@@ -447,14 +979,35 @@ mod tests {
     }
 
     async fn create_store(enable_mdns: bool) -> Result<Ipfs<DefaultParams>> {
-        let sweep_interval = Duration::from_millis(10000);
-        let storage = StorageConfig::new(None, 10, sweep_interval);
-
-        let mut network = NetworkConfig::new(vec!["/ip4/127.0.0.1/tcp/0".parse()?]);
+        // mdns only discovers nodes listening on ip addresses
+        let addr = if enable_mdns { "/ip4/127.0.0.1/tcp/0" } else { "/memory/0" };
+        let mut network = NetworkConfig::new(vec![addr.parse()?]);
         network.enable_mdns = enable_mdns;
         network.allow_non_globals_in_dht = true;
+        create_store_with(network).await
+    }
+
+    /// Waits until `n` peers of `store` are subscribed to `topic`.
+    async fn wait_for_subscribers(store: &Ipfs<DefaultParams>, topic: &str, n: usize) -> Result<()> {
+        async_std::future::timeout(Duration::from_secs(10), async {
+            while store.pubsub_peers(topic).await?.len() < n {
+                async_std::task::sleep(Duration::from_millis(10)).await;
+            }
+            Result::<()>::Ok(())
+        })
+        .await?
+    }
+
+    async fn create_store_with(network: NetworkConfig) -> Result<Ipfs<DefaultParams>> {
+        let sweep_interval = Duration::from_millis(10000);
+        let storage = StorageConfig::new(None, 10, sweep_interval);
 
-        let ipfs = Ipfs::new(Config { storage, network }).await?;
+        let ipfs = Ipfs::new(Config {
+            storage,
+            network,
+            reprovider: Default::default(),
+        })
+        .await?;
         Ok(ipfs)
     }
 
@@ -475,6 +1028,44 @@ mod tests {
         Ok(())
     }
 
+    #[async_std::test]
+    async fn test_persistent_identity() -> Result<()> {
+        tracing_try_init();
+        let dir = tempfile::tempdir()?;
+        let addr: Multiaddr = "/memory/0".parse()?;
+        let config = Config::with_repo(dir.path(), 10, addr.clone())?;
+        let config2 = Config::with_repo(dir.path(), 10, addr)?;
+        assert_eq!(config.network.peer_id(), config2.network.peer_id());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_shutdown() -> Result<()> {
+        tracing_try_init();
+        let dir = tempfile::tempdir()?;
+        let addr: Multiaddr = "/memory/0".parse()?;
+        let block = create_block(b"test_shutdown")?;
+
+        let config = Config::with_repo(dir.path(), 10, addr.clone())?;
+        let store = Ipfs::<DefaultParams>::new(config).await?;
+        store.insert(&block)?.await?;
+        store.alias(b"a", Some(block.cid()))?;
+        store.shutdown().await?;
+        assert!(store.get(block.cid()).is_err());
+        // no connection to the database is left open
+        let conn = rusqlite::Connection::open(dir.path().join("blocks.sqlite"))?;
+        conn.query_row("PRAGMA locking_mode=EXCLUSIVE", rusqlite::NO_PARAMS, |_| Ok(()))?;
+        conn.execute_batch("BEGIN EXCLUSIVE; COMMIT;")?;
+        drop(conn);
+
+        let config = Config::with_repo(dir.path(), 10, addr)?;
+        let store = Ipfs::<DefaultParams>::new(config).await?;
+        assert_eq!(store.resolve(b"a")?, Some(*block.cid()));
+        assert_eq!(store.get(block.cid())?.data(), block.data());
+        store.shutdown().await?;
+        Ok(())
+    }
+
     #[async_std::test]
     #[cfg(not(target_os = "macos"))] // mdns doesn't work on macos in github actions
     async fn test_exchange_mdns() -> Result<()> {
@@ -671,8 +1262,7 @@ mod tests {
             }
             subscriptions.push(store.subscribe(topic).await?);
         }
-
-        async_std::task::sleep(Duration::from_millis(500)).await;
+        wait_for_subscribers(&stores[0], topic, stores.len() - 1).await?;
 
         stores[0].publish(&topic, b"hello world".to_vec()).await.unwrap();
 
@@ -683,4 +1273,603 @@ mod tests {
         }
         Ok(())
     }
+
+    #[async_std::test]
+    async fn test_gossipsub() -> Result<()> {
+        tracing_try_init();
+        let mut stores = vec![];
+        for i in 0..6 {
+            let mut network = NetworkConfig::new(vec!["/memory/0".parse()?]);
+            network.enable_mdns = false;
+            // only stores[0] delivers its own messages to its subscription
+            network.pubsub = PubsubConfig::Gossipsub(GossipsubConfig {
+                subscribe_local_messages: i == 0,
+                ..Default::default()
+            });
+            stores.push(create_store_with(network).await?);
+        }
+        let mut subscriptions = vec![];
+        let topic = "topic";
+        for store in &stores {
+            for other in &stores {
+                if store.local_peer_id() != other.local_peer_id() {
+                    store.dial_address(&other.local_peer_id(), other.listeners().await[0].clone()).await?;
+                }
+            }
+            subscriptions.push(store.subscribe(topic).await?);
+        }
+        for store in &stores[..2] {
+            wait_for_subscribers(store, topic, stores.len() - 1).await?;
+        }
+
+        stores[0].publish(&topic, b"hello world".to_vec()).await.unwrap();
+        stores[1].publish(&topic, b"bye world".to_vec()).await.unwrap();
+
+        for (i, subscription) in subscriptions.iter_mut().enumerate() {
+            let mut msgs = vec![subscription.next().await.unwrap()];
+            if i != 1 {
+                msgs.push(subscription.next().await.unwrap());
+            }
+            msgs.sort_by_key(|msg| msg.data.clone());
+            let hello = msgs.pop().unwrap();
+            assert_eq!(hello.data, &b"hello world"[..]);
+            assert_eq!(hello.source, stores[0].local_peer_id());
+            if let Some(bye) = msgs.pop() {
+                assert_eq!(bye.data, &b"bye world"[..]);
+                assert_eq!(bye.source, stores[1].local_peer_id());
+            }
+        }
+        // stores[1] doesn't deliver its own message
+        let next = async_std::future::timeout(Duration::from_millis(500), subscriptions[1].next());
+        assert!(next.await.is_err());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_topic_validator() -> Result<()> {
+        tracing_try_init();
+        let mut stores = vec![];
+        for _ in 0..3 {
+            let mut network = NetworkConfig::new(vec!["/memory/0".parse()?]);
+            network.enable_mdns = false;
+            network.pubsub = PubsubConfig::Gossipsub(Default::default());
+            stores.push(create_store_with(network).await?);
+        }
+        let topic = "topic";
+        // stores[2] is only connected to stores[1], so it only sees what stores[1] forwards
+        stores[0].dial_address(&stores[1].local_peer_id(), stores[1].listeners().await[0].clone()).await?;
+        stores[2].dial_address(&stores[1].local_peer_id(), stores[1].listeners().await[0].clone()).await?;
+        stores[1]
+            .register_topic_validator(topic, |_, data| {
+                if data == b"spam" {
+                    ValidationResult::Reject
+                } else {
+                    ValidationResult::Accept
+                }
+            })
+            .await?;
+        let mut sub1 = stores[1].subscribe(topic).await?;
+        let mut sub2 = stores[2].subscribe(topic).await?;
+        let _sub0 = stores[0].subscribe(topic).await?;
+        wait_for_subscribers(&stores[0], topic, 1).await?;
+        wait_for_subscribers(&stores[1], topic, 2).await?;
+
+        stores[0].publish(topic, b"spam".to_vec()).await?;
+        stores[0].publish(topic, b"ham".to_vec()).await?;
+
+        assert_eq!(sub1.next().await.unwrap().data, &b"ham"[..]);
+        assert_eq!(sub2.next().await.unwrap().data, &b"ham"[..]);
+
+        // floodsub would forward rejected messages
+        let floodsub = create_store(false).await?;
+        let res = floodsub.register_topic_validator(topic, |_, _| ValidationResult::Reject).await;
+        assert!(res.is_err());
+        Ok(())
+    }
+
+    #[async_std::test]
+    #[allow(clippy::eval_order_dependence)]
+    async fn test_signed_record() -> Result<()> {
+        tracing_try_init();
+        let stores = [create_store(false).await?, create_store(false).await?];
+        stores[0]
+            .bootstrap(&[(stores[1].local_peer_id(), stores[1].listeners().await[0].clone())])
+            .await?;
+        stores[1]
+            .bootstrap(&[(stores[0].local_peer_id(), stores[0].listeners().await[0].clone())])
+            .await?;
+
+        stores[0].put_signed_record("name", b"hello world".to_vec()).await?;
+        let value = stores[1].get_signed_record(&stores[0].local_peer_id(), "name").await?;
+        assert_eq!(value, b"hello world".to_vec());
+
+        // stores[1] can't forge a record of stores[0]
+        let key = SignedRecord::key(&stores[0].local_peer_id(), "name");
+        let forged = SignedRecord::new(&stores[1].keypair, &key, u64::MAX, b"forged".to_vec())?;
+        assert!(stores[1].put_record(key, forged.to_bytes()).await.is_err());
+        Ok(())
+    }
+
+    #[async_std::test]
+    #[allow(clippy::eval_order_dependence)]
+    async fn test_name() -> Result<()> {
+        tracing_try_init();
+        let stores = [create_store(false).await?, create_store(false).await?];
+        stores[0]
+            .bootstrap(&[(stores[1].local_peer_id(), stores[1].listeners().await[0].clone())])
+            .await?;
+        stores[1]
+            .bootstrap(&[(stores[0].local_peer_id(), stores[0].listeners().await[0].clone())])
+            .await?;
+        let a = create_block(b"a")?;
+        let b = create_block(b"b")?;
+        let ttl = Duration::from_secs(60);
+        let name = stores[0].local_peer_id().to_string();
+
+        stores[0].publish_name("self", a.cid(), ttl).await?;
+        assert_eq!(stores[1].resolve_name(&name).await?, *a.cid());
+
+        stores[0].publish_name("self", b.cid(), ttl).await?;
+        assert_eq!(stores[1].resolve_name(&name).await?, *b.cid());
+        assert_eq!(stores[0].resolve_name(&name).await?, *b.cid());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_name_pubsub() -> Result<()> {
+        tracing_try_init();
+        let mut stores = vec![];
+        for _ in 0..2 {
+            let mut network = NetworkConfig::new(vec!["/memory/0".parse()?]);
+            network.enable_mdns = false;
+            network.name_pubsub = true;
+            stores.push(create_store_with(network).await?);
+        }
+        stores[1]
+            .dial_address(&stores[0].local_peer_id(), stores[0].listeners().await[0].clone())
+            .await?;
+        let name = format!("{}/docs", stores[0].local_peer_id());
+        let mut updates = stores[1].subscribe_name(&name).await?;
+        wait_for_subscribers(&stores[0], &name_topic(&NameRecord::parse_key(&name)?), 1).await?;
+
+        let block = create_block(b"docs")?;
+        stores[0].publish_name("docs", block.cid(), Duration::from_secs(60)).await?;
+        assert_eq!(updates.next().await, Some(*block.cid()));
+        assert!(stores[1].network.local_record(&NameRecord::parse_key(&name)?).is_some());
+        Ok(())
+    }
+
+    fn provided(store: &Ipfs<DefaultParams>) -> HashSet<Vec<u8>> {
+        store.network.provided().into_iter().collect()
+    }
+
+    #[async_std::test]
+    async fn test_reprovide_strategy() -> Result<()> {
+        tracing_try_init();
+        let a = create_block(b"a")?;
+        let b = create_block(b"b")?;
+        for strategy in &[ReprovideStrategy::Roots, ReprovideStrategy::All] {
+            let mut network = NetworkConfig::new(vec!["/memory/0".parse()?]);
+            network.enable_mdns = false;
+            let mut config = Config {
+                storage: StorageConfig::new(None, 0, Duration::from_millis(10000)),
+                network,
+                reprovider: Default::default(),
+            };
+            config.reprovider.strategy = *strategy;
+            let store = Ipfs::<DefaultParams>::new(config).await?;
+            let _ = store.insert(&a)?;
+            let _ = store.insert(&b)?;
+            store.alias(b"root", Some(a.cid()))?;
+            assert!(provided(&store).is_empty());
+
+            let count = store.reprovide().await?;
+            let mut expected = HashSet::new();
+            expected.insert(a.cid().to_bytes());
+            if *strategy == ReprovideStrategy::All {
+                assert_eq!(count, 2);
+                expected.insert(b.cid().to_bytes());
+                assert_eq!(provided(&store), expected);
+
+                // the unaliased block is removed by the gc and no longer provided
+                store.evict().await?;
+                expected.remove(&b.cid().to_bytes());
+                for _ in 0..50 {
+                    if provided(&store) == expected {
+                        break;
+                    }
+                    async_std::task::sleep(Duration::from_millis(100)).await;
+                }
+            } else {
+                assert_eq!(count, 1);
+            }
+            assert_eq!(provided(&store), expected);
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_relay() -> Result<()> {
+        tracing_try_init();
+        let mut network = NetworkConfig::new(vec!["/ip4/127.0.0.1/tcp/0".parse()?]);
+        network.enable_mdns = false;
+        network.relay_server = Some(Default::default());
+        let relay = create_store_with(network).await?;
+        let relay_addr = relay.listeners().await[0].clone();
+
+        // the nated node doesn't listen on any address
+        let mut network = NetworkConfig::new(vec![]);
+        network.enable_mdns = false;
+        network.relays = vec![(relay.local_peer_id(), relay_addr)];
+        let nated = create_store_with(network).await?;
+
+        let mut circuit = None;
+        for _ in 0..50 {
+            if let Some(addr) = nated.external_addresses().pop() {
+                circuit = Some(addr);
+                break;
+            }
+            async_std::task::sleep(Duration::from_millis(100)).await;
+        }
+        let circuit = circuit.expect("no relay reservation");
+        assert!(circuit.to_string().ends_with("/p2p-circuit"));
+
+        let mut network = NetworkConfig::new(vec!["/ip4/127.0.0.1/tcp/0".parse()?]);
+        network.enable_mdns = false;
+        let dialer = create_store_with(network).await?;
+        let addr = circuit.with(ipfs_embed_net::Protocol::P2p(nated.local_peer_id().into()));
+        dialer.dial_address(&nated.local_peer_id(), addr).await?;
+        let connections = dialer.connections().await;
+        assert!(connections.iter().any(|(peer, _)| *peer == nated.local_peer_id()));
+
+        // closing the node stops renewing the reservation
+        nated.network.shutdown().await;
+        assert!(nated.external_addresses().is_empty());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_autonat() -> Result<()> {
+        tracing_try_init();
+        let mut stores = vec![];
+        for _ in 0..3 {
+            let mut network = NetworkConfig::new(vec!["/memory/0".parse()?]);
+            network.enable_mdns = false;
+            network.autonat.only_global_ips = false;
+            stores.push(create_store_with(network).await?);
+        }
+        for other in &stores[1..] {
+            stores[0]
+                .dial_address(&other.local_peer_id(), other.listeners().await[0].clone())
+                .await?;
+        }
+        assert_eq!(stores[0].nat_status(), NatStatus::Unknown);
+        assert_eq!(stores[0].probe_nat().await?, NatStatus::Public);
+        let listener = stores[0].listeners().await[0].clone();
+        assert!(stores[0].external_addresses().contains(&listener));
+        assert!(stores[0].network.is_dht_server());
+        Ok(())
+    }
+
+    #[async_std::test]
+    #[allow(clippy::eval_order_dependence)]
+    async fn test_dht_client_mode() -> Result<()> {
+        tracing_try_init();
+        let stores = [create_store(false).await?, create_store(false).await?];
+        let key = b"key".to_vec();
+        stores[0].network.store_record(key.clone(), b"hello world".to_vec())?;
+
+        // a node in client mode refuses the queries of remote peers
+        stores[0].network.set_dht_server(false);
+        assert!(!stores[0].network.is_dht_server());
+        stores[1]
+            .bootstrap(&[(stores[0].local_peer_id(), stores[0].listeners().await[0].clone())])
+            .await?;
+        assert!(stores[1].get_record(&key).await.is_err());
+
+        // switching back to server mode at runtime answers them again
+        stores[0].network.set_dht_server(true);
+        assert!(stores[0].network.is_dht_server());
+        stores[1]
+            .bootstrap(&[(stores[0].local_peer_id(), stores[0].listeners().await[0].clone())])
+            .await?;
+        assert_eq!(stores[1].get_record(&key).await?, b"hello world".to_vec());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_websocket() -> Result<()> {
+        tracing_try_init();
+        let mut stores = vec![];
+        for _ in 0..2 {
+            let mut network = NetworkConfig::new(vec!["/ip4/127.0.0.1/tcp/0/ws".parse()?]);
+            network.enable_mdns = false;
+            network.transport = TransportConfig::default().with_tcp(false).with_websocket(true);
+            stores.push(create_store_with(network).await?);
+        }
+        let addr = stores[1].listeners().await[0].clone();
+        assert!(addr.to_string().ends_with("/ws"));
+        stores[0].dial_address(&stores[1].local_peer_id(), addr).await?;
+        let connections = stores[0].connections().await;
+        assert!(connections
+            .iter()
+            .any(|(peer, addr)| *peer == stores[1].local_peer_id() && addr.to_string().ends_with("/ws")));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_network_events() -> Result<()> {
+        tracing_try_init();
+        let store1 = create_store(false).await?;
+        let store2 = create_store(false).await?;
+        let peer2 = store2.local_peer_id();
+        let mut events = store1.network_events();
+
+        store1.dial_address(&peer2, store2.listeners().await[0].clone()).await?;
+        loop {
+            match events.next().await.unwrap()? {
+                NetworkEvent::ConnectionEstablished { peer, .. } if peer == peer2 => break,
+                _ => {}
+            }
+        }
+
+        store1.ban(peer2);
+        let mut banned = false;
+        loop {
+            match events.next().await.unwrap()? {
+                NetworkEvent::Banned(peer) if peer == peer2 => banned = true,
+                NetworkEvent::ConnectionClosed { peer, .. } if peer == peer2 => break,
+                _ => {}
+            }
+        }
+        assert!(banned);
+        assert!(store1.dial(&peer2).await.is_err());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_secio_opt_in() -> Result<()> {
+        tracing_try_init();
+        let mut network = NetworkConfig::new(vec!["/memory/0".parse()?]);
+        network.enable_mdns = false;
+        network.security = SecurityConfig {
+            noise: false,
+            secio: true,
+        };
+        network.muxer.mplex = false;
+        let legacy = create_store_with(network).await?;
+        let store = create_store(false).await?;
+
+        // the default config only offers noise
+        let addr = legacy.listeners().await[0].clone();
+        assert!(store.dial_address(&legacy.local_peer_id(), addr.clone()).await.is_err());
+
+        let mut network = NetworkConfig::new(vec!["/memory/0".parse()?]);
+        network.enable_mdns = false;
+        network.security.secio = true;
+        let store = create_store_with(network).await?;
+        store.dial_address(&legacy.local_peer_id(), addr).await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_peer_info() -> Result<()> {
+        tracing_try_init();
+        let mut network = NetworkConfig::new(vec!["/memory/0".parse()?]);
+        network.enable_mdns = false;
+        network.node_name = "test-node".into();
+        let store1 = create_store_with(network).await?;
+        let store2 = create_store(false).await?;
+        let peer1 = store1.local_peer_id();
+        let mut events = store2.network_events();
+        store2.dial_address(&peer1, store1.listeners().await[0].clone()).await?;
+        loop {
+            if let NetworkEvent::Identified { peer, .. } = events.next().await.unwrap()? {
+                if peer == peer1 {
+                    break;
+                }
+            }
+        }
+        let info = store2.peer_info(&peer1).await.unwrap();
+        assert!(info.agent_version.starts_with("ipfs-embed/"));
+        assert!(info.agent_version.ends_with("(test-node)"));
+        assert!(info.listen_addrs.contains(&store1.listeners().await[0]));
+        assert!(info.rtt.is_some());
+
+        store1.shutdown().await?;
+        loop {
+            if let NetworkEvent::ConnectionClosed { peer, .. } = events.next().await.unwrap()? {
+                if peer == peer1 {
+                    break;
+                }
+            }
+        }
+        assert!(store2.peer_info(&peer1).await.is_none());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_insert_batch() -> Result<()> {
+        tracing_try_init();
+        let store = create_store(false).await?;
+        let a = create_block(b"test_insert_batch_a")?;
+        let b = create_block(b"test_insert_batch_b")?;
+        let alias = b"test_insert_batch";
+        store
+            .insert_batch(&[b.clone(), a.clone()], b.cid(), Some(alias))?
+            .await?;
+        assert_eq!(store.resolve(alias)?, Some(*b.cid()));
+        assert_eq!(store.get(a.cid())?.data(), a.data());
+        let c = create_block(b"test_insert_batch_c")?;
+        assert!(store.insert_batch::<&[u8]>(&[], c.cid(), None).is_err());
+        assert!(store.insert_batch::<&[u8]>(&[a.clone()], c.cid(), None).is_err());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_import_car_gc_between_batches() -> Result<()> {
+        tracing_try_init();
+        let store = create_store(false).await?;
+        let encode = |ipld: Ipld| Block::<DefaultParams>::encode(DagCborCodec, Code::Blake3_256, &ipld);
+        let a = encode(ipld!({ "a": [] }))?;
+        let b = encode(ipld!({ "b": [] }))?;
+        let root = encode(ipld!({ "root": [a.cid(), b.cid()] }))?;
+        // the root comes last, the blocks of the first batches are only kept by the pin
+        let car = car::write_car(&[*root.cid()], &[a.clone(), b.clone(), root.clone()])?;
+        let roots = store.import_car_with(car.as_slice(), Some(b"imported"), 1, || {
+            async_std::task::block_on(store.evict())
+        })?;
+        assert_eq!(roots, vec![*root.cid()]);
+        store.evict().await?;
+        for block in &[a, b, root] {
+            assert_eq!(store.get(block.cid())?.data(), block.data());
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_car() -> Result<()> {
+        tracing_try_init();
+        let store = create_store(false).await?;
+        let encode = |ipld: Ipld| Block::<DefaultParams>::encode(DagCborCodec, Code::Blake3_256, &ipld);
+        let a = encode(ipld!({ "a": [] }))?;
+        let b = encode(ipld!({ "b": [a.cid()] }))?;
+        let c = encode(ipld!({ "c": [a.cid(), b.cid()] }))?;
+        store.insert_batch(&[a.clone(), b.clone(), c.clone()], c.cid(), Some(b"root"))?.await?;
+
+        for version in &[CarVersion::V1, CarVersion::V2] {
+            let mut car = vec![];
+            store.export_car(c.cid(), *version, &mut car)?;
+            let other = create_store(false).await?;
+            let roots = other.import_car(car.as_slice(), Some(b"imported"))?;
+            assert_eq!(roots, vec![*c.cid()]);
+            assert_eq!(other.resolve(b"imported")?, Some(*c.cid()));
+            for block in &[&a, &b, &c] {
+                assert_eq!(other.get(block.cid())?.data(), block.data());
+            }
+
+            // a corrupted block fails the hash check
+            let last = car.len() - 1;
+            if *version == CarVersion::V1 {
+                car[last] ^= 1;
+                let other = create_store(false).await?;
+                assert!(other.import_car::<_, &[u8]>(car.as_slice(), None).is_err());
+            }
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_aliases() -> Result<()> {
+        tracing_try_init();
+        let store = create_store(false).await?;
+        let a = create_block(b"test_aliases_a")?;
+        let b = create_block(b"test_aliases_b")?;
+        store.insert_batch(&[a.clone()], a.cid(), Some(b"a"))?.await?;
+        store.insert_batch(&[b.clone()], b.cid(), Some(b"b"))?.await?;
+        let mut aliases = store.aliases()?;
+        aliases.sort();
+        assert_eq!(aliases, vec![(b"a".to_vec(), *a.cid()), (b"b".to_vec(), *b.cid())]);
+
+        assert!(store.set_alias_label(b"a", Some("label"))?);
+        let info = store.alias_info(b"a")?.unwrap();
+        assert_eq!(info.root, *a.cid());
+        assert_eq!(info.label.as_deref(), Some("label"));
+        assert_eq!(info.size, a.data().len() as u64);
+        assert_eq!(info.blocks, 1);
+        assert!(info.created.is_some());
+        assert_eq!(store.alias_info(b"c")?, None);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_storage_events() -> Result<()> {
+        tracing_try_init();
+        let store = create_store(false).await?;
+        let mut events = store.storage_events();
+        let a = create_block(b"test_storage_events")?;
+        store.insert(&a)?.await?;
+        store.alias(b"a", Some(a.cid()))?;
+        assert_eq!(
+            events.next().await.unwrap().unwrap(),
+            StorageEvent::Inserted(*a.cid())
+        );
+        assert_eq!(
+            events.next().await.unwrap().unwrap(),
+            StorageEvent::AliasChanged {
+                alias: b"a".to_vec(),
+                old: None,
+                new: Some(*a.cid()),
+            }
+        );
+        store.evict().await?;
+        match events.next().await.unwrap().unwrap() {
+            StorageEvent::GcCompleted { blocks, .. } => assert_eq!(blocks, 0),
+            event => panic!("unexpected event {:?}", event),
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_repo_stats() -> Result<()> {
+        tracing_try_init();
+        let dir = tempfile::tempdir()?;
+        let config = Config::with_repo(dir.path(), 10, "/memory/0".parse()?)?;
+        let store = Ipfs::<DefaultParams>::new(config).await?;
+        let a = create_block(b"test_repo_stats")?;
+        store.insert_batch(&[a.clone()], a.cid(), Some(b"a"))?.await?;
+        let size = a.data().len() as u64;
+        assert_eq!(store.dag_stat(a.cid())?, DagStat { size, blocks: 1 });
+        let stats = store.repo_stats()?;
+        assert_eq!(stats.blocks, 1);
+        assert_eq!(stats.bytes, size);
+        assert_eq!(stats.usage.unwrap().pinned_bytes, size);
+        store.shutdown().await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_quota() -> Result<()> {
+        tracing_try_init();
+        let store = create_store(false).await?;
+        let a = create_block(b"test_quota_a")?;
+        let b = create_block(b"test_quota_b")?;
+        store.set_quota("tenant/", Some(a.data().len() as u64));
+        let pin = store.create_quota_pin("tenant/a")?;
+        store.insert_pinned(&pin, &a)?.await?;
+        store.alias("tenant/a", Some(a.cid()))?;
+        assert_eq!(store.quota_usage("tenant/")?, a.data().len() as u64);
+        store.insert(&b)?.await?;
+        let err = store.alias("tenant/b", Some(b.cid())).unwrap_err();
+        assert!(err.downcast_ref::<QuotaExceeded>().is_some());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_backup() -> Result<()> {
+        tracing_try_init();
+        let dir = tempfile::tempdir()?;
+        let config = Config::with_repo(dir.path().join("repo"), 10, "/memory/0".parse()?)?;
+        let store = Ipfs::<DefaultParams>::new(config).await?;
+        let a = create_block(b"test_backup_a")?;
+        let b = create_block(b"test_backup_b")?;
+        store.insert_batch(&[a.clone()], a.cid(), Some(b"a"))?.await?;
+        let since = store.backup(dir.path().join("backup.sqlite")).await?;
+        store.insert(&b)?.await?;
+        let mut car = vec![];
+        let last = store.export_changes(since, &mut car)?;
+        assert!(last > since);
+        let other = create_store(false).await?;
+        let roots = other.import_car(car.as_slice(), Some(b"backup"))?;
+        assert_eq!(roots.len(), 1);
+        assert!(other.contains(b.cid())?);
+        assert!(!other.contains(a.cid())?);
+        // the index root keeps the exported blocks
+        assert_eq!(other.resolve(b"backup")?, Some(roots[0]));
+        assert_eq!(other.dag_stat(&roots[0])?.blocks, 2);
+        let mut car = vec![];
+        assert_eq!(store.export_changes(last, &mut car)?, last);
+        store.shutdown().await?;
+        Ok(())
+    }
 }
@@ -0,0 +1,142 @@
+use async_global_executor::Task;
+use futures::channel::mpsc;
+use futures::stream::StreamExt;
+use ipfs_embed_net::NetworkService;
+use ipfs_embed_sqlite::{StorageEvent, StorageService};
+use libipld::codec::References;
+use libipld::store::StoreParams;
+use libipld::{Cid, Ipld, Result};
+use std::time::Duration;
+
+/// The blocks the reprovider announces.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReprovideStrategy {
+    /// Announces every block in the block store.
+    All,
+    /// Only announces the roots of aliases.
+    Roots,
+    /// Doesn't announce blocks.
+    None,
+}
+
+/// Reprovider configuration.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ReproviderConfig {
+    /// The blocks to announce.
+    pub strategy: ReprovideStrategy,
+    /// The interval at which provider records are renewed. Provider records expire in the
+    /// dht, so it must be shorter than the provider record ttl of the network.
+    pub interval: Duration,
+    /// The number of provider records put in the dht concurrently.
+    pub batch_size: usize,
+}
+
+impl Default for ReproviderConfig {
+    fn default() -> Self {
+        Self {
+            strategy: ReprovideStrategy::All,
+            interval: Duration::from_secs(12 * 60 * 60),
+            batch_size: 64,
+        }
+    }
+}
+
+/// Announces the blocks of the block store in the dht, and stops announcing blocks that
+/// have been removed by the garbage collector.
+#[derive(Clone)]
+pub(crate) struct Reprovider<P: StoreParams> {
+    config: ReproviderConfig,
+    storage: StorageService<P>,
+    network: NetworkService,
+}
+
+impl<P: StoreParams> Reprovider<P>
+where
+    Ipld: References<P::Codecs>,
+{
+    pub fn new(
+        config: ReproviderConfig,
+        storage: StorageService<P>,
+        network: NetworkService,
+    ) -> Self {
+        Self {
+            config,
+            storage,
+            network,
+        }
+    }
+
+    /// Starts the reprovide loop and the loop removing provider records of removed blocks.
+    /// The blocks are announced once at startup and then every interval. Dropping the
+    /// returned tasks stops the loops.
+    pub fn spawn(&self, storage_events: mpsc::UnboundedReceiver<StorageEvent>) -> Vec<Task<()>> {
+        let mut tasks = vec![];
+        let reprovider = self.clone();
+        tasks.push(async_global_executor::spawn(async move {
+            let batch_size = reprovider.config.batch_size.max(1);
+            let mut events = storage_events.ready_chunks(batch_size);
+            while let Some(events) = events.next().await {
+                let cids = events.into_iter().filter_map(|event| match event {
+                    StorageEvent::Removed(cid) => Some(cid),
+                    _ => None,
+                });
+                reprovider.unprovide(cids).await;
+            }
+        }));
+
+        if self.config.strategy == ReprovideStrategy::None {
+            return tasks;
+        }
+        let reprovider = self.clone();
+        tasks.push(async_global_executor::spawn(async move {
+            loop {
+                match reprovider.reprovide().await {
+                    Ok(count) => tracing::debug!("reprovided {} blocks", count),
+                    Err(err) => tracing::error!("reprovide failed: {}", err),
+                }
+                async_io::Timer::after(reprovider.config.interval).await;
+            }
+        }));
+        tasks
+    }
+
+    /// Returns the blocks to announce according to the strategy.
+    fn cids(&self) -> Result<Vec<Cid>> {
+        Ok(match self.config.strategy {
+            ReprovideStrategy::All => self.storage.iter()?.collect(),
+            ReprovideStrategy::Roots => self
+                .storage
+                .aliases()?
+                .into_iter()
+                .map(|(_, cid)| cid)
+                .collect(),
+            ReprovideStrategy::None => vec![],
+        })
+    }
+
+    /// Announces the blocks in batches, returning the number of announced blocks.
+    pub async fn reprovide(&self) -> Result<usize> {
+        let reprovider = self.clone();
+        let cids = async_global_executor::spawn_blocking(move || reprovider.cids()).await?;
+        for batch in cids.chunks(self.config.batch_size.max(1)) {
+            let provides = batch.iter().map(|cid| {
+                let mut kad = self.network.kad();
+                async move {
+                    if let Err(err) = kad.provide(cid.to_bytes()).await {
+                        tracing::debug!("failed to provide {}: {:?}", cid, err);
+                    }
+                }
+            });
+            futures::future::join_all(provides).await;
+        }
+        Ok(cids.len())
+    }
+
+    async fn unprovide(&self, cids: impl Iterator<Item = Cid>) {
+        let mut kad = self.network.kad();
+        for cid in cids {
+            tracing::debug!("stop providing {}", cid);
+            kad.unprovide(cid.to_bytes()).await;
+        }
+    }
+}